@@ -1,6 +1,7 @@
-use carmen_core::gridstore::{coalesce, stackable, stack_and_coalesce};
+use carmen_core::gridstore::{coalesce, stackable, stack_and_coalesce, CompactCoalesceContext};
+use carmen_core::gridstore::{langarray_to_langfield, langfield_to_langarray};
 use carmen_core::gridstore::{
-    CoalesceContext, GridEntry, GridKey, GridStore, GridStoreBuilder, MatchOpts, MatchKey, MatchKeyWithId, PhrasematchSubquery
+    CancellationToken, CoalesceContext, GridEntry, GridKey, GridStore, GridStoreBuilder, MatchOpts, MatchKey, MatchKeyWithId, PhrasematchSubquery
 };
 
 use neon::prelude::*;
@@ -47,6 +48,8 @@ impl Task for CoalesceTask {
 
 struct StackAndCoalesceTask {
     argument: (Vec<PhrasematchSubquery<ArcGridStore>>, MatchOpts),
+    // only return ids, relevance and coordinates for each entry
+    compact: bool,
 }
 
 impl Task for StackAndCoalesceTask {
@@ -69,9 +72,14 @@ impl Task for StackAndCoalesceTask {
                 Err(s) => return cx.throw_error(s),
             }
         };
-        Ok(neon_serde::to_value(&mut cx, converted_result)?
-            .downcast::<JsArray>()
-            .or_throw(&mut cx)?)
+        let js_result = if self.compact {
+            let compact: Vec<CompactCoalesceContext> =
+                converted_result.iter().map(CompactCoalesceContext::from).collect();
+            neon_serde::to_value(&mut cx, &compact)?
+        } else {
+            neon_serde::to_value(&mut cx, converted_result)?
+        };
+        Ok(js_result.downcast::<JsArray>().or_throw(&mut cx)?)
     }
 }
 
@@ -150,11 +158,8 @@ declare_types! {
 
         method compactAppend(mut cx) {
             let grid_key = cx.argument::<JsObject>(0)?;
-            let phrase_id: u32 = grid_key
-                .get(&mut cx, "phrase_id")?
-                .downcast::<JsNumber>()
-                .or_throw(&mut cx)?
-                .value() as u32;
+            let js_phrase_id = grid_key.get(&mut cx, "phrase_id")?;
+            let phrase_id: u64 = js_to_phrase_id(&mut cx, js_phrase_id)?;
 
             let js_lang_set = grid_key.get(&mut cx, "lang_set")?;
             let lang_set: u128 = langarray_to_langset(&mut cx, js_lang_set)?;
@@ -190,30 +195,21 @@ declare_types! {
         }
 
         method renumber(mut cx) {
-            let js_id_map = cx.argument::<JsArrayBuffer>(0)?;
+            let js_id_map = cx.argument::<JsValue>(0)?;
+            let ids: Vec<u64> = js_to_phrase_ids(&mut cx, js_id_map)?;
             let mut this = cx.this();
 
             let result: Result<(), String> = {
                 let lock = cx.lock();
-
-                let borrow_result = match js_id_map.try_borrow(&lock) {
-                    Ok(data) => {
-                        let slice = data.as_slice::<u32>();
-
-                        let mut gridstore = this.borrow_mut(&lock);
-                        match gridstore.as_mut() {
-                            Some(builder) => {
-                                builder.renumber(slice).map_err(|e| e.to_string())
-                            }
-                            None => {
-                                Err("can't call renumber after finish()".to_owned())
-                            }
-                        }
-                    },
-                    Err(e) => Err(e.to_string())
-                };
-
-                borrow_result
+                let mut gridstore = this.borrow_mut(&lock);
+                match gridstore.as_mut() {
+                    Some(builder) => {
+                        builder.renumber(&ids).map_err(|e| e.to_string())
+                    }
+                    None => {
+                        Err("can't call renumber after finish()".to_owned())
+                    }
+                }
             };
 
             match result {
@@ -223,30 +219,21 @@ declare_types! {
         }
 
         method loadBinBoundaries(mut cx) {
-            let bin_boundaries = cx.argument::<JsArrayBuffer>(0)?;
+            let bin_boundaries = cx.argument::<JsValue>(0)?;
+            let boundaries: Vec<u64> = js_to_phrase_ids(&mut cx, bin_boundaries)?;
             let mut this = cx.this();
 
             let result: Result<(), String> = {
                 let lock = cx.lock();
-
-                let borrow_result = match bin_boundaries.try_borrow(&lock) {
-                    Ok(data) => {
-                        let slice = data.as_slice::<u32>();
-
-                        let mut gridstore = this.borrow_mut(&lock);
-                        match gridstore.as_mut() {
-                            Some(builder) => {
-                                builder.load_bin_boundaries(slice.to_vec()).map_err(|e| e.to_string())
-                            }
-                            None => {
-                                Err("can't call loadBinBoundaries after finish()".to_owned())
-                            }
-                        }
-                    },
-                    Err(e) => Err(e.to_string())
-                };
-
-                borrow_result
+                let mut gridstore = this.borrow_mut(&lock);
+                match gridstore.as_mut() {
+                    Some(builder) => {
+                        builder.load_bin_boundaries(boundaries).map_err(|e| e.to_string())
+                    }
+                    None => {
+                        Err("can't call loadBinBoundaries after finish()".to_owned())
+                    }
+                }
             };
 
             match result {
@@ -305,11 +292,8 @@ declare_types! {
         method get(mut cx) {
             let grid_key = cx.argument::<JsObject>(0)?;
 
-            let phrase_id: u32 = grid_key
-                .get(&mut cx, "phrase_id")?
-                .downcast::<JsNumber>()
-                .or_throw(&mut cx)?
-                .value() as u32;
+            let js_phrase_id = grid_key.get(&mut cx, "phrase_id")?;
+            let phrase_id: u64 = js_to_phrase_id(&mut cx, js_phrase_id)?;
 
             let js_lang_set = grid_key.get(&mut cx, "lang_set")?;
             let lang_set: u128 = langarray_to_langset(&mut cx, js_lang_set)?;
@@ -331,6 +315,35 @@ declare_types! {
                 Err(e) => cx.throw_type_error(e.to_string())
             }
         }
+
+        method generation(mut cx) {
+            let this = cx.this();
+            let generation = {
+                let lock = cx.lock();
+                let grid_store = this.borrow(&lock);
+                grid_store.generation
+            };
+
+            match generation {
+                Some(generation) => Ok(JsNumber::new(&mut cx, generation as f64).upcast()),
+                None => Ok(JsUndefined::new().upcast()),
+            }
+        }
+    }
+
+    pub class JsCancellationToken as JsCancellationToken for CancellationToken {
+        init(_cx) {
+            Ok(CancellationToken::new())
+        }
+
+        method cancel(mut cx) {
+            let this = cx.this();
+            {
+                let lock = cx.lock();
+                this.borrow(&lock).cancel();
+            }
+            Ok(JsUndefined::new().upcast())
+        }
     }
 
     pub class JsGridKeyStoreKeyIterator as JsGridKeyStoreKeyIterator for KeyIterator {
@@ -375,7 +388,10 @@ declare_types! {
                     out.set(&mut cx, value_label, js_gk)?;
 
                     let phrase_id_label = JsString::new(&mut cx, "phrase_id");
-                    let phrase_id_value = JsNumber::new(&mut cx, gk.phrase_id);
+                    if gk.phrase_id > MAX_SAFE_PHRASE_ID {
+                        return cx.throw_range_error(format!("phrase_id {} can't be represented as a JS number", gk.phrase_id));
+                    }
+                    let phrase_id_value = JsNumber::new(&mut cx, gk.phrase_id as f64);
                     js_gk.set(&mut cx, phrase_id_label, phrase_id_value)?;
 
                     let lang_set_label = JsString::new(&mut cx, "lang_set");
@@ -404,17 +420,14 @@ where
     C: Context<'j>,
 {
     if let Ok(lang_array) = maybe_lang_array.downcast::<JsArray>() {
-        let mut out = 0u128;
+        let mut lang_ids = Vec::with_capacity(lang_array.len() as usize);
         for i in 0..lang_array.len() {
-            let converted_lang_array = lang_array.get(cx, i)?.downcast::<JsNumber>().or_throw(cx)?.value() as usize;
-            if  converted_lang_array >= 128 {
-                continue;
-            } else {
-            out = out
-                | (1 << converted_lang_array);
-            }
+            lang_ids.push(lang_array.get(cx, i)?.downcast::<JsNumber>().or_throw(cx)?.value() as u32);
+        }
+        match langarray_to_langfield(&lang_ids) {
+            Ok(lang_set) => Ok(lang_set),
+            Err(err) => cx.throw_range_error(err.to_string())?,
         }
-        Ok(out)
     } else if let Ok(_) = maybe_lang_array.downcast::<JsNull>() {
         Ok(std::u128::MAX)
     } else if let Ok(_) = maybe_lang_array.downcast::<JsUndefined>() {
@@ -424,16 +437,63 @@ where
     }
 }
 
+/// The largest phrase id a JS number can carry without losing precision
+/// (`Number.MAX_SAFE_INTEGER`).
+const MAX_SAFE_PHRASE_ID: u64 = (1 << 53) - 1;
+
+fn js_to_phrase_id<'j, C>(cx: &mut C, js_phrase_id: Handle<'j, JsValue>) -> Result<u64, neon_serde::errors::Error>
+where
+    C: Context<'j>,
+{
+    let value = js_phrase_id.downcast::<JsNumber>().or_throw(cx)?.value();
+    if value >= 0. && value.fract() == 0. && value <= MAX_SAFE_PHRASE_ID as f64 {
+        Ok(value as u64)
+    } else {
+        cx.throw_range_error(format!("phrase_id must be a non-negative safe integer, got {}", value))?
+    }
+}
+
+/// Reads a list of phrase ids from either a `BigUint64Array` (full 64-bit ids) or, as
+/// before, a `Uint32Array` or the `ArrayBuffer` backing one.
+fn js_to_phrase_ids<'j, C>(cx: &mut C, js_ids: Handle<'j, JsValue>) -> Result<Vec<u64>, neon_serde::errors::Error>
+where
+    C: Context<'j>,
+{
+    if let Ok(buffer) = js_ids.downcast::<JsArrayBuffer>() {
+        let lock = cx.lock();
+        let ids = buffer.borrow(&lock).as_slice::<u32>().iter().map(|id| *id as u64).collect();
+        return Ok(ids);
+    }
+
+    let typed_array = match js_ids.downcast::<JsObject>() {
+        Ok(typed_array) => typed_array,
+        Err(_) => cx.throw_type_error("Expected BigUint64Array, Uint32Array, or ArrayBuffer for phrase ids")?,
+    };
+    let element_size = match typed_array.get(cx, "BYTES_PER_ELEMENT")?.downcast::<JsNumber>() {
+        Ok(size) if size.value() == 4. || size.value() == 8. => size.value() as usize,
+        _ => cx.throw_type_error("Expected BigUint64Array, Uint32Array, or ArrayBuffer for phrase ids")?,
+    };
+    let buffer = typed_array.get(cx, "buffer")?.downcast::<JsArrayBuffer>().or_throw(cx)?;
+    let byte_offset =
+        typed_array.get(cx, "byteOffset")?.downcast::<JsNumber>().or_throw(cx)?.value() as usize;
+    let length = typed_array.get(cx, "length")?.downcast::<JsNumber>().or_throw(cx)?.value() as usize;
+
+    let lock = cx.lock();
+    let data = buffer.borrow(&lock);
+    let start = byte_offset / element_size;
+    let ids = if element_size == 8 {
+        data.as_slice::<u64>()[start..start + length].to_vec()
+    } else {
+        data.as_slice::<u32>()[start..start + length].iter().map(|id| *id as u64).collect()
+    };
+    Ok(ids)
+}
+
 fn langset_to_langarray<'j, C: Context<'j>>(cx: &mut C, lang_set: u128) -> Handle<'j, JsArray> {
     let out = JsArray::new(cx, 0);
-    let mut i = 0;
-    for j in 0..128 {
-        let bit = 1u128 << j;
-        if lang_set & bit != 0 {
-            let num = JsNumber::new(cx, j);
-            out.set(cx, i, num).expect("failed to set array slot");
-            i += 1;
-        }
+    for (i, lang_id) in langfield_to_langarray(lang_set).into_iter().enumerate() {
+        let num = JsNumber::new(cx, lang_id);
+        out.set(cx, i as u32, num).expect("failed to set array slot");
     }
     out
 }
@@ -443,7 +503,8 @@ pub fn js_coalesce(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let js_match_ops = { cx.argument::<JsValue>(1)? };
     let phrase_subq: Vec<PhrasematchSubquery<ArcGridStore>> =
         deserialize_phrasesubq(&mut cx, js_phrase_subq)?;
-    let match_opts: MatchOpts = neon_serde::from_value(&mut cx, js_match_ops)?;
+    let mut match_opts: MatchOpts = neon_serde::from_value(&mut cx, js_match_ops)?;
+    match_opts.cancellation = deserialize_cancellation(&mut cx, js_match_ops)?;
     let cb = cx.argument::<JsFunction>(2)?;
 
     let task = CoalesceTask { argument: (phrase_subq, match_opts) };
@@ -457,15 +518,44 @@ pub fn js_stack_and_coalesce(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let js_match_ops = { cx.argument::<JsValue>(1)? };
     let phrase_subq: Vec<PhrasematchSubquery<ArcGridStore>> =
         deserialize_phrasesubq(&mut cx, js_phrase_subq)?;
-    let match_opts: MatchOpts = neon_serde::from_value(&mut cx, js_match_ops)?;
+    let mut match_opts: MatchOpts = neon_serde::from_value(&mut cx, js_match_ops)?;
+    match_opts.cancellation = deserialize_cancellation(&mut cx, js_match_ops)?;
+    let js_compact =
+        js_match_ops.downcast::<JsObject>().or_throw(&mut cx)?.get(&mut cx, "compact")?;
+    let compact: bool = if let Ok(_) = js_compact.downcast::<JsUndefined>() {
+        false
+    } else {
+        js_compact.downcast::<JsBoolean>().or_throw(&mut cx)?.value()
+    };
     let cb = cx.argument::<JsFunction>(2)?;
 
-    let task = StackAndCoalesceTask { argument: (phrase_subq, match_opts) };
+    let task = StackAndCoalesceTask { argument: (phrase_subq, match_opts), compact };
     task.schedule(cb);
 
     Ok(cx.undefined())
 }
 
+/// The `CancellationToken` in the `cancellation` property of the match options, if there is one
+fn deserialize_cancellation<'j, C>(
+    cx: &mut C,
+    js_match_opts: Handle<'j, JsValue>,
+) -> LibResult<Option<CancellationToken>>
+where
+    C: Context<'j>,
+{
+    let js_token =
+        js_match_opts.downcast::<JsObject>().or_throw(cx)?.get(cx, "cancellation")?;
+    if let Ok(_) = js_token.downcast::<JsUndefined>() {
+        return Ok(None);
+    }
+    let js_token = js_token.downcast::<JsCancellationToken>().or_throw(cx)?;
+    let token = {
+        let lock = cx.lock();
+        js_token.borrow(&lock).clone()
+    };
+    Ok(Some(token))
+}
+
 fn deserialize_phrasesubq<'j, C>(
     cx: &mut C,
     js_phrase_subq_array: Handle<'j, JsArray>,
@@ -512,6 +602,13 @@ where
         let phrase_length = js_phrasematch
             .get(cx, "phrase")?.downcast::<JsString>().or_throw(cx)?.size() as usize;
 
+        let js_bbox = js_phrasematch.get(cx, "bbox")?;
+        let bbox: Option<[u16; 4]> = if let Ok(_) = js_bbox.downcast::<JsUndefined>() {
+            None
+        } else {
+            neon_serde::from_value(cx, js_bbox)?
+        };
+
         let subq = PhrasematchSubquery {
             store: gridstore,
             weight: neon_serde::from_value(cx, weight)?,
@@ -524,6 +621,7 @@ where
             mask: neon_serde::from_value(cx, mask)?,
             idx: neon_serde::from_value(cx, idx)?,
             non_overlapping_indexes: non_overlapping_indexes.into_iter().map(|n| n as usize).collect(),
+            bbox,
         };
         phrasematches.push(subq);
     }
@@ -544,11 +642,8 @@ fn prep_for_insert<'j, T: neon::object::This>(cx: &mut CallContext<'j, T>) -> Re
     let grid_key = cx.argument::<JsObject>(0)?;
     let grid_entry = cx.argument::<JsValue>(1)?;
     let values: Vec<GridEntry> = neon_serde::from_value(cx, grid_entry)?;
-    let phrase_id: u32 = grid_key
-        .get(cx, "phrase_id")?
-        .downcast::<JsNumber>()
-        .or_throw(cx)?
-        .value() as u32;
+    let js_phrase_id = grid_key.get(cx, "phrase_id")?;
+    let phrase_id: u64 = js_to_phrase_id(cx, js_phrase_id)?;
 
     let js_lang_set = grid_key.get(cx, "lang_set")?;
     let lang_set: u128 = langarray_to_langset(cx, js_lang_set)?;
@@ -564,6 +659,7 @@ register_module!(mut m, {
     m.export_class::<JsGridStoreBuilder>("GridStoreBuilder")?;
     m.export_class::<JsGridStore>("GridStore")?;
     m.export_class::<JsGridKeyStoreKeyIterator>("GridStoreKeyIterator")?;
+    m.export_class::<JsCancellationToken>("CancellationToken")?;
     m.export_function("coalesce", js_coalesce)?;
     m.export_function("stackable", js_stackable)?;
     m.export_function("stackAndCoalesce", js_stack_and_coalesce)?;
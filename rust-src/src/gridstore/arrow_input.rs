@@ -0,0 +1,212 @@
+//! Builder input from Apache Arrow record batches and Parquet files, so extraction pipelines can
+//! hand entries to the builder without serializing them to NDJSON first.
+//!
+//! Input has one row per grid entry, with these columns:
+//!
+//! * `phrase_id`: UInt64, or UInt32
+//! * `langs`: List<UInt32> of language ids; null means all languages
+//! * `id`: UInt32
+//! * `x`, `y`: UInt16
+//! * `relev`: Float64
+//! * `score`: UInt8
+//! * `source_phrase_hash`: UInt8
+//!
+//! Rows for the same key don't need to be adjacent, but batches are cheaper to load if they are.
+#[cfg(feature = "parquet-input")]
+use std::path::Path;
+
+use arrow::array::{
+    Array, Float64Array, ListArray, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow::record_batch::RecordBatch;
+use failure::{Error, Fail};
+
+use crate::gridstore::builder::GridStoreBuilder;
+use crate::gridstore::common::*;
+
+fn column<'a, T: Array + 'static>(
+    batch: &'a RecordBatch,
+    name: &'static str,
+) -> Result<&'a T, Error> {
+    let column = batch.column_by_name(name).ok_or(ArrowInputError::MissingColumn { name })?;
+    let typed = column.as_any().downcast_ref::<T>().ok_or_else(|| {
+        ArrowInputError::WrongColumnType { name, data_type: format!("{:?}", column.data_type()) }
+    })?;
+    Ok(typed)
+}
+
+/// Converts a row of the `langs` column to a lang_set, ignoring language ids that don't fit
+fn lang_set(langs: &ListArray, row: usize) -> Result<u128, Error> {
+    if langs.is_null(row) {
+        return Ok(std::u128::MAX);
+    }
+    let row_langs = langs.value(row);
+    let row_langs = row_langs.as_any().downcast_ref::<UInt32Array>().ok_or_else(|| {
+        ArrowInputError::WrongColumnType {
+            name: "langs",
+            data_type: format!("{:?}", row_langs.data_type()),
+        }
+    })?;
+    Ok(row_langs.iter().flatten().filter(|lang| *lang < 128).fold(0, |out, lang| out | (1 << lang)))
+}
+
+/// Reads the `phrase_id` column, which can also be UInt32, as it was before phrase ids were widened
+fn phrase_ids(batch: &RecordBatch) -> Result<Vec<u64>, Error> {
+    match column::<UInt64Array>(batch, "phrase_id") {
+        Ok(phrase_ids) => Ok(phrase_ids.values().to_vec()),
+        Err(_) => {
+            let phrase_ids = column::<UInt32Array>(batch, "phrase_id")?;
+            Ok(phrase_ids.values().iter().map(|phrase_id| *phrase_id as u64).collect())
+        }
+    }
+}
+
+impl GridStoreBuilder {
+    /// Appends every entry in an Arrow record batch to the builder. See the `arrow_input` module
+    /// for the expected columns.
+    pub fn append_record_batch(&mut self, batch: &RecordBatch) -> Result<(), Error> {
+        let phrase_ids = phrase_ids(batch)?;
+        let langs = column::<ListArray>(batch, "langs")?;
+        let ids = column::<UInt32Array>(batch, "id")?;
+        let xs = column::<UInt16Array>(batch, "x")?;
+        let ys = column::<UInt16Array>(batch, "y")?;
+        let relevs = column::<Float64Array>(batch, "relev")?;
+        let scores = column::<UInt8Array>(batch, "score")?;
+        let source_phrase_hashes = column::<UInt8Array>(batch, "source_phrase_hash")?;
+
+        let mut current: Option<(GridKey, Vec<GridEntry>)> = None;
+        for row in 0..batch.num_rows() {
+            let key = GridKey { phrase_id: phrase_ids[row], lang_set: lang_set(langs, row)? };
+            let entry = GridEntry {
+                relev: relevs.value(row),
+                score: scores.value(row),
+                x: xs.value(row),
+                y: ys.value(row),
+                id: ids.value(row),
+                source_phrase_hash: source_phrase_hashes.value(row),
+            };
+            match &mut current {
+                Some((current_key, entries)) if *current_key == key => entries.push(entry),
+                _ => {
+                    if let Some((current_key, entries)) = current.take() {
+                        self.append(&current_key, entries)?;
+                    }
+                    current = Some((key, vec![entry]));
+                }
+            }
+        }
+        if let Some((current_key, entries)) = current {
+            self.append(&current_key, entries)?;
+        }
+        Ok(())
+    }
+
+    /// Appends every entry in a Parquet file to the builder. See the `arrow_input` module for the
+    /// expected columns.
+    #[cfg(feature = "parquet-input")]
+    pub fn append_parquet<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let file = std::fs::File::open(path)?;
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+        for batch in reader {
+            self.append_record_batch(&batch?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_batch() -> RecordBatch {
+    use arrow::array::{ArrayRef, ListBuilder, UInt32Builder};
+    use std::sync::Arc;
+
+    let mut langs = ListBuilder::new(UInt32Builder::new());
+    langs.values().append_value(0);
+    langs.append(true);
+    langs.values().append_value(0);
+    langs.append(true);
+    langs.append(false);
+    langs.values().append_value(1);
+    langs.values().append_value(200);
+    langs.append(true);
+
+    let columns: Vec<(&str, ArrayRef)> = vec![
+        ("phrase_id", Arc::new(UInt32Array::from(vec![1, 1, 1, 2]))),
+        ("langs", Arc::new(langs.finish())),
+        ("id", Arc::new(UInt32Array::from(vec![1, 2, 3, 4]))),
+        ("x", Arc::new(UInt16Array::from(vec![1, 2, 3, 4]))),
+        ("y", Arc::new(UInt16Array::from(vec![5, 6, 7, 8]))),
+        ("relev", Arc::new(Float64Array::from(vec![1., 1., 0.8, 1.]))),
+        ("score", Arc::new(UInt8Array::from(vec![3, 2, 1, 0]))),
+        ("source_phrase_hash", Arc::new(UInt8Array::from(vec![0, 0, 0, 0]))),
+    ];
+    RecordBatch::try_from_iter(columns).unwrap()
+}
+
+#[test]
+fn append_record_batch_test() {
+    use crate::gridstore::store::GridStore;
+
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+    builder.append_record_batch(&test_batch()).unwrap();
+    builder.finish().unwrap();
+
+    let reader = GridStore::new(directory.path()).unwrap();
+    let keys: Vec<_> = reader.keys().map(|key| key.unwrap()).collect();
+    assert_eq!(
+        keys,
+        vec![
+            GridKey { phrase_id: 1, lang_set: std::u128::MAX },
+            GridKey { phrase_id: 1, lang_set: 1 },
+            GridKey { phrase_id: 2, lang_set: 2 },
+        ],
+        "rows are grouped by phrase and languages, and out of range languages are dropped"
+    );
+    let ids: Vec<_> = reader
+        .get(&GridKey { phrase_id: 1, lang_set: 1 })
+        .unwrap()
+        .unwrap()
+        .map(|entry| entry.id)
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+
+    let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+    let batch = test_batch();
+    let missing = batch.project(&[0, 1, 2]).unwrap();
+    assert!(builder.append_record_batch(&missing).is_err(), "missing columns are an error");
+}
+
+#[cfg(feature = "parquet-input")]
+#[test]
+fn append_parquet_test() {
+    use crate::gridstore::store::GridStore;
+    use parquet::arrow::ArrowWriter;
+
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let parquet_path = directory.path().join("entries.parquet");
+    let batch = test_batch();
+    let mut writer =
+        ArrowWriter::try_new(std::fs::File::create(&parquet_path).unwrap(), batch.schema(), None)
+            .unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+
+    let store_path = directory.path().join("store");
+    let mut builder = GridStoreBuilder::new(&store_path).unwrap();
+    builder.append_parquet(&parquet_path).unwrap();
+    builder.finish().unwrap();
+
+    let reader = GridStore::new(&store_path).unwrap();
+    let entries: Vec<_> = reader.iter().flat_map(|item| item.unwrap().1).collect();
+    assert_eq!(entries.len(), 4, "every row is loaded");
+}
+
+#[derive(Debug, Fail)]
+enum ArrowInputError {
+    #[fail(display = "missing column: {}", name)]
+    MissingColumn { name: &'static str },
+    #[fail(display = "unexpected type for column {}: {}", name, data_type)]
+    WrongColumnType { name: &'static str, data_type: String },
+}
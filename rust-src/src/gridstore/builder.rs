@@ -1,22 +1,80 @@
 use std::collections::hash_map::Entry as HmEntry;
-use std::collections::{btree_map::Entry, BTreeMap, HashMap};
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use failure::{Error, Fail};
+use fxhash::FxHasher64;
 use itertools::Itertools;
-use morton::interleave_morton;
+use morton::{deinterleave_morton, interleave_morton};
 use rocksdb::{Options, DB};
 use smallvec::{smallvec, SmallVec};
 
 use crate::gridstore::common::*;
 use crate::gridstore::gridstore_format;
+use crate::gridstore::store::{coarse_store_path, coarse_stores_dir, GridStore};
 
 type BuilderEntry = HashMap<u8, HashMap<u32, SmallVec<[u32; 4]>>>;
 
+/// What to do with a key that has more than `BuilderOpts::max_entries_per_key` entries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Keep the entries with the highest relevance and score
+    KeepTopByScore,
+    /// Keep the best entry in each coarse tile (tiles zoomed out by `coarse_zoom_levels`), then
+    /// the second-best in each, and so on, so that geographic coverage is preserved
+    SampleSpatially { coarse_zoom_levels: u16 },
+    /// Fail the build
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuilderOpts {
+    pub max_entries_per_key: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+    pub record_order: RecordOrder,
+}
+
+impl Default for BuilderOpts {
+    fn default() -> Self {
+        BuilderOpts {
+            max_entries_per_key: None,
+            overflow_policy: OverflowPolicy::KeepTopByScore,
+            record_order: RecordOrder::PhraseId,
+        }
+    }
+}
+
 pub struct GridStoreBuilder {
     path: PathBuf,
     data: BTreeMap<GridKey, BuilderEntry>,
-    bin_boundaries: Vec<u32>,
+    bin_boundaries: Vec<u64>,
+    feature_index: bool,
+    tile_index: Option<u16>,
+    merge_adjacent_covers: bool,
+    border_covers: Option<u16>,
+    coarse_zooms: Vec<u16>,
+    relev_weights: Option<[f64; 4]>,
+    generation: Option<u64>,
+    compression: Compression,
+    curve: Curve,
+    opts: BuilderOpts,
+    /// Extents of merged covers, by the key of the record they're in, read back from an existing
+    /// store or recorded as covers are merged
+    extents: BTreeMap<GridKey, CoverExtents>,
+    /// Sub-tile offsets of entries, keyed by feature id and z-order coord
+    offsets: BTreeMap<(u32, u32), u8>,
+    /// Keys an existing store had already truncated, and how many entries it dropped
+    truncated_keys: BTreeSet<GridKey>,
+    truncation_stats: TruncationStats,
+    /// Set for builders made with `open_existing`, which write the new store alongside the
+    /// existing one and only replace it once the new one is complete
+    replaces_existing: bool,
+    /// Set while `finish` is writing the store, so that if it fails partway the incomplete
+    /// output is removed when the builder is dropped
+    writing: bool,
 }
 
 /// Extends a BuildEntry with the given values.
@@ -43,6 +101,24 @@ fn extend_entries(builder_entry: &mut BuilderEntry, values: Vec<GridEntry>) -> (
     }
 }
 
+/// Extends a BuildEntry with values from an iterator, one at a time, so they're never collected.
+fn extend_entries_from_iter<I: Iterator<Item = GridEntry>>(
+    builder_entry: &mut BuilderEntry,
+    values: I,
+) {
+    for value in values {
+        let rs = (relev_float_to_int(value.relev) << 4) | value.score;
+        let zcoord = interleave_morton(value.x, value.y);
+        let id_phrase = (value.id << 8) | (value.source_phrase_hash as u32);
+        builder_entry
+            .entry(rs)
+            .or_insert_with(HashMap::new)
+            .entry(zcoord)
+            .or_insert_with(SmallVec::new)
+            .push(id_phrase);
+    }
+}
+
 fn copy_entries(source_entry: &BuilderEntry, destination_entry: &mut BuilderEntry) -> () {
     for (rs, values) in source_entry.iter() {
         let rs_entry = destination_entry.entry(*rs).or_insert_with(|| HashMap::new());
@@ -53,7 +129,360 @@ fn copy_entries(source_entry: &BuilderEntry, destination_entry: &mut BuilderEntr
     }
 }
 
-fn get_encoded_value(value: BuilderEntry) -> Result<Vec<u8>, Error> {
+/// Dedupes the ids at each coord and returns the total number of entries
+fn count_entries(builder_entry: &mut BuilderEntry) -> usize {
+    let mut count = 0;
+    for coord_group in builder_entry.values_mut() {
+        for ids in coord_group.values_mut() {
+            // reverse sort, the same as they'll be written out
+            ids.sort_by(|id_a, id_b| id_b.cmp(id_a));
+            ids.dedup();
+            count += ids.len();
+        }
+    }
+    count
+}
+
+/// A copy of a record's entries with their tiles zoomed out by `coarse_zoom_levels`, keeping
+/// each feature's most relevant entry in each coarse tile
+fn coarsen_entries(builder_entry: &BuilderEntry, coarse_zoom_levels: u16) -> BuilderEntry {
+    let mut relev_scores: Vec<&u8> = builder_entry.keys().collect();
+    relev_scores.sort_by(|a, b| b.cmp(a));
+
+    let mut coarse_entry = BuilderEntry::new();
+    let mut seen: HashSet<(u32, u32)> = HashSet::new();
+    for relev_score in relev_scores {
+        let coord_group = &builder_entry[relev_score];
+        let mut zcoords: Vec<&u32> = coord_group.keys().collect();
+        zcoords.sort_by(|a, b| b.cmp(a));
+        for zcoord in zcoords {
+            let (x, y) = deinterleave_morton(*zcoord);
+            let coarse_zcoord = interleave_morton(x >> coarse_zoom_levels, y >> coarse_zoom_levels);
+            for id_phrase in coord_group[zcoord].iter() {
+                if seen.insert((id_phrase >> 8, coarse_zcoord)) {
+                    coarse_entry
+                        .entry(*relev_score)
+                        .or_insert_with(HashMap::new)
+                        .entry(coarse_zcoord)
+                        .or_insert_with(SmallVec::new)
+                        .push(*id_phrase);
+                }
+            }
+        }
+    }
+    coarse_entry
+}
+
+/// Writes the copy of a store's records zoomed out by `coarse_zoom_levels` inside the store
+fn write_coarse_store(
+    path: &Path,
+    data: &BTreeMap<GridKey, BuilderEntry>,
+    coarse_zoom_levels: u16,
+    compression: Compression,
+    curve: Curve,
+    bin_boundaries: &[u64],
+    generation: u64,
+) -> Result<(), Error> {
+    let coarse_data: BTreeMap<GridKey, BuilderEntry> = data
+        .iter()
+        .map(|(grid_key, value)| (grid_key.clone(), coarsen_entries(value, coarse_zoom_levels)))
+        .collect();
+    let mut writer = StoreWriter::new(
+        &coarse_store_path(path, coarse_zoom_levels),
+        false,
+        None,
+        compression,
+        bin_boundaries.to_vec(),
+    )?;
+    writer.curve = curve;
+    let content_hash = content_hash(&coarse_data, &BTreeMap::new(), &BTreeMap::new());
+    for (grid_key, value) in coarse_data {
+        writer.write_record(&grid_key, value, None)?;
+    }
+    writer.finish(generation, content_hash, &TruncationStats::default())
+}
+
+/// Drops all but the `max_entries` best entries, by relevance and score. Ties are broken in the
+/// order entries are written out: by descending z-order, then descending id.
+fn keep_top_by_score(builder_entry: &mut BuilderEntry, max_entries: usize) {
+    let mut relev_scores: Vec<u8> = builder_entry.keys().cloned().collect();
+    relev_scores.sort_by(|a, b| b.cmp(a));
+
+    let mut remaining = max_entries;
+    for relev_score in relev_scores {
+        if remaining == 0 {
+            builder_entry.remove(&relev_score);
+            continue;
+        }
+        let coord_group = builder_entry.get_mut(&relev_score).expect("relev_score must exist");
+        let mut zcoords: Vec<u32> = coord_group.keys().cloned().collect();
+        zcoords.sort_by(|a, b| b.cmp(a));
+        for zcoord in zcoords {
+            if remaining == 0 {
+                coord_group.remove(&zcoord);
+                continue;
+            }
+            let ids = coord_group.get_mut(&zcoord).expect("zcoord must exist");
+            ids.truncate(remaining);
+            remaining -= ids.len();
+        }
+    }
+}
+
+/// Drops all but `max_entries` entries, stratified across coarse tiles: every coarse tile gets
+/// its best entry (by relevance and score) kept before any tile gets a second one.
+fn sample_spatially(builder_entry: &mut BuilderEntry, max_entries: usize, coarse_zoom_levels: u16) {
+    let mut entries: Vec<(u8, u32, u32)> = builder_entry
+        .iter()
+        .flat_map(|(relev_score, coord_group)| {
+            coord_group.iter().flat_map(move |(zcoord, ids)| {
+                ids.iter().map(move |id| (*relev_score, *zcoord, *id))
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| b.cmp(a));
+
+    // zooming out one level drops one bit each of x and y, so two bits of z-order
+    let shift = std::cmp::min(2 * coarse_zoom_levels as u32, 31);
+    let mut tile_counts: HashMap<u32, usize> = HashMap::new();
+    let mut ranked: Vec<(usize, usize)> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (_, zcoord, _))| {
+            let count = tile_counts.entry(zcoord >> shift).or_insert(0);
+            *count += 1;
+            (*count, i)
+        })
+        .collect();
+    ranked.sort();
+    ranked.truncate(max_entries);
+
+    builder_entry.clear();
+    for (_, i) in ranked {
+        let (relev_score, zcoord, id) = entries[i];
+        builder_entry
+            .entry(relev_score)
+            .or_insert_with(HashMap::new)
+            .entry(zcoord)
+            .or_insert_with(SmallVec::new)
+            .push(id);
+    }
+}
+
+/// Merges each id's covers in adjacent tiles at each relevance and score into one representative
+/// cover, the one nearest the middle of their extent, and records the extents of the merged
+/// covers keyed by feature id and representative z-order coord
+fn merge_adjacent_covers(builder_entry: &mut BuilderEntry, extents: &mut CoverExtents) {
+    for coord_group in builder_entry.values_mut() {
+        let mut coords_by_id: BTreeMap<u32, Vec<(u16, u16)>> = BTreeMap::new();
+        for (zcoord, ids) in coord_group.iter() {
+            for id in ids.iter() {
+                coords_by_id.entry(*id).or_insert_with(Vec::new).push(deinterleave_morton(*zcoord));
+            }
+        }
+
+        for (id, coords) in coords_by_id {
+            if coords.len() < 2 {
+                continue;
+            }
+            let mut unvisited: BTreeSet<(u16, u16)> = coords.into_iter().collect();
+            while let Some(start) = unvisited.iter().next().cloned() {
+                unvisited.remove(&start);
+                let mut component = vec![start];
+                let mut i = 0;
+                while i < component.len() {
+                    let (x, y) = component[i];
+                    for nx in x.saturating_sub(1)..=x.saturating_add(1) {
+                        for ny in y.saturating_sub(1)..=y.saturating_add(1) {
+                            if unvisited.remove(&(nx, ny)) {
+                                component.push((nx, ny));
+                            }
+                        }
+                    }
+                    i += 1;
+                }
+                if component.len() < 2 {
+                    continue;
+                }
+
+                let min_x = component.iter().map(|(x, _)| *x).min().unwrap();
+                let max_x = component.iter().map(|(x, _)| *x).max().unwrap();
+                let min_y = component.iter().map(|(_, y)| *y).min().unwrap();
+                let max_y = component.iter().map(|(_, y)| *y).max().unwrap();
+                // doubled, to stay in integers
+                let (mid_x, mid_y) = (min_x as i64 + max_x as i64, min_y as i64 + max_y as i64);
+                let representative = *component
+                    .iter()
+                    .min_by_key(|(x, y)| {
+                        let (dx, dy) = (2 * *x as i64 - mid_x, 2 * *y as i64 - mid_y);
+                        (dx * dx + dy * dy, interleave_morton(*x, *y))
+                    })
+                    .unwrap();
+
+                for (x, y) in component {
+                    if (x, y) == representative {
+                        continue;
+                    }
+                    let zcoord = interleave_morton(x, y);
+                    if let Some(ids) = coord_group.get_mut(&zcoord) {
+                        ids.retain(|other| *other != id);
+                        if ids.is_empty() {
+                            coord_group.remove(&zcoord);
+                        }
+                    }
+                }
+                let zcoord = interleave_morton(representative.0, representative.1);
+                insert_extent(extents, id >> 8, zcoord, [min_x, min_y, max_x, max_y]);
+            }
+        }
+    }
+}
+
+/// Whether `id` has a cover at `zcoord` in a record
+fn has_cover(builder_entry: &BuilderEntry, id: u32, zcoord: u32) -> bool {
+    builder_entry.values().any(|coord_group| {
+        coord_group
+            .get(&zcoord)
+            .map_or(false, |id_phrases| id_phrases.iter().any(|id_phrase| id_phrase >> 8 == id))
+    })
+}
+
+/// Copies each cover on the border of its coarse tile (the tile zoomed out by `coarse_zoom_levels`)
+/// into the adjacent tiles across the border, including diagonally, and records the tile each copy
+/// stands in for as its extent. Merged covers, and ids that already have a cover in the adjacent
+/// tile, are left alone.
+fn add_border_covers(
+    builder_entry: &mut BuilderEntry,
+    coarse_zoom_levels: u16,
+    extents: &mut CoverExtents,
+) {
+    let levels = std::cmp::min(coarse_zoom_levels, 15);
+    for coord_group in builder_entry.values_mut() {
+        let mut copies: Vec<(u32, u32)> = Vec::new();
+        for (zcoord, id_phrases) in coord_group.iter() {
+            let (x, y) = deinterleave_morton(*zcoord);
+            let coarse = (x >> levels, y >> levels);
+            let neighbors = (x.saturating_sub(1)..=x.saturating_add(1))
+                .flat_map(|nx| (y.saturating_sub(1)..=y.saturating_add(1)).map(move |ny| (nx, ny)))
+                .filter(|(nx, ny)| (nx >> levels, ny >> levels) != coarse);
+            for (nx, ny) in neighbors {
+                let neighbor = interleave_morton(nx, ny);
+                for id_phrase in id_phrases.iter() {
+                    let id = id_phrase >> 8;
+                    if extents.contains_key(&(id, *zcoord)) || extents.contains_key(&(id, neighbor))
+                    {
+                        continue;
+                    }
+                    let covered = coord_group
+                        .get(&neighbor)
+                        .map_or(false, |others| others.iter().any(|other| other >> 8 == id));
+                    if !covered {
+                        copies.push((neighbor, *id_phrase));
+                        insert_extent(extents, id, neighbor, [x, y, x, y]);
+                    }
+                }
+            }
+        }
+        for (neighbor, id_phrase) in copies {
+            coord_group.entry(neighbor).or_insert_with(SmallVec::new).push(id_phrase);
+        }
+    }
+}
+
+/// A hash of the keys, entries and merged cover extents going into a store that doesn't depend
+/// on the order they were inserted in or how they're laid out on disk
+fn content_hash(
+    data: &BTreeMap<GridKey, BuilderEntry>,
+    extents: &BTreeMap<GridKey, CoverExtents>,
+    offsets: &BTreeMap<(u32, u32), u8>,
+) -> u64 {
+    let mut hasher = ContentHasher::default();
+    for (grid_key, value) in data.iter() {
+        hasher.add_record(grid_key, value, extents.get(grid_key));
+    }
+    hasher.finish(offsets)
+}
+
+/// Computes `content_hash` a record at a time, for records fed in ascending key order
+#[derive(Default)]
+struct ContentHasher(FxHasher64);
+
+impl ContentHasher {
+    fn add_record(
+        &mut self,
+        grid_key: &GridKey,
+        value: &BuilderEntry,
+        extents: Option<&CoverExtents>,
+    ) {
+        let hasher = &mut self.0;
+        hasher.write_u64(grid_key.phrase_id);
+        hasher.write_u64((grid_key.lang_set >> 64) as u64);
+        hasher.write_u64(grid_key.lang_set as u64);
+        let mut entries: Vec<(u8, u32, u32)> = value
+            .iter()
+            .flat_map(|(relev_score, coord_group)| {
+                coord_group.iter().flat_map(move |(zcoord, id_phrases)| {
+                    id_phrases.iter().map(move |id_phrase| (*relev_score, *zcoord, *id_phrase))
+                })
+            })
+            .collect();
+        entries.sort();
+        hasher.write_usize(entries.len());
+        for (relev_score, zcoord, id_phrase) in entries {
+            hasher.write_u8(relev_score);
+            hasher.write_u32(zcoord);
+            hasher.write_u32(id_phrase);
+        }
+        hasher.write_usize(extents.map_or(0, |extents| extents.len()));
+        for ((id, zcoord), extent) in extents.into_iter().flatten() {
+            hasher.write_u32(*id);
+            hasher.write_u32(*zcoord);
+            extent.iter().for_each(|coord| hasher.write_u16(*coord));
+        }
+    }
+
+    fn finish(mut self, offsets: &BTreeMap<(u32, u32), u8>) -> u64 {
+        for ((id, zcoord), offset) in offsets.iter() {
+            self.0.write_u32(*id);
+            self.0.write_u32(*zcoord);
+            self.0.write_u8(*offset);
+        }
+        self.0.finish()
+    }
+}
+
+/// Returns the only entry in a BuilderEntry, if it has exactly one
+fn single_entry(value: &BuilderEntry) -> Option<gridstore_format::SingleEntry> {
+    if value.len() != 1 {
+        return None;
+    }
+    let (relev_score, coord_group) = value.iter().next()?;
+    if coord_group.len() != 1 {
+        return None;
+    }
+    let (coord, ids) = coord_group.iter().next()?;
+    let id = *ids.first()?;
+    if ids.iter().any(|other| *other != id) {
+        return None;
+    }
+    Some(gridstore_format::SingleEntry { relev_score: *relev_score, coord: *coord, id })
+}
+
+/// Encodes a record, with the coords of grouped records ordered along `curve`. A single entry has
+/// nothing to order, and keeps its z-order coord. Entries with sub-tile offsets, keyed by feature
+/// id and z-order coord, are always grouped, since inline entries have no room for one.
+fn get_encoded_value(
+    value: BuilderEntry,
+    curve: Curve,
+    offsets: &BTreeMap<(u32, u32), u8>,
+) -> Result<Vec<u8>, Error> {
+    let offset_of = |id_phrase: u32, zcoord: u32| offsets.get(&(id_phrase >> 8, zcoord)).cloned();
+    if let Some(entry) = single_entry(&value) {
+        if offset_of(entry.id, entry.coord).is_none() {
+            return Ok(entry.write());
+        }
+    }
+
     let mut builder = gridstore_format::Writer::new();
 
     let mut items: Vec<(_, _)> = value.into_iter().collect();
@@ -66,12 +495,35 @@ fn get_encoded_value(value: BuilderEntry) -> Result<Vec<u8>, Error> {
     let mut id_lists: HashMap<_, gridstore_format::FixedVecOffset<u32>> = HashMap::new();
 
     for (relevance_score, coord_group) in items.into_iter() {
-        let mut inner_items: Vec<(_, _)> = coord_group.into_iter().collect();
-        inner_items.sort_by(|(coord_a, _), (coord_b, _)| coord_b.cmp(&coord_a));
+        // coords are written in the wider layout that has room for an offset when any entry in
+        // the vector has one
+        let with_offsets = !offsets.is_empty()
+            && coord_group.iter().any(|(zcoord, ids)| {
+                ids.iter().any(|id_phrase| offset_of(*id_phrase, *zcoord).is_some())
+            });
+        let mut inner_items: Vec<(u32, Option<u8>, SmallVec<[u32; 4]>)> =
+            Vec::with_capacity(coord_group.len());
+        for (zcoord, ids) in coord_group.into_iter() {
+            let coord = curve.from_zcoord(zcoord);
+            if !with_offsets {
+                inner_items.push((coord, None, ids));
+                continue;
+            }
+            // the features at a coord are split up by where in the tile they are
+            let mut by_offset: BTreeMap<Option<u8>, SmallVec<[u32; 4]>> = BTreeMap::new();
+            for id_phrase in ids {
+                let offset = offset_of(id_phrase, zcoord);
+                by_offset.entry(offset).or_insert_with(SmallVec::new).push(id_phrase);
+            }
+            inner_items.extend(by_offset.into_iter().map(|(offset, ids)| (coord, offset, ids)));
+        }
+        inner_items.sort_by(|(coord_a, offset_a, _), (coord_b, offset_b, _)| {
+            (coord_b, offset_b).cmp(&(coord_a, offset_a))
+        });
 
         let mut coords: Vec<_> = Vec::with_capacity(inner_items.len());
 
-        for (coord, mut ids) in inner_items.into_iter() {
+        for (coord, offset, mut ids) in inner_items.into_iter() {
             // reverse sort
             ids.sort_by(|id_a, id_b| id_b.cmp(id_a));
             ids.dedup();
@@ -79,7 +531,7 @@ fn get_encoded_value(value: BuilderEntry) -> Result<Vec<u8>, Error> {
             let encoded_ids =
                 id_lists.entry(ids.clone()).or_insert_with(|| builder.write_fixed_vec(&ids));
 
-            let encoded_coord = gridstore_format::Coord { coord, ids: encoded_ids.clone() };
+            let encoded_coord = gridstore_format::Coord { coord, ids: encoded_ids.clone(), offset };
             coords.push(encoded_coord);
         }
         let encoded_coords = builder.write_uniform_vec(&coords);
@@ -96,16 +548,544 @@ fn get_encoded_value(value: BuilderEntry) -> Result<Vec<u8>, Error> {
     Ok(builder.finish())
 }
 
+/// Writes a store's records, and the prefix bins, feature index and tile index entries derived
+/// from them, to a new DB. Records have to come in ascending phrase id order, so that each prefix
+/// bin can be written out as soon as the records in it are done.
+struct StoreWriter {
+    db: DB,
+    path: PathBuf,
+    width: PhraseIdWidth,
+    db_key: Vec<u8>,
+    checksum_key: Vec<u8>,
+    feature_index: bool,
+    tile_index: Option<u16>,
+    compression: Compression,
+    bin_boundaries: Vec<u64>,
+    /// Position in `bin_boundaries` of the boundary after the current bin
+    next_bin: usize,
+    next_boundary: u64,
+    current_bin: Option<u64>,
+    /// Entries of the records in the current bin so far, by language set
+    bin_entries: HashMap<u128, BuilderEntry>,
+    /// Extents of the merged covers among `bin_entries`, by language set
+    bin_extents: HashMap<u128, CoverExtents>,
+    extent_key: Vec<u8>,
+    /// Zoom levels out the store has coarse copies at, which are written separately
+    coarse_zooms: Vec<u16>,
+    relev_weights: Option<[f64; 4]>,
+    curve: Curve,
+    /// Sub-tile offsets of entries, keyed by feature id and z-order coord
+    offsets: BTreeMap<(u32, u32), u8>,
+    /// Phrases whose records get copied to the hot section, in the order they're laid out in
+    hot_phrases: Vec<u64>,
+    /// Position of each of `hot_phrases` in the hot section, by phrase id
+    hot_ranks: HashMap<u64, u32>,
+    hot_key: Vec<u8>,
+    /// Records and entries written so far, for the manifest
+    keys: u64,
+    entries: u64,
+}
+
+impl StoreWriter {
+    fn new(
+        path: &Path,
+        feature_index: bool,
+        tile_index: Option<u16>,
+        compression: Compression,
+        bin_boundaries: Vec<u64>,
+    ) -> Result<Self, Error> {
+        let mut opts = Options::default();
+        opts.set_disable_auto_compactions(true);
+        opts.create_if_missing(true);
+
+        let db = DB::open(&opts, path)?;
+        // mark the store as incomplete until everything's been written, so it can't be opened
+        // if the build fails partway
+        db.put("~BUILDING", &[])?;
+        Ok(StoreWriter {
+            db,
+            path: path.to_path_buf(),
+            width: PhraseIdWidth::current(),
+            db_key: Vec::with_capacity(MAX_KEY_LENGTH),
+            checksum_key: Vec::with_capacity(MAX_KEY_LENGTH + 1),
+            feature_index,
+            tile_index,
+            compression,
+            bin_boundaries,
+            next_bin: 0,
+            next_boundary: 0,
+            current_bin: None,
+            bin_entries: HashMap::new(),
+            bin_extents: HashMap::new(),
+            extent_key: Vec::with_capacity(MAX_KEY_LENGTH + 10),
+            coarse_zooms: Vec::new(),
+            relev_weights: None,
+            curve: Curve::Morton,
+            offsets: BTreeMap::new(),
+            hot_phrases: Vec::new(),
+            hot_ranks: HashMap::new(),
+            hot_key: Vec::with_capacity(MAX_KEY_LENGTH + 4),
+            keys: 0,
+            entries: 0,
+        })
+    }
+
+    /// Marks a key with a Truncated marker followed by the full key of the entry
+    fn write_truncated(&mut self, grid_key: &GridKey) -> Result<(), Error> {
+        self.db_key.clear();
+        self.db_key.push(TypeMarker::Truncated as u8);
+        grid_key.write_to(TypeMarker::SinglePhrase, self.width, &mut self.db_key)?;
+        self.db.put(&self.db_key, &[])?;
+        Ok(())
+    }
+
+    /// Writes the record for `grid_key`, along with its index entries and the extents of its
+    /// merged covers
+    fn write_record(
+        &mut self,
+        grid_key: &GridKey,
+        mut value: BuilderEntry,
+        extents: Option<&CoverExtents>,
+    ) -> Result<(), Error> {
+        self.keys += 1;
+        self.entries += count_entries(&mut value) as u64;
+        let width = self.width;
+        let db_key = &mut self.db_key;
+
+        if self.feature_index {
+            let ids: BTreeSet<u32> = value
+                .values()
+                .flat_map(|coord_group| coord_group.values())
+                .flat_map(|id_phrases| id_phrases.iter().map(|id_phrase| id_phrase >> 8))
+                .collect();
+            for id in ids {
+                db_key.clear();
+                grid_key.write_feature_index_to(id, width, db_key)?;
+                self.db.put(&db_key, &[])?;
+            }
+        }
+
+        if let Some(coarse_zoom_levels) = self.tile_index {
+            let shift = std::cmp::min(2 * coarse_zoom_levels as u32, 31);
+            let levels = std::cmp::min(coarse_zoom_levels, 15);
+            let mut tiles: BTreeSet<u32> = BTreeSet::new();
+            for (zcoord, id_phrases) in value.values().flat_map(|coord_group| coord_group.iter()) {
+                tiles.insert(zcoord >> shift);
+                // merged covers are in every tile of their extent
+                for id_phrase in id_phrases.iter() {
+                    if let Some([min_x, min_y, max_x, max_y]) =
+                        extents.and_then(|extents| extents.get(&(id_phrase >> 8, *zcoord)))
+                    {
+                        for x in (min_x >> levels)..=(max_x >> levels) {
+                            for y in (min_y >> levels)..=(max_y >> levels) {
+                                tiles.insert(interleave_morton(x << levels, y << levels) >> shift);
+                            }
+                        }
+                    }
+                }
+            }
+            for tile in tiles {
+                db_key.clear();
+                grid_key.write_tile_index_to(tile, width, db_key)?;
+                self.db.put(&db_key, &[])?;
+            }
+        }
+
+        while grid_key.phrase_id >= self.next_boundary {
+            let bin = self.bin_boundaries.get(self.next_bin).cloned();
+            if bin != self.current_bin {
+                self.write_bin()?;
+                self.current_bin = bin;
+            }
+            self.next_bin += 1;
+            self.next_boundary =
+                self.bin_boundaries.get(self.next_bin).cloned().unwrap_or(std::u64::MAX);
+        }
+        if self.current_bin.is_some() {
+            let grouped_entry =
+                self.bin_entries.entry(grid_key.lang_set).or_insert_with(BuilderEntry::new);
+            copy_entries(&value, grouped_entry);
+            let bin_extents = self.bin_extents.entry(grid_key.lang_set).or_default();
+            for ((id, zcoord), extent) in extents.into_iter().flatten() {
+                insert_extent(bin_extents, *id, *zcoord, *extent);
+            }
+        }
+
+        self.db_key.clear();
+        grid_key.write_to(TypeMarker::SinglePhrase, width, &mut self.db_key)?;
+        if let Some(extents) = extents {
+            self.put_extents(extents)?;
+        }
+        let db_data =
+            self.compression.compress(get_encoded_value(value, self.curve, &self.offsets)?)?;
+        if let Some(rank) = self.hot_ranks.get(&grid_key.phrase_id) {
+            write_hot_copy_key(*rank, &self.db_key, &mut self.hot_key);
+            self.db.put(&self.hot_key, &db_data)?;
+        }
+        self.put_value(&db_data)
+    }
+
+    /// Writes a record value under `db_key`, along with its checksum
+    fn put_value(&mut self, db_data: &[u8]) -> Result<(), Error> {
+        self.db.put(&self.db_key, db_data)?;
+        write_checksum_key(&self.db_key, &mut self.checksum_key);
+        self.db.put(&self.checksum_key, &record_checksum(db_data).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Writes the extents of the merged covers in the record at `db_key`
+    fn put_extents(&mut self, extents: &CoverExtents) -> Result<(), Error> {
+        for ((id, zcoord), extent) in extents.iter() {
+            self.extent_key.clear();
+            write_extent_key(&self.db_key, *id, *zcoord, &mut self.extent_key);
+            let encoded_extent: Vec<u8> =
+                extent.iter().flat_map(|coord| coord.to_le_bytes().to_vec()).collect();
+            self.db.put(&self.extent_key, &encoded_extent)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the prefix bin records of the current bin
+    fn write_bin(&mut self) -> Result<(), Error> {
+        let bin_entries = std::mem::take(&mut self.bin_entries);
+        let mut bin_extents = std::mem::take(&mut self.bin_extents);
+        if let Some(group_id) = self.current_bin {
+            for (lang_set, builder_entry) in bin_entries.into_iter() {
+                self.db_key.clear();
+                let group_key = GridKey { phrase_id: group_id, lang_set };
+                group_key.write_to(TypeMarker::PrefixBin, self.width, &mut self.db_key)?;
+                if let Some(extents) = bin_extents.remove(&lang_set) {
+                    self.put_extents(&extents)?;
+                }
+                let grouped_db_data = self.compression.compress(get_encoded_value(
+                    builder_entry,
+                    self.curve,
+                    &self.offsets,
+                )?)?;
+                self.put_value(&grouped_db_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the last prefix bin, the store's metadata and its manifest, and marks it complete
+    fn finish(
+        mut self,
+        generation: u64,
+        content_hash: u64,
+        truncation_stats: &TruncationStats,
+    ) -> Result<(), Error> {
+        self.write_bin()?;
+        let db = &self.db;
+
+        if let Some(coarse_zoom_levels) = self.tile_index {
+            db.put("~TILE_INDEX", &coarse_zoom_levels.to_le_bytes())?;
+        }
+        if self.compression != Compression::None {
+            db.put("~COMPRESSION", &self.compression.to_bytes())?;
+        }
+        if self.curve != Curve::Morton {
+            db.put("~CURVE", &self.curve.to_bytes())?;
+        }
+        if !self.coarse_zooms.is_empty() {
+            let encoded_levels: Vec<u8> =
+                self.coarse_zooms.iter().flat_map(|levels| levels.to_le_bytes().to_vec()).collect();
+            db.put("~COARSE_ZOOMS", &encoded_levels)?;
+        }
+        if let Some(relev_weights) = self.relev_weights {
+            let encoded_weights: Vec<u8> =
+                relev_weights.iter().flat_map(|weight| weight.to_le_bytes().to_vec()).collect();
+            db.put("~RELEV_WEIGHTS", &encoded_weights)?;
+        }
+        if !self.hot_phrases.is_empty() {
+            let encoded_phrases: Vec<u8> = self
+                .hot_phrases
+                .iter()
+                .flat_map(|phrase_id| phrase_id.to_le_bytes().to_vec())
+                .collect();
+            db.put("~HOT_PHRASES", &encoded_phrases)?;
+        }
+
+        // bake the prefix boundaries
+        let mut encoded_boundaries: Vec<u8> = Vec::with_capacity(self.bin_boundaries.len() * 8);
+        for boundary in self.bin_boundaries.iter() {
+            encoded_boundaries.extend_from_slice(&boundary.to_le_bytes());
+        }
+        db.put("~BOUNDS", &encoded_boundaries)?;
+
+        let mut format_version = Vec::with_capacity(4);
+        format_version.extend_from_slice(&FORMAT_MAJOR_VERSION.to_le_bytes());
+        format_version.extend_from_slice(&FORMAT_MINOR_VERSION.to_le_bytes());
+        db.put("~FORMAT", &format_version)?;
+        db.put("~GENERATION", &generation.to_le_bytes())?;
+        db.put("~CONTENT_HASH", &content_hash.to_le_bytes())?;
+        db.put("~TRUNCATION", &truncation_stats.to_bytes())?;
+
+        StoreManifest {
+            format_version: (FORMAT_MAJOR_VERSION, FORMAT_MINOR_VERSION),
+            generation,
+            content_hash,
+            keys: self.keys,
+            entries: self.entries,
+            truncation: truncation_stats.clone(),
+            compression: self.compression,
+            curve: self.curve,
+            feature_index: self.feature_index,
+            tile_index: self.tile_index,
+            coarse_zooms: self.coarse_zooms.clone(),
+            relev_weights: self.relev_weights,
+            bin_boundaries: self.bin_boundaries.len(),
+            hot_phrases: self.hot_phrases.len(),
+            built_by: env!("CARGO_PKG_VERSION").to_string(),
+            built_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        }
+        .write(&self.path)?;
+
+        db.compact_range(None::<&[u8]>, None::<&[u8]>);
+        db.delete("~BUILDING")?;
+        Ok(())
+    }
+}
+
 impl GridStoreBuilder {
     /// Makes a new GridStoreBuilder with a particular filename.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        GridStoreBuilder::new_with_options(path, BuilderOpts::default())
+    }
+
+    /// Makes a new GridStoreBuilder with a particular filename and options.
+    pub fn new_with_options<P: AsRef<Path>>(path: P, opts: BuilderOpts) -> Result<Self, Error> {
         Ok(GridStoreBuilder {
             path: path.as_ref().to_owned(),
             data: BTreeMap::new(),
             bin_boundaries: Vec::new(),
+            feature_index: false,
+            tile_index: None,
+            merge_adjacent_covers: false,
+            border_covers: None,
+            coarse_zooms: Vec::new(),
+            relev_weights: None,
+            generation: None,
+            compression: Compression::None,
+            curve: Curve::Morton,
+            opts,
+            extents: BTreeMap::new(),
+            offsets: BTreeMap::new(),
+            truncated_keys: BTreeSet::new(),
+            truncation_stats: TruncationStats::default(),
+            replaces_existing: false,
+            writing: false,
         })
     }
 
+    /// Makes a GridStoreBuilder that starts out with everything in the finished store at
+    /// `path`, so that more records can be inserted or appended to it without rebuilding it from
+    /// scratch. Bin boundaries, the feature and tile index settings, merged cover extents and
+    /// truncation records carry over, and the generation goes up by one when it's finished.
+    /// Finishing writes the whole store again, and replaces the existing one once the write is
+    /// complete.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        GridStoreBuilder::open_existing_with_options(path, BuilderOpts::default())
+    }
+
+    /// Makes a GridStoreBuilder that starts out with everything in the finished store at `path`,
+    /// with particular options. See `open_existing`.
+    pub fn open_existing_with_options<P: AsRef<Path>>(
+        path: P,
+        opts: BuilderOpts,
+    ) -> Result<Self, Error> {
+        let mut builder = GridStoreBuilder::new_with_options(path, opts)?;
+        let store = GridStore::new(&builder.path)?;
+        for record in store.iter() {
+            let (grid_key, entries) = record?;
+            let extents = store.cover_extents(&grid_key, &entries)?;
+            if !extents.is_empty() {
+                builder.extents.insert(grid_key.clone(), extents);
+            }
+            builder.append(&grid_key, entries)?;
+        }
+        let mut bin_boundaries: Vec<u64> = store.bin_boundaries.iter().cloned().collect();
+        bin_boundaries.sort();
+        builder.bin_boundaries = bin_boundaries;
+        builder.feature_index = store.has_feature_index();
+        builder.tile_index = store.tile_index_zoom_levels;
+        builder.coarse_zooms = store.coarse_zoom_levels.clone();
+        builder.relev_weights = store.relev_weights;
+        builder.compression = store.compression;
+        builder.curve = store.curve;
+        builder.offsets = store.offsets()?;
+        builder.truncated_keys = store.truncated_grid_keys()?.into_iter().collect();
+        builder.truncation_stats = store.truncation_stats.clone();
+        builder.generation = store.generation.map(|generation| generation.wrapping_add(1));
+        builder.replaces_existing = true;
+        Ok(builder)
+    }
+
+    /// Rewrites the finished store at `path` in the current format if it was written with an
+    /// older major version, returning whether it was rewritten. Older major versions are still
+    /// read in place, but only in the layout they were written with; migrating them lets them
+    /// pick up what's changed since. Stores in a newer format are refused as by `GridStore::new`.
+    pub fn migrate<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
+        let (major, _) = GridStore::new(path.as_ref())?.format_version;
+        if major >= FORMAT_MAJOR_VERSION {
+            return Ok(false);
+        }
+        GridStoreBuilder::open_existing(path)?.finish()?;
+        Ok(true)
+    }
+
+    /// Merges several finished stores, such as shards of an index built in parallel, into a new
+    /// store at `output`. Records are streamed from the stores in key order and records under the
+    /// same key are combined, so only one phrase's records are in memory at a time. The merged
+    /// store has the union of the stores' bin boundaries, merged cover extents and truncation
+    /// records, a feature index if any of them had one, and a tile index at the zoom levels of the
+    /// first one that had one. Relevance weights, hot phrases and coarse zoom levels carry over
+    /// when every store has the same ones, with the coarse copies merged the same way as the
+    /// stores; stores that differ in any of them can't be merged.
+    pub fn merge(paths: &[&Path], output: &Path) -> Result<(), Error> {
+        GridStoreBuilder::merge_with_generation(paths, output, None)
+    }
+
+    /// Does the work of `merge`, recording `generation` in the merged store, or its content hash
+    /// if there's none
+    fn merge_with_generation(
+        paths: &[&Path],
+        output: &Path,
+        generation: Option<u64>,
+    ) -> Result<(), Error> {
+        let stores = paths.iter().map(GridStore::new).collect::<Result<Vec<_>, _>>()?;
+        let relev_weights =
+            same_in_every_store(&stores, "relevance weights", |store| store.relev_weights)?;
+        let hot_phrases =
+            same_in_every_store(&stores, "hot phrases", |store| Some(store.hot_phrase_ids()))?
+                .unwrap_or_default();
+        let coarse_zooms = same_in_every_store(&stores, "coarse zoom levels", |store| {
+            Some(store.coarse_zoom_levels.clone())
+        })?
+        .unwrap_or_default();
+
+        let mut bin_boundaries: BTreeSet<u64> = BTreeSet::new();
+        let mut offsets: BTreeMap<(u32, u32), u8> = BTreeMap::new();
+        let mut truncated_keys: BTreeSet<GridKey> = BTreeSet::new();
+        let mut truncation_stats = TruncationStats::default();
+        for store in stores.iter() {
+            bin_boundaries.extend(store.bin_boundaries.iter().cloned());
+            offsets.extend(store.offsets()?);
+            truncated_keys.extend(store.truncated_grid_keys()?);
+            truncation_stats.keys_truncated += store.truncation_stats.keys_truncated;
+            truncation_stats.entries_dropped += store.truncation_stats.entries_dropped;
+        }
+        let feature_index = stores.iter().any(GridStore::has_feature_index);
+        let tile_index = stores.iter().filter_map(|store| store.tile_index_zoom_levels).next();
+        let compression = stores
+            .iter()
+            .map(|store| store.compression)
+            .find(|compression| *compression != Compression::None)
+            .unwrap_or_default();
+        let curve = stores
+            .iter()
+            .map(|store| store.curve)
+            .find(|curve| *curve != Curve::Morton)
+            .unwrap_or_default();
+
+        let mut builder = GridStoreBuilder::new(output)?;
+        let mut writer = StoreWriter::new(
+            &builder.path,
+            feature_index,
+            tile_index,
+            compression,
+            bin_boundaries.into_iter().collect(),
+        )?;
+        writer.curve = curve;
+        writer.offsets = offsets.clone();
+        writer.relev_weights = relev_weights;
+        writer.hot_ranks =
+            hot_phrases.iter().enumerate().map(|(rank, id)| (*id, rank as u32)).collect();
+        writer.hot_phrases = hot_phrases;
+        builder.writing = true;
+        for grid_key in truncated_keys.iter() {
+            writer.write_truncated(grid_key)?;
+        }
+
+        // every store's records are in the order of their db keys, which is the order of their
+        // keys encoded in the current format too
+        let width = PhraseIdWidth::current();
+        let mut records: Vec<_> = stores.iter().map(|store| store.iter().peekable()).collect();
+        let mut hasher = ContentHasher::default();
+        // the records of the phrase in progress, which are hashed in key order once it's done
+        let mut phrase_records: Vec<(GridKey, BuilderEntry, CoverExtents)> = Vec::new();
+        loop {
+            let mut next: Option<(Vec<u8>, GridKey)> = None;
+            for store_records in records.iter_mut() {
+                let grid_key = match store_records.peek() {
+                    Some(Ok((grid_key, _))) => grid_key.clone(),
+                    Some(Err(_)) => return Err(store_records.next().unwrap().unwrap_err()),
+                    None => continue,
+                };
+                let mut db_key = Vec::with_capacity(MAX_KEY_LENGTH);
+                grid_key.write_to(TypeMarker::SinglePhrase, width, &mut db_key)?;
+                if next.as_ref().map_or(true, |(next_db_key, _)| db_key < *next_db_key) {
+                    next = Some((db_key, grid_key));
+                }
+            }
+            let grid_key = next.map(|(_, grid_key)| grid_key);
+
+            let phrase_done = match (&grid_key, phrase_records.first()) {
+                (Some(grid_key), Some((phrase_key, _, _))) => {
+                    grid_key.phrase_id != phrase_key.phrase_id
+                }
+                (None, _) => true,
+                _ => false,
+            };
+            if phrase_done {
+                phrase_records.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+                for (phrase_key, value, extents) in phrase_records.iter() {
+                    hasher.add_record(phrase_key, value, Some(extents));
+                }
+                for (phrase_key, value, extents) in phrase_records.drain(..) {
+                    writer.write_record(&phrase_key, value, Some(&extents))?;
+                }
+            }
+
+            let grid_key = match grid_key {
+                Some(grid_key) => grid_key,
+                None => break,
+            };
+            let mut value = BuilderEntry::new();
+            let mut extents = CoverExtents::new();
+            for (store, store_records) in stores.iter().zip(records.iter_mut()) {
+                if let Some(Ok((store_key, _))) = store_records.peek() {
+                    if *store_key == grid_key {
+                        let (_, entries) = store_records.next().unwrap()?;
+                        for ((id, zcoord), extent) in store.cover_extents(&grid_key, &entries)? {
+                            insert_extent(&mut extents, id, zcoord, extent);
+                        }
+                        extend_entries(&mut value, entries);
+                    }
+                }
+            }
+            phrase_records.push((grid_key, value, extents));
+        }
+
+        let content_hash = hasher.finish(&offsets);
+        let generation = generation.unwrap_or(content_hash);
+        // coarse copies go inside the store, and are written before it's marked complete
+        for coarse_zoom_levels in coarse_zooms.iter() {
+            let coarse_paths: Vec<PathBuf> =
+                paths.iter().map(|path| coarse_store_path(path, *coarse_zoom_levels)).collect();
+            let coarse_paths: Vec<&Path> = coarse_paths.iter().map(PathBuf::as_path).collect();
+            GridStoreBuilder::merge_with_generation(
+                &coarse_paths,
+                &coarse_store_path(output, *coarse_zoom_levels),
+                Some(generation),
+            )?;
+        }
+        writer.coarse_zooms = coarse_zooms;
+        writer.finish(generation, content_hash, &truncation_stats)?;
+        builder.writing = false;
+        Ok(())
+    }
+
     /// Inserts a new GridStore entry with the given values.
     pub fn insert(&mut self, key: &GridKey, values: Vec<GridEntry>) -> Result<(), Error> {
         let mut to_insert = BuilderEntry::new();
@@ -114,6 +1094,47 @@ impl GridStoreBuilder {
         Ok(())
     }
 
+    /// Inserts a new GridStore entry with values from an iterator. Values are added to the
+    /// builder's compact representation as they're read, so phrases with millions of entries
+    /// don't need them all in a Vec first.
+    pub fn insert_from_iter<I: Iterator<Item = GridEntry>>(
+        &mut self,
+        key: &GridKey,
+        values: I,
+    ) -> Result<(), Error> {
+        let mut to_insert = BuilderEntry::new();
+        extend_entries_from_iter(&mut to_insert, values);
+        self.data.insert(key.to_owned(), to_insert);
+        Ok(())
+    }
+
+    /// Removes a GridStore entry, e.g. from a builder made with `open_existing`.
+    pub fn delete(&mut self, key: &GridKey) -> Result<(), Error> {
+        self.data.remove(key);
+        self.extents.remove(key);
+        Ok(())
+    }
+
+    /// Removes every entry for a feature id, under every key, e.g. to purge a feature removed
+    /// upstream from a builder made with `open_existing`. Keys left without entries are removed.
+    pub fn delete_feature(&mut self, id: u32) -> Result<(), Error> {
+        for value in self.data.values_mut() {
+            for coord_group in value.values_mut() {
+                for ids in coord_group.values_mut() {
+                    ids.retain(|id_phrase| *id_phrase >> 8 != id);
+                }
+                coord_group.retain(|_, ids| !ids.is_empty());
+            }
+            value.retain(|_, coord_group| !coord_group.is_empty());
+        }
+        self.data.retain(|_, value| !value.is_empty());
+        for extents in self.extents.values_mut() {
+            extents.retain(|(extent_id, _), _| *extent_id != id);
+        }
+        self.offsets.retain(|(offset_id, _), _| *offset_id != id);
+        Ok(())
+    }
+
     ///  Appends a values to and existing GridStore entry.
     pub fn append(&mut self, key: &GridKey, values: Vec<GridEntry>) -> Result<(), Error> {
         let mut to_append = self.data.entry(key.to_owned()).or_insert_with(|| BuilderEntry::new());
@@ -152,7 +1173,7 @@ impl GridStoreBuilder {
 
     /// In situations under which data has been inserted using temporary phrase IDs, renumber
     /// the data in the index to use final phrase IDs, given a temporary-to-final-ID mapping
-    pub fn renumber(&mut self, tmp_phrase_ids_to_ids: &[u32]) -> Result<(), Error> {
+    pub fn renumber(&mut self, tmp_phrase_ids_to_ids: &[u64]) -> Result<(), Error> {
         let mut old_data: BTreeMap<GridKey, BuilderEntry> = BTreeMap::new();
         std::mem::swap(&mut old_data, &mut self.data);
 
@@ -175,71 +1196,287 @@ impl GridStoreBuilder {
         Ok(())
     }
 
-    pub fn load_bin_boundaries(&mut self, bin_boundaries: Vec<u32>) -> Result<(), Error> {
+    pub fn load_bin_boundaries(&mut self, bin_boundaries: Vec<u64>) -> Result<(), Error> {
         self.bin_boundaries = bin_boundaries;
         Ok(())
     }
 
-    /// Writes data to disk.
-    pub fn finish(self) -> Result<(), Error> {
-        let mut opts = Options::default();
-        opts.set_disable_auto_compactions(true);
-        opts.create_if_missing(true);
+    /// Also write an index from feature id to the keys it's indexed under, so that
+    /// `GridStore::keys_for_feature` can be used on the finished store.
+    pub fn set_feature_index(&mut self, enabled: bool) {
+        self.feature_index = enabled;
+    }
 
-        let db = DB::open(&opts, &self.path)?;
-        let mut db_key: Vec<u8> = Vec::with_capacity(MAX_KEY_LENGTH);
+    /// Also write an index from coarse tiles (tiles zoomed out by `coarse_zoom_levels`) to the
+    /// keys with entries in them, which `GridStore::reverse` uses to read only the records near
+    /// its point rather than every record, and which `GridStore::keys_for_tile` lists. This costs
+    /// a key per tile per key on disk, so it's off by default.
+    pub fn set_tile_index(&mut self, coarse_zoom_levels: Option<u16>) {
+        self.tile_index = coarse_zoom_levels;
+    }
+
+    /// Merge each feature's covers in adjacent tiles (including diagonally adjacent ones) under
+    /// the same key and relevance into one representative cover, recording the extent of the
+    /// tiles it stands in for, so that line features don't need an entry per tile.
+    pub fn set_merge_adjacent_covers(&mut self, enabled: bool) {
+        self.merge_adjacent_covers = enabled;
+    }
+
+    /// Also store a copy of each cover on the border of a coarse tile (a tile zoomed out by
+    /// `coarse_zoom_levels`) in the tiles adjacent to it across the border, so that queries whose
+    /// bbox or proximity scan stops at the border still find features just the other side of
+    /// it. Copies record the tile they stand in for the same way merged covers record their
+    /// extent, so proximity is still scored from where the feature is. Off by default.
+    pub fn set_border_covers(&mut self, coarse_zoom_levels: Option<u16>) {
+        self.border_covers = coarse_zoom_levels;
+    }
 
-        let mut bin_seq = self.bin_boundaries.iter().cloned().peekable();
-        let mut current_bin = None;
-        let mut next_boundary = 0u32;
-        let grouped = somewhat_eager_groupby(self.data.into_iter(), |(key, _value)| {
-            while key.phrase_id >= next_boundary {
-                current_bin = bin_seq.next();
-                next_boundary = *(bin_seq.peek().unwrap_or(&std::u32::MAX));
+    /// Also store a copy of each key's covers zoomed out by each of `coarse_zoom_levels`, with
+    /// each feature's most relevant cover in each coarse tile, so that wide-area queries can run
+    /// against the copy `GridStore::at_zoom` picks instead of a separate low-zoom store. Levels
+    /// have to be from 1 to 15; others are ignored. Copies have their own prefix bins, but no
+    /// feature or tile index.
+    pub fn set_coarse_zooms(&mut self, mut coarse_zoom_levels: Vec<u16>) {
+        coarse_zoom_levels.retain(|levels| (1..=15).contains(levels));
+        coarse_zoom_levels.sort();
+        coarse_zoom_levels.dedup();
+        self.coarse_zooms = coarse_zoom_levels;
+    }
+
+    /// Scales the relevance of each relevance bucket (0.4, 0.6, 0.8 and 1, in that order) by a
+    /// weight when the finished store is queried, e.g. to demote the lowest bucket store-wide
+    /// without rewriting its records. The weights are recorded in the store's metadata, so
+    /// changing them only takes a rebuild from `open_existing`. Weighted buckets have to stay in
+    /// ascending order of relevance, since matching relies on reading them best first.
+    pub fn set_relev_weights(&mut self, relev_weights: Option<[f64; 4]>) -> Result<(), Error> {
+        if let Some(weights) = relev_weights {
+            let weighted: Vec<f64> =
+                weights.iter().enumerate().map(|(i, w)| relev_int_to_float(i as u8) * w).collect();
+            let ascending = weighted.windows(2).all(|pair| pair[0] < pair[1]);
+            if !(weighted[0] > 0. && ascending) {
+                return Err(BuildError::UnorderedRelevWeights { weights }.into());
             }
+        }
+        self.relev_weights = relev_weights;
+        Ok(())
+    }
 
-            current_bin
-        });
+    /// Compresses record values in the finished store, which `GridStore` decompresses
+    /// transparently when reading them. Stores are uncompressed by default.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Places feature `id`'s entries in tile (x, y) at a point within the tile, `offset`
+    /// sixteenths of the tile across and down from its top left corner, so that proximity
+    /// queries measure distance to that point rather than to the middle of the tile. This helps
+    /// rank nearby features in stores at low zooms, where tiles are large, at two bytes more per
+    /// coord in records that have any. Other entries in the same records are still measured to
+    /// the middle of their tiles. Coarse copies don't keep offsets.
+    pub fn set_offset(&mut self, id: u32, x: u16, y: u16, offset: [u8; 2]) -> Result<(), Error> {
+        if offset[0] > 15 || offset[1] > 15 {
+            return Err(BuildError::OffsetOutOfRange { offset }.into());
+        }
+        self.offsets.insert((id, interleave_morton(x, y)), (offset[0] << 4) | offset[1]);
+        Ok(())
+    }
 
-        for (group_id, group_value) in grouped {
-            let mut lang_set_map: HashMap<u128, BuilderEntry> = HashMap::new();
+    /// Orders the coords of each record along `curve` rather than in z-order, as recorded in the
+    /// finished store's metadata, so that matching picks the search that suits the order. Readers
+    /// from before curves could be set would take the coords for z-order ones; they refuse the
+    /// store by its format version.
+    pub fn set_curve(&mut self, curve: Curve) {
+        self.curve = curve;
+    }
 
-            for (grid_key, value) in group_value.into_iter() {
-                // figure out the key
-                db_key.clear();
-                grid_key.write_to(TypeMarker::SinglePhrase, &mut db_key)?;
-
-                let mut grouped_entry =
-                    lang_set_map.entry(grid_key.lang_set).or_insert_with(|| BuilderEntry::new());
-                copy_entries(&value, &mut grouped_entry);
-                // figure out the value
-                let db_data = get_encoded_value(value)?;
-                db.put(&db_key, &db_data)?;
+    /// Sets the generation id to record in the finished store. A builder made with
+    /// `open_existing` defaults to one more than the existing store's generation, so each update
+    /// of a store gets a higher id; otherwise it defaults to the store's content hash, so builds
+    /// of the same data, like the replicas of one release, get the same id without being told,
+    /// and builds of different data get different ones. Set it to order separate builds.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = Some(generation);
+    }
+
+    /// Writes data to disk.
+    pub fn finish(mut self) -> Result<(), Error> {
+        let mut extents = std::mem::take(&mut self.extents);
+        if self.merge_adjacent_covers {
+            for (grid_key, value) in self.data.iter_mut() {
+                merge_adjacent_covers(value, extents.entry(grid_key.clone()).or_default());
+            }
+        }
+        if let Some(coarse_zoom_levels) = self.border_covers {
+            for (grid_key, value) in self.data.iter_mut() {
+                add_border_covers(
+                    value,
+                    coarse_zoom_levels,
+                    extents.entry(grid_key.clone()).or_default(),
+                );
             }
-            if let Some(group_id) = group_id {
-                for (lang_set, builder_entry) in lang_set_map.into_iter() {
-                    db_key.clear();
-                    let group_key = GridKey { phrase_id: group_id, lang_set };
-                    group_key.write_to(TypeMarker::PrefixBin, &mut db_key)?;
-                    let grouped_db_data = get_encoded_value(builder_entry)?;
-                    db.put(&db_key, &grouped_db_data)?;
+        }
+
+        let mut truncation_stats = std::mem::take(&mut self.truncation_stats);
+        let mut truncated_keys = std::mem::take(&mut self.truncated_keys);
+        if let Some(max_entries) = self.opts.max_entries_per_key {
+            for (grid_key, value) in self.data.iter_mut() {
+                let count = count_entries(value);
+                if count <= max_entries {
+                    continue;
                 }
+                match self.opts.overflow_policy {
+                    OverflowPolicy::KeepTopByScore => keep_top_by_score(value, max_entries),
+                    OverflowPolicy::SampleSpatially { coarse_zoom_levels } => {
+                        sample_spatially(value, max_entries, coarse_zoom_levels)
+                    }
+                    OverflowPolicy::Error => {
+                        return Err(Error::from(BuildError::TooManyEntries {
+                            phrase_id: grid_key.phrase_id,
+                            count,
+                        }))
+                    }
+                }
+                if truncated_keys.insert(grid_key.clone()) {
+                    truncation_stats.keys_truncated += 1;
+                }
+                truncation_stats.entries_dropped += (count - max_entries) as u64;
             }
         }
 
-        // bake the prefix boundaries
-        let mut encoded_boundaries: Vec<u8> = Vec::with_capacity(self.bin_boundaries.len() * 4);
-        for boundary in self.bin_boundaries {
-            encoded_boundaries.extend_from_slice(&boundary.to_le_bytes());
+        // truncation can drop merged covers, and their extents go with them
+        for (grid_key, record_extents) in extents.iter_mut() {
+            match self.data.get(grid_key) {
+                Some(value) => {
+                    record_extents.retain(|(id, zcoord), _| has_cover(value, *id, *zcoord))
+                }
+                None => record_extents.clear(),
+            }
         }
-        db.put("~BOUNDS", &encoded_boundaries)?;
+        extents.retain(|_, record_extents| !record_extents.is_empty());
 
-        db.compact_range(None::<&[u8]>, None::<&[u8]>);
-        drop(db);
+        let content_hash = content_hash(&self.data, &extents, &self.offsets);
+
+        // an existing store is rewritten next to itself, and swapped in once it's complete
+        let existing_path = if self.replaces_existing {
+            let mut file_name = self.path.file_name().unwrap_or_default().to_owned();
+            file_name.push(".appending");
+            let new_path = self.path.with_file_name(file_name);
+            if new_path.exists() {
+                DB::destroy(&Options::default(), &new_path)?;
+            }
+            Some(std::mem::replace(&mut self.path, new_path))
+        } else {
+            None
+        };
+
+        let generation = self.generation.unwrap_or(content_hash);
+        let bin_boundaries = std::mem::take(&mut self.bin_boundaries);
+        let mut writer = StoreWriter::new(
+            &self.path,
+            self.feature_index,
+            self.tile_index,
+            self.compression,
+            bin_boundaries.clone(),
+        )?;
+        self.writing = true;
+
+        // coarse copies go inside the store, and are written before it's marked complete
+        let coarse_dir = coarse_stores_dir(&self.path);
+        if coarse_dir.exists() {
+            fs::remove_dir_all(&coarse_dir)?;
+        }
+        for coarse_zoom_levels in self.coarse_zooms.iter() {
+            write_coarse_store(
+                &self.path,
+                &self.data,
+                *coarse_zoom_levels,
+                self.compression,
+                self.curve,
+                &bin_boundaries,
+                generation,
+            )?;
+        }
+        writer.coarse_zooms = self.coarse_zooms.clone();
+        writer.relev_weights = self.relev_weights;
+        writer.curve = self.curve;
+        writer.offsets = std::mem::take(&mut self.offsets);
+        if let RecordOrder::AccessFrequency { frequencies, max_phrases } = &self.opts.record_order {
+            writer.hot_phrases = hot_phrases(&self.data, frequencies, *max_phrases);
+            writer.hot_ranks = writer
+                .hot_phrases
+                .iter()
+                .enumerate()
+                .map(|(rank, phrase_id)| (*phrase_id, rank as u32))
+                .collect();
+        }
+
+        for grid_key in truncated_keys {
+            writer.write_truncated(&grid_key)?;
+        }
+        let data = std::mem::take(&mut self.data);
+        for (grid_key, value) in data {
+            writer.write_record(&grid_key, value, extents.get(&grid_key))?;
+        }
+        writer.finish(generation, content_hash, &truncation_stats)?;
+        self.writing = false;
+
+        if let Some(existing_path) = existing_path {
+            fs::remove_dir_all(&existing_path)?;
+            fs::rename(&self.path, &existing_path)?;
+        }
+        Ok(())
+    }
+
+    /// Discards everything inserted so far, and removes the incomplete output of an earlier build
+    /// to the same path that failed partway, if there is one. Complete stores are left alone.
+    pub fn abort(mut self) -> Result<(), Error> {
+        self.writing = false;
+        let incomplete = match DB::open(&Options::default(), &self.path) {
+            Ok(db) => db.get("~BUILDING")?.is_some(),
+            Err(_) => false,
+        };
+        if incomplete {
+            DB::destroy(&Options::default(), &self.path)?;
+            let coarse_dir = coarse_stores_dir(&self.path);
+            if coarse_dir.exists() {
+                fs::remove_dir_all(coarse_dir)?;
+            }
+        }
         Ok(())
     }
 }
 
+/// The phrases of `data` to copy to the hot section: the `max_phrases` with the highest nonzero
+/// frequencies, hottest first, with ties broken by phrase id
+fn hot_phrases(
+    data: &BTreeMap<GridKey, BuilderEntry>,
+    frequencies: &HashMap<u64, u64>,
+    max_phrases: usize,
+) -> Vec<u64> {
+    let mut phrases: Vec<(u64, u64)> = data
+        .keys()
+        .map(|grid_key| grid_key.phrase_id)
+        .dedup()
+        .filter_map(|phrase_id| match frequencies.get(&phrase_id) {
+            Some(frequency) if *frequency > 0 => Some((*frequency, phrase_id)),
+            _ => None,
+        })
+        .collect();
+    phrases.sort_by_key(|(frequency, phrase_id)| (std::cmp::Reverse(*frequency), *phrase_id));
+    phrases.into_iter().take(max_phrases).map(|(_, phrase_id)| phrase_id).collect()
+}
+
+impl Drop for GridStoreBuilder {
+    fn drop(&mut self) {
+        if self.writing {
+            // finish failed partway; there's nowhere to report an error from here
+            let _ = DB::destroy(&Options::default(), &self.path);
+            let _ = fs::remove_dir_all(coarse_stores_dir(&self.path));
+        }
+    }
+}
+
 #[cfg(test)]
 use tempfile;
 
@@ -291,6 +1528,28 @@ fn insert_test() {
     builder.finish().unwrap();
 }
 
+#[test]
+fn insert_from_iter_test() {
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+    let entries = |ids: std::ops::Range<u32>| {
+        ids.map(|id| GridEntry {
+            id,
+            x: (id % 3) as u16,
+            y: 1,
+            relev: if id % 2 == 0 { 1. } else { 0.8 },
+            score: 3,
+            source_phrase_hash: 0,
+        })
+    };
+    let from_vec = GridKey { phrase_id: 1, lang_set: 1 };
+    let from_iter = GridKey { phrase_id: 2, lang_set: 1 };
+    builder.insert(&from_vec, entries(0..100).collect()).unwrap();
+    builder.insert_from_iter(&from_iter, entries(0..100)).unwrap();
+    assert_eq!(builder.data[&from_vec], builder.data[&from_iter]);
+    builder.finish().unwrap();
+}
+
 #[test]
 fn append_test() {
     let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
@@ -346,10 +1605,244 @@ fn compact_append_test() {
     builder.finish().unwrap();
 }
 
+#[test]
+fn max_entries_per_key_test() {
+    let entries = vec![
+        GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+        GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 3, x: 3, y: 3, relev: 0.8, score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 4, x: 3, y: 3, relev: 1., score: 1, source_phrase_hash: 0 },
+    ];
+
+    let mut entry = BuilderEntry::new();
+    extend_entries(&mut entry, entries.clone());
+    assert_eq!(count_entries(&mut entry), 4);
+    keep_top_by_score(&mut entry, 2);
+    assert_eq!(count_entries(&mut entry), 2, "entry is truncated");
+    // relev 3 (0011) with score 7 (0111) -> 55; relev 3 with score 1 -> 49
+    assert_eq!(entry[&55].len(), 1, "the best relev and score is kept");
+    assert_eq!(entry[&49][&15].as_slice(), &[4 << 8], "ties keep the highest z-order");
+
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let opts = BuilderOpts {
+        max_entries_per_key: Some(2),
+        overflow_policy: OverflowPolicy::Error,
+        ..BuilderOpts::default()
+    };
+    let mut builder = GridStoreBuilder::new_with_options(directory.path(), opts).unwrap();
+    builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+    assert!(builder.finish().is_err(), "the error policy fails the build");
+}
+
+#[test]
+fn sample_spatially_test() {
+    // a dense cluster of high scores in one corner, and lower scores spread around
+    let mut entries = vec![
+        GridEntry { id: 1, x: 0, y: 0, relev: 1., score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 2, x: 1, y: 0, relev: 1., score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 3, x: 0, y: 1, relev: 1., score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 4, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 },
+    ];
+    entries.push(GridEntry { id: 5, x: 8, y: 0, relev: 1., score: 1, source_phrase_hash: 0 });
+    entries.push(GridEntry { id: 6, x: 0, y: 8, relev: 1., score: 2, source_phrase_hash: 0 });
+    entries.push(GridEntry { id: 7, x: 8, y: 8, relev: 1., score: 3, source_phrase_hash: 0 });
+
+    let mut entry = BuilderEntry::new();
+    extend_entries(&mut entry, entries.clone());
+    sample_spatially(&mut entry, 5, 2);
+
+    let mut ids: Vec<u32> = entry
+        .values()
+        .flat_map(|coord_group| coord_group.values())
+        .flat_map(|ids| ids.iter().map(|id| id >> 8))
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![3, 4, 5, 6, 7], "every coarse tile keeps its best entry first");
+
+    let mut entry = BuilderEntry::new();
+    extend_entries(&mut entry, entries);
+    sample_spatially(&mut entry, 2, 2);
+    assert_eq!(count_entries(&mut entry), 2);
+    assert_eq!(entry[&55].len(), 1, "if not every tile fits, the best tiles win");
+    assert_eq!(entry[&51].len(), 1);
+}
+
+#[test]
+fn open_existing_test() {
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let path = directory.path().join("store");
+    let key1 = GridKey { phrase_id: 1, lang_set: 1 };
+    let key2 = GridKey { phrase_id: 2, lang_set: 1 };
+    let grid =
+        |id: u32, x: u16| GridEntry { id, x, y: 1, relev: 1., score: 3, source_phrase_hash: 0 };
+
+    let mut builder = GridStoreBuilder::new(&path).unwrap();
+    builder.insert(&key1, vec![grid(1, 1)]).unwrap();
+    builder.load_bin_boundaries(vec![0, 2]).unwrap();
+    builder.set_feature_index(true);
+    builder.finish().unwrap();
+
+    let mut builder = GridStoreBuilder::open_existing(&path).unwrap();
+    builder.append(&key1, vec![grid(3, 3)]).unwrap();
+    builder.insert(&key2, vec![grid(2, 2)]).unwrap();
+    builder.finish().unwrap();
+    assert!(!directory.path().join("store.appending").exists(), "the new store replaced the old");
+
+    let store = GridStore::new(&path).unwrap();
+    let ids = |key: &GridKey| -> Vec<u32> {
+        let mut ids: Vec<u32> = store.get(key).unwrap().unwrap().map(|entry| entry.id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(&key1), vec![1, 3], "existing records can be appended to");
+    assert_eq!(ids(&key2), vec![2], "new records can be added");
+    assert_eq!(store.bin_boundaries.len(), 2, "bin boundaries carry over");
+    let keys: Vec<GridKey> = store.keys_for_feature(2).map(Result::unwrap).collect();
+    assert_eq!(keys, vec![key2], "the feature index is rebuilt");
+}
+
+#[test]
+fn merge_test() {
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let grid =
+        |id: u32, x: u16| GridEntry { id, x, y: 1, relev: 1., score: 3, source_phrase_hash: 0 };
+    // 0x100 sorts before 0x2 on disk, where language sets are written without leading zeros
+    let records = vec![
+        (GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(1, 1)], vec![grid(3, 3)]),
+        (GridKey { phrase_id: 2, lang_set: 2 }, vec![grid(2, 2)], vec![]),
+        (GridKey { phrase_id: 2, lang_set: 0x100 }, vec![], vec![grid(5, 5)]),
+        (GridKey { phrase_id: 3, lang_set: 1 }, vec![], vec![grid(4, 4)]),
+    ];
+    let build = |name: &str, shard: Option<bool>| {
+        let path = directory.path().join(name);
+        let mut builder = GridStoreBuilder::new(&path).unwrap();
+        for (grid_key, first, second) in records.iter() {
+            let entries = match shard {
+                Some(true) => first.clone(),
+                Some(false) => second.clone(),
+                None => first.iter().chain(second.iter()).cloned().collect(),
+            };
+            if !entries.is_empty() {
+                builder.insert(grid_key, entries).unwrap();
+            }
+        }
+        builder.load_bin_boundaries(vec![0, 2]).unwrap();
+        builder.set_feature_index(shard != Some(false));
+        builder.finish().unwrap();
+        path
+    };
+    let (first, second, whole) =
+        (build("first", Some(true)), build("second", Some(false)), build("whole", None));
+
+    let merged_path = directory.path().join("merged");
+    GridStoreBuilder::merge(&[&first, &second], &merged_path).unwrap();
+    let merged = GridStore::new(&merged_path).unwrap();
+    let whole = GridStore::new(&whole).unwrap();
+
+    let contents = |store: &GridStore| -> Vec<(GridKey, Vec<GridEntry>)> {
+        store.iter().map(Result::unwrap).collect()
+    };
+    assert_eq!(contents(&merged), contents(&whole), "records under the same key are combined");
+    assert_eq!(merged.content_hash, whole.content_hash);
+    let bin_ids = |store: &GridStore| -> Vec<u32> {
+        let key = MatchKey { match_phrase: MatchPhrase::Range { start: 2, end: 4 }, lang_set: 1 };
+        let mut ids: Vec<u32> = store
+            .streaming_get_matching(&key, &MatchOpts::default(), 10)
+            .unwrap()
+            .map(|entry| entry.grid_entry.id)
+            .collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(bin_ids(&merged), vec![2, 4, 5], "prefix bins are rebuilt");
+    let keys: Vec<GridKey> = merged.keys_for_feature(4).map(Result::unwrap).collect();
+    assert_eq!(keys, vec![GridKey { phrase_id: 3, lang_set: 1 }], "the feature index is rebuilt");
+}
+
+#[test]
+fn merge_settings_test() {
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let frequencies: HashMap<u64, u64> = vec![(1, 10), (2, 5)].into_iter().collect();
+    let build = |name: &str, id: u32, relev_weights: Option<[f64; 4]>| {
+        let path = directory.path().join(name);
+        let record_order =
+            RecordOrder::AccessFrequency { frequencies: frequencies.clone(), max_phrases: 1 };
+        let opts = BuilderOpts { record_order, ..BuilderOpts::default() };
+        let mut builder = GridStoreBuilder::new_with_options(&path, opts).unwrap();
+        for phrase_id in 1..3 {
+            let entries =
+                vec![GridEntry { id, x: 4, y: 4, relev: 1., score: 3, source_phrase_hash: 0 }];
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+        }
+        builder.set_relev_weights(relev_weights).unwrap();
+        builder.set_coarse_zooms(vec![2]);
+        builder.finish().unwrap();
+        path
+    };
+    let weights = Some([0.5, 0.6, 0.8, 1.]);
+    let (first, second) = (build("first", 1, weights), build("second", 2, weights));
+
+    let merged_path = directory.path().join("merged");
+    GridStoreBuilder::merge(&[&first, &second], &merged_path).unwrap();
+    let merged = GridStore::new(&merged_path).unwrap();
+    assert_eq!(merged.relev_weights, weights, "relevance weights carry over");
+    assert_eq!(merged.hot_phrase_ids(), vec![1], "hot phrases carry over");
+    assert_eq!(merged.coarse_zoom_levels, vec![2], "coarse zoom levels carry over");
+    let coarse = GridStore::new(coarse_store_path(&merged_path, 2)).unwrap();
+    let ids: Vec<u32> = coarse
+        .get(&GridKey { phrase_id: 1, lang_set: 1 })
+        .unwrap()
+        .unwrap()
+        .map(|entry| entry.id)
+        .collect();
+    assert_eq!(ids, vec![2, 1], "coarse copies are merged");
+
+    let unweighted = build("unweighted", 3, None);
+    let err = GridStoreBuilder::merge(&[&first, &unweighted], &directory.path().join("mismatched"))
+        .unwrap_err();
+    assert_eq!(err.to_string(), "can't merge stores with different relevance weights");
+}
+
+/// The value `setting` reads from every one of `stores`, or an error if they don't all agree
+fn same_in_every_store<T: PartialEq, F: Fn(&GridStore) -> Option<T>>(
+    stores: &[GridStore],
+    name: &'static str,
+    setting: F,
+) -> Result<Option<T>, Error> {
+    let mut values = stores.iter().map(setting);
+    let first = values.next().flatten();
+    if values.any(|value| value != first) {
+        return Err(BuildError::MismatchedMergeSetting { setting: name }.into());
+    }
+    Ok(first)
+}
+
 #[derive(Debug, Fail)]
 enum BuildError {
     #[fail(display = "duplicate rename entry: {}", target_id)]
-    DuplicateRenumberEntry { target_id: u32 },
+    DuplicateRenumberEntry { target_id: u64 },
     #[fail(display = "out of bounds: {}", tmp_id)]
-    OutOfBoundsRenumberEntry { tmp_id: u32 },
+    OutOfBoundsRenumberEntry { tmp_id: u64 },
+    #[fail(display = "too many entries for phrase {}: {}", phrase_id, count)]
+    TooManyEntries { phrase_id: u64, count: usize },
+    #[fail(display = "relevance weights reorder the relevance buckets: {:?}", weights)]
+    UnorderedRelevWeights { weights: [f64; 4] },
+    #[fail(display = "sub-tile offset {:?} isn't within a tile's sixteenths", offset)]
+    OffsetOutOfRange { offset: [u8; 2] },
+    #[fail(display = "can't merge stores with different {}", setting)]
+    MismatchedMergeSetting { setting: &'static str },
+}
+
+#[test]
+fn hot_phrases_test() {
+    let mut data: BTreeMap<GridKey, BuilderEntry> = BTreeMap::new();
+    for (phrase_id, lang_set) in vec![(1, 1), (1, 2), (2, 1), (3, 1), (4, 1)] {
+        data.insert(GridKey { phrase_id, lang_set }, BuilderEntry::new());
+    }
+    let frequencies: HashMap<u64, u64> =
+        vec![(1, 5), (2, 9), (3, 5), (4, 0), (5, 100)].into_iter().collect();
+    // hottest first, ties by phrase id, and neither unused nor absent phrases
+    assert_eq!(hot_phrases(&data, &frequencies, 10), vec![2, 1, 3]);
+    assert_eq!(hot_phrases(&data, &frequencies, 2), vec![2, 1]);
+    assert_eq!(hot_phrases(&data, &HashMap::new(), 10), Vec::<u64>::new());
 }
@@ -1,21 +1,28 @@
 use std::borrow::Borrow;
 use std::cmp::{Ordering, Reverse};
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::mem;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
-use failure::Error;
+use failure::{Error, Fail};
 use fxhash::FxHashSet;
 use indexmap::map::{Entry as IndexMapEntry, IndexMap};
-use itertools::Itertools;
+use itertools::{Either, Itertools};
 use min_max_heap::MinMaxHeap;
 use ordered_float::OrderedFloat;
 use rayon::prelude::*;
+use serde::Serialize;
 use static_bushes::{KDBush, KDBushBuilder};
 
 use crate::gridstore::common::*;
-use crate::gridstore::spatial::adjust_bbox_zoom;
+use crate::gridstore::priority;
+use crate::gridstore::scoring::{self, ScoredistOpts};
+use crate::gridstore::settings::Settings;
+use crate::gridstore::spatial::{self, adjust_bbox_zoom, tile_lonlat};
 use crate::gridstore::stackable::{stackable, StackableNode, StackableTree};
 use crate::gridstore::store::GridStore;
 
@@ -25,82 +32,821 @@ pub fn coalesce<T: Borrow<GridStore> + Clone + Debug>(
     stack: Vec<PhrasematchSubquery<T>>,
     match_opts: &MatchOpts,
 ) -> Result<Vec<CoalesceContext>, Error> {
+    let _admission = priority::admit(match_opts.coalesce.priority, match_opts.scheduler.as_ref());
+    coalesce_with_fetched(stack, match_opts, None)
+}
+
+/// Same as `coalesce`, but also returns stats about the stores that were queried, including the
+/// subqueries that were skipped under `CoalesceOpts::partial_ok` because their store returned an
+/// error
+pub fn coalesce_with_stats<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<(Vec<CoalesceContext>, CoalesceStats), Error> {
+    let _admission = priority::admit(match_opts.coalesce.priority, match_opts.scheduler.as_ref());
+    let mut stats = CoalesceStats::new(&stack)?;
+    let mut budget = Budget::new(&match_opts.coalesce);
+    let contexts = coalesce_pruning(stack, match_opts, None, None, &mut budget, &mut stats)?;
+    Ok((contexts, stats))
+}
+
+/// How many results `coalesce_lazy` asks for the first time
+const LAZY_FIRST_CONTEXTS: usize = 5;
+
+/// Same as `coalesce`, but pulls results as they're asked for, for callers like autocomplete that
+/// often only look at the first few. The query is run with a small `CoalesceOpts::max_contexts`
+/// first, and rerun with twice the limit whenever the results run out, so reading only the first
+/// results costs only what finding them does. Results are the same, in the same order, as
+/// `coalesce` returns.
+pub fn coalesce_lazy<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> LazyContexts<T> {
+    let max_contexts = match_opts
+        .coalesce
+        .max_contexts
+        .map_or(MAX_CONTEXTS, |max| std::cmp::min(max, MAX_CONTEXTS));
+    LazyContexts {
+        stack,
+        match_opts: match_opts.clone(),
+        max_contexts,
+        limit: 0,
+        contexts: Vec::new(),
+        returned: 0,
+    }
+}
+
+/// The results of `coalesce_lazy`
+pub struct LazyContexts<T: Borrow<GridStore> + Clone + Debug> {
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: MatchOpts,
+    /// The most results there can be
+    max_contexts: usize,
+    /// How many results the last run asked for
+    limit: usize,
+    contexts: Vec<CoalesceContext>,
+    returned: usize,
+}
+
+impl<T: Borrow<GridStore> + Clone + Debug> Iterator for LazyContexts<T> {
+    type Item = Result<CoalesceContext, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.returned == self.contexts.len() {
+            // a run that came up short of its limit found everything there is
+            let exhausted = self.limit > 0 && self.contexts.len() < self.limit;
+            if exhausted || self.limit >= self.max_contexts {
+                return None;
+            }
+            self.limit = std::cmp::min(
+                std::cmp::max(2 * self.limit, LAZY_FIRST_CONTEXTS),
+                self.max_contexts,
+            );
+            let mut match_opts = self.match_opts.clone();
+            match_opts.coalesce.max_contexts = Some(self.limit);
+            match coalesce(self.stack.clone(), &match_opts) {
+                Ok(contexts) => self.contexts = contexts,
+                Err(err) => {
+                    self.limit = self.max_contexts;
+                    return Some(Err(err));
+                }
+            }
+        }
+        let context = self.contexts.get(self.returned)?.clone();
+        self.returned += 1;
+        Some(Ok(context))
+    }
+}
+
+/// Same as `coalesce`, but explains the results: where each entry came from and how it was scored,
+/// and which of the contexts that were found didn't make it into the results, and why. This is
+/// for debugging rankings, and copies every context found, so it's slower than `coalesce`.
+pub fn coalesce_with_trace<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<CoalesceTrace, Error> {
+    let _admission = priority::admit(match_opts.coalesce.priority, match_opts.scheduler.as_ref());
+    let mut pruned = Vec::new();
+    let mut budget = Budget::new(&match_opts.coalesce);
+    let mut stats = CoalesceStats::default();
+    let contexts = coalesce_pruning(
+        stack.clone(),
+        match_opts,
+        None,
+        Some(&mut pruned),
+        &mut budget,
+        &mut stats,
+    )?;
+    let contexts = contexts
+        .into_iter()
+        .map(|context| TracedContext {
+            entries: context
+                .entries
+                .iter()
+                .map(|entry| trace_entry(entry, &stack, match_opts))
+                .collect(),
+            context,
+        })
+        .collect();
+    Ok(CoalesceTrace { contexts, pruned })
+}
+
+fn trace_entry<T: Borrow<GridStore> + Clone>(
+    entry: &CoalesceEntry,
+    stack: &[PhrasematchSubquery<T>],
+    match_opts: &MatchOpts,
+) -> EntryTrace {
+    let subquery = stack.iter().find(|subquery| subquery.idx == entry.idx);
+    let multiplier = subquery.map_or(1., |subquery| {
+        relev_multiplier(subquery, match_opts, entry.phrasematch_id, entry.grid_entry.id)
+    });
+    let proximity_adjustment = if match_opts.proximity.is_some() {
+        entry.scoredist - spatial::score_only_scoredist(entry.grid_entry.score)
+    } else {
+        0.
+    };
+    EntryTrace {
+        id: entry.grid_entry.id,
+        idx: entry.idx,
+        type_id: subquery.map_or(0, |subquery| subquery.store.borrow().type_id),
+        phrasematch_id: entry.phrasematch_id,
+        grid_relev: if multiplier == 0. { 0. } else { entry.grid_entry.relev / multiplier },
+        relev: entry.grid_entry.relev,
+        proximity_adjustment,
+    }
+}
+
+/// Why `coalesce` left out a context it found
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneRule {
+    /// It was a relevance gate or more behind the best result; see `CoalesceOpts::relevance_gate`
+    RelevanceGate,
+    /// Enough better results had been returned already; see `CoalesceOpts::max_contexts`
+    MaxContexts,
+    /// A better context for the same feature was returned; see `CoalesceOpts::dedup`
+    Duplicate,
+    /// The caller left it out; see `MatchOpts::context_filter`
+    Filtered,
+}
+
+/// A context `coalesce` found but didn't return
+#[derive(Serialize, Debug, Clone)]
+pub struct PrunedContext {
+    pub context: CoalesceContext,
+    pub rule: PruneRule,
+}
+
+/// Where an entry of a result came from and how it was scored
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryTrace {
+    pub id: u32,
+    pub idx: u16,
+    /// type id of the store the entry was read from
+    pub type_id: u16,
+    /// id of the match key that matched it, as in `CoalesceEntry::phrasematch_id`
+    pub phrasematch_id: u32,
+    /// relevance of the grid as it was stored, before the subquery's weight and any override
+    pub grid_relev: f64,
+    /// relevance the entry adds to its context
+    pub relev: f64,
+    /// how much proximity moved the entry's scoredist from what its score alone would give, or 0
+    /// without proximity
+    pub proximity_adjustment: f64,
+}
+
+/// A result of `coalesce_with_trace`, with a trace for each of its entries, in the same order
+#[derive(Serialize, Debug, Clone)]
+pub struct TracedContext {
+    pub context: CoalesceContext,
+    pub entries: Vec<EntryTrace>,
+}
+
+/// The results of `coalesce_with_trace`
+#[derive(Serialize, Debug, Clone)]
+pub struct CoalesceTrace {
+    pub contexts: Vec<TracedContext>,
+    /// The contexts that were found but not returned. For a single-subquery stack these include
+    /// the features' other grids and the first grid that fell behind the relevance gate, in the
+    /// order they were read; for longer stacks, only the contexts that were cut from the
+    /// finished, sorted list. Grids coalesce never read because it stopped early aren't listed.
+    pub pruned: Vec<PrunedContext>,
+}
+
+/// Same as `coalesce`, but also says whether the query ran out of its budget, set with
+/// `CoalesceOpts::max_grids_examined` and `CoalesceOpts::time_budget`. A query that did returns
+/// the best results among the grids it read before it stopped, which may be missing results an
+/// unlimited query would return.
+pub fn coalesce_with_budget<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<BudgetedContexts, Error> {
+    let _admission = priority::admit(match_opts.coalesce.priority, match_opts.scheduler.as_ref());
+    let mut budget = Budget::new(&match_opts.coalesce);
+    let mut stats = CoalesceStats::default();
+    let contexts = coalesce_pruning(stack, match_opts, None, None, &mut budget, &mut stats)?;
+    Ok(BudgetedContexts { contexts, truncated: budget.is_spent() })
+}
+
+/// The results of `coalesce_with_budget`
+#[derive(Serialize, Debug, Clone)]
+pub struct BudgetedContexts {
+    pub contexts: Vec<CoalesceContext>,
+    /// Whether the query ran out of budget before it read every grid it would have otherwise
+    pub truncated: bool,
+}
+
+/// How often, in grids, a query's `CoalesceOpts::time_budget` is checked
+const BUDGET_CHECK_INTERVAL: usize = 64;
+
+/// The work a query has left under `CoalesceOpts::max_grids_examined` and `time_budget`
+struct Budget {
+    grids_left: Option<usize>,
+    deadline: Option<Instant>,
+    /// Grids to read before the clock is checked again
+    until_check: usize,
+    spent: bool,
+}
+
+impl Budget {
+    fn new(opts: &CoalesceOpts) -> Self {
+        Budget {
+            grids_left: opts.max_grids_examined,
+            deadline: opts.time_budget.map(|budget| Instant::now() + budget),
+            until_check: 0,
+            spent: false,
+        }
+    }
+
+    /// Counts a grid against the budget, returning whether there was budget left to read it
+    #[inline]
+    fn spend(&mut self) -> bool {
+        if self.spent {
+            return false;
+        }
+        if self.grids_left == Some(0) {
+            self.spent = true;
+            return false;
+        }
+        if self.until_check == 0 {
+            if self.is_spent() {
+                return false;
+            }
+            self.until_check = BUDGET_CHECK_INTERVAL;
+        }
+        self.grids_left = self.grids_left.map(|left| left - 1);
+        self.until_check -= 1;
+        true
+    }
+
+    /// Whether the budget has run out, checking the clock if there's a deadline
+    fn is_spent(&mut self) -> bool {
+        if let Some(deadline) = self.deadline {
+            if !self.spent && Instant::now() >= deadline {
+                self.spent = true;
+            }
+        }
+        self.spent
+    }
+}
+
+/// Identifies a grid lookup by the store, the key, and the match options the store looks at
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LookupKey {
+    store: StoreId,
+    key: MatchKey,
+    opts: LookupOpts,
+}
+
+/// Identifies a store by the data it holds and the options it was opened with that change what
+/// its lookups return, so handles to the same store share lookups
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StoreId {
+    path: PathBuf,
+    generation: Option<u64>,
+    zoom: u16,
+    coalesce_radius: OrderedFloat<f64>,
+}
+
+/// The match options `GridStore::streaming_get_matching` looks at
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LookupOpts {
+    bbox: Option<[u16; 4]>,
+    bboxes: Option<Arc<Vec<[u16; 4]>>>,
+    proximity: Option<[u16; 2]>,
+    zoom: u16,
+    proximity_radius: Option<OrderedFloat<f64>>,
+    /// Whether the boost is multiplicative, and by how much
+    language_boost: Option<(bool, OrderedFloat<f64>)>,
+    /// Masks are too big to compare, so they're told apart by address; queries that share one
+    /// share the `Arc`, which outlives the lookups
+    exclude_tiles: Option<usize>,
+    polygon: Option<usize>,
+}
+
+/// Grids already fetched for the subqueries of multi-subquery stacks
+type FetchedGrids = HashMap<LookupKey, Vec<MatchEntry>>;
+
+fn lookup_key<T: Borrow<GridStore> + Clone>(
+    subquery: &PhrasematchSubquery<T>,
+    match_opts: &MatchOpts,
+) -> LookupKey {
+    let store: &GridStore = subquery.store.borrow();
+    let language_boost = match_opts.language_boost.map(|boost| match boost {
+        LanguageBoost::Additive(boost) => (false, OrderedFloat(boost)),
+        LanguageBoost::Multiplicative(boost) => (true, OrderedFloat(boost)),
+    });
+    LookupKey {
+        store: StoreId {
+            path: store.path.clone(),
+            generation: store.generation,
+            zoom: store.zoom,
+            coalesce_radius: OrderedFloat(store.coalesce_radius),
+        },
+        key: subquery.match_keys[0].key.clone(),
+        opts: LookupOpts {
+            bbox: match_opts.bbox,
+            bboxes: match_opts.bboxes.clone(),
+            proximity: match_opts.proximity,
+            zoom: match_opts.zoom,
+            proximity_radius: match_opts.proximity_radius.map(OrderedFloat),
+            language_boost,
+            exclude_tiles: match_opts.exclude_tiles.as_ref().map(|mask| Arc::as_ptr(mask) as usize),
+            polygon: match_opts.polygon.as_ref().map(|mask| Arc::as_ptr(mask) as usize),
+        },
+    }
+}
+
+/// Coalesces a batch of independent queries, as in bulk geocoding. Grid lookups that the
+/// multi-subquery stacks of the batch have in common are only done once, and the queries are
+/// combined in parallel. Results are in the same order as the queries. The batch is scheduled as
+/// a whole, at the highest priority of any query in it, under the scheduler of the first query
+/// that has one.
+pub fn coalesce_batch<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    queries: Vec<(Vec<PhrasematchSubquery<T>>, MatchOpts)>,
+) -> Vec<Result<Vec<CoalesceContext>, Error>> {
+    let batch_priority = queries
+        .iter()
+        .map(|(_, match_opts)| match_opts.coalesce.priority)
+        .max()
+        .unwrap_or(QueryPriority::Batch);
+    let scheduler = queries.iter().find_map(|(_, match_opts)| match_opts.scheduler.clone());
+    priority::run(batch_priority, scheduler.as_ref(), move || coalesce_batch_unscheduled(queries))
+}
+
+fn coalesce_batch_unscheduled<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    queries: Vec<(Vec<PhrasematchSubquery<T>>, MatchOpts)>,
+) -> Vec<Result<Vec<CoalesceContext>, Error>> {
+    let fetched = fetch_grids(queries.iter().map(|(stack, match_opts)| (stack, match_opts)));
+
+    queries
+        .into_par_iter()
+        .map(|(stack, match_opts)| coalesce_with_fetched(stack, &match_opts, Some(&fetched)))
+        .collect()
+}
+
+/// Same as `coalesce`, but the grids of all of a multi-subquery stack's subqueries are fetched in
+/// parallel up front, instead of one subquery after another as they're stacked. That gives up
+/// stopping early on the last subquery's grids, so it pays off for long stacks against slow
+/// stores rather than across the board.
+#[cfg(feature = "parallel")]
+pub fn coalesce_parallel<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<Vec<CoalesceContext>, Error> {
+    priority::run(match_opts.coalesce.priority, match_opts.scheduler.as_ref(), || {
+        let fetched = fetch_grids(std::iter::once((&stack, match_opts)));
+        coalesce_with_fetched(stack, match_opts, Some(&fetched))
+    })
+}
+
+/// Fetches the grids of every subquery of the multi-subquery stacks among `stacks` in parallel,
+/// looking up each distinct store, key and match options only once
+
+fn fetch_grids<'a, T, I>(stacks: I) -> FetchedGrids
+where
+    T: Borrow<GridStore> + Clone + Debug + Send + Sync + 'a,
+    I: Iterator<Item = (&'a Vec<PhrasematchSubquery<T>>, &'a MatchOpts)>,
+{
+    let mut lookups: HashMap<LookupKey, (&PhrasematchSubquery<T>, MatchOpts)> = HashMap::new();
+    for (stack, match_opts) in stacks.filter(|(stack, _)| stack.len() > 1) {
+        // split as the queries will be, so the lookups match theirs
+        let split = match_opts.split_antimeridian();
+        let match_opts = split.as_ref().unwrap_or(match_opts);
+        for subquery in stack {
+            let zoom = subquery.store.borrow().zoom;
+            let subquery_opts = subquery.override_bbox(&match_opts.adjust_to_zoom(zoom));
+            lookups
+                .entry(lookup_key(subquery, &subquery_opts))
+                .or_insert((subquery, subquery_opts));
+        }
+    }
+    lookups
+        .into_par_iter()
+        .filter_map(|(key, (subquery, match_opts))| {
+            // failed lookups are left to the queries that need them to retry, so that the error
+            // ends up in their results
+            let store = subquery.store.borrow();
+            let max_grids = store.limits().max_grids_per_phrase;
+            let grids = store.streaming_get_matching(&key.key, &match_opts, max_grids).ok()?;
+            Some((key, grids.take(max_grids).collect()))
+        })
+        .collect()
+}
+
+fn coalesce_with_fetched<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+    fetched: Option<&FetchedGrids>,
+) -> Result<Vec<CoalesceContext>, Error> {
+    let mut budget = Budget::new(&match_opts.coalesce);
+    coalesce_pruning(stack, match_opts, fetched, None, &mut budget, &mut CoalesceStats::default())
+}
+
+/// Does the work of `coalesce_with_fetched`, adding the contexts that were found but not returned
+/// to `pruned` if it's given, and the subqueries skipped under `CoalesceOpts::partial_ok` to
+/// `stats`
+fn coalesce_pruning<T: Borrow<GridStore> + Clone + Debug>(
+    stack: Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+    fetched: Option<&FetchedGrids>,
+    mut pruned: Option<&mut Vec<PrunedContext>>,
+    budget: &mut Budget,
+    stats: &mut CoalesceStats,
+) -> Result<Vec<CoalesceContext>, Error> {
+    let split = match_opts.split_antimeridian();
+    let match_opts = split.as_ref().unwrap_or(match_opts);
+    let gate = match_opts.coalesce.relevance_gate.gap(stack.len());
+    let stack_len = stack.len();
+    if stack_len < match_opts.coalesce.min_entries_per_context {
+        return Ok(Vec::new());
+    }
     let contexts = if stack.len() <= 1 {
-        coalesce_single(&stack[0], match_opts)?
+        coalesce_single(&stack[0], match_opts, pruned.as_deref_mut(), budget, stats)?
     } else {
-        coalesce_multi(stack, match_opts)?
+        coalesce_multi(stack, match_opts, fetched, budget, stats)?
     };
 
-    let mut out = Vec::with_capacity(MAX_CONTEXTS);
+    // multi-subquery stacks are always deduplicated by feature
+    let dedup = if stack_len <= 1 { match_opts.coalesce.dedup } else { DedupKey::Id };
+    let contexts = match dedup {
+        DedupKey::Id => dedup_contexts(contexts, match_opts, pruned.as_deref_mut()),
+        DedupKey::IdAndLanguage | DedupKey::None => contexts,
+    };
+    let max_contexts = match_opts
+        .coalesce
+        .max_contexts
+        .map_or(MAX_CONTEXTS, |max| std::cmp::min(max, MAX_CONTEXTS));
+    let mut out = Vec::with_capacity(max_contexts);
     if !contexts.is_empty() {
         let max_relevance = contexts[0].relev;
-        let mut sets: HashSet<u64> = HashSet::new();
+        let mut sets: HashSet<(u32, usize)> = HashSet::new();
         for context in contexts {
-            if out.len() >= MAX_CONTEXTS {
-                break;
-            }
-            // 0.25 is the smallest allowed relevance
-            if max_relevance - context.relev >= 0.25 {
-                break;
-            }
-            let inserted = sets.insert(context.entries[0].tmp_id.into());
-            if inserted {
+            let rule = if max_relevance - context.relev >= gate {
+                PruneRule::RelevanceGate
+            } else if out.len() >= max_contexts {
+                PruneRule::MaxContexts
+            } else if sets.insert(dedup_key(&context.entries[0], dedup, out.len())) {
                 out.push(context);
+                continue;
+            } else {
+                PruneRule::Duplicate
+            };
+            // contexts are sorted, so once one is gated or over the limit all the rest are too
+            match pruned.as_mut() {
+                Some(pruned) => pruned.push(PrunedContext { context, rule }),
+                None if rule == PruneRule::Duplicate => {}
+                None => break,
             }
         }
     }
+    assign_confidence(&mut out, match_opts);
     Ok(out)
 }
 
+/// Gives each of a list of results, best first, a confidence from 0 to 1 that it's the one the
+/// query was after. That's its relevance, which is how much of the query it covers, scaled by how
+/// far ahead of the best other result it is: mostly by relevance, where being a relevance gate
+/// ahead is certain and a gate behind is hopeless, and partly by scoredist, which is what breaks
+/// relevance ties. A result with nothing to compete with is as confident as its coverage.
+fn assign_confidence(contexts: &mut [CoalesceContext], match_opts: &MatchOpts) {
+    let longest = contexts.iter().map(|context| context.entries.len()).max().unwrap_or(1);
+    let gate = match_opts.coalesce.relevance_gate.gap(longest);
+    let ranked: Vec<(f64, f64)> =
+        contexts.iter().map(|context| (context.relev, context.entries[0].scoredist)).collect();
+    for (i, context) in contexts.iter_mut().enumerate() {
+        let coverage = context.relev.clamp(0., 1.);
+        let runner_up = if i == 0 { ranked.get(1) } else { ranked.first() };
+        context.confidence = match runner_up {
+            Some((other_relev, other_scoredist)) => {
+                let scoredist = context.entries[0].scoredist;
+                let relev_lead = ((context.relev - other_relev) / gate).clamp(-1., 1.);
+                let scale = scoredist.abs().max(other_scoredist.abs()).max(1.);
+                let scoredist_lead = ((scoredist - other_scoredist) / scale).clamp(-1., 1.);
+                coverage * (0.75 * (relev_lead + 1.) / 2. + 0.25 * (scoredist_lead + 1.) / 2.)
+            }
+            None => coverage,
+        };
+    }
+}
+
+/// With `stable_tiebreak` or `tie_jitter` set, contexts that tie on relevance and scoredist are
+/// ordered by a hash of their feature id rather than by position
+#[inline]
+fn tiebreak(context: &CoalesceContext, match_opts: &MatchOpts) -> u32 {
+    let id = context.entries[0].grid_entry.id;
+    match match_opts.tie_jitter {
+        Some(seed) => seeded_id_hash(id, seed),
+        None if match_opts.stable_tiebreak => stable_id_hash(id),
+        None => 0,
+    }
+}
+
+/// The order results are returned in, best first once reversed: relevance, scoredist, zoom when
+/// `prefer_higher_zoom` is set, index, the tie-break hash, and position and id last so that no two
+/// distinct features compare equal
+type RankKey = (OrderedFloat<f64>, OrderedFloat<f64>, u16, Reverse<u16>, u32, u16, u16, u32);
+
+#[inline]
+fn rank_key(context: &CoalesceContext, match_opts: &MatchOpts) -> RankKey {
+    let entry = &context.entries[0];
+    (
+        OrderedFloat(context.relev),
+        OrderedFloat(entry.scoredist),
+        if match_opts.prefer_higher_zoom { entry.zoom } else { 0 },
+        Reverse(entry.idx),
+        tiebreak(context, match_opts),
+        entry.grid_entry.x,
+        entry.grid_entry.y,
+        entry.grid_entry.id,
+    )
+}
+
+/// What tree coalesce keeps of each result it finds, and so what it returns
+trait QueuedResult: Send {
+    /// A context that's returned as is
+    fn finished(context: CoalesceContext) -> Self;
+    /// A context that's still being stacked on, returned with relevance `relev`
+    fn stacked(context: &CoalesceContext, relev: f64) -> Self;
+    /// The mask and tmp ids of the stacked features, which the language variants of a result
+    /// share
+    fn features(&self) -> (u32, Vec<u32>);
+    /// How many of the stacked entries match the query's languages
+    fn language_matches(&self) -> usize;
+}
+
+impl QueuedResult for CoalesceContext {
+    fn finished(context: CoalesceContext) -> Self {
+        context
+    }
+
+    fn stacked(context: &CoalesceContext, relev: f64) -> Self {
+        CoalesceContext { relev, ..context.clone() }
+    }
+
+    fn features(&self) -> (u32, Vec<u32>) {
+        (self.mask, self.entries.iter().map(|entry| entry.tmp_id).collect())
+    }
+
+    fn language_matches(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.matches_language).count()
+    }
+}
+
+/// A result of `stack_and_coalesce_compact`, with what deduplicating it takes
+struct CompactResult {
+    context: CompactCoalesceContext,
+    features: (u32, Vec<u32>),
+    language_matches: usize,
+}
+
+impl CompactResult {
+    fn new(context: &CoalesceContext, relev: f64) -> Self {
+        CompactResult {
+            context: CompactCoalesceContext { relev, ..CompactCoalesceContext::from(context) },
+            features: context.features(),
+            language_matches: context.language_matches(),
+        }
+    }
+}
+
+impl QueuedResult for CompactResult {
+    fn finished(context: CoalesceContext) -> Self {
+        CompactResult::new(&context, context.relev)
+    }
+
+    fn stacked(context: &CoalesceContext, relev: f64) -> Self {
+        CompactResult::new(context, relev)
+    }
+
+    fn features(&self) -> (u32, Vec<u32>) {
+        self.features.clone()
+    }
+
+    fn language_matches(&self) -> usize {
+        self.language_matches
+    }
+}
+
+/// A result ordered by its `rank_key`, so that bounded queues of them evict the same results
+/// the final sort would drop, whatever order tied results arrive in
+struct RankedContext<R> {
+    key: RankKey,
+    result: R,
+}
+
+impl<R: QueuedResult> RankedContext<R> {
+    #[inline]
+    fn finished(context: CoalesceContext, match_opts: &MatchOpts) -> Self {
+        RankedContext { key: rank_key(&context, match_opts), result: R::finished(context) }
+    }
+
+    /// A stacked context as a result, less the penalty some multi-subquery results take
+    #[inline]
+    fn stacked(context: &CoalesceContext, match_opts: &MatchOpts) -> Self {
+        let relev = context.relev - multi_context_penalty(context);
+        let mut key = rank_key(context, match_opts);
+        key.0 = OrderedFloat(relev);
+        RankedContext { key, result: R::stacked(context, relev) }
+    }
+
+    #[inline]
+    fn relev(&self) -> f64 {
+        self.key.0.into_inner()
+    }
+}
+
+impl<R> Ord for RankedContext<R> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+impl<R> PartialOrd for RankedContext<R> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<R> PartialEq for RankedContext<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl<R> Eq for RankedContext<R> {}
+
+/// The key results are deduplicated on; `seq` is the result's position, which makes every result
+/// distinct when deduplication is off
+#[inline]
+fn dedup_key(entry: &CoalesceEntry, dedup: DedupKey, seq: usize) -> (u32, usize) {
+    match dedup {
+        DedupKey::Id => (entry.tmp_id, 0),
+        DedupKey::IdAndLanguage => (entry.tmp_id, entry.matches_language as usize),
+        DedupKey::None => (entry.tmp_id, seq + 1),
+    }
+}
+
+/// The entry for `grid`, found with `match_opts` adjusted to the zoom of its store, in a query at
+/// `query_zoom`
 fn grid_to_coalesce_entry<T: Borrow<GridStore> + Clone>(
     grid: &MatchEntry,
     subquery: &PhrasematchSubquery<T>,
     match_opts: &MatchOpts,
+    query_zoom: u16,
     phrasematch_id: u32,
 ) -> CoalesceEntry {
     // Zoom has been adjusted in coalesce_multi, or correct zoom has been passed in for coalesce_single
     debug_assert!(match_opts.zoom == subquery.store.borrow().zoom);
-    let relevance = grid.grid_entry.relev * subquery.weight;
+    let relevance = grid.grid_entry.relev
+        * relev_multiplier(subquery, match_opts, phrasematch_id, grid.grid_entry.id);
 
-    CoalesceEntry {
+    let mut entry = CoalesceEntry {
         grid_entry: GridEntry { relev: relevance, ..grid.grid_entry },
         matches_language: grid.matches_language,
         idx: subquery.idx,
         tmp_id: ((subquery.idx as u32) << 25) + grid.grid_entry.id,
         mask: subquery.mask,
         distance: grid.distance,
-        scoredist: grid.scoredist,
+        scoredist: zoom_normalized_scoredist(grid, subquery, match_opts, query_zoom),
         phrasematch_id,
+        lonlat: match_opts.lonlat.map(|anchor| {
+            tile_lonlat(grid.grid_entry.x, grid.grid_entry.y, match_opts.zoom, anchor)
+        }),
+        zoom: match_opts.zoom,
+    };
+    entry.scoredist = match_opts.ranking().scoredist(&entry);
+    entry
+}
+
+/// What the relevance of a grid is scaled by to give the relevance of its entry: the subquery's
+/// weight, and any override for it in `MatchOpts::relev_overrides`
+fn relev_multiplier<T: Borrow<GridStore> + Clone>(
+    subquery: &PhrasematchSubquery<T>,
+    match_opts: &MatchOpts,
+    phrasematch_id: u32,
+    id: u32,
+) -> f64 {
+    let mut multiplier = subquery.weight;
+    if let Some(overrides) = &match_opts.relev_overrides {
+        let phrase_id = subquery.match_keys.iter().find(|key| key.id == phrasematch_id).and_then(
+            |key| match key.key.match_phrase {
+                MatchPhrase::Exact(phrase_id) => Some(phrase_id),
+                MatchPhrase::Range { .. } => None,
+            },
+        );
+        multiplier *= overrides.multiplier(subquery.idx, phrase_id, id);
+    }
+    multiplier
+}
+
+/// The scoredist of `grid`, which the store computed on tiles at its own zoom. A stack can have
+/// stores at different zooms, so grids from stores at any other zoom than the query's are scored
+/// on their distance in tiles at the query's zoom instead, in every coalesce strategy alike.
+fn zoom_normalized_scoredist<T: Borrow<GridStore> + Clone>(
+    grid: &MatchEntry,
+    subquery: &PhrasematchSubquery<T>,
+    match_opts: &MatchOpts,
+    query_zoom: u16,
+) -> f64 {
+    let store = subquery.store.borrow();
+    if match_opts.proximity.is_none() || store.zoom == query_zoom {
+        return grid.scoredist;
+    }
+    let scoredist = scoring::zoom_normalized_scoredist(
+        grid.grid_entry.score,
+        grid.distance,
+        store.zoom,
+        query_zoom,
+        &ScoredistOpts::new(store.coalesce_radius, match_opts),
+    );
+    match (grid.matches_language, match_opts.language_boost) {
+        (true, Some(boost)) => boost.apply(scoredist),
+        _ => scoredist,
     }
 }
 
 fn coalesce_single<T: Borrow<GridStore> + Clone>(
     subquery: &PhrasematchSubquery<T>,
     match_opts: &MatchOpts,
+    mut pruned: Option<&mut Vec<PrunedContext>>,
+    budget: &mut Budget,
+    stats: &mut CoalesceStats,
 ) -> Result<Vec<CoalesceContext>, Error> {
+    let ranking = match_opts.ranking();
+    let to_context = |entry: CoalesceEntry| CoalesceContext {
+        mask: entry.mask,
+        relev: ranking.context_relevance(std::slice::from_ref(&entry), entry.grid_entry.relev),
+        confidence: 0.,
+        entries: vec![entry],
+    };
+    let mut prune = |entry: &CoalesceEntry, rule: PruneRule| {
+        if let Some(pruned) = pruned.as_mut() {
+            pruned.push(PrunedContext { context: to_context(entry.clone()), rule });
+        }
+    };
+
     let bigger_max = 2 * MAX_CONTEXTS;
+    let match_opts = &subquery.override_bbox(match_opts);
+    let gate = match_opts.coalesce.relevance_gate.gap(1);
 
-    let grids = subquery.store.borrow().streaming_get_matching(
+    let grids = match subquery.store.borrow().streaming_get_matching(
         &subquery.match_keys[0].key,
         match_opts,
         bigger_max,
-    )?;
+    ) {
+        Ok(grids) => grids,
+        Err(err) if match_opts.coalesce.partial_ok => {
+            stats.failed_match_keys.insert(subquery.match_keys[0].id, err.to_string());
+            return Ok(Vec::new());
+        }
+        Err(err) => return Err(err),
+    };
     let mut max_relevance: f64 = 0.;
     let mut previous_id: u32 = 0;
+    let mut previous_key: (u32, usize) = (0, 0);
     let mut previous_relevance: f64 = 0.;
     let mut previous_scoredist: f64 = 0.;
     let mut min_scoredist = std::f64::MAX;
     let mut feature_count: usize = 0;
 
-    let mut coalesced: HashMap<u32, CoalesceEntry> = HashMap::new();
+    let mut coalesced: HashMap<(u32, usize), CoalesceEntry> = HashMap::new();
+
+    for (seq, grid) in grids.enumerate() {
+        if !budget.spend() {
+            break;
+        }
+        let coalesce_entry =
+            grid_to_coalesce_entry(&grid, subquery, match_opts, match_opts.zoom, 0);
 
-    for grid in grids {
-        let coalesce_entry = grid_to_coalesce_entry(&grid, subquery, match_opts, 0);
+        let current_key = dedup_key(&coalesce_entry, match_opts.coalesce.dedup, seq);
 
         // If it's the same feature as the last one, but a lower scoredist don't add it
-        if previous_id == coalesce_entry.grid_entry.id
-            && coalesce_entry.scoredist <= previous_scoredist
+        if previous_key == current_key && coalesce_entry.scoredist <= previous_scoredist {
+            prune(&coalesce_entry, PruneRule::Duplicate);
+            continue;
+        }
+        // grids the caller leaves out don't count towards the gate or any of the limits
+        if match_opts.context_filter.is_some()
+            && !match_opts.keeps_context(&to_context(coalesce_entry.clone()))
         {
+            prune(&coalesce_entry, PruneRule::Filtered);
             continue;
         }
 
@@ -114,9 +860,19 @@ fn coalesce_single<T: Borrow<GridStore> + Clone>(
             }
         }
 
-        if max_relevance - coalesce_entry.grid_entry.relev >= 0.25 {
+        if max_relevance - coalesce_entry.grid_entry.relev >= gate {
+            prune(&coalesce_entry, PruneRule::RelevanceGate);
             break;
         }
+        // everything kept so far is more relevant than this grid and the ones after it, so if
+        // there's enough of it, the results that'll be returned are settled
+        if let Some(max_contexts) = match_opts.coalesce.max_contexts {
+            if coalesce_entry.grid_entry.relev < previous_relevance
+                && coalesced.len() >= max_contexts
+            {
+                break;
+            }
+        }
         if coalesce_entry.grid_entry.relev > max_relevance {
             max_relevance = coalesce_entry.grid_entry.relev;
         }
@@ -127,12 +883,15 @@ fn coalesce_single<T: Borrow<GridStore> + Clone>(
         let current_scoredist = coalesce_entry.scoredist;
 
         // If it's the same feature as one that's been added before, but a higher scoredist, update the entry
-        match coalesced.entry(current_id) {
+        match coalesced.entry(current_key) {
             Entry::Occupied(mut already_coalesced) => {
                 if current_scoredist > already_coalesced.get().scoredist
                     && current_relev >= already_coalesced.get().grid_entry.relev
                 {
-                    already_coalesced.insert(coalesce_entry);
+                    let replaced = already_coalesced.insert(coalesce_entry);
+                    prune(&replaced, PruneRule::Duplicate);
+                } else {
+                    prune(&coalesce_entry, PruneRule::Duplicate);
                 }
             }
             Entry::Vacant(entry) => {
@@ -150,29 +909,35 @@ fn coalesce_single<T: Borrow<GridStore> + Clone>(
             min_scoredist = current_scoredist;
         }
         previous_id = current_id;
+        previous_key = current_key;
         previous_relevance = current_relev;
         previous_scoredist = current_scoredist;
     }
 
-    let mut contexts: Vec<CoalesceContext> = coalesced
-        .iter()
-        .map(|(_, entry)| CoalesceContext {
-            entries: vec![entry.clone()],
-            mask: entry.mask,
-            relev: entry.grid_entry.relev,
-        })
-        .collect();
+    // go through entries in key order rather than the map's, so contexts the sort can't tell
+    // apart come out in the same order every time
+    let mut coalesced: Vec<((u32, usize), CoalesceEntry)> = coalesced.into_iter().collect();
+    coalesced.sort_by_key(|(key, _)| *key);
+    let mut contexts: Vec<CoalesceContext> =
+        coalesced.into_iter().map(|(_, entry)| to_context(entry)).collect();
 
     contexts.sort_by_key(|context| {
         Reverse((
             OrderedFloat(context.relev),
             OrderedFloat(context.entries[0].scoredist),
+            tiebreak(context, match_opts),
             context.entries[0].grid_entry.x,
             context.entries[0].grid_entry.y,
             context.entries[0].grid_entry.id,
         ))
     });
 
+    if let Some(pruned) = pruned {
+        pruned.extend(contexts.iter().skip(MAX_CONTEXTS).map(|context| PrunedContext {
+            context: context.clone(),
+            rule: PruneRule::MaxContexts,
+        }));
+    }
     contexts.truncate(MAX_CONTEXTS);
     Ok(contexts)
 }
@@ -180,17 +945,47 @@ fn coalesce_single<T: Borrow<GridStore> + Clone>(
 fn coalesce_multi<T: Borrow<GridStore> + Clone>(
     mut stack: Vec<PhrasematchSubquery<T>>,
     match_opts: &MatchOpts,
+    fetched: Option<&FetchedGrids>,
+    budget: &mut Budget,
+    stats: &mut CoalesceStats,
 ) -> Result<Vec<CoalesceContext>, Error> {
     stack.sort_by_key(|subquery| (subquery.store.borrow().zoom, subquery.idx));
+    let gate = match_opts.coalesce.relevance_gate.gap(stack.len());
+
+    // probing ranks and cuts down contexts itself, so score policies and context filters are left
+    // to the hash join
+    let probe = match_opts.join_strategy == JoinStrategy::DocumentAtATime
+        && match_opts.score_policy.is_none()
+        && match_opts.context_filter.is_none();
+    if probe && stack.len() == 2 {
+        match coalesce_pair_by_probing(&stack[0], &stack[1], match_opts) {
+            Ok(Some(contexts)) => return Ok(contexts),
+            Ok(None) => {}
+            // the hash join below finds which of the stores failed and leaves it out
+            Err(_) if match_opts.coalesce.partial_ok => {}
+            Err(err) => return Err(err),
+        }
+    }
 
     let mut coalesced: HashMap<(u16, u16, u16), Vec<CoalesceContext>> = HashMap::new();
     let mut contexts: Vec<CoalesceContext> = Vec::new();
 
     let mut max_relevance: f64 = 0.;
+    let min_entries = match_opts.coalesce.min_entries_per_context;
+    // the most relevant entry seen for each subquery, to bound how relevant a context can get
+    let mut best_relevance: Vec<f64> = vec![0.; stack.len()];
+    let mut memory_used: usize = 0;
+    let memory_limit =
+        match_opts.coalesce.max_memory_bytes.or(Settings::global().load().max_memory_bytes);
 
     let mut zoom_adjusted_match_options = match_opts.clone();
 
     for (i, subquery) in stack.iter().enumerate() {
+        match_opts.check_cancelled()?;
+        // the contexts made so far are still returned
+        if budget.is_spent() {
+            break;
+        }
         let mut to_add_to_coalesced: HashMap<(u16, u16, u16), Vec<CoalesceContext>> =
             HashMap::new();
         let compatible_zooms: Vec<u16> = stack
@@ -211,15 +1006,76 @@ fn coalesce_multi<T: Borrow<GridStore> + Clone>(
             zoom_adjusted_match_options = match_opts.adjust_to_zoom(subquery.store.borrow().zoom);
         }
 
-        let grids = subquery.store.borrow().streaming_get_matching(
-            &subquery.match_keys[0].key,
-            &zoom_adjusted_match_options,
-            MAX_GRIDS_PER_PHRASE,
-        )?;
+        let subquery_match_options = subquery.override_bbox(&zoom_adjusted_match_options);
+
+        let already_fetched =
+            fetched.and_then(|fetched| fetched.get(&lookup_key(subquery, &subquery_match_options)));
+        let grids = match already_fetched {
+            Some(grids) => Either::Left(grids.iter().cloned()),
+            None => {
+                let store = subquery.store.borrow();
+                let max_grids = store.limits().max_grids_per_phrase;
+                match store.streaming_get_matching(
+                    &subquery.match_keys[0].key,
+                    &subquery_match_options,
+                    max_grids,
+                ) {
+                    Ok(grids) => Either::Right(grids.take(max_grids)),
+                    Err(err) if match_opts.coalesce.partial_ok => {
+                        // the contexts the subquery would have been part of still come back,
+                        // less relevant, from the rest of the stack
+                        stats.failed_match_keys.insert(subquery.match_keys[0].id, err.to_string());
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        };
+
+        // Grids come in descending relevance, so once the last subquery's grids can't make a
+        // context relevant enough to get past the gate, none of the rest can either. Overrides
+        // can reorder relevance per feature, and score policies can compute it some other way,
+        // so they rule this out.
+        let can_stop_early = i == stack.len() - 1
+            && match_opts.relev_overrides.is_none()
+            && match_opts.score_policy.is_none();
+        let others_relevance: f64 = best_relevance[..i].iter().sum();
+        let mut tier_relevance = f64::MAX;
 
-        for grid in grids.take(MAX_GRIDS_PER_PHRASE) {
-            let coalesce_entry =
-                grid_to_coalesce_entry(&grid, subquery, &zoom_adjusted_match_options, 0);
+        for grid in grids {
+            match_opts.check_cancelled()?;
+            if !budget.spend() {
+                break;
+            }
+            let coalesce_entry = grid_to_coalesce_entry(
+                &grid,
+                subquery,
+                &subquery_match_options,
+                match_opts.zoom,
+                0,
+            );
+
+            let entry_relevance = coalesce_entry.grid_entry.relev;
+            if can_stop_early && max_relevance - (entry_relevance + others_relevance) >= gate {
+                stats.saturated_subqueries += 1;
+                break;
+            }
+            // likewise, once enough results are more relevant than any context this grid or the
+            // ones after it could make, they're settled, unless the caller might still leave some
+            // of them out
+            let can_settle = can_stop_early && match_opts.context_filter.is_none();
+            if let (true, Some(max_contexts)) = (can_settle, match_opts.coalesce.max_contexts) {
+                if entry_relevance < tier_relevance {
+                    let bound = entry_relevance + others_relevance;
+                    if settled_count(&contexts, &coalesced, bound, min_entries) >= max_contexts {
+                        break;
+                    }
+                    tier_relevance = entry_relevance;
+                }
+            }
+            if entry_relevance > best_relevance[i] {
+                best_relevance[i] = entry_relevance;
+            }
 
             let zxy = (subquery.store.borrow().zoom, grid.grid_entry.x, grid.grid_entry.y);
 
@@ -268,42 +1124,62 @@ fn coalesce_multi<T: Borrow<GridStore> + Clone>(
                     }
                 }
             }
-            if context_relevance > max_relevance {
+            context_relevance = match_opts.ranking().context_relevance(&entries, context_relevance);
+            let mut context = CoalesceContext {
+                entries,
+                mask: context_mask,
+                relev: context_relevance,
+                confidence: 0.,
+            };
+            // contexts too short to be returned, or that the caller leaves out, don't get to set
+            // the bar for the rest
+            let returnable =
+                context.entries.len() >= min_entries && match_opts.keeps_context(&context);
+            if context_relevance > max_relevance && returnable {
                 max_relevance = context_relevance;
             }
 
             if i == (stack.len() - 1) {
-                if entries.len() == 1 {
+                if context.entries.len() == 1 {
                     // Slightly penalize contexts that have no stacking
-                    context_relevance -= 0.01;
-                } else if entries[0].mask > entries[1].mask {
+                    context.relev -= 0.01;
+                } else if context.entries[0].mask > context.entries[1].mask {
                     // Slightly penalize contexts in ascending order
-                    context_relevance -= 0.01
+                    context.relev -= 0.01
                 }
 
-                if max_relevance - context_relevance < 0.25 {
-                    contexts.push(CoalesceContext {
-                        entries,
-                        mask: context_mask,
-                        relev: context_relevance,
-                    });
+                if max_relevance - context.relev < gate && returnable {
+                    memory_used += context_bytes(&context);
+                    contexts.push(context);
                 }
-            } else if i == 0 || entries.len() > 1 {
+            } else if i == 0 || context.entries.len() > 1 {
+                memory_used += context_bytes(&context);
                 if let Some(already_coalesced) = to_add_to_coalesced.get_mut(&zxy) {
-                    already_coalesced.push(CoalesceContext {
-                        entries,
-                        mask: context_mask,
-                        relev: context_relevance,
-                    });
+                    already_coalesced.push(context);
                 } else {
-                    to_add_to_coalesced.insert(
-                        zxy,
-                        vec![CoalesceContext {
-                            entries,
-                            mask: context_mask,
-                            relev: context_relevance,
-                        }],
-                    );
+                    memory_used += TILE_BYTES;
+                    to_add_to_coalesced.insert(zxy, vec![context]);
+                }
+            }
+
+            if let Some(limit) = memory_limit {
+                if memory_used > limit {
+                    if match_opts.coalesce.on_memory_limit == MemoryLimitAction::Spill {
+                        memory_used = spill_contexts(
+                            &mut coalesced,
+                            &mut to_add_to_coalesced,
+                            &mut contexts,
+                            max_relevance,
+                            gate,
+                        );
+                    }
+                    if memory_used > limit {
+                        return Err(CoalesceError::MemoryLimitExceeded {
+                            used: memory_used,
+                            limit,
+                        }
+                        .into());
+                    }
                 }
             }
         }
@@ -316,32 +1192,273 @@ fn coalesce_multi<T: Borrow<GridStore> + Clone>(
         }
     }
 
+    // go through tiles in order rather than the map's, so contexts the sort can't tell apart come
+    // out in the same order every time
+    let mut coalesced: Vec<((u16, u16, u16), Vec<CoalesceContext>)> =
+        coalesced.into_iter().collect();
+    coalesced.sort_by_key(|(zxy, _)| *zxy);
     for (_, matched) in coalesced {
         for context in matched {
-            if max_relevance - context.relev < 0.25 {
+            if max_relevance - context.relev < gate
+                && context.entries.len() >= min_entries
+                && match_opts.keeps_context(&context)
+            {
                 contexts.push(context);
             }
         }
     }
 
-    contexts.sort_by_key(|context| {
-        (
-            Reverse(OrderedFloat(context.relev)),
-            Reverse(OrderedFloat(context.entries[0].scoredist)),
-            context.entries[0].idx,
-            Reverse(context.entries[0].grid_entry.x),
-            Reverse(context.entries[0].grid_entry.y),
-            Reverse(context.entries[0].grid_entry.id),
-        )
-    });
+    sort_stable_tiebreak(&mut contexts, match_opts);
 
     Ok(contexts)
 }
 
-struct TreeCoalesceState {
-    contexts: Vec<CoalesceContext>,
-    bush: KDBush<u16>,
-}
+/// How many distinct features lead contexts that are more relevant than `bound`, among the
+/// finished contexts and the intermediate ones that are returned as they are
+fn settled_count(
+    contexts: &[CoalesceContext],
+    coalesced: &HashMap<(u16, u16, u16), Vec<CoalesceContext>>,
+    bound: f64,
+    min_entries: usize,
+) -> usize {
+    let intermediate = coalesced.values().flatten().filter(|c| c.entries.len() >= min_entries);
+    let settled: HashSet<u32> = contexts
+        .iter()
+        .chain(intermediate)
+        .filter(|context| context.relev > bound)
+        .map(|context| context.entries[0].tmp_id)
+        .collect();
+    settled.len()
+}
+
+/// Approximate bytes a context takes up in coalesce's intermediate results
+fn context_bytes(context: &CoalesceContext) -> usize {
+    mem::size_of::<CoalesceContext>() + context.entries.capacity() * mem::size_of::<CoalesceEntry>()
+}
+
+/// Approximate bytes each tile with contexts takes up in coalesce's map of them
+const TILE_BYTES: usize = mem::size_of::<((u16, u16, u16), Vec<CoalesceContext>)>();
+
+/// Shrinks the intermediate results of `coalesce_multi` once they've outgrown the memory limit:
+/// drops contexts that are already too irrelevant to be returned, then coarsens what's left to the
+/// most relevant context per tile. Returns the approximate bytes still in use.
+fn spill_contexts(
+    coalesced: &mut HashMap<(u16, u16, u16), Vec<CoalesceContext>>,
+    to_add_to_coalesced: &mut HashMap<(u16, u16, u16), Vec<CoalesceContext>>,
+    contexts: &mut Vec<CoalesceContext>,
+    max_relevance: f64,
+    gate: f64,
+) -> usize {
+    // contexts only ever get less relevant relative to the max, so this loses nothing
+    contexts.retain(|context| max_relevance - context.relev < gate);
+
+    let mut best_by_tile: Vec<CoalesceContext> = Vec::new();
+    let mut positions: HashMap<(u16, u16), usize> = HashMap::new();
+    for context in contexts.drain(..) {
+        let tile = (context.entries[0].grid_entry.x, context.entries[0].grid_entry.y);
+        match positions.entry(tile) {
+            Entry::Occupied(position) => {
+                let best = &mut best_by_tile[*position.get()];
+                if context.relev > best.relev {
+                    *best = context;
+                }
+            }
+            Entry::Vacant(position) => {
+                position.insert(best_by_tile.len());
+                best_by_tile.push(context);
+            }
+        }
+    }
+    *contexts = best_by_tile;
+    let mut memory_used: usize = contexts.iter().map(context_bytes).sum();
+
+    for map in [coalesced, to_add_to_coalesced].iter_mut() {
+        for tile_contexts in map.values_mut() {
+            if let Some(best) =
+                tile_contexts.drain(..).max_by_key(|context| OrderedFloat(context.relev))
+            {
+                tile_contexts.push(best);
+            }
+            tile_contexts.shrink_to_fit();
+            memory_used += TILE_BYTES + tile_contexts.iter().map(context_bytes).sum::<usize>();
+        }
+    }
+    memory_used
+}
+
+/// Most grids the selective subquery of a pair can have for `JoinStrategy::DocumentAtATime` to
+/// probe for each of them rather than falling back to a hash join, unless it's changed with
+/// `Limits::probe_max_candidates`
+pub const PROBE_MAX_CANDIDATES: usize = 32;
+
+/// The document-at-a-time join for a parent subquery and a child subquery at the same or a higher
+/// zoom: takes the grids of whichever has at most `Limits::probe_max_candidates` of them and probes the
+/// other's store for grids in the same tiles, producing the same contexts `coalesce` would.
+/// Returns `None` if neither subquery is selective enough, or if the other subquery's unstacked
+/// grids could be relevant enough to be returned, in which case they'd all have to be fetched.
+fn coalesce_pair_by_probing<T: Borrow<GridStore> + Clone>(
+    parent: &PhrasematchSubquery<T>,
+    child: &PhrasematchSubquery<T>,
+    match_opts: &MatchOpts,
+) -> Result<Option<Vec<CoalesceContext>>, Error> {
+    if parent.idx == child.idx || parent.mask & child.mask != 0 {
+        return Ok(None);
+    }
+    let parent_opts = parent.override_bbox(&match_opts.adjust_to_zoom(parent.store.borrow().zoom));
+    let child_opts = child.override_bbox(&match_opts.adjust_to_zoom(child.store.borrow().zoom));
+    let scale_factor: u16 = 1 << (child.store.borrow().zoom - parent.store.borrow().zoom);
+    let gate = match_opts.coalesce.relevance_gate.gap(2);
+    let probe_max_candidates = Settings::global().load().probe_max_candidates;
+
+    let candidates = |subquery: &PhrasematchSubquery<T>, opts: &MatchOpts| {
+        let grids = subquery.store.borrow().streaming_get_matching(
+            &subquery.match_keys[0].key,
+            opts,
+            probe_max_candidates + 1,
+        )?;
+        Ok::<Vec<MatchEntry>, Error>(grids.take(probe_max_candidates + 1).collect())
+    };
+    let parent_grids = candidates(parent, &parent_opts)?;
+    let child_grids = candidates(child, &child_opts)?;
+    let probe_children = if parent_grids.len() <= probe_max_candidates {
+        true
+    } else if child_grids.len() <= probe_max_candidates {
+        false
+    } else {
+        return Ok(None);
+    };
+
+    let to_entry = |grid: &MatchEntry, subquery: &PhrasematchSubquery<T>, opts: &MatchOpts| {
+        grid_to_coalesce_entry(grid, subquery, opts, match_opts.zoom, 0)
+    };
+    let probe = |subquery: &PhrasematchSubquery<T>, opts: &MatchOpts, tile: [u16; 4]| {
+        let bbox = match opts.bbox {
+            Some(bbox) => [
+                std::cmp::max(bbox[0], tile[0]),
+                std::cmp::max(bbox[1], tile[1]),
+                std::cmp::min(bbox[2], tile[2]),
+                std::cmp::min(bbox[3], tile[3]),
+            ],
+            None => tile,
+        };
+        if bbox[0] > bbox[2] || bbox[1] > bbox[3] {
+            return Ok(Vec::new());
+        }
+        let probe_opts = MatchOpts { bbox: Some(bbox), ..opts.clone() };
+        let store = subquery.store.borrow();
+        let max_grids = store.limits().max_grids_per_phrase;
+        let grids =
+            store.streaming_get_matching(&subquery.match_keys[0].key, &probe_opts, max_grids)?;
+        Ok::<_, Error>(grids.take(max_grids).collect::<Vec<_>>())
+    };
+
+    // children only stack on the best parent grid in their parent's tile, if there is one
+    let mut best_parents: HashMap<(u16, u16), Option<CoalesceEntry>> = HashMap::new();
+    let mut stacked: Vec<(CoalesceEntry, (u16, u16))> = Vec::new();
+    if probe_children {
+        for grid in parent_grids.iter() {
+            let tile = (grid.grid_entry.x, grid.grid_entry.y);
+            match best_parents.entry(tile) {
+                Entry::Occupied(_) => continue,
+                Entry::Vacant(best_parent) => {
+                    best_parent.insert(Some(to_entry(grid, parent, &parent_opts)));
+                }
+            }
+            let children = probe(
+                child,
+                &child_opts,
+                [
+                    tile.0 * scale_factor,
+                    tile.1 * scale_factor,
+                    tile.0 * scale_factor + (scale_factor - 1),
+                    tile.1 * scale_factor + (scale_factor - 1),
+                ],
+            )?;
+            for child_grid in children.iter() {
+                stacked.push((to_entry(child_grid, child, &child_opts), tile));
+            }
+        }
+    } else {
+        for grid in child_grids.iter() {
+            let tile = (grid.grid_entry.x / scale_factor, grid.grid_entry.y / scale_factor);
+            let best_parent = match best_parents.entry(tile) {
+                Entry::Occupied(best_parent) => best_parent.into_mut(),
+                Entry::Vacant(best_parent) => {
+                    let parents = probe(parent, &parent_opts, [tile.0, tile.1, tile.0, tile.1])?;
+                    best_parent.insert(parents.first().map(|g| to_entry(g, parent, &parent_opts)))
+                }
+            };
+            if best_parent.is_some() {
+                stacked.push((to_entry(grid, child, &child_opts), tile));
+            }
+        }
+    }
+
+    let mut contexts: Vec<CoalesceContext> = stacked
+        .into_iter()
+        .map(|(child_entry, tile)| {
+            let parent_entry =
+                best_parents[&tile].clone().expect("only grids with a parent are stacked");
+            let mut relev = child_entry.grid_entry.relev + parent_entry.grid_entry.relev;
+            if child_entry.mask > parent_entry.mask {
+                // Slightly penalize contexts in ascending order
+                relev -= 0.01;
+            }
+            CoalesceContext {
+                mask: child_entry.mask | parent_entry.mask,
+                relev,
+                confidence: 0.,
+                entries: vec![child_entry, parent_entry],
+            }
+        })
+        .collect();
+
+    // unstacked grids are returned too, unless contexts have to stack; the selective side's are
+    // all known, and the other side's can only be skipped if even the most relevant of them
+    // wouldn't make the cut
+    let min_entries = match_opts.coalesce.min_entries_per_context;
+    let (selective, selective_penalty, other_best) = if min_entries > 1 {
+        (Vec::new(), 0., None)
+    } else if probe_children {
+        let best_child = child_grids.first().map(|g| to_entry(g, child, &child_opts));
+        (
+            parent_grids.iter().map(|g| to_entry(g, parent, &parent_opts)).collect(),
+            0.,
+            best_child.map(|e| e.grid_entry.relev - 0.01),
+        )
+    } else {
+        let best_parent = parent_grids.first().map(|g| to_entry(g, parent, &parent_opts));
+        (
+            child_grids.iter().map(|g| to_entry(g, child, &child_opts)).collect::<Vec<_>>(),
+            0.01,
+            best_parent.map(|e| e.grid_entry.relev),
+        )
+    };
+    for entry in selective {
+        contexts.push(CoalesceContext {
+            mask: entry.mask,
+            relev: entry.grid_entry.relev - selective_penalty,
+            confidence: 0.,
+            entries: vec![entry],
+        });
+    }
+
+    contexts.retain(|context| context.entries.len() >= min_entries);
+    let max_relevance = contexts.iter().map(|c| c.relev).fold(0., f64::max);
+    if let Some(other_best) = other_best {
+        if max_relevance - other_best < gate {
+            return Ok(None);
+        }
+    }
+    contexts.retain(|context| max_relevance - context.relev < gate);
+    sort_stable_tiebreak(&mut contexts, match_opts);
+    Ok(Some(contexts))
+}
+
+struct TreeCoalesceState {
+    contexts: Vec<CoalesceContext>,
+    bush: KDBush<u16>,
+}
 
 impl TreeCoalesceState {
     fn new(contexts: Vec<CoalesceContext>) -> TreeCoalesceState {
@@ -374,9 +1491,9 @@ impl<T: Borrow<GridStore> + Clone + Debug> CoalesceStep<'_, T> {
     ) -> CoalesceStep<'a, T> {
         let subquery = node.phrasematch.expect("phrasematch required");
         let match_opts = if match_opts.zoom == subquery.store.borrow().zoom {
-            match_opts.clone()
+            subquery.override_bbox(match_opts)
         } else {
-            match_opts.adjust_to_zoom(subquery.store.borrow().zoom)
+            subquery.override_bbox(&match_opts.adjust_to_zoom(subquery.store.borrow().zoom))
         };
 
         let contains_prox = if let Some(prox) = match_opts.proximity {
@@ -429,15 +1546,17 @@ struct KeyFetchStep<T: Borrow<GridStore> + Clone + Debug> {
 // this is the thing that comes out of the first phase of two-phase coalesce
 // for single coalesce, we just do everything in phase 1, whereas for multi-coalesce,
 // we only do the first part, depending what kind of node we're on, we'll return different things
-enum KeyFetchResult {
-    Single(ConstrainedPriorityQueue<CoalesceContext>),
+enum KeyFetchResult<R> {
+    Single(ConstrainedPriorityQueue<RankedContext<R>>),
     Multi((u32, Vec<MatchEntry>)),
 }
 
-fn penalize_multi_context(context: &mut CoalesceContext) {
+fn multi_context_penalty(context: &CoalesceContext) -> f64 {
     // penalize single-entry stacks and ascending stacks for... some reason?
     if context.entries.len() == 1 || context.entries[0].mask > context.entries[1].mask {
-        context.relev -= 0.01
+        0.01
+    } else {
+        0.
     }
 }
 
@@ -452,12 +1571,45 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
     stack_tree: &StackableTree<T>,
     match_opts: &MatchOpts,
 ) -> Result<Vec<CoalesceContext>, Error> {
+    Ok(tree_coalesce_reporting_failures(stack_tree, match_opts)?.0)
+}
+
+/// Same as `tree_coalesce`, but also returns the match keys that were skipped because their store
+/// returned an error (only possible with `CoalesceOpts::partial_ok`), with the error
+fn tree_coalesce_reporting_failures<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    stack_tree: &StackableTree<T>,
+    match_opts: &MatchOpts,
+) -> Result<(Vec<CoalesceContext>, BTreeMap<u32, String>), Error> {
+    let (mut contexts, failed_keys) =
+        priority::run(match_opts.coalesce.priority, match_opts.scheduler.as_ref(), || {
+            tree_coalesce_unscheduled::<_, CoalesceContext>(stack_tree, match_opts)
+        })?;
+    #[cfg(feature = "relev-fuzz")]
+    assert!(
+        ordering_survives_relev_fuzz(&contexts, match_opts),
+        "result order depends on float noise in relevances"
+    );
+    assign_confidence(&mut contexts, match_opts);
+    Ok((contexts, failed_keys))
+}
+
+fn tree_coalesce_unscheduled<T, R>(
+    stack_tree: &StackableTree<T>,
+    match_opts: &MatchOpts,
+) -> Result<(Vec<R>, BTreeMap<u32, String>), Error>
+where
+    T: Borrow<GridStore> + Clone + Debug + Send + Sync,
+    R: QueuedResult,
+{
     debug_assert!(stack_tree.root.phrasematch.is_none(), "no phrasematch on root node");
+    let split = match_opts.split_antimeridian();
+    let match_opts = split.as_ref().unwrap_or(match_opts);
 
-    let mut contexts: ConstrainedPriorityQueue<CoalesceContext> =
+    let mut contexts: ConstrainedPriorityQueue<RankedContext<R>> =
         ConstrainedPriorityQueue::new(MAX_CONTEXTS * 20);
     let mut steps: MinMaxHeap<CoalesceStep<T>> = MinMaxHeap::new();
     let mut data_cache: HashMap<u32, Vec<MatchEntry>> = HashMap::new();
+    let mut failed_keys: BTreeMap<u32, String> = BTreeMap::new();
 
     let mut one_letter_range_count: usize = 0;
     let mut one_word_range_count: usize = 0;
@@ -477,6 +1629,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
         }
     }
 
+    let min_entries = match_opts.coalesce.min_entries_per_context;
     let mut complete = false;
     while steps.len() > 0 && !complete {
         // as long as there's still work to do, we'll execute it a chunk at a time, peeling off
@@ -494,7 +1647,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                 // if anything we have left has the possibility of beating our worst current result
                 if contexts.len() >= contexts.max_size {
                     if step.node.max_relev
-                        <= contexts.peek_min().expect("contexts can't be empty").relev
+                        <= contexts.peek_min().expect("contexts can't be empty").relev()
                     {
                         complete = true;
                         break;
@@ -504,6 +1657,10 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                 // if this is a single item with no parents or children, we can do a more-efficient
                 // coalesce operation in the first phase, rather, than a two-phase coalesce
                 let is_single = step.prev_state.is_none() && step.node.children.len() == 0;
+                if is_single && match_opts.coalesce.min_entries_per_context > 1 {
+                    // its contexts are all a single entry, too short to be returned
+                    continue;
+                }
 
                 let subquery = step
                     .node
@@ -512,6 +1669,9 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                     .expect("phrasematch must be set on non-root tree nodes");
 
                 for key_group in subquery.match_keys.iter() {
+                    if failed_keys.contains_key(&key_group.id) {
+                        continue;
+                    }
                     if is_single || !data_cache.contains_key(&key_group.id) {
                         let match_opts = if key_group.nearby_only {
                             step.match_opts.with_nearby_only()
@@ -529,9 +1689,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                                 && step.node.is_leaf()
                                 && step.possible_relev
                                     <= 0.75
-                                        * contexts
-                                            .peek_max()
-                                            .map_or(0.0, |coalesce_context| coalesce_context.relev)
+                                        * contexts.peek_max().map_or(0.0, |ranked| ranked.relev())
                             {
                                 // this is a potentially-slow leaf subquery in a high-zoom index
                                 // that isn't likely to make our best results better, so skip it
@@ -613,67 +1771,73 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
 
         // phase 1: we get any data we don't already have in cache (and for single coalesce, we
         // just do the whole operation)
-        let key_data: Vec<Result<_, Error>> = keys
-            .into_par_iter()
-            .map(|key_step| {
-                if key_step.is_single {
-                    // this is a first-level node with no children, so short-circuit to a single-coalesce
-                    // stategy
-                    //
-                    // we're not stacking this on top of anything, and we're not stacking anything else
-                    // on top of this, so we can grab a minimal set of elements here
-                    let bigger_max = 2 * MAX_CONTEXTS;
-
-                    // call tree_coalesce_single on each key group
-                    let mut step_contexts: ConstrainedPriorityQueue<CoalesceContext> =
-                        ConstrainedPriorityQueue::new(MAX_CONTEXTS);
-
-                    let grids = key_step.subquery.store.borrow().streaming_get_matching(
-                        &key_step.key,
-                        &key_step.match_opts,
-                        // double to give us some sorting wiggle room
-                        bigger_max,
-                    )?;
-
-                    let coalesced = tree_coalesce_single(
-                        &key_step.subquery,
-                        &key_step.match_opts,
-                        grids,
-                        key_step.key_id,
-                    )?;
-
-                    for entry in coalesced {
-                        step_contexts.push(entry);
-                    }
+        let fetch_key = |key_step: KeyFetchStep<T>| -> Result<KeyFetchResult<R>, Error> {
+            if key_step.is_single {
+                // this is a first-level node with no children, so short-circuit to a single-coalesce
+                // stategy
+                //
+                // we're not stacking this on top of anything, and we're not stacking anything else
+                // on top of this, so we can grab a minimal set of elements here
+                let bigger_max = 2 * MAX_CONTEXTS;
 
-                    Ok(KeyFetchResult::Single(step_contexts))
-                } else {
-                    let mut unique_ids = FxHashSet::default();
-                    let data: Vec<_> = key_step
-                        .subquery
-                        .store
-                        .borrow()
-                        .streaming_get_matching(
-                            &key_step.key,
-                            &key_step.match_opts,
-                            MAX_GRIDS_PER_PHRASE,
-                        )?
-                        .take(MAX_GRIDS_PER_PHRASE)
-                        .filter(|grid| {
-                            unique_ids.insert((
-                                grid.grid_entry.x,
-                                grid.grid_entry.y,
-                                grid.grid_entry.id,
-                            ))
-                        })
-                        .collect();
-                    Ok(KeyFetchResult::Multi((key_step.key_id, data)))
+                // call tree_coalesce_single on each key group
+                let mut step_contexts: ConstrainedPriorityQueue<RankedContext<R>> =
+                    ConstrainedPriorityQueue::new(MAX_CONTEXTS);
+
+                let grids = key_step.subquery.store.borrow().streaming_get_matching(
+                    &key_step.key,
+                    &key_step.match_opts,
+                    // double to give us some sorting wiggle room
+                    bigger_max,
+                )?;
+
+                let coalesced = tree_coalesce_single(
+                    &key_step.subquery,
+                    &key_step.match_opts,
+                    match_opts.zoom,
+                    grids,
+                    key_step.key_id,
+                )?;
+
+                for entry in coalesced {
+                    step_contexts.push(RankedContext::finished(entry, &key_step.match_opts));
                 }
-            })
-            .collect();
 
-        for result in key_data {
-            match result? {
+                Ok(KeyFetchResult::Single(step_contexts))
+            } else {
+                let mut unique_ids = FxHashSet::default();
+                let store = key_step.subquery.store.borrow();
+                let max_grids = store.limits().max_grids_per_phrase;
+                let data: Vec<_> = store
+                    .streaming_get_matching(&key_step.key, &key_step.match_opts, max_grids)?
+                    .take(max_grids)
+                    .filter(|grid| {
+                        unique_ids.insert((
+                            grid.grid_entry.x,
+                            grid.grid_entry.y,
+                            grid.grid_entry.id,
+                        ))
+                    })
+                    .collect();
+                Ok(KeyFetchResult::Multi((key_step.key_id, data)))
+            }
+        };
+        let key_data: Vec<(u32, Result<_, Error>)> =
+            keys.into_par_iter().map(|key_step| (key_step.key_id, fetch_key(key_step))).collect();
+
+        for (key_id, result) in key_data {
+            let result = match result {
+                Ok(result) => result,
+                Err(err) if match_opts.coalesce.partial_ok => {
+                    // leaving the key out of the cache skips it in phase 2; the stacks it would
+                    // have been part of still come back, less relevant, from the branches of the
+                    // tree that leave its subquery out
+                    failed_keys.insert(key_id, err.to_string());
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            match result {
                 KeyFetchResult::Single(phrasematch_contexts) => {
                     // for coalesce single we got back full-on contexts
                     for context in phrasematch_contexts {
@@ -689,7 +1853,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
 
         // phase 2: for complex coalesce, we do the coalescing in a second phase now that the data has been
         // fetched
-        let chunk_results: Vec<Result<(Vec<CoalesceContext>, Vec<CoalesceStep<'_, T>>), Error>> =
+        let chunk_results: Vec<Result<(Vec<RankedContext<R>>, Vec<CoalesceStep<'_, T>>), Error>> =
             step_chunk
                 .into_par_iter()
                 .map(|step| {
@@ -700,7 +1864,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                         .as_ref()
                         .expect("phrasematch must be set on non-root tree nodes");
 
-                    let mut phrasematch_contexts: Vec<CoalesceContext> = Vec::new();
+                    let mut phrasematch_contexts: Vec<RankedContext<R>> = Vec::new();
 
                     let scale_factor: u16 = 1 << (subquery.store.borrow().zoom - step.prev_zoom);
 
@@ -715,7 +1879,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                             }
                         };
 
-                        let mut step_contexts: ConstrainedPriorityQueue<CoalesceContext> =
+                        let mut step_contexts: ConstrainedPriorityQueue<RankedContext<R>> =
                             ConstrainedPriorityQueue::new(MAX_CONTEXTS);
 
                         if let Some(prev_state) = &step.prev_state {
@@ -730,6 +1894,7 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                                     &grid,
                                     &subquery,
                                     &step.match_opts,
+                                    match_opts.zoom,
                                     key_group.id,
                                 );
 
@@ -746,9 +1911,12 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                                         relev_so_far = new_context.relev;
                                     }
 
-                                    let mut out_context = new_context.clone();
-                                    penalize_multi_context(&mut out_context);
-                                    step_contexts.push(out_context);
+                                    if new_context.entries.len() >= min_entries {
+                                        step_contexts.push(RankedContext::stacked(
+                                            &new_context,
+                                            &step.match_opts,
+                                        ));
+                                    }
 
                                     if step.node.children.len() > 0 {
                                         // only bother with getting ready to recurse if we have any children to
@@ -765,11 +1933,13 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                                     &grid,
                                     &subquery,
                                     &step.match_opts,
+                                    match_opts.zoom,
                                     key_group.id,
                                 );
                                 let context = CoalesceContext {
                                     mask: subquery.mask,
                                     relev: entry.grid_entry.relev,
+                                    confidence: 0.,
                                     entries: vec![entry],
                                 };
 
@@ -777,9 +1947,10 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
                                     relev_so_far = context.relev;
                                 }
 
-                                let mut out_context = context.clone();
-                                penalize_multi_context(&mut out_context);
-                                step_contexts.push(out_context);
+                                if min_entries <= 1 {
+                                    step_contexts
+                                        .push(RankedContext::stacked(&context, &step.match_opts));
+                                }
 
                                 state_contexts.push(context);
                             }
@@ -854,38 +2025,148 @@ pub fn tree_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
 
     // other stuff that ought to happen here:
     // - deduplication? if we have the same mask, same stack, better relevance, we should prefer it
-    // - the thing where we don't allow jumps down in relevance that are bigger than 0.25
+    // - the thing where we don't allow jumps down in relevance that are bigger than the gate
     // - way smarter stopping earlier, sorting, cutting off, etc.
     // - there's a relevance penalty for ascending vs. descending stuff for some reason... maybe
     //   we just shouldn't do that anymore though?
 
-    Ok(contexts.into_vec_desc())
+    // the queue is already in rank order, tie-breaks included
+    let ranked = match match_opts.coalesce.dedup {
+        DedupKey::Id => dedup_language_variants(contexts.into_vec_desc(), None),
+        DedupKey::IdAndLanguage | DedupKey::None => contexts.into_vec_desc(),
+    };
+    Ok((ranked.into_iter().map(|ranked| ranked.result).collect(), failed_keys))
+}
+
+/// Checks that the order of a set of results doesn't change when their relevances are nudged by
+/// +/-1e-12, as they might be by differences in float arithmetic across platforms. Contexts with
+/// exactly the same relevance are nudged by the same amount, so ties are still broken the same
+/// way; it's relevances that differ only by float noise that can be reordered.
+#[cfg(feature = "relev-fuzz")]
+fn ordering_survives_relev_fuzz(contexts: &[CoalesceContext], match_opts: &MatchOpts) -> bool {
+    let sort = |contexts: &mut Vec<CoalesceContext>| {
+        if match_opts.reorders_ties() {
+            sort_stable_tiebreak(contexts, match_opts);
+        } else {
+            contexts.sort_by(|a, b| b.cmp(a));
+        }
+    };
+    let order = |contexts: &[CoalesceContext]| -> Vec<(u32, u32)> {
+        contexts.iter().map(|context| (context.mask, context.entries[0].tmp_id)).collect()
+    };
+
+    let mut expected = contexts.to_vec();
+    sort(&mut expected);
+    let expected = order(&expected);
+
+    (0..8u64).all(|seed| {
+        let mut fuzzed = contexts.to_vec();
+        for context in fuzzed.iter_mut() {
+            // splitmix64, so every seed nudges each relevance independently
+            let mut hash = context.relev.to_bits() ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            hash ^= hash >> 31;
+            context.relev += if hash & 1 == 1 { 1e-12 } else { -1e-12 };
+        }
+        sort(&mut fuzzed);
+        order(&fuzzed) == expected
+    })
+}
+
+fn sort_stable_tiebreak(contexts: &mut Vec<CoalesceContext>, match_opts: &MatchOpts) {
+    contexts.sort_by_cached_key(|context| Reverse(rank_key(context, match_opts)));
+}
+
+/// Collapses contexts that stack the same features with the same mask, which happens when a
+/// feature matches under keys in several languages. The variant with the most language-matching
+/// entries is kept in place of the rest, so the duplicates don't use up result slots.
+/// Expects results in descending order and keeps them that way. The variants left out are added
+/// to `dropped` if it's given.
+fn dedup_language_variants<R: QueuedResult>(
+    results: Vec<RankedContext<R>>,
+    mut dropped: Option<&mut Vec<R>>,
+) -> Vec<RankedContext<R>> {
+    let mut deduped: Vec<RankedContext<R>> = Vec::with_capacity(results.len());
+    let mut positions: HashMap<(u32, Vec<u32>), usize> = HashMap::new();
+    let mut replaced = false;
+    for ranked in results {
+        match positions.entry(ranked.result.features()) {
+            Entry::Occupied(position) => {
+                let existing = &mut deduped[*position.get()];
+                let left_out =
+                    if ranked.result.language_matches() > existing.result.language_matches() {
+                        replaced = true;
+                        mem::replace(existing, ranked)
+                    } else {
+                        ranked
+                    };
+                if let Some(dropped) = dropped.as_mut() {
+                    dropped.push(left_out.result);
+                }
+            }
+            Entry::Vacant(position) => {
+                position.insert(deduped.len());
+                deduped.push(ranked);
+            }
+        }
+    }
+    if replaced {
+        deduped.sort_by(|a, b| b.cmp(a));
+    }
+    deduped
+}
+
+/// `dedup_language_variants` for finished contexts, in the order they're returned in. This is
+/// the deduplication every coalesce path that deduplicates by feature shares; the variants left
+/// out are added to `pruned` if it's given.
+fn dedup_contexts(
+    contexts: Vec<CoalesceContext>,
+    match_opts: &MatchOpts,
+    pruned: Option<&mut Vec<PrunedContext>>,
+) -> Vec<CoalesceContext> {
+    let ranked =
+        contexts.into_iter().map(|context| RankedContext::finished(context, match_opts)).collect();
+    let mut dropped = Vec::new();
+    let deduped = dedup_language_variants(ranked, Some(&mut dropped));
+    if let Some(pruned) = pruned {
+        pruned.extend(
+            dropped
+                .into_iter()
+                .map(|context| PrunedContext { context, rule: PruneRule::Duplicate }),
+        );
+    }
+    deduped.into_iter().map(|ranked| ranked.result).collect()
 }
 
 fn tree_coalesce_single<T: Borrow<GridStore> + Clone, U: Iterator<Item = MatchEntry>>(
     subquery: &PhrasematchSubquery<T>,
     match_opts: &MatchOpts,
+    query_zoom: u16,
     grids: U,
     phrasematch_id: u32,
 ) -> Result<impl Iterator<Item = CoalesceContext>, Error> {
     let bigger_max = 2 * MAX_CONTEXTS;
+    let gate = match_opts.coalesce.relevance_gate.gap(1);
 
     let mut max_relevance: f64 = 0.;
     let mut previous_id: u32 = 0;
+    let mut previous_key: (u32, usize) = (0, 0);
     let mut previous_relevance: f64 = 0.;
     let mut previous_scoredist: f64 = 0.;
     let mut min_scoredist = std::f64::MAX;
     let mut feature_count: usize = 0;
 
-    let mut coalesced: HashMap<u32, CoalesceEntry> = HashMap::new();
+    let mut coalesced: HashMap<(u32, usize), CoalesceEntry> = HashMap::new();
 
-    for grid in grids {
-        let coalesce_entry = grid_to_coalesce_entry(&grid, &subquery, match_opts, phrasematch_id);
+    for (seq, grid) in grids.enumerate() {
+        let coalesce_entry =
+            grid_to_coalesce_entry(&grid, &subquery, match_opts, query_zoom, phrasematch_id);
+
+        let current_key = dedup_key(&coalesce_entry, match_opts.coalesce.dedup, seq);
 
         // If it's the same feature as the last one, but a lower scoredist don't add it
-        if previous_id == coalesce_entry.grid_entry.id
-            && coalesce_entry.scoredist <= previous_scoredist
-        {
+        if previous_key == current_key && coalesce_entry.scoredist <= previous_scoredist {
             continue;
         }
 
@@ -899,7 +2180,7 @@ fn tree_coalesce_single<T: Borrow<GridStore> + Clone, U: Iterator<Item = MatchEn
             }
         }
 
-        if max_relevance - coalesce_entry.grid_entry.relev >= 0.25 {
+        if max_relevance - coalesce_entry.grid_entry.relev >= gate {
             break;
         }
         if coalesce_entry.grid_entry.relev > max_relevance {
@@ -912,7 +2193,7 @@ fn tree_coalesce_single<T: Borrow<GridStore> + Clone, U: Iterator<Item = MatchEn
         let current_scoredist = coalesce_entry.scoredist;
 
         // If it's the same feature as one that's been added before, but a higher scoredist, update the entry
-        match coalesced.entry(current_id) {
+        match coalesced.entry(current_key) {
             Entry::Occupied(mut already_coalesced) => {
                 if current_scoredist > already_coalesced.get().scoredist
                     && current_relev >= already_coalesced.get().grid_entry.relev
@@ -935,15 +2216,21 @@ fn tree_coalesce_single<T: Borrow<GridStore> + Clone, U: Iterator<Item = MatchEn
             min_scoredist = current_scoredist;
         }
         previous_id = current_id;
+        previous_key = current_key;
         previous_relevance = current_relev;
         previous_scoredist = current_scoredist;
     }
 
-    let mut ids: Vec<_> = coalesced.keys().cloned().collect();
-    ids.sort();
-    let contexts = ids.into_iter().map(move |id| {
-        let entry = coalesced.remove(&id).expect("hashmap must contain key");
-        CoalesceContext { mask: entry.mask, relev: entry.grid_entry.relev, entries: vec![entry] }
+    let mut keys: Vec<_> = coalesced.keys().cloned().collect();
+    keys.sort();
+    let contexts = keys.into_iter().map(move |key| {
+        let entry = coalesced.remove(&key).expect("hashmap must contain key");
+        CoalesceContext {
+            mask: entry.mask,
+            relev: entry.grid_entry.relev,
+            confidence: 0.,
+            entries: vec![entry],
+        }
     });
 
     Ok(contexts)
@@ -984,14 +2271,317 @@ pub fn stack_and_coalesce<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
     tree_coalesce(&tree, &match_opts)
 }
 
+/// Information about the stores behind a coalesce call
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CoalesceStats {
+    /// Generation of the store queried for each index, keyed by idx. Stores built without a
+    /// generation id are left out.
+    pub generations: BTreeMap<u16, u64>,
+    /// Ids of the match keys whose entries were truncated when their store was built, so their
+    /// results may be incomplete
+    pub truncated_match_keys: Vec<u32>,
+    /// Errors from the stores of match keys that were skipped under `CoalesceOpts::partial_ok`,
+    /// keyed by match key id
+    pub failed_match_keys: BTreeMap<u32, String>,
+    /// How many multi-subquery stacks coalesce stopped reading the last subquery's grids of early,
+    /// because no further grid could make it into the results
+    pub saturated_subqueries: usize,
+}
+
+impl CoalesceStats {
+    pub fn new<T: Borrow<GridStore> + Clone>(
+        phrasematches: &[PhrasematchSubquery<T>],
+    ) -> Result<Self, Error> {
+        let generations = phrasematches
+            .iter()
+            .filter_map(|subquery| subquery.store.borrow().generation.map(|g| (subquery.idx, g)))
+            .collect();
+
+        let mut truncated_match_keys = Vec::new();
+        for subquery in phrasematches {
+            for match_key in subquery.match_keys.iter() {
+                if subquery.store.borrow().is_truncated(&match_key.key)? {
+                    truncated_match_keys.push(match_key.id);
+                }
+            }
+        }
+        truncated_match_keys.sort();
+        truncated_match_keys.dedup();
+
+        Ok(CoalesceStats {
+            generations,
+            truncated_match_keys,
+            failed_match_keys: BTreeMap::new(),
+            saturated_subqueries: 0,
+        })
+    }
+}
+
+/// Same as `stack_and_coalesce`, but also returns stats about the stores that were queried
+pub fn stack_and_coalesce_with_stats<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    phrasematches: &Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<(Vec<CoalesceContext>, CoalesceStats), Error> {
+    let collapsed_phrasematches = collapse_phrasematches(phrasematches.to_vec());
+    let tree = stackable(&collapsed_phrasematches);
+    let (contexts, failed_match_keys) = tree_coalesce_reporting_failures(&tree, match_opts)?;
+    Ok((contexts, CoalesceStats { failed_match_keys, ..CoalesceStats::new(phrasematches)? }))
+}
+
+/// A feature surfaced in the results of a coalesce call, for joining against click logs
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Impression {
+    pub id: u32,
+    pub idx: u16,
+    /// position of the context containing this feature in the results
+    pub rank: usize,
+    /// relevance of the context containing this feature
+    pub relev: f64,
+}
+
+/// Lists every feature surfaced in a set of coalesce results, in rank order
+pub fn impressions(contexts: &[CoalesceContext]) -> Vec<Impression> {
+    contexts
+        .iter()
+        .enumerate()
+        .flat_map(|(rank, context)| {
+            context.entries.iter().map(move |entry| Impression {
+                id: entry.grid_entry.id,
+                idx: entry.idx,
+                rank,
+                relev: context.relev,
+            })
+        })
+        .collect()
+}
+
+/// Same as `stack_and_coalesce`, but also reports the features that were surfaced to the given
+/// callback
+pub fn stack_and_coalesce_with_impressions<T, F>(
+    phrasematches: &Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+    on_impressions: F,
+) -> Result<Vec<CoalesceContext>, Error>
+where
+    T: Borrow<GridStore> + Clone + Debug + Send + Sync,
+    F: FnOnce(Vec<Impression>),
+{
+    let contexts = stack_and_coalesce(phrasematches, match_opts)?;
+    on_impressions(impressions(&contexts));
+    Ok(contexts)
+}
+
+/// Same as `stack_and_coalesce`, but hands the full pool of candidate contexts to `calibrate`
+/// before it's cut down to `MAX_CONTEXTS`, so a calibration model can rescale or reorder more
+/// candidates than end up being returned. `calibrate` is responsible for leaving the contexts in
+/// the order they should be returned in.
+pub fn stack_and_coalesce_with_calibration<T, F>(
+    phrasematches: &Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+    calibrate: F,
+) -> Result<Vec<CoalesceContext>, Error>
+where
+    T: Borrow<GridStore> + Clone + Debug + Send + Sync,
+    F: FnOnce(&mut Vec<CoalesceContext>),
+{
+    let mut contexts = stack_and_coalesce(phrasematches, match_opts)?;
+    calibrate(&mut contexts);
+    contexts.truncate(MAX_CONTEXTS);
+    assign_confidence(&mut contexts, match_opts);
+    Ok(contexts)
+}
+
+/// Same as `stack_and_coalesce`, but returns compact contexts that are cheaper to serialize for
+/// callers that only need ids, relevance and coordinates. Results are kept in compact form from
+/// the moment they're found, so full copies of them are never made.
+pub fn stack_and_coalesce_compact<T: Borrow<GridStore> + Clone + Debug + Send + Sync>(
+    phrasematches: &Vec<PhrasematchSubquery<T>>,
+    match_opts: &MatchOpts,
+) -> Result<Vec<CompactCoalesceContext>, Error> {
+    let collapsed_phrasematches = collapse_phrasematches(phrasematches.to_vec());
+    let tree = stackable(&collapsed_phrasematches);
+    let (results, _) =
+        priority::run(match_opts.coalesce.priority, match_opts.scheduler.as_ref(), || {
+            tree_coalesce_unscheduled::<_, CompactResult>(&tree, match_opts)
+        })?;
+    Ok(results.into_iter().map(|result| result.context).collect())
+}
+
+/// Merges the results of coalescing the same query against several shards into a single result
+/// list, as `stack_and_coalesce` would have returned for an unsharded set of stores. Shards are
+/// expected to share feature ids within each index, so a tmp_id seen in more than one shard is the
+/// same feature and only its best context is kept.
+pub fn merge_contexts(
+    shards: Vec<Vec<CoalesceContext>>,
+    match_opts: &MatchOpts,
+) -> Vec<CoalesceContext> {
+    let mut contexts: Vec<CoalesceContext> = shards.into_iter().flatten().collect();
+    sort_stable_tiebreak(&mut contexts, match_opts);
+    let contexts = dedup_contexts(contexts, match_opts, None);
+
+    let mut out = Vec::with_capacity(MAX_CONTEXTS);
+    if let Some(max_relevance) = contexts.first().map(|context| context.relev) {
+        // the shards' stacks can be of different lengths, so gate on the longest
+        let stack_len = contexts.iter().map(|context| context.entries.len()).max().unwrap_or(1);
+        let gate = match_opts.coalesce.relevance_gate.gap(stack_len);
+        let mut sets: HashSet<u32> = HashSet::new();
+        for context in contexts {
+            if out.len() >= MAX_CONTEXTS || max_relevance - context.relev >= gate {
+                break;
+            }
+            if sets.insert(context.entries[0].tmp_id) {
+                out.push(context);
+            }
+        }
+    }
+    assign_confidence(&mut out, match_opts);
+    out
+}
+
+/// The parts of a `CoalesceEntry` that go into its ranking
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryComponents {
+    pub id: u32,
+    pub idx: u16,
+    pub relev: f64,
+    pub score: u8,
+    pub distance: f64,
+    pub scoredist: f64,
+    pub matches_language: bool,
+}
+
+impl From<&CoalesceEntry> for EntryComponents {
+    fn from(entry: &CoalesceEntry) -> Self {
+        EntryComponents {
+            id: entry.grid_entry.id,
+            idx: entry.idx,
+            relev: entry.grid_entry.relev,
+            score: entry.grid_entry.score,
+            distance: entry.distance,
+            scoredist: entry.scoredist,
+            matches_language: entry.matches_language,
+        }
+    }
+}
+
+/// How a result changed between two sets of coalesce results for the same query
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RankDiff {
+    /// tmp ids of the result's entries, which identify it in both sets
+    pub tmp_ids: Vec<u32>,
+    /// rank and relevance before, if it was in the first set
+    pub before: Option<(usize, f64)>,
+    /// rank and relevance after, if it's in the second set
+    pub after: Option<(usize, f64)>,
+    /// components of the entries that changed, before and after
+    pub changed_entries: Vec<(Option<EntryComponents>, Option<EntryComponents>)>,
+}
+
+/// Reverse geocodes `point`, a tile at `zoom`, against `stores`, in layer order with each at the
+/// idx of its position: the features covering or nearest to the point in any of them, as found by
+/// `GridStore::reverse`. Distances are rescaled to tiles at `zoom` so that stores at different
+/// zooms can be compared, and entries are ordered by distance, then score, then idx. Entries don't
+/// come from a query, so they have no mask.
+pub fn reverse_coalesce<T: Borrow<GridStore>>(
+    stores: &[T],
+    point: [u16; 2],
+    zoom: u16,
+    limit: usize,
+) -> Result<Vec<CoalesceEntry>, Error> {
+    let mut entries = Vec::new();
+    for (idx, store) in stores.iter().enumerate() {
+        let store = store.borrow();
+        let scale = 2f64.powi(zoom as i32 - store.zoom as i32);
+        for matched in store.reverse(point, zoom, limit)? {
+            entries.push(CoalesceEntry {
+                tmp_id: ((idx as u32) << 25) + matched.grid_entry.id,
+                grid_entry: matched.grid_entry,
+                matches_language: matched.matches_language,
+                idx: idx as u16,
+                mask: 0,
+                distance: matched.distance * scale,
+                scoredist: matched.scoredist,
+                phrasematch_id: 0,
+                lonlat: None,
+                zoom: store.zoom,
+            });
+        }
+    }
+    entries.sort_by_key(|entry| {
+        (OrderedFloat(entry.distance), Reverse(entry.grid_entry.score), entry.idx, entry.tmp_id)
+    });
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// Compares two sets of coalesce results for the same query, e.g. from before and after a store
+/// rebuild, and lists every result that moved, appeared, disappeared or changed score, along with
+/// the entry components that changed. Diffs are ordered by the best rank the result had in
+/// either set.
+pub fn diff_contexts(before: &[CoalesceContext], after: &[CoalesceContext]) -> Vec<RankDiff> {
+    let key = |context: &CoalesceContext| -> Vec<u32> {
+        context.entries.iter().map(|entry| entry.tmp_id).collect()
+    };
+    let mut ranked: IndexMap<Vec<u32>, (Option<usize>, Option<usize>)> = IndexMap::new();
+    for (rank, context) in before.iter().enumerate() {
+        ranked.entry(key(context)).or_insert((None, None)).0.get_or_insert(rank);
+    }
+    for (rank, context) in after.iter().enumerate() {
+        ranked.entry(key(context)).or_insert((None, None)).1.get_or_insert(rank);
+    }
+
+    let mut diffs: Vec<RankDiff> = ranked
+        .into_iter()
+        .filter_map(|(tmp_ids, (before_rank, after_rank))| {
+            let before_context = before_rank.map(|rank| &before[rank]);
+            let after_context = after_rank.map(|rank| &after[rank]);
+            let changed_entries: Vec<_> = (0..tmp_ids.len())
+                .filter_map(|i| {
+                    let before_entry = before_context.map(|c| EntryComponents::from(&c.entries[i]));
+                    let after_entry = after_context.map(|c| EntryComponents::from(&c.entries[i]));
+                    if before_entry == after_entry {
+                        None
+                    } else {
+                        Some((before_entry, after_entry))
+                    }
+                })
+                .collect();
+            if before_rank == after_rank && changed_entries.is_empty() {
+                return None;
+            }
+            Some(RankDiff {
+                tmp_ids,
+                before: before_context.map(|c| (before_rank.unwrap(), c.relev)),
+                after: after_context.map(|c| (after_rank.unwrap(), c.relev)),
+                changed_entries,
+            })
+        })
+        .collect();
+    diffs.sort_by_key(|diff| {
+        std::cmp::min(
+            diff.before.map_or(usize::MAX, |(rank, _)| rank),
+            diff.after.map_or(usize::MAX, |(rank, _)| rank),
+        )
+    });
+    diffs
+}
+
+#[derive(Debug, Fail)]
+pub enum CoalesceError {
+    #[fail(display = "coalesce used ~{} bytes, over its limit of {}", used, limit)]
+    MemoryLimitExceeded { used: usize, limit: usize },
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::gridstore::builder::*;
-    use crate::gridstore::common::MatchPhrase::Range;
+    use crate::gridstore::common::MatchPhrase::{Exact, Range};
+    use crate::gridstore::scoring::{DefaultScorePolicy, ScorePolicy, SharedScorePolicy};
     use crate::gridstore::spatial::global_bbox_for_zoom;
 
     use fixedbitset::FixedBitSet;
+    use std::time::Duration;
 
     #[test]
     fn collapse_phrasematches_test() {
@@ -1028,6 +2618,7 @@ mod test {
                 id: 1,
                 ..MatchKeyWithId::default()
             }],
+            bbox: None,
         };
 
         let a2 = PhrasematchSubquery {
@@ -1041,6 +2632,7 @@ mod test {
                 id: 2,
                 ..MatchKeyWithId::default()
             }],
+            bbox: None,
         };
         let phrasematch_results = vec![a1, a2];
         let collapsed_phrasematch = collapse_phrasematches(phrasematch_results.to_vec());
@@ -1052,4 +2644,1718 @@ mod test {
         assert_eq!(collapsed_phrasematch[0].match_keys[0].id, 1);
         assert_eq!(collapsed_phrasematch[0].match_keys[1].id, 2);
     }
+
+    #[test]
+    fn stack_and_coalesce_compact_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries = vec![
+            GridEntry { id: 2, x: 2, y: 3, relev: 0.8, score: 3, source_phrase_hash: 0 },
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 },
+        ];
+        builder.insert(&key, entries).expect("Unable to insert record");
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = PhrasematchSubquery {
+            store: &store,
+            idx: 2,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Range { start: 1, end: 2 }, lang_set: 1 },
+                id: 1,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+
+        let contexts = stack_and_coalesce(&stack, &match_opts).unwrap();
+        let compact = stack_and_coalesce_compact(&stack, &match_opts).unwrap();
+        assert_eq!(compact.len(), contexts.len());
+        assert_eq!(
+            compact[1],
+            CompactCoalesceContext {
+                relev: contexts[1].relev,
+                entries: vec![CompactCoalesceEntry { id: 2, idx: 2, x: 2, y: 3, relev: 0.8 }],
+            },
+            "compact contexts keep ids, relevance and coordinates in the same order"
+        );
+    }
+
+    #[test]
+    fn dedup_language_variants_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+
+        // the same feature is indexed under a phrase in each of two languages
+        let entries =
+            vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 3, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries.clone()).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 2 }, entries).unwrap();
+        let entries =
+            vec![GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 1, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |phrase_id: u64, id: u32| PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(2, 0), subquery(1, 1)];
+        // near both features, so the language penalty doesn't apply to the second phrase
+        let match_opts = MatchOpts { zoom: 14, proximity: Some([1, 1]), ..MatchOpts::default() };
+        let contexts = stack_and_coalesce(&stack, &match_opts).unwrap();
+
+        let ids: Vec<_> = contexts.iter().map(|c| c.entries[0].grid_entry.id).collect();
+        assert_eq!(ids, vec![1, 2], "each feature is only returned once");
+        assert!(contexts[0].entries[0].matches_language, "the language-matching variant is kept");
+        assert_eq!(contexts[0].entries[0].phrasematch_id, 1);
+
+        // coalesce doesn't merge the subqueries into one, so it finds both variants as well
+        let contexts = coalesce(stack.clone(), &match_opts).unwrap();
+        let variants: Vec<_> =
+            contexts.iter().filter(|context| context.entries[0].grid_entry.id == 1).collect();
+        assert_eq!(variants.len(), 1, "coalesce only returns each feature once");
+        assert!(variants[0].entries[0].matches_language, "coalesce keeps the matching variant");
+    }
+
+    #[test]
+    fn saturated_subquery_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id: u32, x: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y: 1,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(1, 1, 1.)]).unwrap();
+        // a few grids stacking on the first subquery's, then many too irrelevant to matter
+        let grids = (10..13).map(|id| grid(id, 1, 1.)).chain((20..60).map(|id| grid(id, 2, 0.4)));
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, grids.collect()).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |idx: u16, phrase_id: u64| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(1, 1), subquery(2, 2)];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        // overrides rule out stopping early, and these leave relevance alone
+        let overridden_opts = MatchOpts {
+            relev_overrides: Some(Arc::new(RelevOverrides::default())),
+            ..match_opts.clone()
+        };
+
+        let (contexts, stats) = coalesce_with_stats(stack.clone(), &match_opts).unwrap();
+        assert_eq!(stats.saturated_subqueries, 1, "stopped reading the last subquery early");
+        let (_, stats) = coalesce_with_stats(stack.clone(), &overridden_opts).unwrap();
+        assert_eq!(stats.saturated_subqueries, 0);
+
+        let entries = |contexts: Vec<CoalesceContext>| {
+            contexts.into_iter().map(|context| context.entries).collect::<Vec<_>>()
+        };
+        let expected = coalesce(stack, &overridden_opts).unwrap();
+        assert_eq!(contexts.len(), 3);
+        assert_eq!(entries(contexts), entries(expected), "stopping early changes nothing");
+    }
+
+    #[test]
+    fn min_entries_per_context_test() {
+        let grid = |id: u32, x: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y: 1,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        let build = |directory: &tempfile::TempDir, type_id: u16, grids: Vec<GridEntry>| {
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                14,
+                type_id,
+                200.,
+                global_bbox_for_zoom(14),
+                1.0,
+            )
+            .unwrap()
+        };
+        // a weak pair of grids that stack, and strong ones elsewhere that stack on nothing
+        let place_dir = tempfile::tempdir().unwrap();
+        let places = build(&place_dir, 1, vec![grid(1, 1, 0.4), grid(2, 5, 1.), grid(3, 6, 1.)]);
+        let address_dir = tempfile::tempdir().unwrap();
+        let addresses = build(&address_dir, 2, vec![grid(10, 1, 0.4), grid(11, 9, 1.)]);
+
+        let subquery = |store, idx: u16, weight: f64| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(&places, 0, 0.9), subquery(&addresses, 1, 0.1)];
+        let opts = |min_entries_per_context: usize| MatchOpts {
+            zoom: 14,
+            coalesce: CoalesceOpts { min_entries_per_context, ..CoalesceOpts::default() },
+            ..MatchOpts::default()
+        };
+        let ids = |contexts: Vec<CoalesceContext>| {
+            contexts
+                .into_iter()
+                .map(|context| context.entries.iter().map(|entry| entry.grid_entry.id).collect())
+                .collect::<Vec<Vec<u32>>>()
+        };
+
+        let unrestricted = ids(coalesce(stack.clone(), &opts(0)).unwrap());
+        assert!(unrestricted.iter().all(|ids| ids.len() == 1), "the stacked pair is gated out");
+        assert_eq!(ids(coalesce(stack.clone(), &opts(2)).unwrap()), vec![vec![10, 1]]);
+        assert_eq!(ids(stack_and_coalesce(&stack, &opts(2)).unwrap()), vec![vec![10, 1]]);
+        assert_eq!(ids(coalesce(stack.clone(), &opts(3)).unwrap()), Vec::<Vec<u32>>::new());
+        assert_eq!(
+            ids(coalesce(vec![stack[0].clone()], &opts(2)).unwrap()),
+            Vec::<Vec<u32>>::new()
+        );
+    }
+
+    #[test]
+    fn max_contexts_test() {
+        let grid = |id: u32, x: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y: 1,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        let build = |directory: &tempfile::TempDir, type_id: u16, grids: Vec<GridEntry>| {
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                14,
+                type_id,
+                200.,
+                global_bbox_for_zoom(14),
+                1.0,
+            )
+            .unwrap()
+        };
+        // two tiers of relevance, each with features that stack and features that don't
+        let tiers = |base: u32| {
+            (0..8).map(move |i| grid(base + i, i as u16 * 2, if i < 4 { 1. } else { 0.8 }))
+        };
+        let place_dir = tempfile::tempdir().unwrap();
+        let places = build(&place_dir, 1, tiers(1).collect());
+        let address_dir = tempfile::tempdir().unwrap();
+        let addresses = build(&address_dir, 2, tiers(100).step_by(2).collect());
+
+        let subquery = |store, idx: u16, weight: f64| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let opts = |max_contexts: Option<usize>| MatchOpts {
+            zoom: 14,
+            coalesce: CoalesceOpts { max_contexts, ..CoalesceOpts::default() },
+            ..MatchOpts::default()
+        };
+        let ids = |contexts: Vec<CoalesceContext>| {
+            contexts
+                .into_iter()
+                .map(|context| context.entries.iter().map(|entry| entry.grid_entry.id).collect())
+                .collect::<Vec<Vec<u32>>>()
+        };
+
+        let stacks = vec![
+            vec![subquery(&places, 0, 1.)],
+            vec![subquery(&places, 0, 0.5), subquery(&addresses, 1, 0.5)],
+        ];
+        for stack in stacks {
+            let unlimited = ids(coalesce(stack.clone(), &opts(None)).unwrap());
+            assert!(unlimited.len() > 3);
+            for max in 1..=unlimited.len() + 1 {
+                let limited = ids(coalesce(stack.clone(), &opts(Some(max))).unwrap());
+                let expected = std::cmp::min(max, unlimited.len());
+                assert_eq!(limited[..], unlimited[..expected], "max_contexts {}", max);
+            }
+
+            let lazy: Vec<CoalesceContext> =
+                coalesce_lazy(stack.clone(), &opts(None)).map(Result::unwrap).collect();
+            assert_eq!(ids(lazy), unlimited);
+            let first: Vec<CoalesceContext> =
+                coalesce_lazy(stack.clone(), &opts(None)).take(2).map(Result::unwrap).collect();
+            assert_eq!(ids(first), unlimited[..2].to_vec());
+            let capped: Vec<CoalesceContext> =
+                coalesce_lazy(stack.clone(), &opts(Some(3))).map(Result::unwrap).collect();
+            assert_eq!(ids(capped), unlimited[..3].to_vec());
+        }
+    }
+
+    #[test]
+    fn coalesce_with_budget_test() {
+        let grid =
+            |id: u32, x: u16| GridEntry { id, x, y: 1, relev: 1., score: 3, source_phrase_hash: 0 };
+        let build = |directory: &tempfile::TempDir, type_id: u16, grids: Vec<GridEntry>| {
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                14,
+                type_id,
+                200.,
+                global_bbox_for_zoom(14),
+                1.0,
+            )
+            .unwrap()
+        };
+        let place_dir = tempfile::tempdir().unwrap();
+        let places = build(&place_dir, 1, (0..8).map(|i| grid(i + 1, i as u16 * 2)).collect());
+        let address_dir = tempfile::tempdir().unwrap();
+        let addresses =
+            build(&address_dir, 2, (0..8).map(|i| grid(i + 100, i as u16 * 2)).collect());
+
+        let subquery = |store, idx: u16, weight: f64| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let opts = |max_grids_examined: Option<usize>, time_budget: Option<Duration>| MatchOpts {
+            zoom: 14,
+            coalesce: CoalesceOpts { max_grids_examined, time_budget, ..CoalesceOpts::default() },
+            ..MatchOpts::default()
+        };
+
+        let stacks = vec![
+            vec![subquery(&places, 0, 1.)],
+            vec![subquery(&places, 0, 0.5), subquery(&addresses, 1, 0.5)],
+        ];
+        for stack in stacks {
+            let unlimited = coalesce_with_budget(stack.clone(), &opts(None, None)).unwrap();
+            assert!(!unlimited.truncated);
+            assert_eq!(
+                unlimited.contexts,
+                coalesce(stack.clone(), &opts(None, None)).unwrap(),
+                "without a budget, the results are the same as coalesce's"
+            );
+
+            let ample = coalesce_with_budget(
+                stack.clone(),
+                &opts(Some(1000), Some(Duration::from_secs(60))),
+            )
+            .unwrap();
+            assert!(!ample.truncated);
+            assert_eq!(ample.contexts, unlimited.contexts);
+
+            let limited = coalesce_with_budget(stack.clone(), &opts(Some(3), None)).unwrap();
+            assert!(limited.truncated, "three grids isn't enough to read the whole stack");
+            assert!(!limited.contexts.is_empty(), "the best results so far are returned");
+            assert!(limited.contexts.len() < unlimited.contexts.len());
+            assert_eq!(
+                coalesce(stack.clone(), &opts(Some(3), None)).unwrap(),
+                limited.contexts,
+                "coalesce stops at the budget too"
+            );
+
+            let expired =
+                coalesce_with_budget(stack.clone(), &opts(None, Some(Duration::from_secs(0))))
+                    .unwrap();
+            assert!(expired.truncated);
+            assert!(expired.contexts.is_empty());
+        }
+    }
+
+    #[test]
+    fn cancellation_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grids = (0..4)
+            .map(|i| GridEntry {
+                id: i + 1,
+                x: i as u16,
+                y: 1,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            })
+            .collect();
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |idx: u16| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let token = CancellationToken::new();
+        let match_opts =
+            MatchOpts { zoom: 14, cancellation: Some(token.clone()), ..MatchOpts::default() };
+        let stacks = vec![vec![subquery(0)], vec![subquery(0), subquery(1)]];
+
+        for stack in stacks.iter() {
+            assert!(!coalesce(stack.clone(), &match_opts).unwrap().is_empty());
+        }
+
+        token.cancel();
+        assert!(match_opts.cancellation.as_ref().unwrap().is_cancelled(), "clones share the flag");
+        let key = MatchKey { match_phrase: Exact(1), lang_set: 1 };
+        let err = store.streaming_get_matching(&key, &match_opts, 10).err().unwrap();
+        assert!(matches!(err.downcast_ref::<QueryError>(), Some(QueryError::Cancelled)));
+        for stack in stacks {
+            let err = coalesce(stack, &match_opts).unwrap_err();
+            assert!(matches!(err.downcast_ref::<QueryError>(), Some(QueryError::Cancelled)));
+        }
+
+        assert_ne!(token, CancellationToken::new(), "only clones of a token are equal to it");
+    }
+
+    #[test]
+    fn read_timeout_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grids =
+            vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 3, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder.finish().unwrap();
+        let mut store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+        let settings = Settings::default();
+        store.set_settings(settings.clone());
+
+        let subquery = |store| PhrasematchSubquery {
+            store,
+            idx: 0,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        assert_eq!(coalesce(vec![subquery(&store)], &match_opts).unwrap().len(), 1);
+
+        // every read takes longer than no time at all
+        settings.update(|limits| limits.read_timeout = Some(Duration::from_secs(0)));
+        let err = coalesce(vec![subquery(&store)], &match_opts).unwrap_err();
+        match err.downcast_ref::<QueryError>() {
+            Some(QueryError::ReadTimedOut { path, timeout }) => {
+                assert_eq!(path, &store.path);
+                assert_eq!(*timeout, Duration::from_secs(0));
+            }
+            other => panic!("expected a read timeout, got {:?}", other),
+        }
+
+        settings.update(|limits| limits.read_timeout = Some(Duration::from_secs(60)));
+        assert_eq!(coalesce(vec![subquery(&store)], &match_opts).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lookup_key_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_generation(7);
+        builder.finish().unwrap();
+        let open = || {
+            GridStore::new_with_options(directory.path(), 14, 1, 200., global_bbox_for_zoom(14), 1.)
+                .unwrap()
+        };
+        let (store_a, store_b) = (open(), open());
+        let subquery = |store| PhrasematchSubquery {
+            store,
+            idx: 0,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId::default()],
+            bbox: None,
+        };
+        let match_opts =
+            MatchOpts { zoom: 14, proximity_radius: Some(10.), ..MatchOpts::default() };
+        assert_eq!(
+            lookup_key(&subquery(&store_a), &match_opts),
+            lookup_key(&subquery(&store_b), &match_opts),
+            "handles to the same store share lookups"
+        );
+        let wider = MatchOpts { proximity_radius: Some(20.), ..match_opts.clone() };
+        assert_ne!(
+            lookup_key(&subquery(&store_a), &match_opts),
+            lookup_key(&subquery(&store_a), &wider)
+        );
+    }
+
+    #[test]
+    fn coalesce_partial_ok_test() {
+        let build = |id: u32, settings: Option<Settings>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            let grids =
+                vec![GridEntry { id, x: 1, y: 1, relev: 1., score: 3, source_phrase_hash: 0 }];
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+            builder.finish().unwrap();
+            let mut store = GridStore::new_with_options(
+                directory.path(),
+                14,
+                id as u16,
+                200.,
+                global_bbox_for_zoom(14),
+                1.0,
+            )
+            .unwrap();
+            if let Some(settings) = settings {
+                store.set_settings(settings);
+            }
+            (directory, store)
+        };
+        let subquery = |store, idx: u16| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        // every read of the second store times out
+        let settings = Settings::default();
+        settings.update(|limits| limits.read_timeout = Some(Duration::from_secs(0)));
+        let (_dir_a, store_a) = build(1, None);
+        let (_dir_b, store_b) = build(2, Some(settings));
+        let stack = vec![subquery(&store_a, 0), subquery(&store_b, 1)];
+
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        assert!(coalesce(stack.clone(), &match_opts).is_err(), "a store error fails the query");
+
+        let mut match_opts = match_opts;
+        match_opts.coalesce.partial_ok = true;
+        let (contexts, stats) = coalesce_with_stats(stack.clone(), &match_opts).unwrap();
+        assert_eq!(contexts.len(), 1, "the rest of the stack still comes back");
+        assert_eq!(contexts[0].entries.len(), 1);
+        assert_eq!(contexts[0].entries[0].grid_entry.id, 1);
+        assert_eq!(stats.failed_match_keys.keys().collect::<Vec<_>>(), vec![&1]);
+        let ids = |contexts: &[CoalesceContext]| -> Vec<u32> {
+            contexts.iter().map(|context| context.entries[0].grid_entry.id).collect()
+        };
+        assert_eq!(
+            ids(&coalesce(stack, &match_opts).unwrap()),
+            ids(&contexts),
+            "coalesce skips the failed store too"
+        );
+
+        let (contexts, stats) =
+            coalesce_with_stats(vec![subquery(&store_b, 1)], &match_opts).unwrap();
+        assert!(contexts.is_empty());
+        assert_eq!(stats.failed_match_keys.keys().collect::<Vec<_>>(), vec![&1]);
+    }
+
+    #[test]
+    fn context_filter_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id: u32, relev: f64| GridEntry {
+            id,
+            x: id as u16,
+            y: 1,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        // feature 1 is a relevance gate ahead of the rest, and there's twice MAX_CONTEXTS of them
+        let grids = (1..=81).map(|id| grid(id, if id == 1 { 1. } else { 0.6 })).collect();
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder
+            .insert(
+                &GridKey { phrase_id: 2, lang_set: 1 },
+                (1..=81).map(|id| grid(id, 1.)).collect(),
+            )
+            .unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |idx: u16, phrase_id: u64| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: if idx == 0 { 1. } else { 0.5 },
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let ids = |contexts: &[CoalesceContext]| -> Vec<u32> {
+            contexts.iter().map(|context| context.entries[0].grid_entry.id).collect()
+        };
+
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        let single = vec![subquery(0, 1)];
+        assert_eq!(ids(&coalesce(single.clone(), &match_opts).unwrap()), vec![1]);
+
+        // say the odd features are closed
+        let filter = ContextFilter(Arc::new(|context: &CoalesceContext| {
+            context.entries.iter().all(|entry| entry.grid_entry.id % 2 == 0)
+        }));
+        let match_opts = MatchOpts { context_filter: Some(filter), ..match_opts };
+        let contexts = coalesce(single.clone(), &match_opts).unwrap();
+        assert_eq!(contexts.len(), MAX_CONTEXTS, "the even features fill the results");
+        assert!(ids(&contexts).iter().all(|id| id % 2 == 0));
+
+        let trace = coalesce_with_trace(single, &match_opts).unwrap();
+        assert!(trace.pruned.iter().any(|pruned| pruned.rule == PruneRule::Filtered
+            && pruned.context.entries[0].grid_entry.id == 1));
+
+        let contexts = coalesce(vec![subquery(1, 1), subquery(2, 2)], &match_opts).unwrap();
+        assert_eq!(contexts.len(), MAX_CONTEXTS);
+        assert!(ids(&contexts).iter().all(|id| id % 2 == 0));
+        assert!(contexts.iter().all(|context| context.entries.len() == 2));
+    }
+
+    #[test]
+    fn antimeridian_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id: u32, x: u16| GridEntry {
+            id,
+            x,
+            y: 10,
+            relev: 1.,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        // features at either edge of the map, and one in the middle
+        let grids = || vec![grid(1, 1), grid(2, 32), grid(3, 62)];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids()).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, grids()).unwrap();
+        builder.finish().unwrap();
+        let store =
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.0)
+                .unwrap();
+
+        let subquery = |idx: u16, phrase_id: u64| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let ids = |stack, match_opts: &MatchOpts| {
+            let mut ids: Vec<u32> = coalesce(stack, match_opts)
+                .unwrap()
+                .iter()
+                .map(|context| context.entries[0].grid_entry.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // a viewport over the Pacific
+        let match_opts = MatchOpts { zoom: 6, bbox: Some([60, 0, 3, 63]), ..MatchOpts::default() };
+        assert_eq!(ids(vec![subquery(0, 1)], &match_opts), vec![1, 3]);
+        assert_eq!(ids(vec![subquery(0, 1), subquery(1, 2)], &match_opts), vec![1, 3]);
+        let proximity = MatchOpts { proximity: Some([63, 10]), ..match_opts.clone() };
+        assert_eq!(ids(vec![subquery(0, 1)], &proximity), vec![1, 3]);
+
+        // a subquery's own bbox still narrows it down, to one side of the antimeridian
+        let mut narrowed = subquery(0, 1);
+        narrowed.bbox = Some([0, 0, 10, 63]);
+        assert_eq!(ids(vec![narrowed], &match_opts), vec![1]);
+    }
+
+    #[test]
+    fn coalesce_with_trace_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id: u32, x: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y: 1,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        // feature 1 twice, and a feature too far behind it to pass the relevance gate
+        let grids = vec![grid(1, 1, 1.), grid(1, 3, 1.), grid(2, 2, 0.4)];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            3,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+        let stack = vec![PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 7,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        }];
+        let match_opts = MatchOpts { zoom: 14, proximity: Some([1, 1]), ..MatchOpts::default() };
+
+        let trace = coalesce_with_trace(stack.clone(), &match_opts).unwrap();
+        let contexts = coalesce(stack, &match_opts).unwrap();
+        assert_eq!(trace.contexts.len(), contexts.len());
+        for (traced, context) in trace.contexts.iter().zip(contexts.iter()) {
+            assert_eq!(traced.context.entries, context.entries);
+        }
+
+        let entry = &trace.contexts[0].entries[0];
+        assert_eq!((entry.id, entry.idx, entry.type_id), (1, 1, 3));
+        assert_eq!(entry.phrasematch_id, contexts[0].entries[0].phrasematch_id);
+        assert!((entry.grid_relev - 1.).abs() < 1e-9, "relevance before the weight");
+        assert!((entry.relev - 0.5).abs() < 1e-9, "relevance after the weight");
+        assert!(entry.proximity_adjustment > 0., "on the proximity tile");
+
+        let pruned: Vec<(u32, u16, PruneRule)> = trace
+            .pruned
+            .iter()
+            .map(|pruned| {
+                let grid_entry = &pruned.context.entries[0].grid_entry;
+                (grid_entry.id, grid_entry.x, pruned.rule)
+            })
+            .collect();
+        assert_eq!(pruned, vec![(1, 3, PruneRule::Duplicate), (2, 2, PruneRule::RelevanceGate)]);
+    }
+
+    #[test]
+    fn score_policy_test() {
+        /// Ranks low scores first, and halves the relevance of stacked contexts
+        #[derive(Debug)]
+        struct Inverted;
+        impl ScorePolicy for Inverted {
+            fn scoredist(&self, entry: &CoalesceEntry) -> f64 {
+                -entry.scoredist
+            }
+            fn context_relevance(&self, entries: &[CoalesceEntry], summed: f64) -> f64 {
+                if entries.len() > 1 {
+                    summed / 2.
+                } else {
+                    summed
+                }
+            }
+        }
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id: u32, x: u16, score: u8| GridEntry {
+            id,
+            x,
+            y: 1,
+            relev: 1.,
+            score,
+            source_phrase_hash: 0,
+        };
+        let grids = vec![grid(1, 1, 7), grid(2, 2, 1)];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(3, 1, 3)]).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+        let subquery = |idx: u16, phrase_id: u64| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask: 1 << idx,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        let with_policy = |policy: Arc<dyn ScorePolicy>| MatchOpts {
+            score_policy: Some(SharedScorePolicy(policy)),
+            ..match_opts.clone()
+        };
+        let ids = |contexts: Vec<CoalesceContext>| -> Vec<Vec<u32>> {
+            contexts
+                .iter()
+                .map(|context| context.entries.iter().map(|entry| entry.grid_entry.id).collect())
+                .collect()
+        };
+
+        let single = vec![subquery(1, 1)];
+        assert_eq!(ids(coalesce(single.clone(), &match_opts).unwrap()), vec![vec![1], vec![2]]);
+        assert_eq!(
+            ids(coalesce(single.clone(), &with_policy(Arc::new(DefaultScorePolicy))).unwrap()),
+            vec![vec![1], vec![2]],
+            "the default policy is the built-in ranking"
+        );
+        assert_eq!(
+            ids(coalesce(single, &with_policy(Arc::new(Inverted))).unwrap()),
+            vec![vec![2], vec![1]],
+            "the policy's scoredist decides between equally relevant results"
+        );
+
+        let stack = vec![subquery(1, 1), subquery(2, 2)];
+        let builtin = coalesce(stack.clone(), &match_opts).unwrap();
+        let inverted = coalesce(stack, &with_policy(Arc::new(Inverted))).unwrap();
+        let stacked_relev = |contexts: &[CoalesceContext]| {
+            contexts.iter().find(|context| context.entries.len() == 2).map(|context| context.relev)
+        };
+        assert_eq!(stacked_relev(&builtin), Some(0.99));
+        assert_eq!(stacked_relev(&inverted), Some(0.49), "the policy's context relevance is used");
+        assert_eq!(ids(inverted).len(), 3, "and the stack no longer gates out unstacked results");
+    }
+
+    #[test]
+    fn determinism_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        // the same features in the same tiles under several phrases, which only differ in
+        // which phrase they came from
+        for phrase_id in 1..=4 {
+            let grids = (1..=8)
+                .map(|id| GridEntry {
+                    id,
+                    x: (id % 3) as u16,
+                    y: 1,
+                    relev: 1.,
+                    score: 3,
+                    source_phrase_hash: phrase_id as u8,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, grids).unwrap();
+        }
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |idx: u16, mask: u32, match_phrase: MatchPhrase| PhrasematchSubquery {
+            store: &store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase, lang_set: 1 },
+                id: idx as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let single = vec![subquery(1, 1, Range { start: 1, end: 5 })];
+        let multi = vec![
+            subquery(1, 1, Range { start: 1, end: 3 }),
+            subquery(2, 2, Range { start: 3, end: 5 }),
+        ];
+        let match_opts = MatchOpts {
+            zoom: 14,
+            coalesce: CoalesceOpts { dedup: DedupKey::None, ..CoalesceOpts::default() },
+            ..MatchOpts::default()
+        };
+        // contexts compare equal if they sort the same, so compare their entries
+        let entries = |stack: Vec<PhrasematchSubquery<&GridStore>>| {
+            let contexts = coalesce(stack, &match_opts).unwrap();
+            contexts.into_iter().map(|context| context.entries).collect::<Vec<_>>()
+        };
+        for stack in vec![single, multi] {
+            let expected = entries(stack.clone());
+            for _ in 0..100 {
+                assert_eq!(entries(stack.clone()), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn store_settings_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        for phrase_id in 1..=2 {
+            let grids = (1..=6)
+                .map(|x| GridEntry {
+                    id: (phrase_id * 10) as u32 + x,
+                    x: x as u16,
+                    y: 1,
+                    relev: 1.,
+                    score: 3,
+                    source_phrase_hash: 0,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, grids).unwrap();
+        }
+        builder.finish().unwrap();
+        let mut store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+        let settings = Settings::default();
+        store.set_settings(settings.clone());
+
+        let stacked = |store: &GridStore| {
+            let subquery = |idx: u16, mask: u32, phrase_id: u64| PhrasematchSubquery {
+                store,
+                idx,
+                non_overlapping_indexes: FixedBitSet::with_capacity(128),
+                weight: 0.5,
+                mask,
+                match_keys: vec![MatchKeyWithId {
+                    key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                    id: idx as u32,
+                    ..MatchKeyWithId::default()
+                }],
+                bbox: None,
+            };
+            let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+            let stack = vec![subquery(1, 1, 1), subquery(2, 2, 2)];
+            let contexts = coalesce(stack, &match_opts).unwrap();
+            contexts.iter().filter(|context| context.entries.len() == 2).count()
+        };
+        assert_eq!(stacked(&store), 6);
+        settings.update(|limits| limits.max_grids_per_phrase = 2);
+        assert_eq!(stacked(&store), 2, "the new cap applies to the next query");
+        assert_eq!(Settings::global().load().max_grids_per_phrase, MAX_GRIDS_PER_PHRASE);
+    }
+
+    #[test]
+    fn dedup_key_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id, x| GridEntry { id, x, y: 1, relev: 1., score: 3, source_phrase_hash: 0 };
+        // feature 2 has two grids in the query language; feature 1 has one in each language
+        builder
+            .insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(1, 1), grid(2, 3)])
+            .unwrap();
+        builder.append(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(2, 4)]).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 2 }, vec![grid(1, 2)]).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Range { start: 1, end: 3 }, lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let count = |dedup: DedupKey| {
+            let match_opts = MatchOpts {
+                zoom: 14,
+                coalesce: CoalesceOpts { dedup, ..CoalesceOpts::default() },
+                ..MatchOpts::default()
+            };
+            coalesce(vec![subquery.clone()], &match_opts).unwrap().len()
+        };
+        assert_eq!(count(DedupKey::Id), 2, "one result per feature by default");
+        assert_eq!(count(DedupKey::IdAndLanguage), 3, "one per feature per language match");
+        assert_eq!(count(DedupKey::None), 4, "one per grid");
+
+        let tree_count = |dedup: DedupKey| {
+            let match_opts = MatchOpts {
+                zoom: 14,
+                coalesce: CoalesceOpts { dedup, ..CoalesceOpts::default() },
+                ..MatchOpts::default()
+            };
+            stack_and_coalesce(&vec![subquery.clone()], &match_opts).unwrap().len()
+        };
+        assert_eq!(tree_count(DedupKey::Id), 2, "tree coalesce dedups the same way");
+        assert_eq!(tree_count(DedupKey::IdAndLanguage), 3);
+        assert_eq!(tree_count(DedupKey::None), 4);
+    }
+
+    #[test]
+    fn calibration_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        for phrase_id in 1..=2 {
+            let entries = (1..=30)
+                .map(|i| GridEntry {
+                    id: phrase_id as u32 * 100 + i,
+                    x: i as u16,
+                    y: phrase_id as u16,
+                    relev: 1.,
+                    score: 7 - (i / 5) as u8,
+                    source_phrase_hash: 0,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+        }
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = |phrase_id: u64| PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(phrase_id), lang_set: 1 },
+                id: phrase_id as u32,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(1), subquery(2)];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+
+        let uncalibrated = stack_and_coalesce(&stack, &match_opts).unwrap();
+        assert!(uncalibrated.len() > MAX_CONTEXTS, "the candidate pool is bigger than the output");
+        let worst = uncalibrated.last().unwrap().entries[0].grid_entry.id;
+
+        let mut pool_size = 0;
+        let calibrated = stack_and_coalesce_with_calibration(&stack, &match_opts, |contexts| {
+            pool_size = contexts.len();
+            for context in contexts.iter_mut() {
+                if context.entries[0].grid_entry.id == worst {
+                    context.relev = 2.;
+                }
+            }
+            contexts.sort_by(|a, b| b.cmp(a));
+        })
+        .unwrap();
+        assert_eq!(pool_size, uncalibrated.len(), "calibration sees every candidate");
+        assert_eq!(calibrated.len(), MAX_CONTEXTS);
+        assert_eq!(
+            calibrated[0].entries[0].grid_entry.id, worst,
+            "calibration can promote a candidate that wouldn't have been returned"
+        );
+    }
+
+    #[test]
+    fn merge_contexts_test() {
+        let build_shard = |entries: Vec<GridEntry>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+            builder.finish().unwrap();
+            let store = GridStore::new_with_options(
+                directory.path(),
+                14,
+                1,
+                200.,
+                global_bbox_for_zoom(14),
+                1.0,
+            )
+            .unwrap();
+            let stack = vec![PhrasematchSubquery {
+                store: &store,
+                idx: 1,
+                non_overlapping_indexes: FixedBitSet::with_capacity(128),
+                weight: 1.,
+                mask: 1,
+                match_keys: vec![MatchKeyWithId {
+                    key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                    id: 0,
+                    ..MatchKeyWithId::default()
+                }],
+                bbox: None,
+            }];
+            stack_and_coalesce(&stack, &MatchOpts::default()).unwrap()
+        };
+
+        // feature 2 sits on the boundary between the two shards, so both have it
+        let shard_a = build_shard(vec![
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 3, source_phrase_hash: 0 },
+        ]);
+        let shard_b = build_shard(vec![
+            GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 3, source_phrase_hash: 0 },
+            GridEntry { id: 3, x: 3, y: 3, relev: 1., score: 7, source_phrase_hash: 0 },
+        ]);
+        // the best match in this shard is much worse than in the others
+        let shard_c = build_shard(vec![GridEntry {
+            id: 4,
+            x: 4,
+            y: 4,
+            relev: 0.6,
+            score: 7,
+            source_phrase_hash: 0,
+        }]);
+        assert_eq!(shard_c.len(), 1);
+
+        let shards = vec![shard_a, shard_b, shard_c];
+        let merged = merge_contexts(shards.clone(), &MatchOpts::default());
+        let ids: Vec<u32> = merged.iter().map(|c| c.entries[0].grid_entry.id).collect();
+        assert_eq!(
+            ids,
+            vec![3, 2, 1],
+            "results are re-sorted across shards, deduped, and cut off at 0.25 below the best"
+        );
+        assert!(merge_contexts(vec![vec![], vec![]], &MatchOpts::default()).is_empty());
+
+        let relevance_gate = RelevanceGate::PerSubquery { base: 0.5, per_subquery: 0.1, max: 0.6 };
+        assert_eq!(relevance_gate.gap(3), 0.6);
+        let match_opts = MatchOpts {
+            coalesce: CoalesceOpts { relevance_gate, ..CoalesceOpts::default() },
+            ..MatchOpts::default()
+        };
+        assert_eq!(merge_contexts(shards, &match_opts).len(), 4, "the gate is configurable");
+    }
+
+    #[cfg(feature = "relev-fuzz")]
+    #[test]
+    fn ordering_survives_relev_fuzz_test() {
+        let context = |id: u32, relev: f64| CoalesceContext {
+            mask: 1,
+            relev,
+            confidence: 0.,
+            entries: vec![CoalesceEntry {
+                grid_entry: GridEntry { id, x: 1, y: 1, relev, score: 1, source_phrase_hash: 0 },
+                matches_language: true,
+                idx: 1,
+                tmp_id: id,
+                mask: 1,
+                distance: 0.,
+                scoredist: 1.,
+                phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
+            }],
+        };
+
+        let exact_ties = vec![context(2, 0.3), context(1, 0.3), context(3, 0.2)];
+        assert!(ordering_survives_relev_fuzz(&exact_ties, &MatchOpts::default()));
+
+        // 0.1 + 0.2 is 0.30000000000000004, so these only differ by float noise
+        let near_ties = vec![context(1, 0.1 + 0.2), context(2, 0.3)];
+        assert!(!ordering_survives_relev_fuzz(&near_ties, &MatchOpts::default()));
+    }
+
+    #[test]
+    fn confidence_test() {
+        let context = |id: u32, relev: f64, scoredist: f64| CoalesceContext {
+            mask: 1,
+            relev,
+            confidence: 0.,
+            entries: vec![CoalesceEntry {
+                grid_entry: GridEntry { id, x: 1, y: 1, relev, score: 1, source_phrase_hash: 0 },
+                matches_language: true,
+                idx: 1,
+                tmp_id: id,
+                mask: 1,
+                distance: 0.,
+                scoredist,
+                phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
+            }],
+        };
+        let match_opts = MatchOpts::default();
+        let confidences = |mut contexts: Vec<CoalesceContext>| -> Vec<f64> {
+            assign_confidence(&mut contexts, &match_opts);
+            contexts.iter().map(|context| context.confidence).collect()
+        };
+
+        assert_eq!(confidences(vec![context(1, 0.8, 1.)]), vec![0.8], "alone, it's the coverage");
+        assert_eq!(
+            confidences(vec![context(1, 1., 3.), context(2, 0.5, 3.)]),
+            vec![0.875, 0.0625],
+            "a gate ahead is as sure as relevance gets, and a gate behind is hopeless"
+        );
+        let tied = confidences(vec![context(1, 1., 3.), context(2, 1., 1.)]);
+        assert!(0.5 < tied[0] && tied[0] < 0.75, "a relevance tie only leans on scoredist");
+        assert!(tied[1] < 0.5 && tied[1] > 0.25);
+        assert!(confidences(vec![]).is_empty());
+    }
+
+    #[test]
+    fn diff_contexts_test() {
+        let context = |id: u32, relev: f64, scoredist: f64| CoalesceContext {
+            mask: 1,
+            relev,
+            confidence: 0.,
+            entries: vec![CoalesceEntry {
+                grid_entry: GridEntry { id, x: 1, y: 1, relev, score: 1, source_phrase_hash: 0 },
+                matches_language: true,
+                idx: 1,
+                tmp_id: id,
+                mask: 1,
+                distance: 0.,
+                scoredist,
+                phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
+            }],
+        };
+
+        let before = vec![context(1, 1., 3.), context(2, 1., 2.), context(3, 0.9, 1.)];
+        let after = vec![context(2, 1., 4.), context(1, 1., 3.), context(4, 0.8, 1.)];
+        let diffs = diff_contexts(&before, &after);
+        assert_eq!(diffs.len(), 4);
+
+        assert_eq!(diffs[0].tmp_ids, vec![1]);
+        assert_eq!((diffs[0].before, diffs[0].after), (Some((0, 1.)), Some((1, 1.))));
+        assert!(diffs[0].changed_entries.is_empty(), "feature 1 moved without changing");
+
+        assert_eq!(diffs[1].tmp_ids, vec![2]);
+        assert_eq!((diffs[1].before, diffs[1].after), (Some((1, 1.)), Some((0, 1.))));
+        let (entry_before, entry_after) = &diffs[1].changed_entries[0];
+        assert_eq!(
+            (entry_before.as_ref().unwrap().scoredist, entry_after.as_ref().unwrap().scoredist),
+            (2., 4.),
+            "feature 2 moved up because its scoredist went up"
+        );
+
+        assert_eq!((diffs[2].tmp_ids.clone(), diffs[2].after), (vec![3], None));
+        assert_eq!((diffs[3].tmp_ids.clone(), diffs[3].before), (vec![4], None));
+
+        assert!(diff_contexts(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn document_at_a_time_test() {
+        let build_store = |zoom: u16, entries: Vec<GridEntry>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                zoom,
+                1,
+                200.,
+                global_bbox_for_zoom(zoom),
+                1.0,
+            )
+            .unwrap()
+        };
+        let grid = |id: u32, x: u16, y: u16, score: u8| GridEntry {
+            id,
+            x,
+            y,
+            relev: 1.,
+            score,
+            source_phrase_hash: 0,
+        };
+
+        // lots of cities, few streets
+        let cities: Vec<GridEntry> =
+            (0..50).map(|i| grid(i + 1, 10 + (i % 10) as u16, 20 + (i / 10) as u16, 3)).collect();
+        let city_store = build_store(6, cities);
+        let street_store = build_store(
+            14,
+            vec![
+                grid(101, 11 * 256 + 3, 20 * 256 + 5, 1),
+                grid(102, 11 * 256 + 9, 20 * 256 + 1, 2),
+                grid(103, 15 * 256 + 9, 23 * 256 + 1, 2),
+                grid(104, 40 * 256, 40 * 256, 7),
+            ],
+        );
+        let subquery = |store, idx: u16, mask: u32| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(&city_store, 0, 1 << 1), subquery(&street_store, 1, 1 << 0)];
+
+        for match_opts in vec![
+            MatchOpts { zoom: 14, ..MatchOpts::default() },
+            MatchOpts { zoom: 14, proximity: Some([11 * 256, 20 * 256]), ..MatchOpts::default() },
+            MatchOpts { zoom: 14, bbox: Some([0, 0, 12 * 256, 21 * 256]), ..MatchOpts::default() },
+        ] {
+            let probed = coalesce_pair_by_probing(&stack[0], &stack[1], &match_opts).unwrap();
+            assert!(probed.is_some(), "the street subquery is selective enough to probe with");
+
+            let hash = coalesce(stack.clone(), &match_opts).unwrap();
+            let match_opts =
+                MatchOpts { join_strategy: JoinStrategy::DocumentAtATime, ..match_opts };
+            let document_at_a_time = coalesce(stack.clone(), &match_opts).unwrap();
+            assert_eq!(hash, document_at_a_time);
+            assert!(hash.len() >= 2);
+            assert!(
+                hash.iter().all(|context| context.entries.len() == 2),
+                "only the streets in a city are returned"
+            );
+        }
+
+        // few cities, lots of streets
+        let city_store = build_store(6, vec![grid(1, 11, 20, 3), grid(2, 12, 20, 1)]);
+        let streets: Vec<GridEntry> = (0..50)
+            .map(|i| grid(100 + i, 11 * 256 + (i as u16) * 10, 20 * 256 + (i as u16) * 3, 1))
+            .collect();
+        let street_store = build_store(14, streets);
+        let stack = vec![subquery(&city_store, 0, 1 << 1), subquery(&street_store, 1, 1 << 0)];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        assert!(coalesce_pair_by_probing(&stack[0], &stack[1], &match_opts).unwrap().is_some());
+        let hash = coalesce(stack.clone(), &match_opts).unwrap();
+        let match_opts = MatchOpts { join_strategy: JoinStrategy::DocumentAtATime, ..match_opts };
+        assert_eq!(hash, coalesce(stack.clone(), &match_opts).unwrap());
+        assert!(hash.iter().any(|context| context.entries[1].grid_entry.id == 2));
+
+        // too many candidates on both sides
+        let stack = vec![subquery(&street_store, 0, 1 << 1), subquery(&street_store, 1, 1 << 0)];
+        assert!(coalesce_pair_by_probing(&stack[0], &stack[1], &MatchOpts::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn check_masks_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new(directory.path()).unwrap();
+        let subquery = |mask| PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask,
+            match_keys: vec![],
+            bbox: None,
+        };
+
+        let stack = vec![subquery(0b011), subquery(0b100)];
+        assert!(check_masks(&stack, &[0..2, 2..3]).is_ok());
+        let err = check_masks(&stack, &[0..1, 2..3]).unwrap_err();
+        assert_eq!(err.to_string(), "subquery 0 has mask 0b11, but its span implies 0b1");
+        assert!(check_masks(&stack, &[0..2]).is_err(), "every subquery needs a span");
+    }
+
+    #[test]
+    fn relev_overrides_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let entries = vec![
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 1, source_phrase_hash: 0 },
+        ];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let stack = vec![PhrasematchSubquery {
+            store: &store,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 3,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        }];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        let ids = |contexts: Vec<CoalesceContext>| -> Vec<(u32, f64)> {
+            contexts.iter().map(|c| (c.entries[0].grid_entry.id, c.relev)).collect()
+        };
+        let baseline = ids(stack_and_coalesce(&stack, &match_opts).unwrap());
+        assert_eq!(baseline[0].0, 1);
+
+        let mut overrides = RelevOverrides::default();
+        overrides.set_feature(1, 1, 0.5);
+        overrides.set_phrase(1, 1, 0.8);
+        let match_opts = MatchOpts { relev_overrides: Some(Arc::new(overrides)), ..match_opts };
+        let overridden = ids(stack_and_coalesce(&stack, &match_opts).unwrap());
+        assert_eq!(overridden[0].0, 2, "feature overrides change the ranking");
+        assert!((overridden[0].1 - baseline[1].1 * 0.8).abs() < 1e-9, "phrase overrides apply");
+    }
+
+    #[test]
+    fn impressions_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries = vec![
+            GridEntry { id: 2, x: 2, y: 2, relev: 0.8, score: 3, source_phrase_hash: 0 },
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 },
+        ];
+        builder.insert(&key, entries).expect("Unable to insert record");
+        builder.finish().unwrap();
+        let store = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.0,
+        )
+        .unwrap();
+
+        let subquery = PhrasematchSubquery {
+            store: &store,
+            idx: 2,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Range { start: 1, end: 2 }, lang_set: 1 },
+                id: 1,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+
+        let mut reported = Vec::new();
+        let contexts = stack_and_coalesce_with_impressions(
+            &vec![subquery],
+            &MatchOpts { zoom: 14, ..MatchOpts::default() },
+            |impressions| reported = impressions,
+        )
+        .unwrap();
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(
+            reported,
+            vec![
+                Impression { id: 1, idx: 2, rank: 0, relev: contexts[0].relev },
+                Impression { id: 2, idx: 2, rank: 1, relev: contexts[1].relev },
+            ],
+            "every surfaced feature is reported with its rank and relevance"
+        );
+    }
+
+    #[test]
+    fn memory_limit_test() {
+        let build_store = |zoom: u16, entries: Vec<GridEntry>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                zoom,
+                1,
+                200.,
+                global_bbox_for_zoom(zoom),
+                1.0,
+            )
+            .unwrap()
+        };
+        let grid = |id: u32, x: u16, y: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y,
+            relev,
+            score: 1,
+            source_phrase_hash: 0,
+        };
+
+        // lots of overlapping cities, of which only the most relevant can be stacked on
+        let cities: Vec<GridEntry> =
+            (0..40).map(|i| grid(i + 1, 11, 20, 0.4 + 0.6 * (i as f64) / 39.)).collect();
+        let city_store = build_store(6, cities);
+        let street_store = build_store(
+            14,
+            vec![
+                grid(101, 11 * 256 + 3, 20 * 256 + 5, 1.),
+                grid(102, 11 * 256 + 9, 20 * 256 + 1, 1.),
+                grid(103, 11 * 256 + 20, 20 * 256 + 7, 1.),
+            ],
+        );
+        let subquery = |store, idx: u16, mask: u32| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(&city_store, 0, 1 << 1), subquery(&street_store, 1, 1 << 0)];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        let unlimited = coalesce(stack.clone(), &match_opts).unwrap();
+        assert_eq!(unlimited.len(), 3);
+
+        let limit = 12 * (mem::size_of::<CoalesceContext>() + mem::size_of::<CoalesceEntry>());
+        let limited = |on_memory_limit: MemoryLimitAction, max_memory_bytes: usize| {
+            let match_opts = MatchOpts {
+                coalesce: CoalesceOpts {
+                    max_memory_bytes: Some(max_memory_bytes),
+                    on_memory_limit,
+                    ..CoalesceOpts::default()
+                },
+                ..match_opts.clone()
+            };
+            coalesce(stack.clone(), &match_opts)
+        };
+
+        let spilled = limited(MemoryLimitAction::Spill, limit).unwrap();
+        assert_eq!(spilled, unlimited, "spilling only dropped cities nothing could stack on");
+
+        let error = limited(MemoryLimitAction::Abort, limit).unwrap_err();
+        match error.downcast_ref::<CoalesceError>() {
+            Some(CoalesceError::MemoryLimitExceeded { used, limit: error_limit }) => {
+                assert!(used > error_limit);
+                assert_eq!(*error_limit, limit);
+            }
+            None => panic!("expected a memory limit error, got {:?}", error),
+        }
+
+        assert!(
+            limited(MemoryLimitAction::Spill, 1).is_err(),
+            "spilling fails if it can't get under the limit"
+        );
+    }
+
+    #[test]
+    fn coalesce_batch_test() {
+        let build_store = |zoom: u16, entries: Vec<GridEntry>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(
+                directory.path(),
+                zoom,
+                1,
+                200.,
+                global_bbox_for_zoom(zoom),
+                1.0,
+            )
+            .unwrap()
+        };
+        let grid = |id: u32, x: u16, y: u16| GridEntry {
+            id,
+            x,
+            y,
+            relev: 1.,
+            score: 1,
+            source_phrase_hash: 0,
+        };
+        let city_store = build_store(6, vec![grid(1, 11, 20), grid(2, 30, 30)]);
+        let street_store = build_store(
+            14,
+            vec![grid(101, 11 * 256 + 3, 20 * 256 + 5), grid(102, 30 * 256 + 9, 30 * 256 + 1)],
+        );
+        let subquery = |store, idx: u16, mask: u32| PhrasematchSubquery {
+            store,
+            idx,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 0.5,
+            mask,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: Exact(1), lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        };
+        let stack = vec![subquery(&city_store, 0, 1 << 1), subquery(&street_store, 1, 1 << 0)];
+        let single = vec![subquery(&street_store, 0, 1 << 0)];
+        let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+        let near = MatchOpts { proximity: Some([30 * 256, 30 * 256]), ..match_opts.clone() };
+        let batch = MatchOpts {
+            coalesce: CoalesceOpts { priority: QueryPriority::Batch, ..CoalesceOpts::default() },
+            ..match_opts.clone()
+        };
+
+        let queries = vec![
+            (stack.clone(), match_opts.clone()),
+            (single.clone(), match_opts.clone()),
+            (stack.clone(), near.clone()),
+            (stack.clone(), match_opts.clone()),
+            (stack.clone(), batch.clone()),
+        ];
+        let results: Vec<Vec<CoalesceContext>> =
+            coalesce_batch(queries.clone()).into_iter().map(Result::unwrap).collect();
+        assert_eq!(results.len(), queries.len());
+        #[cfg(feature = "parallel")]
+        for (stack, match_opts) in queries.iter().cloned() {
+            let entries = |contexts: Vec<CoalesceContext>| {
+                contexts.into_iter().map(|context| context.entries).collect::<Vec<_>>()
+            };
+            assert_eq!(
+                entries(coalesce_parallel(stack.clone(), &match_opts).unwrap()),
+                entries(coalesce(stack, &match_opts).unwrap()),
+                "fetching in parallel changes nothing"
+            );
+        }
+        for ((stack, match_opts), result) in queries.into_iter().zip(results.iter()) {
+            assert_eq!(result, &coalesce(stack, &match_opts).unwrap(), "same as coalescing alone");
+        }
+        assert_eq!(results[0], results[3]);
+        assert_eq!(results[2][0].entries[0].grid_entry.id, 102, "proximity isn't shared");
+    }
 }
@@ -1,13 +1,28 @@
 use core::cmp::{Ordering, Reverse};
 use std::borrow::Borrow;
-
-use crate::gridstore::spatial::adjust_bbox_zoom;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::hash::Hasher;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gridstore::priority::QueryScheduler;
+use crate::gridstore::scoring::{DefaultScorePolicy, ScorePolicy, SharedScorePolicy};
+use crate::gridstore::spatial::{
+    adjust_bbox_zoom, bbox_hull, hilbert_coords, hilbert_index, intersect_bboxes, PolygonMask,
+};
 use crate::gridstore::store::GridStore;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use failure::Error;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::{Error, Fail};
 use fixedbitset::FixedBitSet;
+use fxhash::FxHasher64;
 use min_max_heap::MinMaxHeap;
+use morton::{deinterleave_morton, interleave_morton};
 use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize, Serializer};
 
@@ -15,19 +30,173 @@ use serde::{Deserialize, Serialize, Serializer};
 pub enum TypeMarker {
     SinglePhrase = 0,
     PrefixBin = 1,
+    FeatureIndex = 2,
+    Truncated = 3,
+    TileIndex = 4,
+    Extent = 5,
+    Checksum = 6,
+    HotCopy = 7,
+    RecordExtent = 8,
+}
+
+impl TypeMarker {
+    /// The marker for a section of the store this version of the reader knows how to read
+    pub fn from_u8(marker: u8) -> Option<TypeMarker> {
+        match marker {
+            0 => Some(TypeMarker::SinglePhrase),
+            1 => Some(TypeMarker::PrefixBin),
+            2 => Some(TypeMarker::FeatureIndex),
+            3 => Some(TypeMarker::Truncated),
+            4 => Some(TypeMarker::TileIndex),
+            5 => Some(TypeMarker::Extent),
+            6 => Some(TypeMarker::Checksum),
+            7 => Some(TypeMarker::HotCopy),
+            8 => Some(TypeMarker::RecordExtent),
+            _ => None,
+        }
+    }
+}
+
+/// Version of the store format written by the builder. Minor versions only add sections or
+/// metadata that older readers can skip; anything else bumps the major version.
+///
+/// Major versions:
+/// * 1: phrase ids are 32 bits in keys and prefix bin boundaries
+/// * 2: phrase ids are 64 bits
+/// * 3: record values may be compressed, as recorded in the store's metadata
+/// * 4: coords may carry a sub-tile offset, in a layout older readers can't decode, and records
+///   may order their coords along a Hilbert curve, which older readers would take for z-order
+///
+/// Minor versions:
+/// * 2.1: extents of merged adjacent covers
+/// * 3.1: checksums of record values
+/// * 3.2: relevance bucket weights
+/// * 3.3: copies of the records of the most-read phrases, packed together in a section of their own
+/// * 4.1: extents of merged covers kept per record, rather than per feature and coord across the
+///   whole store
+pub const FORMAT_MAJOR_VERSION: u16 = 4;
+pub const FORMAT_MINOR_VERSION: u16 = 1;
+
+/// How phrase ids are encoded in the keys of a store, which depends on its format version
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PhraseIdWidth {
+    U32,
+    U64,
+}
+
+impl PhraseIdWidth {
+    /// The width of phrase ids in stores with the given major format version
+    pub fn for_format(major: u16) -> Self {
+        if major < 2 {
+            PhraseIdWidth::U32
+        } else {
+            PhraseIdWidth::U64
+        }
+    }
+
+    /// The width of phrase ids in stores written by this version of the builder
+    pub fn current() -> Self {
+        PhraseIdWidth::for_format(FORMAT_MAJOR_VERSION)
+    }
+
+    /// How many bytes a phrase id takes up
+    pub fn bytes(self) -> usize {
+        match self {
+            PhraseIdWidth::U32 => 4,
+            PhraseIdWidth::U64 => 8,
+        }
+    }
+
+    /// The largest phrase id that can be encoded
+    pub fn max_phrase_id(self) -> u64 {
+        match self {
+            PhraseIdWidth::U32 => std::u32::MAX as u64,
+            PhraseIdWidth::U64 => std::u64::MAX,
+        }
+    }
+
+    fn write(self, phrase_id: u64, db_key: &mut Vec<u8>) -> Result<(), Error> {
+        if phrase_id > self.max_phrase_id() {
+            return Err(KeyError::PhraseIdTooLarge { phrase_id, width: self }.into());
+        }
+        match self {
+            PhraseIdWidth::U32 => db_key.write_u32::<BigEndian>(phrase_id as u32)?,
+            PhraseIdWidth::U64 => db_key.write_u64::<BigEndian>(phrase_id)?,
+        }
+        Ok(())
+    }
+
+    /// Reads a phrase id from the start of a key body
+    pub fn read(self, key_body: &[u8]) -> Result<u64, Error> {
+        let mut key_body = key_body;
+        Ok(match self {
+            PhraseIdWidth::U32 => key_body.read_u32::<BigEndian>()? as u64,
+            PhraseIdWidth::U64 => key_body.read_u64::<BigEndian>()?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub struct GridKey {
-    pub phrase_id: u32,
+    pub phrase_id: u64,
     pub lang_set: u128,
 }
 
 impl GridKey {
-    pub fn write_to(&self, type_marker: TypeMarker, db_key: &mut Vec<u8>) -> Result<(), Error> {
+    /// The key for `phrase_id` in the languages with the given ids
+    pub fn with_lang_ids(phrase_id: u64, lang_ids: &[u32]) -> Result<Self, Error> {
+        Ok(GridKey { phrase_id, lang_set: langarray_to_langfield(lang_ids)? })
+    }
+
+    /// The key for `phrase_id` in `languages`, given as codes from `registry`
+    pub fn for_languages(
+        phrase_id: u64,
+        languages: &[&str],
+        registry: &LanguageRegistry,
+    ) -> Result<Self, Error> {
+        Ok(GridKey { phrase_id, lang_set: registry.lang_set(languages)? })
+    }
+
+    pub fn write_to(
+        &self,
+        type_marker: TypeMarker,
+        width: PhraseIdWidth,
+        db_key: &mut Vec<u8>,
+    ) -> Result<(), Error> {
         db_key.push(type_marker as u8);
+        self.write_body_to(width, db_key)
+    }
+
+    /// Writes the key for this GridKey's entry in the feature id => keys index, which sorts
+    /// by feature id first so that all the keys for a feature can be read with one scan
+    pub fn write_feature_index_to(
+        &self,
+        id: u32,
+        width: PhraseIdWidth,
+        db_key: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        db_key.push(TypeMarker::FeatureIndex as u8);
+        db_key.write_u32::<BigEndian>(id)?;
+        self.write_body_to(width, db_key)
+    }
+
+    /// Writes the key for this GridKey's entry in the coarse tile => keys index, which sorts by
+    /// the z-order of the coarse tile first so that all the keys in a tile can be read with one
+    /// scan
+    pub fn write_tile_index_to(
+        &self,
+        tile: u32,
+        width: PhraseIdWidth,
+        db_key: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        db_key.push(TypeMarker::TileIndex as u8);
+        db_key.write_u32::<BigEndian>(tile)?;
+        self.write_body_to(width, db_key)
+    }
+
+    fn write_body_to(&self, width: PhraseIdWidth, db_key: &mut Vec<u8>) -> Result<(), Error> {
         // next goes the ID
-        db_key.write_u32::<BigEndian>(self.phrase_id)?;
+        width.write(self.phrase_id, db_key)?;
         // now the language ID
         match self.lang_set {
             std::u128::MAX => { /* do nothing -- this is the all-languages marker */ }
@@ -44,13 +213,54 @@ impl GridKey {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
+/// Extents (min x, min y, max x, max y) of the merged covers in a record, keyed by feature id and
+/// the z-order coord of the cover that stands in for them
+pub(crate) type CoverExtents = BTreeMap<(u32, u32), [u16; 4]>;
+
+/// Writes the start of the keys of the extents of the merged covers in the record at `record_key`.
+/// The record's key is prefixed with its length, so that no record's extents run on into those
+/// of another record whose key starts the same way.
+pub fn write_extent_prefix(record_key: &[u8], db_key: &mut Vec<u8>) {
+    db_key.push(TypeMarker::RecordExtent as u8);
+    db_key.push(record_key.len() as u8);
+    db_key.extend_from_slice(record_key);
+}
+
+/// Writes the key for the extent of a feature's merged covers in the record at `record_key`,
+/// which is keyed by the z-order coord of the cover that stands in for them
+pub fn write_extent_key(record_key: &[u8], id: u32, zcoord: u32, db_key: &mut Vec<u8>) {
+    write_extent_prefix(record_key, db_key);
+    db_key.extend_from_slice(&id.to_be_bytes());
+    db_key.extend_from_slice(&zcoord.to_be_bytes());
+}
+
+/// Reads an extent back from the feature id and coord its key ends with and its encoded value
+pub fn decode_extent(id_coord: &[u8], value: &[u8]) -> Option<((u32, u32), [u16; 4])> {
+    let id = u32::from_be_bytes(id_coord.get(0..4)?.try_into().ok()?);
+    let zcoord = u32::from_be_bytes(id_coord.get(4..8)?.try_into().ok()?);
+    let mut extent = [0u16; 4];
+    for (i, coord) in value.chunks_exact(2).take(4).enumerate() {
+        extent[i] = u16::from_le_bytes([coord[0], coord[1]]);
+    }
+    Some(((id, zcoord), extent))
+}
+
+/// Adds the extent of a merged cover, growing the one already there to take it in if the cover
+/// already has one
+pub(crate) fn insert_extent(extents: &mut CoverExtents, id: u32, zcoord: u32, extent: [u16; 4]) {
+    extents
+        .entry((id, zcoord))
+        .and_modify(|existing| *existing = bbox_hull(&[*existing, extent]))
+        .or_insert(extent);
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Clone)]
 pub enum MatchPhrase {
-    Exact(u32),
-    Range { start: u32, end: u32 },
+    Exact(u64),
+    Range { start: u64, end: u64 },
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Clone)]
 pub struct MatchKey {
     pub match_phrase: MatchPhrase,
     pub lang_set: u128,
@@ -63,34 +273,61 @@ impl Default for MatchKey {
 }
 
 impl MatchKey {
+    /// A key matching `phrase_id` in any of the languages with the given ids
+    pub fn with_lang_ids(phrase_id: u64, lang_ids: &[u32]) -> Result<Self, Error> {
+        Ok(MatchKey {
+            match_phrase: MatchPhrase::Exact(phrase_id),
+            lang_set: langarray_to_langfield(lang_ids)?,
+        })
+    }
+
+    /// A key matching `phrase_id` in any of `languages`, given as codes from `registry`
+    pub fn for_languages(
+        phrase_id: u64,
+        languages: &[&str],
+        registry: &LanguageRegistry,
+    ) -> Result<Self, Error> {
+        Ok(MatchKey {
+            match_phrase: MatchPhrase::Exact(phrase_id),
+            lang_set: registry.lang_set(languages)?,
+        })
+    }
+
     pub fn write_start_to(
         &self,
         type_marker: TypeMarker,
+        width: PhraseIdWidth,
         db_key: &mut Vec<u8>,
     ) -> Result<(), Error> {
         db_key.push(type_marker as u8);
-        // next goes the ID
+        // next goes the ID. Phrase ids too large for the store can't match anything in it, so
+        // seeking to the last possible id is as good as any.
         let start = match self.match_phrase {
             MatchPhrase::Exact(phrase_id) => phrase_id,
             MatchPhrase::Range { start, .. } => start,
         };
-        db_key.write_u32::<BigEndian>(start)?;
+        width.write(std::cmp::min(start, width.max_phrase_id()), db_key)?;
         Ok(())
     }
 
-    pub fn matches_key(&self, type_marker: TypeMarker, db_key: &[u8]) -> Result<bool, Error> {
-        let key_phrase = (&db_key[1..]).read_u32::<BigEndian>()?;
+    pub fn matches_key(
+        &self,
+        type_marker: TypeMarker,
+        width: PhraseIdWidth,
+        db_key: &[u8],
+    ) -> Result<bool, Error> {
         if db_key[0] != (type_marker as u8) {
             return Ok(false);
         }
+        let key_phrase = width.read(&db_key[1..])?;
         Ok(match self.match_phrase {
             MatchPhrase::Exact(phrase_id) => phrase_id == key_phrase,
             MatchPhrase::Range { start, end } => start <= key_phrase && key_phrase < end,
         })
     }
 
-    pub fn matches_language(&self, db_key: &[u8]) -> Result<bool, Error> {
-        let key_lang_partial = &db_key[5..];
+    pub fn matches_language(&self, width: PhraseIdWidth, db_key: &[u8]) -> Result<bool, Error> {
+        let key_lang_partial = &db_key[1 + width.bytes()..];
         if key_lang_partial.len() == 0 {
             // 0-length language array is the shorthand for "matches everything"
             return Ok(true);
@@ -107,21 +344,837 @@ impl MatchKey {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct MatchOpts {
+    /// Min x, min y, max x and max y of the tiles matching grids have to be in. A min x east of
+    /// the max x is a box that crosses the antimeridian, which `coalesce` and `tree_coalesce`
+    /// split in two; see `split_antimeridian`.
     pub bbox: Option<[u16; 4]>,
+    /// Bboxes matching grids have to be in at least one of, on top of `bbox`, e.g. the user's
+    /// viewport and a country hint. Each is filtered on its own, so this only reads the tiles of
+    /// the boxes themselves rather than of one box around all of them.
+    #[serde(default)]
+    pub bboxes: Option<Arc<Vec<[u16; 4]>>>,
     pub proximity: Option<[u16; 2]>,
     pub zoom: u16,
+    /// Break ties between equally-relevant results with a stable hash of the feature id instead
+    /// of by position, so rankings don't shift when a rebuild reorders equal-score entries
+    #[serde(default)]
+    pub stable_tiebreak: bool,
+    /// Break ties between equally-relevant results with a hash of the feature id and this seed,
+    /// in place of `stable_tiebreak`, so each seed orders tied features differently. Seeding it
+    /// per request, e.g. with `tie_jitter_seed`, spreads exposure fairly across tied features
+    /// for experiments; results that don't tie are ranked the same as without it.
+    #[serde(default)]
+    pub tie_jitter: Option<u64>,
+    /// Break ties between results that are equally relevant and have the same scoredist in favour
+    /// of the one whose leading entry comes from the higher-zoom index, since its location is more
+    /// precise. This is applied before any other tiebreak, which otherwise falls back on idx order.
+    #[serde(default)]
+    pub prefer_higher_zoom: bool,
+    /// Radius in miles beyond which scoredist ignores distance and is driven by score alone
+    #[serde(default)]
+    pub proximity_radius: Option<f64>,
+    /// Relevance multipliers to apply to grids as they're coalesced
+    #[serde(skip)]
+    pub relev_overrides: Option<Arc<RelevOverrides>>,
+    /// Boost to the scoredist of grids that match the query's languages, so that they rank
+    /// ahead of equally relevant grids that don't
+    #[serde(default)]
+    pub language_boost: Option<LanguageBoost>,
+    /// How the grids of multi-subquery stacks are joined
+    #[serde(default)]
+    pub join_strategy: JoinStrategy,
+    /// Limits on the work coalesce does for a query
+    #[serde(default)]
+    pub coalesce: CoalesceOpts,
+    /// Tiles to drop matching grids in, e.g. known-bad regions while a data fix lands
+    #[serde(skip)]
+    pub exclude_tiles: Option<Arc<TileMask>>,
+    /// Region outside of which matching grids are dropped, e.g. a city boundary
+    #[serde(skip)]
+    pub polygon: Option<Arc<PolygonMask>>,
+    /// Also give each result entry the longitude and latitude of this point of its tile
+    #[serde(default)]
+    pub lonlat: Option<TileAnchor>,
+    /// Ranking to use in place of the built-in one
+    #[serde(skip)]
+    pub score_policy: Option<SharedScorePolicy>,
+    /// Stops the query partway through with `QueryError::Cancelled` once it's cancelled
+    #[serde(skip)]
+    pub cancellation: Option<CancellationToken>,
+    /// Results to leave out, e.g. places the caller knows are closed. They're left out before
+    /// results are cut down to `MAX_CONTEXTS`, and don't count towards the relevance gate, so
+    /// they don't cost the query any of the results it would otherwise return. Only `coalesce`
+    /// honors this.
+    #[serde(skip)]
+    pub context_filter: Option<ContextFilter>,
+    /// Shared with the other queries in the process that batch queries should yield to; see
+    /// `CoalesceOpts::priority`
+    #[serde(skip)]
+    pub scheduler: Option<QueryScheduler>,
+}
+
+/// Limits on the work coalesce does for a query
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CoalesceOpts {
+    /// Approximate cap in bytes on the intermediate results of joining a multi-subquery stack.
+    /// Falls back to `Limits::max_memory_bytes` if unset.
+    #[serde(default)]
+    pub max_memory_bytes: Option<usize>,
+    /// What to do once the intermediate results outgrow `max_memory_bytes`
+    #[serde(default)]
+    pub on_memory_limit: MemoryLimitAction,
+    /// Skip subqueries whose store returns an error rather than failing the whole query. Stacks
+    /// come back without the skipped subqueries, so they're less relevant than they'd otherwise
+    /// be; `coalesce_with_stats` and `stack_and_coalesce_with_stats` report the failures.
+    #[serde(default)]
+    pub partial_ok: bool,
+    /// How far below the best result a result can be and still be returned; every coalesce
+    /// path gates on this, and it defaults to a fixed 0.25
+    #[serde(default)]
+    pub relevance_gate: RelevanceGate,
+    /// Which queries yield to which when several share a `MatchOpts::scheduler`
+    #[serde(default)]
+    pub priority: QueryPriority,
+    /// Which grids of single-subquery stacks count as the same result, of which only the best
+    /// is kept. Anything but `DedupKey::Id` also stops tree coalesce from collapsing the language
+    /// variants of a stack into one.
+    #[serde(default)]
+    pub dedup: DedupKey,
+    /// Fewest entries a context needs to be returned, e.g. 2 to only return results that stack
+    /// on something. Shorter contexts are left out before relevance gating and result limits
+    /// are applied, so they don't crowd out ones that qualify. 0 or 1 lets any context through.
+    #[serde(default)]
+    pub min_entries_per_context: usize,
+    /// Stop reading grids once the first this many results are settled, for callers that only
+    /// need the first few. What's returned is the same as the start of what an unlimited query
+    /// would return. Only `coalesce` honors this; see also `coalesce_lazy`.
+    #[serde(default)]
+    pub max_contexts: Option<usize>,
+    /// Most grids coalesce reads for a query before it stops and returns the best results it's
+    /// found so far, so pathological queries can't hold a thread for long. Only `coalesce`
+    /// honors this; `coalesce_with_budget` says whether a query ran out.
+    #[serde(default)]
+    pub max_grids_examined: Option<usize>,
+    /// Like `max_grids_examined`, but for how long coalesce reads grids for
+    #[serde(default)]
+    pub time_budget: Option<Duration>,
+}
+
+/// A handle for cancelling queries from another thread, e.g. once the client that asked for them
+/// has disconnected. Clones share one flag, and compare equal only to each other.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancels every query using this token. Queries stop the next time they check it, so some
+    /// may still finish if they were nearly done.
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A caller's rule for which results a query keeps, which compares equal only to its clones. It
+/// can be asked about the same result more than once, so it should be cheap and consistent.
+#[derive(Clone)]
+pub struct ContextFilter(pub Arc<dyn Fn(&CoalesceContext) -> bool + Send + Sync>);
+
+impl fmt::Debug for ContextFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ContextFilter")
+    }
+}
+
+impl PartialEq for ContextFilter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// What identifies a result when deduplicating the grids of a single-subquery stack
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DedupKey {
+    /// The feature id
+    Id,
+    /// The feature id and whether the grid matches the query's languages, so a feature can come
+    /// back once for a language match and once for a cross-language one
+    IdAndLanguage,
+    /// Nothing: every grid is a result, for when ids are reused across sources
+    None,
+}
+
+impl Default for DedupKey {
+    fn default() -> Self {
+        DedupKey::Id
+    }
+}
+
+/// How urgently a query's results are needed. Batch queries wait for the running interactive ones
+/// that share their `QueryScheduler` before starting, for a bounded time, and do their parallel
+/// work in a smaller thread pool of their own, so bulk jobs sharing a process with autocomplete
+/// traffic can't starve it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QueryPriority {
+    Batch,
+    Interactive,
+}
+
+impl Default for QueryPriority {
+    fn default() -> Self {
+        QueryPriority::Interactive
+    }
+}
+
+/// How far below the best result's relevance a result's can be and still be returned. Queries
+/// with more subqueries legitimately spread further, so the gap can widen with stack length.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RelevanceGate {
+    /// The same gap whatever the stack length
+    Fixed(f64),
+    /// `base` for single-subquery stacks, plus `per_subquery` for each subquery past the first,
+    /// up to `max`
+    PerSubquery { base: f64, per_subquery: f64, max: f64 },
+}
+
+impl RelevanceGate {
+    /// The gap for stacks of `subqueries` subqueries
+    pub fn gap(&self, subqueries: usize) -> f64 {
+        match *self {
+            RelevanceGate::Fixed(gap) => gap,
+            RelevanceGate::PerSubquery { base, per_subquery, max } => {
+                let extra = subqueries.saturating_sub(1) as f64 * per_subquery;
+                (base + extra).min(max)
+            }
+        }
+    }
+}
+
+impl Default for RelevanceGate {
+    fn default() -> Self {
+        RelevanceGate::Fixed(0.25)
+    }
+}
+
+/// What coalesce does when its intermediate results outgrow `CoalesceOpts::max_memory_bytes`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MemoryLimitAction {
+    /// Drop contexts that can no longer be returned, then keep only the most relevant context per
+    /// tile, failing only if that still isn't enough. Results may be missing some lower-ranked
+    /// stacks.
+    Spill,
+    /// Fail as soon as the limit is exceeded
+    Abort,
+}
+
+impl Default for MemoryLimitAction {
+    fn default() -> Self {
+        MemoryLimitAction::Spill
+    }
+}
+
+/// How `coalesce` joins the grids of the subqueries in a stack
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JoinStrategy {
+    /// Fetch the grids of every subquery and join them by tile
+    Hash,
+    /// For two-subquery stacks where one subquery has few grids, iterate over those and probe
+    /// the other subquery's store for grids in the same tiles. Falls back to `Hash` otherwise.
+    /// Probes aren't capped at `MAX_GRIDS_PER_PHRASE` grids the way full fetches are, so this can
+    /// find stacks a `Hash` join would miss when the other subquery has that many grids.
+    DocumentAtATime,
+}
+
+impl Default for JoinStrategy {
+    fn default() -> Self {
+        JoinStrategy::Hash
+    }
+}
+
+/// Which point of a result's tile `MatchOpts::lonlat` gives the longitude and latitude of
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TileAnchor {
+    /// The center of the tile
+    Center,
+    /// The northwest corner of the tile, where its x and y start
+    Corner,
+}
+
+/// How the scoredist of a grid that matches the query's languages is boosted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LanguageBoost {
+    /// Added to the scoredist
+    Additive(f64),
+    /// Multiplies the scoredist
+    Multiplicative(f64),
+}
+
+impl LanguageBoost {
+    #[inline]
+    pub fn apply(&self, scoredist: f64) -> f64 {
+        match self {
+            LanguageBoost::Additive(boost) => scoredist + boost,
+            LanguageBoost::Multiplicative(boost) => scoredist * boost,
+        }
+    }
 }
 
 impl Default for MatchOpts {
     fn default() -> Self {
-        MatchOpts { bbox: None, proximity: None, zoom: 16 }
+        MatchOpts {
+            bbox: None,
+            bboxes: None,
+            proximity: None,
+            zoom: 16,
+            stable_tiebreak: false,
+            tie_jitter: None,
+            prefer_higher_zoom: false,
+            proximity_radius: None,
+            relev_overrides: None,
+            language_boost: None,
+            join_strategy: JoinStrategy::Hash,
+            coalesce: CoalesceOpts::default(),
+            exclude_tiles: None,
+            polygon: None,
+            lonlat: None,
+            score_policy: None,
+            cancellation: None,
+            context_filter: None,
+            scheduler: None,
+        }
+    }
+}
+
+/// A stable hash of a feature id (the murmur3 finalizer), used for breaking ties
+#[inline]
+pub fn stable_id_hash(id: u32) -> u32 {
+    let mut h = id;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// Like `stable_id_hash`, but the order it puts ids in depends on `seed` too
+#[inline]
+pub fn seeded_id_hash(id: u32, seed: u64) -> u32 {
+    // splitmix64
+    let mut h = (id as u64) ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+    (h >> 32) as u32
+}
+
+/// A `MatchOpts::tie_jitter` seed for a request id, the same for the same id in every process
+pub fn tie_jitter_seed(request_id: &str) -> u64 {
+    let mut hasher = FxHasher64::default();
+    hasher.write(request_id.as_bytes());
+    hasher.finish()
+}
+
+/// How many keys were cut down to `BuilderOpts::max_entries_per_key` when a store was built
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct TruncationStats {
+    pub keys_truncated: u64,
+    pub entries_dropped: u64,
+}
+
+impl TruncationStats {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(16);
+        encoded.extend_from_slice(&self.keys_truncated.to_le_bytes());
+        encoded.extend_from_slice(&self.entries_dropped.to_le_bytes());
+        encoded
+    }
+
+    pub fn from_bytes(encoded: &[u8]) -> Result<Self, Error> {
+        let mut reader = encoded;
+        let keys_truncated = reader.read_u64::<LittleEndian>()?;
+        let entries_dropped = reader.read_u64::<LittleEndian>()?;
+        Ok(TruncationStats { keys_truncated, entries_dropped })
+    }
+}
+
+/// Checksum of a record value as stored on disk, i.e. after compression
+pub fn record_checksum(value: &[u8]) -> u64 {
+    let mut hasher = FxHasher64::default();
+    hasher.write(value);
+    hasher.finish()
+}
+
+/// Key of the hot copy of the record at `db_key`, for the phrase of the given hot section rank.
+/// Copies are keyed by rank first, so the section is laid out hottest phrase first, and then by
+/// the record's own key, so a phrase's copies are in the same order as its records.
+pub fn write_hot_copy_key(rank: u32, db_key: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.push(TypeMarker::HotCopy as u8);
+    out.extend_from_slice(&rank.to_be_bytes());
+    out.extend_from_slice(&db_key[1..]);
+}
+
+/// Key of the checksum of the record at `db_key`
+pub fn write_checksum_key(db_key: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.push(TypeMarker::Checksum as u8);
+    out.extend_from_slice(db_key);
+}
+
+/// Name of the manifest file a store's builder writes next to its files
+pub const MANIFEST_FILE_NAME: &str = "gridstore.json";
+
+/// A human-readable description of a store, written as JSON to `MANIFEST_FILE_NAME` in its
+/// directory when it's built, for telling what a directory holds without opening it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StoreManifest {
+    /// Major and minor version of the store format
+    pub format_version: (u16, u16),
+    pub generation: u64,
+    /// Hash of the store's records, as in `GridStore::content_hash`
+    pub content_hash: u64,
+    /// How many phrase/language-set keys have records
+    pub keys: u64,
+    /// How many grid entries there are across all keys
+    pub entries: u64,
+    pub truncation: TruncationStats,
+    pub compression: Compression,
+    pub curve: Curve,
+    pub feature_index: bool,
+    /// Zoom levels out of the tile index, if the store has one
+    pub tile_index: Option<u16>,
+    /// Zoom levels out the store has coarse copies at
+    pub coarse_zooms: Vec<u16>,
+    pub relev_weights: Option<[f64; 4]>,
+    /// How many prefix bin boundaries the store has
+    pub bin_boundaries: usize,
+    /// How many phrases have their records copied to the hot section, as in
+    /// `RecordOrder::AccessFrequency`
+    #[serde(default)]
+    pub hot_phrases: usize,
+    /// Version of carmen-core the store was built with
+    pub built_by: String,
+    /// When the store was built, in milliseconds since the Unix epoch
+    pub built_at: u64,
+}
+
+impl StoreManifest {
+    /// Reads the manifest of the store at `path`, if it has one; stores built before manifests
+    /// were written don't
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>, Error> {
+        let manifest_path = path.as_ref().join(MANIFEST_FILE_NAME);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(manifest_path)?;
+        Ok(Some(serde_json::from_reader(std::io::BufReader::new(file))?))
+    }
+
+    /// Writes the manifest to the store directory at `path`
+    pub(crate) fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let file = std::fs::File::create(path.as_ref().join(MANIFEST_FILE_NAME))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+/// How the record values of a store are compressed on disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    /// zstd, at the given compression level. Reading and writing these takes the
+    /// `zstd-compression` feature.
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
     }
 }
 
+impl Compression {
+    pub fn compress(self, value: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(value),
+            #[cfg(feature = "zstd-compression")]
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(&value, level)?),
+            #[cfg(not(feature = "zstd-compression"))]
+            Compression::Zstd(_) => Err(CompressionError::Unsupported { mode: "zstd" }.into()),
+        }
+    }
+
+    pub fn decompress(self, value: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(value.to_vec()),
+            #[cfg(feature = "zstd-compression")]
+            Compression::Zstd(_) => Ok(zstd::stream::decode_all(value)?),
+            #[cfg(not(feature = "zstd-compression"))]
+            Compression::Zstd(_) => Err(CompressionError::Unsupported { mode: "zstd" }.into()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Compression::None => vec![0],
+            Compression::Zstd(level) => {
+                let mut encoded = vec![1];
+                encoded.extend_from_slice(&level.to_le_bytes());
+                encoded
+            }
+        }
+    }
+
+    pub fn from_bytes(encoded: &[u8]) -> Result<Self, Error> {
+        let mut reader = encoded;
+        match reader.read_u8()? {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd(reader.read_i32::<LittleEndian>()?)),
+            mode => Err(CompressionError::UnknownMode { mode }.into()),
+        }
+    }
+}
+
+/// The space-filling curve a store's grouped records order their coords along
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    /// z-order, which bbox queries can skip through a range at a time
+    Morton,
+    /// A Hilbert curve, which keeps nearby tiles closer together along it than z-order does
+    /// at the edges of quadrants, but which bbox queries scan in full
+    Hilbert,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::Morton
+    }
+}
+
+impl Curve {
+    /// The position along this curve of the tile at z-order coord `zcoord`
+    pub fn from_zcoord(self, zcoord: u32) -> u32 {
+        match self {
+            Curve::Morton => zcoord,
+            Curve::Hilbert => {
+                let (x, y) = deinterleave_morton(zcoord);
+                hilbert_index(x, y)
+            }
+        }
+    }
+
+    /// The z-order coord of the tile at `position` along this curve
+    pub fn to_zcoord(self, position: u32) -> u32 {
+        match self {
+            Curve::Morton => position,
+            Curve::Hilbert => {
+                let (x, y) = hilbert_coords(position);
+                interleave_morton(x, y)
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Curve::Morton => vec![0],
+            Curve::Hilbert => vec![1],
+        }
+    }
+
+    pub fn from_bytes(encoded: &[u8]) -> Result<Self, Error> {
+        let mut reader = encoded;
+        match reader.read_u8()? {
+            0 => Ok(Curve::Morton),
+            1 => Ok(Curve::Hilbert),
+            curve => Err(CurveError::UnknownCurve { curve }.into()),
+        }
+    }
+}
+
+/// How a store's records are laid out on disk, which decides how many pages the first queries
+/// against a freshly opened store have to fault in
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordOrder {
+    /// Records in phrase id order only, which keeps phrases sharing a prefix, and so the records
+    /// behind a popular prefix, on neighbouring pages
+    PhraseId,
+    /// Also copy the records of the `max_phrases` phrases with the highest expected access
+    /// frequencies, by phrase id, into a section of their own, hottest phrase first, and read
+    /// those phrases from there, so the hottest records are packed into as few pages as possible.
+    /// Phrases without a frequency, or with a frequency of zero, aren't copied. The copied
+    /// phrases are recorded in the store's metadata, under `~HOT_PHRASES`, in the order copied.
+    AccessFrequency { frequencies: HashMap<u64, u64>, max_phrases: usize },
+}
+
+/// Query-time relevance multipliers for phrases and features, keyed by index, so ranking
+/// experiments can be run without building experimental stores
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RelevOverrides {
+    phrases: HashMap<(u16, u64), f64>,
+    features: HashMap<(u16, u32), f64>,
+}
+
+impl RelevOverrides {
+    pub fn set_phrase(&mut self, idx: u16, phrase_id: u64, multiplier: f64) {
+        self.phrases.insert((idx, phrase_id), multiplier);
+    }
+
+    pub fn set_feature(&mut self, idx: u16, id: u32, multiplier: f64) {
+        self.features.insert((idx, id), multiplier);
+    }
+
+    /// Parses overrides with one per line, either `phrase <idx> <phrase id> <multiplier>` or
+    /// `feature <idx> <feature id> <multiplier>`. Blank lines and lines starting with `#` are
+    /// ignored.
+    pub fn parse(overrides: &str) -> Result<Self, Error> {
+        let mut out = RelevOverrides::default();
+        for (i, line) in overrides.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || OverridesError::InvalidLine { line: i + 1 };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 {
+                return Err(invalid().into());
+            }
+            let idx: u16 = fields[1].parse().map_err(|_| invalid())?;
+            let multiplier: f64 = fields[3].parse().map_err(|_| invalid())?;
+            match fields[0] {
+                "phrase" => {
+                    let id: u64 = fields[2].parse().map_err(|_| invalid())?;
+                    out.set_phrase(idx, id, multiplier)
+                }
+                "feature" => {
+                    let id: u32 = fields[2].parse().map_err(|_| invalid())?;
+                    out.set_feature(idx, id, multiplier)
+                }
+                _ => return Err(invalid().into()),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        RelevOverrides::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The multiplier for a feature matched by a phrase (if the phrase is known) in an index
+    #[inline]
+    pub fn multiplier(&self, idx: u16, phrase_id: Option<u64>, id: u32) -> f64 {
+        let phrase_multiplier = phrase_id
+            .and_then(|phrase_id| self.phrases.get(&(idx, phrase_id)))
+            .cloned()
+            .unwrap_or(1.);
+        let feature_multiplier = self.features.get(&(idx, id)).cloned().unwrap_or(1.);
+        phrase_multiplier * feature_multiplier
+    }
+}
+
+/// Converts an array of language ids into the langfield (lang_set) to use for a GridKey or
+/// MatchKey. Ids past the 128 a langfield holds are an error.
+pub fn langarray_to_langfield(array: &[u32]) -> Result<u128, Error> {
+    let mut out = 0u128;
+    for id in array {
+        if *id >= 128 {
+            return Err(LanguageError::LangIdTooLarge { id: *id }.into());
+        }
+        out |= 1 << id;
+    }
+    Ok(out)
+}
+
+/// The language ids in a langfield, in ascending order
+pub fn langfield_to_langarray(langfield: u128) -> Vec<u32> {
+    (0..128).filter(|id| langfield & (1 << id) != 0).collect()
+}
+
+/// The language codes of the language ids that make up lang_sets, so keys can be built from
+/// codes rather than from hand-assembled bitmasks. A language's id is its position in the list
+/// the registry is made from, which has to be the list the stores were built with.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct LanguageRegistry {
+    ids: HashMap<String, u32>,
+}
+
+impl LanguageRegistry {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(codes: I) -> Self {
+        let ids = codes.into_iter().enumerate().map(|(id, code)| (code.into(), id as u32));
+        LanguageRegistry { ids: ids.collect() }
+    }
+
+    /// Parses a registry with one language code per line, in id order. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn parse(codes: &str) -> Self {
+        LanguageRegistry::new(
+            codes.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')),
+        )
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(LanguageRegistry::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// The id of a language code, if the registry has it
+    pub fn id(&self, code: &str) -> Option<u32> {
+        self.ids.get(code).cloned()
+    }
+
+    /// The lang_set of a list of language codes, which is empty (0) for an empty list
+    pub fn lang_set(&self, codes: &[&str]) -> Result<u128, Error> {
+        let mut ids = Vec::with_capacity(codes.len());
+        for code in codes {
+            match self.id(code) {
+                Some(id) if id < 128 => ids.push(id),
+                Some(id) => {
+                    return Err(LanguageError::IdTooLarge { code: code.to_string(), id }.into())
+                }
+                None => return Err(LanguageError::UnknownCode { code: code.to_string() }.into()),
+            }
+        }
+        langarray_to_langfield(&ids)
+    }
+}
+
+/// A set of tiles at a single (usually coarse) zoom, one bit per tile in z-order
+#[derive(Debug, PartialEq, Clone)]
+pub struct TileMask {
+    zoom: u16,
+    tiles: FixedBitSet,
+}
+
+impl TileMask {
+    /// An empty mask; it takes 4^zoom bits, so it's meant for coarse zooms
+    pub fn new(zoom: u16) -> Self {
+        TileMask { zoom, tiles: FixedBitSet::with_capacity(1 << (2 * zoom as usize)) }
+    }
+
+    pub fn zoom(&self) -> u16 {
+        self.zoom
+    }
+
+    pub fn insert(&mut self, x: u16, y: u16) {
+        self.tiles.insert(interleave_morton(x, y) as usize);
+    }
+
+    /// Whether the tile with z-order coord `zcoord` at `zoom` is masked out. A tile at or past
+    /// the mask's zoom is masked if the mask tile containing it is; a coarser tile only if every
+    /// mask tile within it is.
+    #[inline]
+    pub fn excludes(&self, zcoord: u32, zoom: u16) -> bool {
+        if zoom >= self.zoom {
+            self.tiles.contains((zcoord as usize) >> (2 * (zoom - self.zoom)))
+        } else {
+            // the mask tiles within a coarser tile are a contiguous run of z-order coords
+            let shift = 2 * (self.zoom - zoom);
+            let start = (zcoord as usize) << shift;
+            (start..start + (1 << shift)).all(|i| self.tiles.contains(i))
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+enum KeyError {
+    #[fail(display = "phrase id {} is too large for {:?} keys", phrase_id, width)]
+    PhraseIdTooLarge { phrase_id: u64, width: PhraseIdWidth },
+}
+
+#[derive(Debug, Fail)]
+enum CompressionError {
+    #[fail(display = "unknown compression mode: {}", mode)]
+    UnknownMode { mode: u8 },
+    #[cfg(not(feature = "zstd-compression"))]
+    #[fail(display = "{} compression needs the {}-compression feature", mode, mode)]
+    Unsupported { mode: &'static str },
+}
+
+#[derive(Debug, Fail)]
+enum CurveError {
+    #[fail(display = "unknown space-filling curve: {}", curve)]
+    UnknownCurve { curve: u8 },
+}
+
+#[derive(Debug, Fail)]
+enum OverridesError {
+    #[fail(display = "invalid override on line {}", line)]
+    InvalidLine { line: usize },
+}
+
+#[derive(Debug, Fail)]
+enum LanguageError {
+    #[fail(display = "unknown language code: {}", code)]
+    UnknownCode { code: String },
+    #[fail(display = "language {} has id {}, but lang_sets only hold ids below 128", code, id)]
+    IdTooLarge { code: String, id: u32 },
+    #[fail(display = "language id {} is too large; lang_sets only hold ids below 128", id)]
+    LangIdTooLarge { id: u32 },
+}
+
+#[derive(Debug, Fail)]
+pub enum QueryError {
+    #[fail(display = "query was cancelled")]
+    Cancelled,
+    #[fail(display = "reading from the store at {:?} took longer than {:?}", path, timeout)]
+    ReadTimedOut { path: PathBuf, timeout: Duration },
+}
+
 pub const EARTH_CIRC_IN_MILES: f64 = 24901.0;
 pub const NEARBY_RADIUS: f64 = 25.0;
 
 impl MatchOpts {
+    /// The ranking to coalesce with: `score_policy` if one is set, otherwise the built-in one
+    pub fn ranking(&self) -> &dyn ScorePolicy {
+        match &self.score_policy {
+            Some(policy) => policy.0.as_ref(),
+            None => &DefaultScorePolicy,
+        }
+    }
+
+    /// Whether ties are broken by a hash of the feature id rather than by position, with
+    /// `stable_tiebreak` or `tie_jitter`
+    #[inline]
+    pub fn hashes_ties(&self) -> bool {
+        self.stable_tiebreak || self.tie_jitter.is_some()
+    }
+
+    /// Whether ties are broken by anything other than position: a hash of the feature id, or
+    /// `prefer_higher_zoom`
+    #[inline]
+    pub fn reorders_ties(&self) -> bool {
+        self.hashes_ties() || self.prefer_higher_zoom
+    }
+
+    /// Whether `context` gets past the query's `context_filter`, if it has one
+    #[inline]
+    pub fn keeps_context(&self, context: &CoalesceContext) -> bool {
+        match &self.context_filter {
+            Some(filter) => (filter.0)(context),
+            None => true,
+        }
+    }
+
+    /// Fails with `QueryError::Cancelled` if the query's `cancellation` token has been cancelled
+    #[inline]
+    pub fn check_cancelled(&self) -> Result<(), QueryError> {
+        match &self.cancellation {
+            Some(token) if token.is_cancelled() => Err(QueryError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
     pub fn adjust_to_zoom(&self, target_z: u16) -> MatchOpts {
         if self.zoom == target_z {
             self.clone()
@@ -150,8 +1203,22 @@ impl MatchOpts {
             };
 
             let adjusted_bbox = self.bbox.map(|bbox| adjust_bbox_zoom(bbox, self.zoom, target_z));
-
-            MatchOpts { zoom: target_z, proximity: adjusted_proximity, bbox: adjusted_bbox }
+            let adjusted_bboxes = self.bboxes.as_ref().map(|bboxes| {
+                Arc::new(
+                    bboxes
+                        .iter()
+                        .map(|bbox| adjust_bbox_zoom(*bbox, self.zoom, target_z))
+                        .collect(),
+                )
+            });
+
+            MatchOpts {
+                zoom: target_z,
+                proximity: adjusted_proximity,
+                bbox: adjusted_bbox,
+                bboxes: adjusted_bboxes,
+                ..self.clone()
+            }
         }
     }
 
@@ -185,6 +1252,29 @@ impl MatchOpts {
         constrained.bbox = Some(new_box);
         constrained
     }
+
+    /// These match options with a `bbox` that crosses the antimeridian, e.g. a viewport over the
+    /// Pacific, split into `bboxes` for either side of it, or `None` if it doesn't cross it. Tile
+    /// x coords don't wrap around, so elsewhere a box whose min x is past its max x is empty, as
+    /// the intersection of boxes that don't overlap is; this is done before any such
+    /// intersections.
+    pub fn split_antimeridian(&self) -> Option<MatchOpts> {
+        let bbox = self.bbox?;
+        if bbox[0] <= bbox[2] || bbox[1] > bbox[3] {
+            return None;
+        }
+        let max_x = ((1u32 << self.zoom) - 1) as u16;
+        let halves = [[bbox[0], bbox[1], max_x, bbox[3]], [0, bbox[1], bbox[2], bbox[3]]];
+        let bboxes = match &self.bboxes {
+            Some(bboxes) => bboxes
+                .iter()
+                .flat_map(|bbox| halves.iter().map(move |half| intersect_bboxes(*bbox, *half)))
+                .filter(|bbox| bbox[0] <= bbox[2] && bbox[1] <= bbox[3])
+                .collect(),
+            None => halves.to_vec(),
+        };
+        Some(MatchOpts { bbox: None, bboxes: Some(Arc::new(bboxes)), ..self.clone() })
+    }
 }
 
 #[cfg(test)]
@@ -335,20 +1425,35 @@ mod tests {
         let opts = matchopts_proximity_generator([100, 100], 14);
         assert_eq!(
             opts.with_nearby_only(),
-            MatchOpts { bbox: Some([83, 83, 117, 117]), proximity: Some([100, 100]), zoom: 14 }
+            MatchOpts {
+                bbox: Some([83, 83, 117, 117]),
+                proximity: Some([100, 100]),
+                zoom: 14,
+                ..MatchOpts::default()
+            }
         );
 
         let opts = matchopts_proximity_generator([100, 100], 6);
         assert_eq!(
             opts.with_nearby_only(),
-            MatchOpts { bbox: Some([99, 99, 101, 101]), proximity: Some([100, 100]), zoom: 6 }
+            MatchOpts {
+                bbox: Some([99, 99, 101, 101]),
+                proximity: Some([100, 100]),
+                zoom: 6,
+                ..MatchOpts::default()
+            }
         );
 
         // truncate at the antemeridian
         let opts = matchopts_proximity_generator([5, 5], 14);
         assert_eq!(
             opts.with_nearby_only(),
-            MatchOpts { bbox: Some([0, 0, 22, 22]), proximity: Some([5, 5]), zoom: 14 }
+            MatchOpts {
+                bbox: Some([0, 0, 22, 22]),
+                proximity: Some([5, 5]),
+                zoom: 14,
+                ..MatchOpts::default()
+            }
         );
 
         // test interaction between existing bbox and limiter
@@ -356,16 +1461,44 @@ mod tests {
         opts.bbox = Some([90, 70, 115, 180]);
         assert_eq!(
             opts.with_nearby_only(),
-            MatchOpts { bbox: Some([90, 83, 115, 117]), proximity: Some([100, 100]), zoom: 14 }
+            MatchOpts {
+                bbox: Some([90, 83, 115, 117]),
+                proximity: Some([100, 100]),
+                zoom: 14,
+                ..MatchOpts::default()
+            }
+        );
+    }
+
+    #[test]
+    fn split_antimeridian() {
+        let opts = MatchOpts { zoom: 6, bbox: Some([60, 10, 3, 20]), ..MatchOpts::default() };
+        let split = opts.split_antimeridian().unwrap();
+        assert_eq!(split.bbox, None);
+        assert_eq!(split.bboxes.unwrap().as_slice(), &[[60, 10, 63, 20], [0, 10, 3, 20]]);
+
+        let bboxes = Some(Arc::new(vec![[0, 0, 1, 63], [30, 0, 40, 63], [62, 15, 63, 63]]));
+        let opts = MatchOpts { bboxes, ..opts };
+        assert_eq!(
+            opts.split_antimeridian().unwrap().bboxes.unwrap().as_slice(),
+            &[[0, 10, 1, 20], [62, 15, 63, 20]],
+            "other bboxes are intersected with each side"
         );
+
+        let opts = MatchOpts { zoom: 6, bbox: Some([3, 10, 60, 20]), ..MatchOpts::default() };
+        assert!(opts.split_antimeridian().is_none());
+        let opts = MatchOpts { bbox: Some([60, 20, 3, 10]), ..opts };
+        assert!(opts.split_antimeridian().is_none(), "a box that's empty top to bottom too");
+        assert!(MatchOpts::default().split_antimeridian().is_none());
     }
 }
 
 // keys consist of a marker byte indicating type (regular entry, prefix cache, etc.) followed by
-// a 32-bit phrase ID followed by a variable-length set of bytes for language -- everything after
-// the phrase ID is assumed to be language, and it might be up to 128 bits long, but we'll strip
-// leading (in a big-endian sense/most-significant sense) zero bytes for compactness
-pub const MAX_KEY_LENGTH: usize = 1 + (32 / 8) + (128 / 8);
+// a 64-bit phrase ID (32-bit before format 2) followed by a variable-length set of bytes for
+// language -- everything after the phrase ID is assumed to be language, and it might be up to 128
+// bits long, but we'll strip leading (in a big-endian sense/most-significant sense) zero bytes for
+// compactness
+pub const MAX_KEY_LENGTH: usize = 1 + (64 / 8) + (128 / 8);
 
 // The max number of contexts to return from Coalesce
 pub const MAX_CONTEXTS: usize = 40;
@@ -387,12 +1520,17 @@ pub struct GridEntry {
     pub source_phrase_hash: u8,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialOrd, PartialEq, Clone)]
 pub struct MatchEntry {
     pub grid_entry: GridEntry,
     pub matches_language: bool,
     pub distance: f64,
     pub scoredist: f64,
+    /// Whether the entries of the phrase this came from were truncated when its store was built,
+    /// so the phrase may have matches that weren't returned. Entries from a prefix bin can't be
+    /// traced to their phrase, so they're flagged if any phrase in the range read was truncated.
+    #[serde(default)]
+    pub truncated: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialOrd, PartialEq, Clone)]
@@ -405,12 +1543,23 @@ pub struct CoalesceEntry {
     pub distance: f64,
     pub scoredist: f64,
     pub phrasematch_id: u32,
+    /// [longitude, latitude] of the entry's tile, if asked for with `MatchOpts::lonlat`
+    #[serde(default)]
+    pub lonlat: Option<[f64; 2]>,
+    /// Zoom of the index the entry came from
+    #[serde(default)]
+    pub zoom: u16,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CoalesceContext {
     pub mask: u32,
     pub relev: f64,
+    /// How sure coalesce is, from 0 to 1, that this is the result the query was after, going by
+    /// how much of the query it covers and how far it's ahead of or behind the runner-up. Only
+    /// meaningful on returned results.
+    #[serde(default)]
+    pub confidence: f64,
     pub entries: Vec<CoalesceEntry>,
 }
 
@@ -445,6 +1594,43 @@ impl PartialEq for CoalesceContext {
 }
 impl Eq for CoalesceContext {}
 
+/// A `CoalesceEntry` trimmed down to what's needed to identify and place a result, for callers
+/// that don't need masks, tmp ids or distances
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CompactCoalesceEntry {
+    pub id: u32,
+    pub idx: u16,
+    pub x: u16,
+    pub y: u16,
+    pub relev: f64,
+}
+
+/// A `CoalesceContext` made of `CompactCoalesceEntry`s
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CompactCoalesceContext {
+    pub relev: f64,
+    pub entries: Vec<CompactCoalesceEntry>,
+}
+
+impl From<&CoalesceContext> for CompactCoalesceContext {
+    fn from(context: &CoalesceContext) -> Self {
+        CompactCoalesceContext {
+            relev: context.relev,
+            entries: context
+                .entries
+                .iter()
+                .map(|entry| CompactCoalesceEntry {
+                    id: entry.grid_entry.id,
+                    idx: entry.idx,
+                    x: entry.grid_entry.x,
+                    y: entry.grid_entry.y,
+                    relev: entry.grid_entry.relev,
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MatchKeyWithId {
     pub key: MatchKey,
@@ -477,6 +1663,74 @@ pub struct PhrasematchSubquery<T: Borrow<GridStore> + Clone> {
     pub weight: f64,
     pub mask: u32,
     pub match_keys: Vec<MatchKeyWithId>,
+    /// Optional bbox, at the zoom of this subquery's store, that this subquery is limited to on
+    /// top of the bbox in MatchOpts, e.g. the known extent of a country-specific index
+    pub bbox: Option<[u16; 4]>,
+}
+
+impl<T: Borrow<GridStore> + Clone> PhrasematchSubquery<T> {
+    /// Intersects this subquery's bbox, if it has one, with the bbox of match options that have
+    /// already been adjusted to the zoom of its store. Boxes that don't overlap intersect in an
+    /// empty bbox, whose min is past its max, which matches nothing.
+    pub fn override_bbox(&self, match_opts: &MatchOpts) -> MatchOpts {
+        debug_assert!(match_opts.zoom == self.store.borrow().zoom);
+        let bbox = match (self.bbox, match_opts.bbox) {
+            (Some(bbox), Some(global)) => intersect_bboxes(bbox, global),
+            (Some(bbox), None) => bbox,
+            (None, _) => return match_opts.clone(),
+        };
+        MatchOpts { bbox: Some(bbox), ..match_opts.clone() }
+    }
+}
+
+/// Computes the mask of each subquery from the range of token positions it covers, with bit `i`
+/// set for token `i`
+pub fn infer_masks(spans: &[Range<usize>]) -> Result<Vec<u32>, Error> {
+    spans
+        .iter()
+        .enumerate()
+        .map(|(subquery, span)| {
+            if span.start >= span.end {
+                return Err(MaskError::EmptySpan { subquery }.into());
+            }
+            if span.end > 32 {
+                return Err(MaskError::TooManyTokens { subquery, end: span.end }.into());
+            }
+            Ok(span.clone().fold(0u32, |mask, token| mask | (1 << token)))
+        })
+        .collect()
+}
+
+/// Checks the masks of a stack of subqueries against the token spans they're meant to cover, in
+/// the same order, and reports the first subquery whose mask doesn't match its span
+pub fn check_masks<T: Borrow<GridStore> + Clone>(
+    stack: &[PhrasematchSubquery<T>],
+    spans: &[Range<usize>],
+) -> Result<(), Error> {
+    if stack.len() != spans.len() {
+        return Err(MaskError::SpanCount { subqueries: stack.len(), spans: spans.len() }.into());
+    }
+    for (subquery, (phrasematch, expected)) in stack.iter().zip(infer_masks(spans)?).enumerate() {
+        if phrasematch.mask != expected {
+            return Err(MaskError::Mismatch { subquery, expected, actual: phrasematch.mask }.into());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Fail)]
+enum MaskError {
+    #[fail(display = "subquery {} covers no tokens", subquery)]
+    EmptySpan { subquery: usize },
+    #[fail(display = "subquery {} ends at token {}, but masks only fit 32 tokens", subquery, end)]
+    TooManyTokens { subquery: usize, end: usize },
+    #[fail(display = "{} subqueries but {} spans", subqueries, spans)]
+    SpanCount { subqueries: usize, spans: usize },
+    #[fail(
+        display = "subquery {} has mask {:#b}, but its span implies {:#b}",
+        subquery, actual, expected
+    )]
+    Mismatch { subquery: usize, expected: u32, actual: u32 },
 }
 
 fn serialize_fixedbitset<S>(bits: &FixedBitSet, serializer: S) -> Result<S::Ok, S::Error>
@@ -656,3 +1910,72 @@ fn eager_test() {
         ]
     );
 }
+
+#[test]
+fn relev_overrides_test() {
+    let overrides = RelevOverrides::parse(
+        "# boost a phrase in index 1 and bury a feature
+        phrase 1 10 1.5
+
+        feature 1 7 0.5
+        ",
+    )
+    .unwrap();
+    assert_eq!(overrides.multiplier(1, Some(10), 7), 0.75, "phrase and feature overrides stack");
+    assert_eq!(overrides.multiplier(1, None, 7), 0.5);
+    assert_eq!(overrides.multiplier(2, Some(10), 7), 1., "overrides are per index");
+
+    assert!(RelevOverrides::parse("phrase 1 10").is_err());
+    assert!(RelevOverrides::parse("score 1 10 1.5").is_err());
+    assert!(RelevOverrides::parse("feature 1 ten 1.5").is_err());
+}
+
+#[test]
+fn language_registry_test() {
+    let registry = LanguageRegistry::parse(
+        "# ids are line order
+        en
+        fr
+
+        de
+        ",
+    );
+    assert_eq!(registry.id("de"), Some(2));
+    assert_eq!(
+        MatchKey::for_languages(7, &["en", "de"], &registry).unwrap(),
+        MatchKey { match_phrase: MatchPhrase::Exact(7), lang_set: 0b101 }
+    );
+    assert_eq!(
+        GridKey::for_languages(7, &["fr"], &registry).unwrap(),
+        GridKey::with_lang_ids(7, &[1]).unwrap()
+    );
+    assert_eq!(registry.lang_set(&[]).unwrap(), 0);
+    assert!(registry.lang_set(&["xx"]).is_err(), "unknown codes are rejected");
+
+    let registry = LanguageRegistry::new((0..130).map(|id| format!("l{}", id)));
+    assert_eq!(registry.lang_set(&["l127"]).unwrap(), 1 << 127);
+    assert!(registry.lang_set(&["l128"]).is_err(), "ids past 127 don't fit in a lang_set");
+}
+
+#[test]
+fn langfield_test() {
+    let langfield = langarray_to_langfield(&[0, 5, 127]).unwrap();
+    assert_eq!(langfield, 1 | 1 << 5 | 1 << 127);
+    assert_eq!(langfield_to_langarray(langfield), vec![0, 5, 127]);
+    assert_eq!(langarray_to_langfield(&[]).unwrap(), 0);
+    assert!(langarray_to_langfield(&[3, 128]).is_err(), "ids past 127 don't fit");
+    assert_eq!(
+        MatchKey::with_lang_ids(2, &[1, 3]).unwrap(),
+        MatchKey { match_phrase: MatchPhrase::Exact(2), lang_set: 0b1010 }
+    );
+    assert!(GridKey::with_lang_ids(2, &[200]).is_err());
+}
+
+#[test]
+fn infer_masks_test() {
+    // "main st springfield": "main st" in one index, "springfield" in another, and "st" alone
+    assert_eq!(infer_masks(&[0..2, 2..3, 1..2]).unwrap(), vec![0b011, 0b100, 0b010]);
+    assert_eq!(infer_masks(&[31..32]).unwrap(), vec![1 << 31]);
+    assert!(infer_masks(&[0..2, 1..1]).is_err(), "empty spans are rejected");
+    assert!(infer_masks(&[30..33]).is_err(), "spans past the 32nd token are rejected");
+}
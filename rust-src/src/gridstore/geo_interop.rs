@@ -0,0 +1,202 @@
+//! Conversions between the tile coordinates used for queries and results and `geo-types`
+//! geometries in longitude and latitude, plus helpers for getting query bboxes and proximity
+//! points out of GeoJSON.
+//!
+//! Tiles are Web Mercator tiles, with y increasing southward.
+use std::f64::consts::PI;
+
+use failure::{Error, Fail};
+use geo_types::{Point, Rect};
+use geojson::{GeoJson, Geometry, Value};
+
+use crate::gridstore::common::MatchOpts;
+use crate::gridstore::spatial::{x_to_lon, y_to_lat};
+
+/// The furthest latitude from the equator Web Mercator tiles cover
+const MAX_LAT: f64 = 85.051_128_779_806_59;
+
+/// A tile at a zoom, such as a result's grid or a query's proximity point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub zoom: u16,
+    pub x: u16,
+    pub y: u16,
+}
+
+/// A range of tiles at a zoom, as in `MatchOpts::bbox`: [min x, min y, max x, max y]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileRange {
+    pub zoom: u16,
+    pub bbox: [u16; 4],
+}
+
+fn lon_to_x(lon: f64, zoom: u16) -> u16 {
+    let tiles = (1u32 << zoom) as f64;
+    let x = ((lon + 180.) / 360. * tiles).floor();
+    x.max(0.).min(tiles - 1.) as u16
+}
+
+fn lat_to_y(lat: f64, zoom: u16) -> u16 {
+    let tiles = (1u32 << zoom) as f64;
+    let lat = lat.max(-MAX_LAT).min(MAX_LAT).to_radians();
+    let y = ((1. - (lat.tan() + 1. / lat.cos()).ln() / PI) / 2. * tiles).floor();
+    y.max(0.).min(tiles - 1.) as u16
+}
+
+impl Tile {
+    /// The tile containing a point
+    pub fn from_point(point: Point<f64>, zoom: u16) -> Self {
+        Tile { zoom, x: lon_to_x(point.x(), zoom), y: lat_to_y(point.y(), zoom) }
+    }
+}
+
+impl From<Tile> for Point<f64> {
+    /// The center of the tile
+    fn from(tile: Tile) -> Self {
+        Point::new(
+            x_to_lon(tile.x as f64 + 0.5, tile.zoom),
+            y_to_lat(tile.y as f64 + 0.5, tile.zoom),
+        )
+    }
+}
+
+impl TileRange {
+    /// The tiles covering a rectangle
+    pub fn from_rect(rect: Rect<f64>, zoom: u16) -> Self {
+        let (min, max) = (rect.min(), rect.max());
+        TileRange {
+            zoom,
+            bbox: [
+                lon_to_x(min.x, zoom),
+                lat_to_y(max.y, zoom),
+                lon_to_x(max.x, zoom),
+                lat_to_y(min.y, zoom),
+            ],
+        }
+    }
+}
+
+impl From<TileRange> for Rect<f64> {
+    /// The area covered by the tiles
+    fn from(range: TileRange) -> Self {
+        let [min_x, min_y, max_x, max_y] = range.bbox;
+        Rect::new(
+            (x_to_lon(min_x as f64, range.zoom), y_to_lat(max_y as f64 + 1., range.zoom)),
+            (x_to_lon(max_x as f64 + 1., range.zoom), y_to_lat(min_y as f64, range.zoom)),
+        )
+    }
+}
+
+impl MatchOpts {
+    /// Match options at `zoom` limited to `bbox` and sorted by proximity to `proximity`
+    pub fn from_geo(zoom: u16, bbox: Option<Rect<f64>>, proximity: Option<Point<f64>>) -> Self {
+        MatchOpts {
+            zoom,
+            bbox: bbox.map(|rect| TileRange::from_rect(rect, zoom).bbox),
+            proximity: proximity.map(|point| {
+                let tile = Tile::from_point(point, zoom);
+                [tile.x, tile.y]
+            }),
+            ..MatchOpts::default()
+        }
+    }
+}
+
+/// Parses a GeoJSON geometry, or the geometry of a GeoJSON feature
+pub fn parse_geometry(geojson: &str) -> Result<Geometry, Error> {
+    match geojson.parse::<GeoJson>()? {
+        GeoJson::Geometry(geometry) => Ok(geometry),
+        GeoJson::Feature(feature) => Ok(feature.geometry.ok_or(GeoInteropError::NoGeometry)?),
+        GeoJson::FeatureCollection(_) => Err(GeoInteropError::NoGeometry.into()),
+    }
+}
+
+/// A GeoJSON point geometry as a proximity point
+pub fn point_from_geometry(geometry: &Geometry) -> Result<Point<f64>, Error> {
+    match &geometry.value {
+        Value::Point(position) if position.len() >= 2 => Ok(Point::new(position[0], position[1])),
+        _ => Err(GeoInteropError::NotAPoint.into()),
+    }
+}
+
+/// The bounding box of any GeoJSON geometry, as a query bbox
+pub fn rect_from_geometry(geometry: &Geometry) -> Result<Rect<f64>, Error> {
+    let mut bounds: Option<[f64; 4]> = None;
+    extend_bounds(&geometry.value, &mut bounds);
+    let [min_x, min_y, max_x, max_y] = bounds.ok_or(GeoInteropError::EmptyGeometry)?;
+    Ok(Rect::new((min_x, min_y), (max_x, max_y)))
+}
+
+fn extend_bounds(value: &Value, bounds: &mut Option<[f64; 4]>) {
+    let mut add = |position: &Vec<f64>| {
+        if position.len() < 2 {
+            return;
+        }
+        let (x, y) = (position[0], position[1]);
+        *bounds = Some(match *bounds {
+            Some([min_x, min_y, max_x, max_y]) => {
+                [min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)]
+            }
+            None => [x, y, x, y],
+        });
+    };
+    match value {
+        Value::Point(position) => add(position),
+        Value::MultiPoint(positions) | Value::LineString(positions) => {
+            positions.iter().for_each(add)
+        }
+        Value::MultiLineString(lines) | Value::Polygon(lines) => {
+            lines.iter().flatten().for_each(add)
+        }
+        Value::MultiPolygon(polygons) => polygons.iter().flatten().flatten().for_each(add),
+        Value::GeometryCollection(geometries) => {
+            for geometry in geometries {
+                extend_bounds(&geometry.value, bounds);
+            }
+        }
+    }
+}
+
+#[test]
+fn tile_conversion_test() {
+    let tile = Tile::from_point(Point::new(-77.03, 38.9), 14);
+    assert_eq!(tile, Tile { zoom: 14, x: 4686, y: 6267 });
+    let center: Point<f64> = tile.into();
+    assert_eq!(Tile::from_point(center, 14), tile, "a tile's center is in the tile");
+
+    let range = TileRange { zoom: 2, bbox: [0, 0, 3, 3] };
+    let rect: Rect<f64> = range.into();
+    assert!((rect.min().x + 180.).abs() < 1e-9 && (rect.max().x - 180.).abs() < 1e-9);
+    assert!((rect.max().y - MAX_LAT).abs() < 1e-9, "the world's north edge is Mercator's");
+    assert_eq!(TileRange::from_rect(rect, 2), range);
+
+    let match_opts = MatchOpts::from_geo(2, Some(rect), Some(Point::new(0., 0.)));
+    assert_eq!(match_opts.bbox, Some([0, 0, 3, 3]));
+    assert_eq!(match_opts.proximity, Some([2, 2]));
+}
+
+#[test]
+fn geometry_test() {
+    let point = parse_geometry(r#"{"type": "Point", "coordinates": [1.5, 2.5]}"#).unwrap();
+    assert_eq!(point_from_geometry(&point).unwrap(), Point::new(1.5, 2.5));
+
+    let feature = parse_geometry(
+        r#"{"type": "Feature", "properties": {}, "geometry":
+            {"type": "LineString", "coordinates": [[0, 1], [3, -2], [2, 4]]}}"#,
+    )
+    .unwrap();
+    assert!(point_from_geometry(&feature).is_err(), "only points are proximity points");
+    assert_eq!(rect_from_geometry(&feature).unwrap(), Rect::new((0., -2.), (3., 4.)));
+
+    assert!(parse_geometry(r#"{"type": "FeatureCollection", "features": []}"#).is_err());
+}
+
+#[derive(Debug, Fail)]
+enum GeoInteropError {
+    #[fail(display = "no geometry in GeoJSON")]
+    NoGeometry,
+    #[fail(display = "geometry is not a point")]
+    NotAPoint,
+    #[fail(display = "geometry has no coordinates")]
+    EmptyGeometry,
+}
@@ -1,6 +1,8 @@
 use std::convert::TryInto;
 use std::marker::PhantomData;
 
+#[cfg(any(test, feature = "checked-decode", feature = "shared-cache"))]
+use failure::Fail;
 use integer_encoding::VarInt;
 
 #[derive(Copy, Clone)]
@@ -423,15 +425,26 @@ impl VarEncodable for RelevScore {
     }
 }
 
+/// Coords in a vector where any coord has a sub-tile offset are written as the coord, a byte
+/// that's 1 if the coord has an offset and 0 if not, the offset and a full-width pointer to the
+/// ids, a size that coords in vectors without offsets never take
+pub const COORD_WITH_OFFSET_SIZE: usize = 10;
+
 #[derive(Copy, Clone)]
 pub struct Coord {
     pub coord: u32,
     pub ids: FixedVecOffset<u32>,
+    /// Where in the tile the features at this coord are, in sixteenths of the tile from its top
+    /// left corner: x in the high four bits and y in the low four
+    pub offset: Option<u8>,
 }
 
 impl UniformEncodable for Coord {
-    const MAX_SIZE: usize = 8;
+    const MAX_SIZE: usize = COORD_WITH_OFFSET_SIZE;
     fn get_min_size(&self) -> usize {
+        if self.offset.is_some() {
+            return COORD_WITH_OFFSET_SIZE;
+        }
         match self.ids.addr {
             0..=255 => 4 + 1,
             256..=65535 => 4 + 2,
@@ -442,18 +455,31 @@ impl UniformEncodable for Coord {
 
     fn write_with_size_to(&self, size: usize, buffer: &mut Vec<u8>) {
         buffer.extend_from_slice(&(self.coord as u32).to_le_bytes());
-        buffer.extend_from_slice(&(self.ids.addr as u32).to_le_bytes()[..(size - 4)]);
+        if size == COORD_WITH_OFFSET_SIZE {
+            buffer.push(self.offset.is_some() as u8);
+            buffer.push(self.offset.unwrap_or(0));
+            buffer.extend_from_slice(&(self.ids.addr as u32).to_le_bytes());
+        } else {
+            buffer.extend_from_slice(&(self.ids.addr as u32).to_le_bytes()[..(size - 4)]);
+        }
     }
 
     fn read_with_size_from(buffer: &[u8], size: usize, offset: UniformScalarOffset<Self>) -> Self {
         let coord = u32::from_le_bytes(buffer[offset.addr..(offset.addr + 4)].try_into().unwrap());
-        let ptr_size = size - 4;
+        let (sub_tile_offset, ptr_start, ptr_size) = if size == COORD_WITH_OFFSET_SIZE {
+            let sub_tile_offset = match buffer[offset.addr + 4] {
+                0 => None,
+                _ => Some(buffer[offset.addr + 5]),
+            };
+            (sub_tile_offset, offset.addr + 6, 4)
+        } else {
+            (None, offset.addr + 4, size - 4)
+        };
         let mut ptr_buf = [0u8; 4];
-        ptr_buf[..ptr_size]
-            .clone_from_slice(&buffer[(offset.addr + 4)..(offset.addr + 4 + ptr_size)]);
+        ptr_buf[..ptr_size].clone_from_slice(&buffer[ptr_start..(ptr_start + ptr_size)]);
         let ptr = u32::from_le_bytes(ptr_buf);
         let ids = FixedVecOffset::<u32>::new(ptr as usize);
-        Coord { coord, ids }
+        Coord { coord, ids, offset: sub_tile_offset }
     }
 }
 
@@ -489,6 +515,121 @@ impl FixedEncodable for PhraseRecord {
     }
 }
 
+/// Records with exactly one entry are written inline as the relev/score byte, the z-order coord
+/// and the id (with its source phrase hash), with no vectors or pointers. A regular record can't
+/// be this size: an empty one is 5 bytes, and one with any entries is at least 19.
+pub const SINGLE_ENTRY_SIZE: usize = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SingleEntry {
+    pub relev_score: u8,
+    pub coord: u32,
+    pub id: u32,
+}
+
+impl SingleEntry {
+    pub fn write(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(SINGLE_ENTRY_SIZE);
+        buffer.push(self.relev_score);
+        buffer.extend_from_slice(&self.coord.to_le_bytes());
+        buffer.extend_from_slice(&self.id.to_le_bytes());
+        buffer
+    }
+
+    /// Reads a record written by `SingleEntry::write`, or returns None for a regular record
+    #[inline]
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() != SINGLE_ENTRY_SIZE {
+            return None;
+        }
+        Some(SingleEntry {
+            relev_score: buffer[0],
+            coord: u32::from_le_bytes(buffer[1..5].try_into().unwrap()),
+            id: u32::from_le_bytes(buffer[5..9].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(any(test, feature = "checked-decode", feature = "shared-cache"))]
+#[derive(Debug, Fail)]
+pub enum FormatError {
+    #[fail(display = "malformed record: {} at byte {}", what, offset)]
+    Malformed { what: &'static str, offset: usize },
+}
+
+/// Reads a varint the same way `VarInt::decode_var` does, but fails instead of running off the end
+/// of `data` or reading more bytes than a u32 takes
+#[cfg(any(test, feature = "checked-decode", feature = "shared-cache"))]
+fn checked_decode_var(data: &[u8], offset: usize) -> Result<(u32, usize), FormatError> {
+    let mut result: u64 = 0;
+    for len in 0..5 {
+        let byte = *data
+            .get(offset + len)
+            .ok_or(FormatError::Malformed { what: "truncated varint", offset })?;
+        result |= ((byte & 0x7f) as u64) << (7 * len);
+        if byte & 0x80 == 0 {
+            return Ok((result as u32, len + 1));
+        }
+    }
+    Err(FormatError::Malformed { what: "varint too long", offset })
+}
+
+/// Checks that a vector of `len` items of `item_size` bytes starting at `start` fits in `data`
+#[cfg(any(test, feature = "checked-decode", feature = "shared-cache"))]
+fn check_vec_bounds(
+    data: &[u8],
+    start: usize,
+    len: u32,
+    item_size: usize,
+) -> Result<(), FormatError> {
+    let end = (len as usize).checked_mul(item_size).and_then(|size| size.checked_add(start));
+    match end {
+        Some(end) if end <= data.len() => Ok(()),
+        _ => Err(FormatError::Malformed { what: "vector out of bounds", offset: start }),
+    }
+}
+
+/// Walks every offset and length in a record, checking that it's in bounds, so that decoding a
+/// record that passes can't read outside of it. Inline single-entry records always pass.
+#[cfg(any(test, feature = "checked-decode", feature = "shared-cache"))]
+pub fn validate_record(buffer: &[u8]) -> Result<(), FormatError> {
+    if SingleEntry::read(buffer).is_some() {
+        return Ok(());
+    }
+    if buffer.len() < PhraseRecord::SIZE {
+        return Err(FormatError::Malformed { what: "record too short", offset: 0 });
+    }
+    let record = read_phrase_record_from(&Reader::new(buffer));
+
+    let (rs_len, rs_len_len) = checked_decode_var(buffer, record.relev_scores.addr)?;
+    let mut loc = record.relev_scores.addr + rs_len_len;
+    for _ in 0..rs_len {
+        if loc >= buffer.len() {
+            return Err(FormatError::Malformed { what: "relev score out of bounds", offset: loc });
+        }
+        let (coords_addr, addr_len) = checked_decode_var(buffer, loc + 1)?;
+        loc += 1 + addr_len;
+
+        let coords_addr = coords_addr as usize;
+        let (coords_len, coords_len_len) = checked_decode_var(buffer, coords_addr)?;
+        let rec_size = match buffer.get(coords_addr + coords_len_len) {
+            Some(size @ 5..=8) | Some(size @ 10) => *size as usize,
+            _ => {
+                return Err(FormatError::Malformed { what: "bad coord size", offset: coords_addr })
+            }
+        };
+        let coords_start = coords_addr + coords_len_len + 1;
+        check_vec_bounds(buffer, coords_start, coords_len, rec_size)?;
+        for i in 0..(coords_len as usize) {
+            let coord_offset = UniformScalarOffset::new(coords_start + i * rec_size);
+            let coord = Coord::read_with_size_from(buffer, rec_size, coord_offset);
+            let (ids_len, ids_len_len) = checked_decode_var(buffer, coord.ids.addr)?;
+            check_vec_bounds(buffer, coord.ids.addr + ids_len_len, ids_len, u32::SIZE)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 use itertools::Itertools;
 
@@ -528,7 +669,7 @@ fn test_write() {
         for (coord, coord_group) in &rs_group.into_iter().group_by(|g| g.coord) {
             let ids: Vec<_> = coord_group.into_iter().map(|g| g.id).dedup().collect();
             let w_ids = writer.write_fixed_vec(&ids);
-            coords.push(Coord { coord, ids: w_ids });
+            coords.push(Coord { coord, ids: w_ids, offset: None });
         }
         let w_coords = writer.write_uniform_vec(&coords);
         rses.push(RelevScore { relev_score, coords: w_coords });
@@ -565,3 +706,87 @@ fn test_write() {
     let deduped_grids: Vec<_> = grids.iter().cloned().dedup().collect();
     assert_eq!(deduped_grids, out_grids);
 }
+
+#[test]
+fn test_coord_offsets() {
+    let mut writer = Writer::new();
+    let ids = writer.write_fixed_vec(&[1u32, 2]);
+    let plain = writer.write_uniform_vec(&[Coord { coord: 7, ids, offset: None }]);
+    let offset = writer.write_uniform_vec(&[
+        Coord { coord: 7, ids, offset: Some(0x2f) },
+        Coord { coord: 3, ids, offset: None },
+    ]);
+    let reader = Reader::new(writer.finish());
+    let plain: Vec<_> =
+        reader.read_uniform_vec(plain).iter().map(|c| (c.coord, c.offset)).collect();
+    assert_eq!(plain, vec![(7, None)]);
+    let coords = reader.read_uniform_vec(offset);
+    assert_eq!(
+        coords.iter().map(|c| (c.coord, c.offset)).collect::<Vec<_>>(),
+        vec![(7, Some(0x2f)), (3, None)],
+        "coords without offsets stay without one among coords that have them"
+    );
+    assert_eq!(reader.read_fixed_vec(coords.get(1).ids).iter().collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn test_single_entry() {
+    let entry = SingleEntry { relev_score: 250, coord: 16777215, id: 12835 };
+    let buffer = entry.write();
+    assert_eq!(buffer.len(), SINGLE_ENTRY_SIZE);
+    assert_eq!(SingleEntry::read(&buffer), Some(entry));
+
+    // the smallest regular record with an entry isn't mistaken for an inline one
+    let mut writer = Writer::new();
+    let ids = writer.write_fixed_vec(&[0u32]);
+    let coords = writer.write_uniform_vec(&[Coord { coord: 0, ids, offset: None }]);
+    let rses = writer.write_var_vec(&[RelevScore { relev_score: 0, coords }]);
+    writer.write_fixed_scalar(PhraseRecord { relev_scores: rses });
+    let buffer = writer.finish();
+    assert!(buffer.len() > SINGLE_ENTRY_SIZE);
+    assert_eq!(SingleEntry::read(&buffer), None);
+}
+
+#[test]
+fn test_validate_record() {
+    let mut writer = Writer::new();
+    let ids = writer.write_fixed_vec(&[1u32, 2, 3]);
+    let coords = writer.write_uniform_vec(&[
+        Coord { coord: 7, ids, offset: None },
+        Coord { coord: 3, ids, offset: Some(0x2f) },
+    ]);
+    let rses = writer.write_var_vec(&[RelevScore { relev_score: 100, coords }]);
+    writer.write_fixed_scalar(PhraseRecord { relev_scores: rses });
+    let buffer = writer.finish();
+    assert!(validate_record(&buffer).is_ok());
+    assert!(validate_record(&SingleEntry { relev_score: 1, coord: 2, id: 3 }.write()).is_ok());
+    assert!(validate_record(&buffer[..(buffer.len() - 1)]).is_err());
+
+    let decode_all = |buffer: &[u8]| {
+        let reader = Reader::new(buffer);
+        let record = read_phrase_record_from(&reader);
+        let mut count = 0;
+        for rs in reader.read_var_vec(record.relev_scores).iter() {
+            for coord in reader.read_uniform_vec(rs.coords).iter() {
+                count += reader.read_fixed_vec(coord.ids).iter().count();
+            }
+        }
+        count
+    };
+    assert_eq!(decode_all(&buffer), 6);
+
+    // whatever passes validation decodes without reading out of bounds
+    let mut damaged: Vec<Vec<u8>> = (0..buffer.len()).map(|len| buffer[..len].to_vec()).collect();
+    for pos in 0..buffer.len() {
+        for byte in [0u8, 1, 5, 9, 0x7f, 0x80, 0xff].iter() {
+            let mut corrupted = buffer.clone();
+            corrupted[pos] = *byte;
+            damaged.push(corrupted);
+        }
+    }
+    for record in damaged {
+        if validate_record(&record).is_ok() && SingleEntry::read(&record).is_none() {
+            decode_all(&record);
+        }
+    }
+}
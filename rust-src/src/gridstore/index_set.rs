@@ -0,0 +1,195 @@
+//! Checks that a set of stores fits together as one geocoder, e.g. one store per layer from
+//! countries down to addresses, to catch deployments that were assembled from the wrong builds
+//! before they serve traffic.
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+
+use failure::Error;
+use serde::Serialize;
+
+use crate::gridstore::store::GridStore;
+
+/// How many bits of a result's tmp_id hold the feature id; the store's idx goes above them
+const FEATURE_ID_BITS: u32 = 25;
+
+/// A way the stores of an index set don't fit together
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub enum IndexSetProblem {
+    /// There are more stores than tmp_ids have room to tell apart
+    TooManyStores { count: usize, max: usize },
+    /// A store is at a lower zoom than the layer before it
+    ZoomDecreases { idx: u16, zoom: u16, previous_zoom: u16 },
+    /// Two stores of the same type, i.e. one layer split across stores, have feature ids in
+    /// common, so an id doesn't name one feature of the layer
+    OverlappingIds { idx: u16, other_idx: u16, type_id: u16 },
+    /// A store was built in a different generation than most of the set, or doesn't say which
+    /// generation it was built in
+    GenerationMismatch { idx: u16, generation: Option<u64>, expected: u64 },
+}
+
+/// What `check_index_set` found
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct IndexSetReport {
+    pub stores_checked: usize,
+    pub problems: Vec<IndexSetProblem>,
+}
+
+impl IndexSetReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks that `stores`, in layer order with each at the idx of its position, make up one
+/// geocoder: there are few enough of them for each idx to get its own tmp_ids, zooms don't
+/// decrease from one layer to the next, the stores a layer is split across have disjoint ranges
+/// of feature ids, and the stores were built in the same generation. This reads every store in
+/// full to find its feature ids, so it's meant for deployment checks, not for query processes
+/// starting up.
+pub fn check_index_set<T: Borrow<GridStore>>(stores: &[T]) -> Result<IndexSetReport, Error> {
+    let mut report = IndexSetReport::default();
+    let max_stores = 1usize << (32 - FEATURE_ID_BITS);
+    if stores.len() > max_stores {
+        report
+            .problems
+            .push(IndexSetProblem::TooManyStores { count: stores.len(), max: max_stores });
+    }
+
+    let mut previous_zoom: Option<u16> = None;
+    // the lowest and highest feature id of each store so far, with its idx, by type
+    let mut id_ranges: BTreeMap<u16, Vec<(u16, u32, u32)>> = BTreeMap::new();
+    for (idx, store) in stores.iter().enumerate() {
+        let store = store.borrow();
+        let idx = idx as u16;
+        if let Some(previous_zoom) = previous_zoom.filter(|previous| store.zoom < *previous) {
+            report.problems.push(IndexSetProblem::ZoomDecreases {
+                idx,
+                zoom: store.zoom,
+                previous_zoom,
+            });
+        }
+        previous_zoom = Some(store.zoom);
+
+        let mut id_range: Option<(u32, u32)> = None;
+        for item in store.iter() {
+            let (_, entries) = item?;
+            for entry in entries {
+                id_range = Some(match id_range {
+                    Some((min, max)) => (min.min(entry.id), max.max(entry.id)),
+                    None => (entry.id, entry.id),
+                });
+            }
+        }
+        if let Some((min, max)) = id_range {
+            let same_type = id_ranges.entry(store.type_id).or_default();
+            for (other_idx, other_min, other_max) in same_type.iter() {
+                if min <= *other_max && *other_min <= max {
+                    report.problems.push(IndexSetProblem::OverlappingIds {
+                        idx,
+                        other_idx: *other_idx,
+                        type_id: store.type_id,
+                    });
+                }
+            }
+            same_type.push((idx, min, max));
+        }
+        report.stores_checked += 1;
+    }
+
+    // the odd ones out are the stores that don't share the most common generation, or the newest
+    // of the most common ones
+    let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+    for store in stores.iter() {
+        if let Some(generation) = store.borrow().generation {
+            *counts.entry(generation).or_insert(0) += 1;
+        }
+    }
+    let expected =
+        counts.iter().max_by_key(|(_, count)| **count).map(|(generation, _)| *generation);
+    if let Some(expected) = expected {
+        for (idx, store) in stores.iter().enumerate() {
+            let generation = store.borrow().generation;
+            if generation != Some(expected) {
+                report.problems.push(IndexSetProblem::GenerationMismatch {
+                    idx: idx as u16,
+                    generation,
+                    expected,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gridstore::builder::GridStoreBuilder;
+    use crate::gridstore::common::{GridEntry, GridKey};
+    use crate::gridstore::spatial::global_bbox_for_zoom;
+
+    fn build(
+        directory: &tempfile::TempDir,
+        name: &str,
+        (zoom, type_id): (u16, u16),
+        id: u32,
+        generation: Option<u64>,
+    ) -> GridStore {
+        let path = directory.path().join(name);
+        let mut builder = GridStoreBuilder::new(&path).unwrap();
+        let grid = GridEntry { id, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid]).unwrap();
+        if let Some(generation) = generation {
+            builder.set_generation(generation);
+        }
+        builder.finish().unwrap();
+        GridStore::new_with_options(&path, zoom, type_id, 200., global_bbox_for_zoom(zoom), 1.)
+            .unwrap()
+    }
+
+    #[test]
+    fn index_set_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let country = build(&directory, "country", (6, 1), 1, Some(7));
+        let place = build(&directory, "place", (12, 2), 1, Some(7));
+        let address = build(&directory, "address", (14, 3), 1, Some(7));
+
+        let report = check_index_set(&[&country, &place, &address]).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.stores_checked, 3);
+
+        let report = check_index_set(&[&country, &address, &place]).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![IndexSetProblem::ZoomDecreases { idx: 2, zoom: 12, previous_zoom: 14 }]
+        );
+
+        let stale = build(&directory, "stale", (12, 2), 1, Some(6));
+        // builds that aren't given a generation get one of their own
+        let unaligned = build(&directory, "unaligned", (14, 3), 2, None);
+        let report = check_index_set(&[&country, &stale, &address, &unaligned]).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                IndexSetProblem::GenerationMismatch { idx: 1, generation: Some(6), expected: 7 },
+                IndexSetProblem::GenerationMismatch {
+                    idx: 3,
+                    generation: unaligned.generation,
+                    expected: 7
+                },
+            ]
+        );
+
+        // addresses split across two stores, which both have a feature 1
+        let more_addresses = build(&directory, "more_addresses", (14, 3), 1, Some(7));
+        let report = check_index_set(&[&country, &address, &more_addresses]).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![IndexSetProblem::OverlappingIds { idx: 2, other_idx: 1, type_id: 3 }]
+        );
+        let other_addresses = build(&directory, "other_addresses", (14, 3), 2, Some(7));
+        assert!(check_index_set(&[&country, &address, &other_addresses]).unwrap().is_ok());
+
+        assert!(check_index_set::<&GridStore>(&[]).unwrap().is_ok());
+    }
+}
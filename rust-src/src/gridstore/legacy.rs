@@ -0,0 +1,274 @@
+//! Reading grids from carmen-cache stores, so existing indexes can be migrated to gridstores.
+//!
+//! carmen-cache keeps a RocksDB of phrase text (followed by `|` and the little-endian bytes of
+//! the language bitmask, up to the last non-zero byte, unless the phrase is for all languages)
+//! to a protobuf message whose first field is a packed list of grids. The grids are sorted in
+//! descending order and delta-encoded, and each packs an entry into 53 bits: the id in the low 20,
+//! then 14 bits each of x and y, 3 of score, and 2 of relevance in steps of 0.2 from 0.4.
+use std::path::Path;
+
+use failure::{Error, Fail};
+use integer_encoding::VarInt;
+use rocksdb::{IteratorMode, Options, DB};
+
+use crate::gridstore::builder::GridStoreBuilder;
+use crate::gridstore::common::*;
+
+const LANGFIELD_SEPARATOR: u8 = b'|';
+const GRID_FIELD: u64 = 1;
+
+/// Unpacks a carmen-cache grid
+pub fn decode_grid(grid: u64) -> GridEntry {
+    GridEntry {
+        relev: 0.4 + ((grid >> 51) % 4) as f64 * 0.2,
+        score: ((grid >> 48) % 8) as u8,
+        y: ((grid >> 34) % (1 << 14)) as u16,
+        x: ((grid >> 20) % (1 << 14)) as u16,
+        id: (grid % (1 << 20)) as u32,
+        source_phrase_hash: 0,
+    }
+}
+
+fn read_varint(buffer: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    if *pos >= buffer.len() {
+        return Err(LegacyError::Truncated.into());
+    }
+    let (value, len) = u64::decode_var(&buffer[*pos..]);
+    if len == 0 || *pos + len > buffer.len() {
+        return Err(LegacyError::Truncated.into());
+    }
+    *pos += len;
+    Ok(value)
+}
+
+/// Decodes the grids in a carmen-cache value
+pub fn decode_grids(message: &[u8]) -> Result<Vec<GridEntry>, Error> {
+    let mut raw: Vec<u64> = Vec::new();
+    let mut pos = 0;
+    while pos < message.len() {
+        let tag = read_varint(message, &mut pos)?;
+        let (field, wire_type) = (tag >> 3, tag & 7);
+        match wire_type {
+            0 => {
+                let value = read_varint(message, &mut pos)?;
+                if field == GRID_FIELD {
+                    raw.push(value);
+                }
+            }
+            1 => pos += 8,
+            2 => {
+                let len = read_varint(message, &mut pos)? as usize;
+                let end = pos + len;
+                if end > message.len() {
+                    return Err(LegacyError::Truncated.into());
+                }
+                if field == GRID_FIELD {
+                    while pos < end {
+                        raw.push(read_varint(&message[..end], &mut pos)?);
+                    }
+                }
+                pos = end;
+            }
+            5 => pos += 4,
+            _ => return Err(LegacyError::UnsupportedWireType { wire_type }.into()),
+        }
+    }
+    if pos > message.len() {
+        return Err(LegacyError::Truncated.into());
+    }
+
+    // every grid after the first is stored as its difference from the previous one, which it
+    // can't be bigger than, since grids are sorted in descending order
+    let mut last: Option<u64> = None;
+    let mut grids = Vec::with_capacity(raw.len());
+    for value in raw {
+        let grid = match last {
+            Some(last) => last.checked_sub(value).ok_or(LegacyError::UnsortedGrids)?,
+            None => value,
+        };
+        grids.push(decode_grid(grid));
+        last = Some(grid);
+    }
+    Ok(grids)
+}
+
+/// Splits a carmen-cache key into its phrase text and language set
+pub fn decode_key(key: &[u8]) -> Result<(String, u128), Error> {
+    let (phrase, lang_set) = match key.iter().position(|byte| *byte == LANGFIELD_SEPARATOR) {
+        Some(separator) => {
+            let lang_bytes = &key[(separator + 1)..];
+            if lang_bytes.len() > 16 {
+                return Err(LegacyError::InvalidKey.into());
+            }
+            let mut lang_set = [0u8; 16];
+            lang_set[..lang_bytes.len()].copy_from_slice(lang_bytes);
+            (&key[..separator], u128::from_le_bytes(lang_set))
+        }
+        None => (key, std::u128::MAX),
+    };
+    let phrase = String::from_utf8(phrase.to_vec()).map_err(|_| LegacyError::InvalidKey)?;
+    Ok((phrase, lang_set))
+}
+
+/// How a carmen-cache migration went
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationStats {
+    pub keys_migrated: u64,
+    /// Keys whose phrase had no id, which are left out
+    pub keys_skipped: u64,
+    pub entries_migrated: u64,
+}
+
+impl GridStoreBuilder {
+    /// Inserts every grid in a carmen-cache store, looking up the id of each phrase with
+    /// `phrase_id`. Phrases without an id are skipped.
+    pub fn insert_from_carmen_cache<P: AsRef<Path>, F: Fn(&str) -> Option<u64>>(
+        &mut self,
+        cache_path: P,
+        phrase_id: F,
+    ) -> Result<MigrationStats, Error> {
+        let mut opts = Options::default();
+        opts.set_read_only(true);
+        let db = DB::open(&opts, cache_path.as_ref())?;
+
+        let mut stats = MigrationStats::default();
+        for (key, value) in db.iterator(IteratorMode::Start) {
+            let (phrase, lang_set) = decode_key(&key)?;
+            match phrase_id(&phrase) {
+                Some(phrase_id) => {
+                    let entries = decode_grids(&value)?;
+                    stats.keys_migrated += 1;
+                    stats.entries_migrated += entries.len() as u64;
+                    self.append(&GridKey { phrase_id, lang_set }, entries)?;
+                }
+                None => stats.keys_skipped += 1,
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+fn encode_grid(entry: &GridEntry) -> u64 {
+    (((entry.relev - 0.4) / 0.2).round() as u64) << 51
+        | (entry.score as u64) << 48
+        | (entry.y as u64) << 34
+        | (entry.x as u64) << 20
+        | entry.id as u64
+}
+
+#[cfg(test)]
+fn encode_grids(entries: &[GridEntry]) -> Vec<u8> {
+    let mut grids: Vec<u64> = entries.iter().map(encode_grid).collect();
+    grids.sort_by(|a, b| b.cmp(a));
+    let mut values = Vec::with_capacity(grids.len());
+    let mut last: Option<u64> = None;
+    for grid in grids {
+        values.push(last.map_or(grid, |last| last - grid));
+        last = Some(grid);
+    }
+    encode_grid_values(&values)
+}
+
+/// A carmen-cache value with the given raw, still delta-encoded, grid values
+#[cfg(test)]
+fn encode_grid_values(values: &[u64]) -> Vec<u8> {
+    let mut packed = Vec::new();
+    for value in values {
+        packed.extend(value.encode_var_vec());
+    }
+    let mut message = ((GRID_FIELD << 3) | 2).encode_var_vec();
+    message.extend((packed.len() as u64).encode_var_vec());
+    message.extend(packed);
+    message
+}
+
+#[test]
+fn decode_grids_test() {
+    let entries = vec![
+        GridEntry { id: 1048575, x: 16383, y: 16383, relev: 1., score: 7, source_phrase_hash: 0 },
+        GridEntry { id: 2, x: 3, y: 4, relev: 0.6, score: 2, source_phrase_hash: 0 },
+        GridEntry { id: 5, x: 0, y: 0, relev: 0.4, score: 0, source_phrase_hash: 0 },
+    ];
+    let decoded = decode_grids(&encode_grids(&entries)).unwrap();
+    assert_eq!(decoded.len(), 3);
+    for (expected, actual) in entries.iter().zip(decoded.iter()) {
+        assert_eq!((expected.id, expected.x, expected.y), (actual.id, actual.x, actual.y));
+        assert_eq!(expected.score, actual.score);
+        assert!((expected.relev - actual.relev).abs() < 1e-9, "relevance is decoded");
+    }
+
+    let message = encode_grids(&entries);
+    assert!(decode_grids(&message[..message.len() - 1]).is_err(), "truncated values fail");
+
+    // a grid of all zeros is a real grid, not the start of the list
+    let zero = GridEntry { id: 0, x: 0, y: 0, relev: 0.4, score: 0, source_phrase_hash: 0 };
+    let entries = vec![entries[1].clone(), zero.clone(), zero];
+    let decoded = decode_grids(&encode_grids(&entries)).unwrap();
+    let ids: Vec<u32> = decoded.iter().map(|grid| grid.id).collect();
+    assert_eq!(ids, vec![2, 0, 0]);
+
+    assert!(decode_grids(&encode_grid_values(&[5, 7])).is_err(), "grids that go up fail");
+    assert!(
+        decode_grids(&encode_grid_values(&[5, 5, 3])).is_err(),
+        "a grid that goes up after a zero grid fails"
+    );
+    assert_eq!(decode_grids(&encode_grid_values(&[5, 5, 0])).unwrap().len(), 3);
+}
+
+#[test]
+fn decode_key_test() {
+    assert_eq!(decode_key(b"main st").unwrap(), ("main st".to_string(), std::u128::MAX));
+    assert_eq!(decode_key(b"main st|\x01").unwrap(), ("main st".to_string(), 1));
+    assert_eq!(decode_key(b"main st|\x00\x02").unwrap(), ("main st".to_string(), 512));
+}
+
+#[test]
+fn insert_from_carmen_cache_test() {
+    use crate::gridstore::store::GridStore;
+
+    let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+    let cache_path = directory.path().join("cache");
+    {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let cache = DB::open(&opts, &cache_path).unwrap();
+        let entry = GridEntry { id: 7, x: 1, y: 2, relev: 1., score: 3, source_phrase_hash: 0 };
+        cache.put(b"main st|\x01", encode_grids(&[entry.clone()])).unwrap();
+        cache.put(b"main st", encode_grids(&[entry.clone()])).unwrap();
+        cache.put(b"unknown", encode_grids(&[entry])).unwrap();
+    }
+
+    let store_path = directory.path().join("store");
+    let mut builder = GridStoreBuilder::new(&store_path).unwrap();
+    let stats = builder
+        .insert_from_carmen_cache(
+            &cache_path,
+            |phrase| if phrase == "main st" { Some(3) } else { None },
+        )
+        .unwrap();
+    builder.finish().unwrap();
+    assert_eq!(stats, MigrationStats { keys_migrated: 2, keys_skipped: 1, entries_migrated: 2 });
+
+    let reader = GridStore::new(&store_path).unwrap();
+    let keys: Vec<_> = reader.keys().map(|key| key.unwrap()).collect();
+    assert_eq!(
+        keys,
+        vec![
+            GridKey { phrase_id: 3, lang_set: std::u128::MAX },
+            GridKey { phrase_id: 3, lang_set: 1 }
+        ]
+    );
+}
+
+#[derive(Debug, Fail)]
+enum LegacyError {
+    #[fail(display = "truncated carmen-cache value")]
+    Truncated,
+    #[fail(display = "unsupported protobuf wire type: {}", wire_type)]
+    UnsupportedWireType { wire_type: u64 },
+    #[fail(display = "invalid carmen-cache key")]
+    InvalidKey,
+    #[fail(display = "carmen-cache grids aren't in descending order")]
+    UnsortedGrids,
+}
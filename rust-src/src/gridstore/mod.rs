@@ -1,15 +1,39 @@
+#[cfg(feature = "arrow-input")]
+pub mod arrow_input;
 mod builder;
 mod coalesce;
 mod common;
+#[cfg(feature = "geo-interop")]
+pub mod geo_interop;
 mod gridstore_format;
+mod index_set;
+pub mod legacy;
+mod priority;
+pub mod scoring;
+mod settings;
+#[cfg(feature = "shared-cache")]
+pub mod shared_cache;
 mod spatial;
 mod stackable;
 mod store;
 
 pub use builder::*;
-pub use coalesce::{coalesce, collapse_phrasematches, stack_and_coalesce, tree_coalesce};
+#[cfg(feature = "parallel")]
+pub use coalesce::coalesce_parallel;
+pub use coalesce::{
+    coalesce, coalesce_batch, coalesce_lazy, coalesce_with_budget, coalesce_with_stats,
+    coalesce_with_trace, collapse_phrasematches, diff_contexts, impressions, merge_contexts,
+    reverse_coalesce, stack_and_coalesce, stack_and_coalesce_compact,
+    stack_and_coalesce_with_calibration, stack_and_coalesce_with_impressions,
+    stack_and_coalesce_with_stats, tree_coalesce, BudgetedContexts, CoalesceError, CoalesceStats,
+    CoalesceTrace, EntryComponents, EntryTrace, Impression, LazyContexts, PruneRule, PrunedContext,
+    RankDiff, TracedContext,
+};
 pub use common::*;
-pub use spatial::global_bbox_for_zoom;
+pub use index_set::{check_index_set, IndexSetProblem, IndexSetReport};
+pub use priority::QueryScheduler;
+pub use settings::{AdaptiveOpts, Limits, Settings};
+pub use spatial::{global_bbox_for_zoom, tile_lonlat, PolygonMask};
 pub use stackable::stackable;
 pub use store::*;
 
@@ -17,8 +41,10 @@ pub use store::*;
 mod tests {
     use super::*;
     use fixedbitset::FixedBitSet;
+    use morton::interleave_morton;
     use once_cell::sync::Lazy;
     use std::collections::BTreeMap;
+    use std::sync::Arc;
 
     #[test]
     fn combined_test() {
@@ -85,10 +111,1559 @@ mod tests {
         for id in 0..=2 {
             let entries: Vec<_> =
                 reader.get(&GridKey { phrase_id: id, lang_set: 1 }).unwrap().unwrap().collect();
-            assert_eq!(id, entries[0].id);
+            assert_eq!(id as u32, entries[0].id);
         }
     }
 
+    #[test]
+    fn feature_index_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_feature_index(true);
+
+        let items = vec![
+            (GridKey { phrase_id: 1, lang_set: 1 }, vec![1, 2]),
+            (GridKey { phrase_id: 2, lang_set: 0 }, vec![2]),
+            (GridKey { phrase_id: 3, lang_set: std::u128::MAX }, vec![2, 3]),
+        ];
+        for (key, ids) in items {
+            let entries = ids
+                .into_iter()
+                .map(|id| GridEntry { id, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 })
+                .collect();
+            builder.insert(&key, entries).expect("Unable to insert record");
+        }
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        let keys: Vec<_> = reader.keys_for_feature(2).map(|key| key.unwrap()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                GridKey { phrase_id: 1, lang_set: 1 },
+                GridKey { phrase_id: 2, lang_set: 0 },
+                GridKey { phrase_id: 3, lang_set: std::u128::MAX },
+            ],
+            "every key the feature is indexed under is found"
+        );
+        let keys: Vec<_> = reader.keys_for_feature(1).map(|key| key.unwrap()).collect();
+        assert_eq!(keys, vec![GridKey { phrase_id: 1, lang_set: 1 }]);
+        assert_eq!(reader.keys_for_feature(4).count(), 0, "unknown features have no keys");
+        assert_eq!(reader.keys().count(), 3, "the index doesn't show up as regular keys");
+    }
+
+    #[test]
+    fn generation_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_generation(42);
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.generation, Some(42), "an explicit generation is kept");
+
+        let grid = GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        let mut builder = GridStoreBuilder::open_existing(directory.path()).unwrap();
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid]).unwrap();
+        drop(reader);
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.generation, Some(43), "an update is a generation on from the last");
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.generation, reader.content_hash, "by default it's the content hash");
+
+        let subquery = PhrasematchSubquery {
+            store: &reader,
+            idx: 3,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId::default()],
+            bbox: None,
+        };
+        let (_, stats) = stack_and_coalesce_with_stats(&vec![subquery], &MatchOpts::default())
+            .expect("coalesce should succeed");
+        assert_eq!(stats.generations.get(&3), reader.generation.as_ref());
+        assert!(stats.failed_match_keys.is_empty());
+    }
+
+    #[test]
+    fn content_hash_test() {
+        let grid = |id, x| GridEntry { id, x, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        let build = |inserts: Vec<(u64, Vec<GridEntry>)>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            for (phrase_id, entries) in inserts {
+                builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+            }
+            builder.finish().unwrap();
+            GridStore::new(directory.path()).unwrap().content_hash.unwrap()
+        };
+
+        let hash =
+            build(vec![(1, vec![grid(1, 1), grid(2, 2), grid(3, 1)]), (2, vec![grid(4, 4)])]);
+        assert_eq!(
+            build(vec![(2, vec![grid(4, 4)]), (1, vec![grid(2, 2), grid(3, 1), grid(1, 1)])]),
+            hash,
+            "insertion order doesn't matter"
+        );
+        assert_ne!(build(vec![(1, vec![grid(1, 1), grid(2, 2), grid(3, 1)])]), hash);
+        assert_ne!(
+            build(vec![(1, vec![grid(1, 1), grid(2, 2), grid(3, 2)]), (2, vec![grid(4, 4)])]),
+            hash
+        );
+    }
+
+    #[test]
+    fn health_check_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id, x| GridEntry { id, x, y: 0, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder
+            .insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(1, 0), grid(2, 3)])
+            .unwrap();
+        // a tile that doesn't exist at zoom 2
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(3, 9)]).unwrap();
+        builder.finish().unwrap();
+
+        let open = |zoom| {
+            GridStore::new_with_options(
+                directory.path(),
+                zoom,
+                1,
+                200.,
+                global_bbox_for_zoom(zoom),
+                1.,
+            )
+            .unwrap()
+        };
+        let report = open(14).health_check(10);
+        assert_eq!(
+            report,
+            HealthReport {
+                healthy: true,
+                keys_read: 2,
+                entries_decoded: 3,
+                query_ok: true,
+                errors: vec![]
+            }
+        );
+        assert_eq!(open(14).health_check(1).keys_read, 1, "reads are bounded");
+
+        let report = open(2).health_check(10);
+        assert!(!report.healthy);
+        assert_eq!(report.errors.len(), 1);
+        assert!(serde_json::to_string(&report).unwrap().contains("\"healthy\":false"));
+    }
+
+    #[test]
+    fn coverage_heatmap_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let entries = vec![
+            GridEntry { id: 1, x: 0, y: 0, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 3, y: 3, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 3, x: 4, y: 0, relev: 1., score: 1, source_phrase_hash: 0 },
+        ];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        let entries =
+            vec![GridEntry { id: 3, x: 4, y: 0, relev: 1., score: 1, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.,
+        )
+        .unwrap();
+        let heatmap: Vec<_> = reader.coverage_heatmap(12).unwrap().into_iter().collect();
+        assert_eq!(heatmap, vec![((0, 0), 2), ((1, 0), 2)], "entries are counted per coarse tile");
+        assert_eq!(
+            reader.coverage_heatmap(16).unwrap().len(),
+            3,
+            "zooms past the store's zoom count per store tile"
+        );
+    }
+
+    #[test]
+    fn compression_stats_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let single = GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![single]).unwrap();
+        // many features at one coord share it, so they pack down well
+        let crowded: Vec<_> = (1..=50)
+            .map(|id| GridEntry { id, x: 2, y: 2, relev: 1., score: 1, source_phrase_hash: 0 })
+            .collect();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, crowded).unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        let stats: Vec<_> = reader.compression_stats().map(|stats| stats.unwrap()).collect();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].key, GridKey { phrase_id: 1, lang_set: 1 });
+        assert_eq!(stats[0].encoding, RecordEncoding::SingleEntry);
+        assert_eq!(stats[0].entries, 1);
+        assert_eq!(stats[1].encoding, RecordEncoding::Grouped);
+        assert_eq!(stats[1].entries, 50);
+        assert_eq!(stats[1].unpacked_size, 50 * KeyCompressionStats::UNPACKED_ENTRY_SIZE);
+        assert!(stats[1].ratio() < 1.);
+        for stats in stats.iter() {
+            assert_eq!(stats.encoded_size, stats.stored_size, "the store isn't compressed");
+        }
+
+        #[cfg(feature = "zstd-compression")]
+        {
+            let compressed_directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(compressed_directory.path())
+                .unwrap()
+                .with_compression(Compression::Zstd(3));
+            let crowded: Vec<_> = (1..=50)
+                .map(|id| GridEntry { id, x: 2, y: 2, relev: 1., score: 1, source_phrase_hash: 0 })
+                .collect();
+            builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, crowded).unwrap();
+            builder.finish().unwrap();
+            let reader = GridStore::new(compressed_directory.path()).unwrap();
+            let compressed = reader.compression_stats().next().unwrap().unwrap();
+            assert_eq!(compressed.entries, 50);
+            assert!(compressed.stored_size < compressed.encoded_size);
+        }
+    }
+
+    #[test]
+    fn manifest_test() {
+        let compression = if cfg!(feature = "zstd-compression") {
+            Compression::Zstd(3)
+        } else {
+            Compression::None
+        };
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder =
+            GridStoreBuilder::new(directory.path()).unwrap().with_compression(compression);
+        builder.set_generation(7);
+        let entries: Vec<_> = (1..=3)
+            .map(|id| GridEntry {
+                id,
+                x: id as u16,
+                y: 1,
+                relev: 1.,
+                score: 1,
+                source_phrase_hash: 0,
+            })
+            .collect();
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries.clone()).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, entries[..1].to_vec()).unwrap();
+        builder.finish().unwrap();
+
+        let manifest = StoreManifest::read(directory.path()).unwrap().unwrap();
+        assert_eq!(manifest.format_version, (FORMAT_MAJOR_VERSION, FORMAT_MINOR_VERSION));
+        assert_eq!(manifest.generation, 7);
+        assert_eq!(manifest.keys, 2);
+        assert_eq!(manifest.entries, 4);
+        assert_eq!(manifest.compression, compression);
+        assert_eq!(manifest.curve, Curve::Morton);
+        assert_eq!(manifest.built_by, env!("CARGO_PKG_VERSION"));
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(Some(manifest.content_hash), reader.content_hash);
+        assert_eq!(reader.manifest().unwrap(), Some(manifest), "the store reads its own manifest");
+
+        let empty: tempfile::TempDir = tempfile::tempdir().unwrap();
+        assert_eq!(StoreManifest::read(empty.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn reverse_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let entry =
+            |id, x, y, score| GridEntry { id, x, y, relev: 1., score, source_phrase_hash: 0 };
+        builder
+            .insert(
+                &GridKey { phrase_id: 1, lang_set: 1 },
+                vec![entry(1, 5, 5, 1), entry(2, 5, 5, 3), entry(3, 8, 5, 7), entry(4, 9, 9, 7)],
+            )
+            .unwrap();
+        // feature 3 is also under another key, with a grid nearer the point
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![entry(3, 6, 5, 7)]).unwrap();
+        builder.finish().unwrap();
+
+        let mut reader =
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.0)
+                .unwrap();
+        let ids_and_distances = |entries: Vec<MatchEntry>| -> Vec<(u32, f64)> {
+            entries.iter().map(|entry| (entry.grid_entry.id, entry.distance)).collect()
+        };
+
+        assert_eq!(
+            ids_and_distances(reader.reverse([5, 5], 6, 10).unwrap()),
+            vec![(2, 0.), (1, 0.), (3, 1.), (4, 4f64.hypot(4.))],
+            "covering features come first, by score, and each feature by its nearest grid"
+        );
+        assert_eq!(
+            ids_and_distances(reader.reverse([10, 10], 7, 2).unwrap()),
+            vec![(2, 0.), (1, 0.)],
+            "the point is brought to the store's zoom, and results are cut off at the limit"
+        );
+
+        reader.set_tombstones(vec![2]);
+        assert_eq!(
+            ids_and_distances(reader.reverse([5, 5], 6, 2).unwrap()),
+            vec![(1, 0.), (3, 1.)],
+            "tombstoned features are left out"
+        );
+    }
+
+    #[test]
+    fn reverse_tile_index_test() {
+        let entry = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        let near_key = GridKey { phrase_id: 1, lang_set: 1 };
+        let far_key = GridKey { phrase_id: 2, lang_set: 1 };
+        let build = |tile_index: Option<u16>| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.set_tile_index(tile_index);
+            builder.insert(&near_key, vec![entry(1, 5, 5)]).unwrap();
+            builder.insert(&far_key, vec![entry(2, 40, 40)]).unwrap();
+            builder.finish().unwrap();
+            directory
+        };
+        let open = |directory: &tempfile::TempDir| {
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.0)
+                .unwrap()
+        };
+        let ids_and_distances = |reader: &GridStore, limit| -> Vec<(u32, f64)> {
+            let entries = reader.reverse([5, 5], 6, limit).unwrap();
+            entries.iter().map(|entry| (entry.grid_entry.id, entry.distance)).collect()
+        };
+        // swaps the far key's record for one with a feature right at the point, so that reading
+        // the record shows up in the results
+        let plant = |directory: &tempfile::TempDir| {
+            let db = rocksdb::DB::open_default(directory.path()).unwrap();
+            let mut db_key = Vec::new();
+            far_key
+                .write_to(TypeMarker::SinglePhrase, PhraseIdWidth::current(), &mut db_key)
+                .unwrap();
+            let planted = gridstore_format::SingleEntry {
+                relev_score: (3 << 4) | 7,
+                coord: interleave_morton(5, 5),
+                id: 99 << 8,
+            };
+            db.put(&db_key, planted.write()).unwrap();
+        };
+
+        let indexed = build(Some(2));
+        let reader = open(&indexed);
+        assert_eq!(
+            ids_and_distances(&reader, 2),
+            vec![(1, 0.), (2, 35f64.hypot(35.))],
+            "the search widens until it's found enough features"
+        );
+        drop(reader);
+        plant(&indexed);
+        assert_eq!(
+            ids_and_distances(&open(&indexed), 1),
+            vec![(1, 0.)],
+            "records with no covers near the point aren't read"
+        );
+
+        let unindexed = build(None);
+        plant(&unindexed);
+        assert_eq!(
+            ids_and_distances(&open(&unindexed), 1),
+            vec![(99, 0.)],
+            "without an index, every record is read"
+        );
+    }
+
+    #[test]
+    fn coords_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let entries = vec![
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 3, x: 5, y: 2, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 4, x: 9, y: 9, relev: 0.6, score: 3, source_phrase_hash: 0 },
+        ];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        let single = GridEntry { id: 5, x: 5, y: 2, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![single]).unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.,
+        )
+        .unwrap();
+        let coords = |phrase_id: u64, bbox: Option<[u16; 4]>| -> Option<Vec<u32>> {
+            let key = GridKey { phrase_id, lang_set: 1 };
+            reader.coords(&key, bbox).unwrap().map(Iterator::collect)
+        };
+        let (a, b, c) = (interleave_morton(1, 1), interleave_morton(5, 2), interleave_morton(9, 9));
+        assert_eq!(coords(1, None), Some(vec![b, a, c]), "one coord per tile and bucket");
+        assert_eq!(coords(1, Some([0, 0, 6, 6])), Some(vec![b, a]));
+        assert_eq!(coords(2, None), Some(vec![b]), "single-entry keys have coords too");
+        assert_eq!(coords(2, Some([0, 0, 4, 4])), Some(vec![]));
+        assert_eq!(coords(3, None), None);
+    }
+
+    #[test]
+    fn relev_weights_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        assert!(
+            builder.set_relev_weights(Some([2., 1., 1., 1.])).is_err(),
+            "weights can't put a bucket above a more relevant one"
+        );
+        builder.set_relev_weights(Some([0.5, 1., 1., 1.])).unwrap();
+        let weak = GridEntry { id: 1, x: 1, y: 1, relev: 0.4, score: 1, source_phrase_hash: 0 };
+        let strong = GridEntry { id: 2, x: 2, y: 2, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![weak.clone(), strong]).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![weak.clone()]).unwrap();
+        builder.finish().unwrap();
+
+        let open = || {
+            GridStore::new_with_options(directory.path(), 14, 1, 200., global_bbox_for_zoom(14), 1.)
+                .unwrap()
+        };
+        let reader = open();
+        assert_eq!(reader.relev_weights, Some([0.5, 1., 1., 1.]));
+        let relevs = |reader: &GridStore, phrase_id: u64| -> Vec<f64> {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+            reader
+                .streaming_get_matching(&key, &match_opts, 10)
+                .unwrap()
+                .map(|entry| entry.grid_entry.relev)
+                .collect()
+        };
+        assert_eq!(relevs(&reader, 1), vec![1., 0.2], "the lowest bucket is demoted");
+        assert_eq!(relevs(&reader, 2), vec![0.2], "single-entry records are weighted too");
+        let entries: Vec<_> =
+            reader.get(&GridKey { phrase_id: 2, lang_set: 1 }).unwrap().unwrap().collect();
+        assert_eq!(entries, vec![weak], "stored relevances are left alone");
+        drop(reader);
+
+        GridStoreBuilder::open_existing(directory.path()).unwrap().finish().unwrap();
+        assert_eq!(open().relev_weights, Some([0.5, 1., 1., 1.]), "rebuilds keep the weights");
+    }
+
+    #[test]
+    fn tile_index_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_tile_index(Some(2));
+        let entries = vec![
+            GridEntry { id: 1, x: 0, y: 0, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 5, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+        ];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        let entries =
+            vec![GridEntry { id: 3, x: 6, y: 2, relev: 1., score: 1, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.tile_index_zoom_levels, Some(2));
+        let keys: Vec<_> = reader.keys_for_tile(3, 3).map(|key| key.unwrap()).collect();
+        assert_eq!(keys, vec![GridKey { phrase_id: 1, lang_set: 1 }]);
+        let keys: Vec<_> = reader.keys_for_tile(7, 0).map(|key| key.unwrap()).collect();
+        assert_eq!(
+            keys,
+            vec![GridKey { phrase_id: 1, lang_set: 1 }, GridKey { phrase_id: 2, lang_set: 1 }],
+            "every key with an entry in the coarse tile is found"
+        );
+        assert_eq!(reader.keys_for_tile(8, 8).count(), 0);
+        assert_eq!(reader.keys().count(), 2, "the tile index doesn't show up as keys");
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.tile_index_zoom_levels, None, "the tile index is off by default");
+    }
+
+    #[test]
+    fn merge_adjacent_covers_test() {
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let cover = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        // a road along y = 10 that steps diagonally up to y = 11, and again further away
+        let mut entries: Vec<_> = (10..=14).map(|x| cover(1, x, 10)).collect();
+        entries.push(cover(1, 15, 11));
+        entries.push(cover(1, 30, 30));
+        entries.push(cover(2, 11, 10));
+
+        let build = |merge: bool| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+            builder.set_merge_adjacent_covers(merge);
+            builder.set_tile_index(Some(2));
+            builder.insert(&key, entries.clone()).unwrap();
+            builder.finish().unwrap();
+            (GridStore::new(directory.path()).unwrap(), directory)
+        };
+
+        let (reader, _directory) = build(false);
+        assert_eq!(reader.get(&key).unwrap().unwrap().count(), 8);
+        assert_eq!(reader.extent(&key, 1, 12, 10).unwrap(), None);
+
+        let (reader, _directory) = build(true);
+        let mut covers: Vec<_> =
+            reader.get(&key).unwrap().unwrap().map(|entry| (entry.id, entry.x, entry.y)).collect();
+        covers.sort();
+        assert_eq!(
+            covers,
+            vec![(1, 12, 10), (1, 30, 30), (2, 11, 10)],
+            "adjacent covers are merged into the one nearest the middle of their extent"
+        );
+        assert_eq!(reader.extent(&key, 1, 12, 10).unwrap(), Some([10, 10, 15, 11]));
+        assert_eq!(reader.extent(&key, 1, 30, 30).unwrap(), None, "lone covers aren't merged");
+        assert_eq!(
+            reader.keys_for_tile(15, 11).count(),
+            1,
+            "merged covers are in the tile index for every tile of their extent"
+        );
+    }
+
+    #[test]
+    fn extent_distance_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_merge_adjacent_covers(true);
+        let cover = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        // a long road along y = 10, merged into a single cover at its middle
+        let road: Vec<_> = (0..=20).map(|x| cover(1, x, 10)).collect();
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, road.clone()).unwrap();
+        let mut with_other = road;
+        with_other.push(cover(2, 100, 100));
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, with_other).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        let road_key = GridKey { phrase_id: 1, lang_set: 1 };
+        assert_eq!(reader.extent(&road_key, 1, 10, 10).unwrap(), Some([0, 10, 20, 10]));
+
+        let match_opts = MatchOpts { zoom: 14, proximity: Some([20, 12]), ..MatchOpts::default() };
+        for phrase_id in 1..=2 {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let road = reader
+                .streaming_get_matching(&key, &match_opts, 10)
+                .unwrap()
+                .find(|entry| entry.grid_entry.id == 1)
+                .unwrap();
+            assert_eq!((road.grid_entry.x, road.grid_entry.y), (10, 10));
+            assert_eq!(road.distance, 2., "distance is to the nearest tile of the extent");
+        }
+
+        // covers are ordered by their rescored distance, so the road, whose cover is stored
+        // further away than the other feature but whose extent reaches the point, comes first
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_merge_adjacent_covers(true);
+        let mut entries: Vec<_> = (0..=40).map(|x| cover(1, x, 10)).collect();
+        entries.push(cover(3, 35, 10));
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+        let open_opts = OpenOpts::default();
+        let reader =
+            GridStore::new_with_open_opts(directory.path(), 14, 1, 200., vec![], 1., open_opts)
+                .unwrap();
+        let match_opts = MatchOpts { zoom: 14, proximity: Some([40, 10]), ..MatchOpts::default() };
+        let key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let matched: Vec<_> = reader
+            .streaming_get_matching(&key, &match_opts, 10)
+            .unwrap()
+            .map(|entry| (entry.grid_entry.id, entry.distance))
+            .collect();
+        assert_eq!(matched, vec![(1, 0.), (3, 5.)]);
+    }
+
+    #[test]
+    fn record_extents_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_merge_adjacent_covers(true);
+        let cover = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        // the same road under two phrases, the whole of it under one and part of it under the
+        // other, merged into covers at the same tile with different extents
+        let long_key = GridKey { phrase_id: 1, lang_set: 1 };
+        let mut long_road: Vec<_> = (0..=20).map(|x| cover(1, x, 10)).collect();
+        long_road.push(cover(2, 100, 100));
+        builder.insert(&long_key, long_road).unwrap();
+        let short_key = GridKey { phrase_id: 2, lang_set: 1 };
+        builder.insert(&short_key, (5..=15).map(|x| cover(1, x, 10)).collect()).unwrap();
+        builder.finish().unwrap();
+
+        let check = |reader: &GridStore| {
+            assert_eq!(reader.extent(&long_key, 1, 10, 10).unwrap(), Some([0, 10, 20, 10]));
+            assert_eq!(reader.extent(&short_key, 1, 10, 10).unwrap(), Some([5, 10, 15, 10]));
+
+            // a box that only the whole road's extent reaches, well away from its cover
+            let match_opts =
+                MatchOpts { zoom: 14, bbox: Some([0, 0, 2, 12]), ..MatchOpts::default() };
+            let ids = |phrase_id| -> Vec<u32> {
+                let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+                reader
+                    .streaming_get_matching(&key, &match_opts, 10)
+                    .unwrap()
+                    .map(|entry| entry.grid_entry.id)
+                    .collect()
+            };
+            assert_eq!(ids(1), vec![1], "merged covers are in a box their extent reaches");
+            assert_eq!(ids(2), Vec::<u32>::new());
+        };
+        check(&GridStore::new(directory.path()).unwrap());
+
+        GridStoreBuilder::open_existing(directory.path()).unwrap().finish().unwrap();
+        check(&GridStore::new(directory.path()).unwrap());
+    }
+
+    #[test]
+    fn border_covers_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_border_covers(Some(2));
+        let cover = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        // coarse tiles are 4 tiles across: feature 1 is on the east edge of its coarse tile, and
+        // feature 2 is in the middle of it
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        builder.insert(&key, vec![cover(1, 3, 1), cover(2, 1, 1)]).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(
+            reader.extent(&key, 1, 4, 1).unwrap(),
+            Some([3, 1, 3, 1]),
+            "copies record the original tile"
+        );
+        assert_eq!(reader.extent(&key, 1, 3, 1).unwrap(), None);
+
+        let match_key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let ids = |match_opts: &MatchOpts| -> Vec<(u32, u16, u16)> {
+            let mut ids: Vec<_> = reader
+                .streaming_get_matching(&match_key, match_opts, 10)
+                .unwrap()
+                .map(|entry| (entry.grid_entry.id, entry.grid_entry.x, entry.grid_entry.y))
+                .collect();
+            ids.sort();
+            ids
+        };
+        let east = MatchOpts { zoom: 6, bbox: Some([4, 0, 7, 3]), ..MatchOpts::default() };
+        assert_eq!(
+            ids(&east),
+            vec![(1, 4, 0), (1, 4, 1), (1, 4, 2)],
+            "only border covers are copied"
+        );
+
+        let near = MatchOpts { zoom: 6, proximity: Some([5, 1]), ..MatchOpts::default() };
+        let copy = reader
+            .streaming_get_matching(&match_key, &near, 10)
+            .unwrap()
+            .find(|entry| (entry.grid_entry.x, entry.grid_entry.y) == (4, 1))
+            .unwrap();
+        assert_eq!(copy.distance, 2., "copies are scored from the original tile");
+    }
+
+    #[test]
+    fn exclude_tiles_test() {
+        let mut mask = TileMask::new(2);
+        mask.insert(0, 0);
+        mask.insert(1, 0);
+        mask.insert(0, 1);
+        assert!(mask.excludes(interleave_morton(3, 1), 3), "finer tiles in a masked tile are");
+        assert!(!mask.excludes(interleave_morton(2, 2), 3));
+        assert!(!mask.excludes(0, 1), "coarser tiles are only if they're entirely masked");
+        mask.insert(1, 1);
+        assert!(mask.excludes(0, 1));
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        // at zoom 6, the zoom-2 tile (1, 1) spans x and y 16 through 31
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        builder.insert(&key, vec![grid(1, 20, 20), grid(2, 40, 40)]).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(3, 16, 31)]).unwrap();
+        builder.finish().unwrap();
+        let reader =
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.)
+                .unwrap();
+
+        let match_opts =
+            MatchOpts { zoom: 6, exclude_tiles: Some(Arc::new(mask)), ..MatchOpts::default() };
+        let ids = |phrase_id: u64, match_opts: &MatchOpts| -> Vec<u32> {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            reader
+                .streaming_get_matching(&key, match_opts, 10)
+                .unwrap()
+                .map(|entry| entry.grid_entry.id)
+                .collect()
+        };
+        assert_eq!(ids(1, &match_opts), vec![2]);
+        assert_eq!(ids(2, &match_opts), Vec::<u32>::new(), "single entries are masked too");
+        let proximity = MatchOpts { proximity: Some([20, 20]), ..match_opts };
+        assert_eq!(ids(1, &proximity), vec![2]);
+        assert_eq!(ids(1, &MatchOpts { zoom: 6, ..MatchOpts::default() }).len(), 2);
+    }
+
+    #[test]
+    fn polygon_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let grids = vec![grid(1, 21, 21), grid(2, 30, 21), grid(3, 30, 30), grid(4, 50, 50)];
+        builder.insert(&key, grids).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(5, 30, 30)]).unwrap();
+        builder.insert(&GridKey { phrase_id: 3, lang_set: 1 }, vec![grid(6, 21, 30)]).unwrap();
+        builder.finish().unwrap();
+        let reader =
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.)
+                .unwrap();
+
+        // a triangle whose hypotenuse runs from (40, 20) to (20, 40) at zoom 6
+        let corner = |x, y| tile_lonlat(x, y, 6, TileAnchor::Corner);
+        let polygon =
+            PolygonMask::from_lonlat(&[vec![corner(20, 20), corner(40, 20), corner(20, 40)]])
+                .unwrap();
+        let match_opts =
+            MatchOpts { zoom: 6, polygon: Some(Arc::new(polygon)), ..MatchOpts::default() };
+        let ids = |phrase_id: u64, match_opts: &MatchOpts| -> Vec<u32> {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let mut ids: Vec<u32> = reader
+                .streaming_get_matching(&key, match_opts, 10)
+                .unwrap()
+                .map(|entry| entry.grid_entry.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(1, &match_opts), vec![1, 2], "(30, 30) is in the bbox but not the polygon");
+        assert_eq!(ids(2, &match_opts), Vec::<u32>::new(), "single entries are filtered too");
+        assert_eq!(ids(3, &match_opts), vec![6]);
+        let proximity = MatchOpts { proximity: Some([30, 30]), ..match_opts.clone() };
+        assert_eq!(ids(1, &proximity), vec![1, 2]);
+        let bbox = MatchOpts { bbox: Some([25, 0, 63, 63]), ..match_opts.clone() };
+        assert_eq!(ids(1, &bbox), vec![2], "the query bbox still applies");
+        assert_eq!(ids(1, &MatchOpts { zoom: 6, ..MatchOpts::default() }).len(), 4);
+    }
+
+    #[test]
+    fn bboxes_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id, x, y| GridEntry { id, x, y, relev: 1., score: 1, source_phrase_hash: 0 };
+        let grids = vec![grid(1, 2, 2), grid(2, 5, 5), grid(3, 30, 30), grid(4, 50, 50)];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, grids).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(5, 20, 20)]).unwrap();
+        builder.finish().unwrap();
+        let reader =
+            GridStore::new_with_options(directory.path(), 6, 1, 200., global_bbox_for_zoom(6), 1.)
+                .unwrap();
+
+        // a viewport and a country hint around it, which overlap, and a box far from both
+        let bboxes = vec![[0, 0, 4, 4], [2, 2, 6, 6], [29, 29, 31, 31]];
+        let match_opts =
+            MatchOpts { zoom: 6, bboxes: Some(Arc::new(bboxes)), ..MatchOpts::default() };
+        let ids = |phrase_id: u64, match_opts: &MatchOpts| -> Vec<u32> {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let mut ids: Vec<u32> = reader
+                .streaming_get_matching(&key, match_opts, 10)
+                .unwrap()
+                .map(|entry| entry.grid_entry.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(1, &match_opts), vec![1, 2, 3], "a grid in two boxes comes once");
+        assert_eq!(ids(2, &match_opts), Vec::<u32>::new(), "single entries are filtered too");
+        let proximity = MatchOpts { proximity: Some([3, 3]), ..match_opts.clone() };
+        assert_eq!(ids(1, &proximity), vec![1, 2, 3]);
+        let bbox = MatchOpts { bbox: Some([3, 3, 63, 63]), ..match_opts.clone() };
+        assert_eq!(ids(1, &bbox), vec![2, 3], "the query bbox still applies");
+        let zoomed = match_opts.adjust_to_zoom(5);
+        assert_eq!(zoomed.bboxes.unwrap()[2], [14, 14, 15, 15]);
+    }
+
+    #[test]
+    fn match_fallback_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let grid = |id| GridEntry { id, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        for phrase_id in [10u64, 11, 12, 25].iter() {
+            let key = GridKey { phrase_id: *phrase_id, lang_set: 1 };
+            builder.insert(&key, vec![grid(*phrase_id as u32)]).unwrap();
+        }
+        builder.load_bin_boundaries(vec![10, 20, 30]).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+
+        let lookup = |phrase_id| {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let (tier, entries) = reader
+                .streaming_get_matching_with_fallback(&key, &MatchOpts::default(), 10)
+                .unwrap();
+            let mut ids: Vec<u32> = entries.map(|entry| entry.grid_entry.id).collect();
+            ids.sort();
+            (tier, ids)
+        };
+        assert_eq!(lookup(11), (MatchTier::Exact, vec![11]));
+        assert_eq!(
+            lookup(15),
+            (MatchTier::Degraded, vec![10, 11, 12]),
+            "a miss falls back to the prefix bin the phrase id is in"
+        );
+        assert_eq!(lookup(35), (MatchTier::Exact, vec![]), "the last bin has no end to look up");
+    }
+
+    #[test]
+    fn tombstones_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let store_path = directory.path().join("store");
+        let mut builder = GridStoreBuilder::new(&store_path).unwrap();
+        let grid = |id| GridEntry { id, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(1), grid(2)]).unwrap();
+        builder.insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![grid(3)]).unwrap();
+        builder.finish().unwrap();
+        let mut reader = GridStore::new(&store_path).unwrap();
+
+        let ids = |reader: &GridStore| {
+            let key =
+                MatchKey { match_phrase: MatchPhrase::Range { start: 0, end: 3 }, lang_set: 1 };
+            let mut ids: Vec<u32> = reader
+                .streaming_get_matching(&key, &MatchOpts::default(), 10)
+                .unwrap()
+                .map(|entry| entry.grid_entry.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&reader), vec![1, 2, 3]);
+        reader.set_tombstones(vec![2, 3]);
+        assert_eq!(ids(&reader), vec![1], "tombstoned ids are skipped in every kind of record");
+        assert_eq!(reader.get(&GridKey { phrase_id: 2, lang_set: 1 }).unwrap().unwrap().count(), 1);
+
+        let tombstone_path = directory.path().join("tombstones");
+        std::fs::write(&tombstone_path, "3\n\n").unwrap();
+        reader.load_tombstones(&tombstone_path).unwrap();
+        assert_eq!(ids(&reader), vec![1, 2], "loading tombstones replaces the old ones");
+
+        let mut builder = GridStoreBuilder::open_existing(&store_path).unwrap();
+        builder.delete_feature(1).unwrap();
+        builder.delete(&GridKey { phrase_id: 2, lang_set: 1 }).unwrap();
+        builder.finish().unwrap();
+        let reader = GridStore::new(&store_path).unwrap();
+        let records: Vec<(GridKey, Vec<GridEntry>)> = reader.iter().map(Result::unwrap).collect();
+        assert_eq!(records, vec![(GridKey { phrase_id: 1, lang_set: 1 }, vec![grid(2)])]);
+    }
+
+    #[test]
+    fn coarse_zooms_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.set_coarse_zooms(vec![8, 4, 0]);
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let grid = |id: u32, x: u16, relev: f64| GridEntry {
+            id,
+            x,
+            y: 2000,
+            relev,
+            score: 3,
+            source_phrase_hash: 0,
+        };
+        // feature 1 covers four tiles in one z10 tile, and feature 2 one in the next z10 tile
+        let entries = vec![
+            grid(1, 1000, 1.),
+            grid(1, 1001, 0.6),
+            grid(1, 1002, 1.),
+            grid(1, 1003, 1.),
+            grid(2, 1010, 0.8),
+        ];
+        builder.insert(&key, entries).unwrap();
+        builder.finish().unwrap();
+
+        let mut reader = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            vec![[0, 0, 16383, 16383]],
+            1.,
+        )
+        .unwrap();
+        assert_eq!(reader.coarse_zoom_levels, vec![4, 8]);
+        assert_eq!(reader.at_zoom(16).zoom, 14);
+        assert_eq!(reader.at_zoom(14).zoom, 14);
+        assert_eq!(reader.at_zoom(12).zoom, 14, "no copy is fine enough");
+        assert_eq!(reader.at_zoom(9).zoom, 10);
+        assert_eq!(reader.at_zoom(2).zoom, 6, "the coarsest copy");
+        assert_eq!(reader.at_zoom(9).bboxes, vec![[0, 0, 1023, 1023]]);
+
+        let coarse = reader.at_zoom(10);
+        let entries: Vec<(u32, u16, u16, f64)> = coarse
+            .get(&key)
+            .unwrap()
+            .unwrap()
+            .map(|entry| (entry.id, entry.x, entry.y, entry.relev))
+            .collect();
+        assert_eq!(entries, vec![(1, 62, 125, 1.), (2, 63, 125, 0.8)]);
+
+        let match_opts = MatchOpts { zoom: 10, ..MatchOpts::default() };
+        let stack = vec![PhrasematchSubquery {
+            store: coarse,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![MatchKeyWithId {
+                key: MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 },
+                id: 0,
+                ..MatchKeyWithId::default()
+            }],
+            bbox: None,
+        }];
+        let contexts = coalesce(stack, &match_opts).unwrap();
+        assert_eq!(contexts.len(), 2, "coarse copies can be coalesced like any store");
+
+        reader.set_tombstones(vec![2]);
+        let match_key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let matching: Vec<u32> = reader
+            .at_zoom(6)
+            .streaming_get_matching(&match_key, &MatchOpts { zoom: 6, ..MatchOpts::default() }, 10)
+            .unwrap()
+            .map(|entry| entry.grid_entry.id)
+            .collect();
+        assert_eq!(matching, vec![1], "tombstones apply to the copies too");
+        drop(reader);
+
+        GridStoreBuilder::open_existing(directory.path()).unwrap().finish().unwrap();
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(
+            reader.coarse_zoom_levels,
+            vec![4],
+            "levels beyond the store's zoom are left out"
+        );
+    }
+
+    #[test]
+    fn verify_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.load_bin_boundaries(vec![0, 2, 4]).unwrap();
+        let key = |phrase_id: u64| GridKey { phrase_id, lang_set: 1 };
+        for phrase_id in 0..4 {
+            let entries = vec![GridEntry {
+                id: phrase_id as u32,
+                x: 1,
+                y: 1,
+                relev: 1.,
+                score: 7,
+                source_phrase_hash: 0,
+            }];
+            builder.insert(&key(phrase_id), entries).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let report = GridStore::new(directory.path()).unwrap().verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.records_checked, 6, "four keys and two prefix bins");
+
+        {
+            let db = rocksdb::DB::open_default(directory.path()).unwrap();
+            let db_key = |type_marker, phrase_id| {
+                let mut db_key = Vec::new();
+                key(phrase_id)
+                    .write_to(type_marker, PhraseIdWidth::current(), &mut db_key)
+                    .unwrap();
+                db_key
+            };
+            let mut value = db.get(db_key(TypeMarker::SinglePhrase, 1)).unwrap().unwrap();
+            value.truncate(value.len() - 1);
+            db.put(db_key(TypeMarker::SinglePhrase, 1), &value).unwrap();
+            db.delete(db_key(TypeMarker::SinglePhrase, 3)).unwrap();
+            db.put(db_key(TypeMarker::PrefixBin, 2), &[0, 1, 2]).unwrap();
+        }
+
+        let report = GridStore::new(directory.path()).unwrap().verify().unwrap();
+        assert_eq!(report.corrupt_keys, vec![key(1), key(3)]);
+        assert_eq!(report.corrupt_bins, vec![key(2)]);
+
+        let open_opts = OpenOpts { verify: true, ..OpenOpts::default() };
+        let err = GridStore::new_with_open_opts(directory.path(), 6, 0, 0., vec![], 0., open_opts)
+            .unwrap_err();
+        match err.downcast_ref::<StoreError>() {
+            Some(StoreError::Corrupt { corrupt, .. }) => assert_eq!(*corrupt, 3),
+            _ => panic!("expected a corrupt store error, got {:?}", err),
+        }
+    }
+
+    #[cfg(not(feature = "zstd-compression"))]
+    #[test]
+    fn compression_unsupported_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder =
+            GridStoreBuilder::new(directory.path()).unwrap().with_compression(Compression::Zstd(3));
+        let grid = GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![grid]).unwrap();
+        let err = builder.finish().unwrap_err();
+        assert_eq!(err.to_string(), "zstd compression needs the zstd-compression feature");
+    }
+
+    #[cfg(feature = "zstd-compression")]
+    #[test]
+    fn compression_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let build = |name: &str, compression: Compression| {
+            let store_path = directory.path().join(name);
+            let mut builder =
+                GridStoreBuilder::new(&store_path).unwrap().with_compression(compression);
+            builder.load_bin_boundaries(vec![0, 2, 4]).unwrap();
+            for phrase_id in 0..4 {
+                let entries: Vec<GridEntry> = (0..50)
+                    .map(|id| GridEntry {
+                        id,
+                        x: (id % 7) as u16,
+                        y: 1,
+                        relev: 1.,
+                        score: (id % 4) as u8,
+                        source_phrase_hash: 0,
+                    })
+                    .collect();
+                builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+            }
+            builder
+                .insert(
+                    &GridKey { phrase_id: 5, lang_set: 1 },
+                    vec![GridEntry {
+                        id: 1,
+                        x: 1,
+                        y: 1,
+                        relev: 1.,
+                        score: 1,
+                        source_phrase_hash: 0,
+                    }],
+                )
+                .unwrap();
+            builder.finish().unwrap();
+            GridStore::new(&store_path).unwrap()
+        };
+        let plain = build("plain", Compression::None);
+        let compressed = build("compressed", Compression::Zstd(3));
+        assert_eq!(plain.compression, Compression::None);
+        assert_eq!(compressed.compression, Compression::Zstd(3), "compression is recorded");
+        assert_eq!(plain.content_hash, compressed.content_hash);
+
+        let records = |reader: &GridStore| {
+            reader.iter().map(Result::unwrap).collect::<Vec<(GridKey, Vec<GridEntry>)>>()
+        };
+        assert_eq!(records(&plain), records(&compressed));
+        let key = GridKey { phrase_id: 5, lang_set: 1 };
+        assert_eq!(
+            plain.get(&key).unwrap().unwrap().collect::<Vec<_>>(),
+            compressed.get(&key).unwrap().unwrap().collect::<Vec<_>>()
+        );
+        for match_phrase in vec![MatchPhrase::Exact(1), MatchPhrase::Range { start: 0, end: 2 }] {
+            let match_key = MatchKey { match_phrase, lang_set: 1 };
+            let matching = |reader: &GridStore| {
+                reader
+                    .streaming_get_matching(&match_key, &MatchOpts::default(), 100)
+                    .unwrap()
+                    .collect::<Vec<MatchEntry>>()
+            };
+            assert_eq!(matching(&plain), matching(&compressed));
+        }
+
+        let merged_path = directory.path().join("merged");
+        GridStoreBuilder::merge(&[&plain.path, &compressed.path], &merged_path).unwrap();
+        let merged = GridStore::new(&merged_path).unwrap();
+        assert_eq!(merged.compression, Compression::Zstd(3));
+        assert_eq!(records(&merged), records(&plain));
+    }
+
+    #[test]
+    fn curve_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let build = |name: &str, curve: Curve| {
+            let store_path = directory.path().join(name);
+            let mut builder = GridStoreBuilder::new(&store_path).unwrap();
+            builder.set_curve(curve);
+            let entries: Vec<GridEntry> = (0..60)
+                .map(|id| GridEntry {
+                    id,
+                    x: (id * 7 % 32) as u16,
+                    y: (id * 13 % 32) as u16,
+                    relev: 1.,
+                    score: (id % 3) as u8,
+                    source_phrase_hash: 0,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+            builder.finish().unwrap();
+            GridStore::new_with_options(&store_path, 14, 1, 200., global_bbox_for_zoom(14), 1.)
+                .unwrap()
+        };
+        let morton = build("morton", Curve::Morton);
+        let hilbert = build("hilbert", Curve::Hilbert);
+        assert_eq!(morton.curve, Curve::Morton);
+        assert_eq!(hilbert.curve, Curve::Hilbert, "the curve is recorded");
+
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let sorted = |mut entries: Vec<GridEntry>| {
+            entries.sort_by_key(|entry| (entry.id, entry.x, entry.y));
+            entries
+        };
+        assert_eq!(
+            sorted(morton.get(&key).unwrap().unwrap().collect()),
+            sorted(hilbert.get(&key).unwrap().unwrap().collect())
+        );
+        let coords = |reader: &GridStore| {
+            let mut coords: Vec<u32> =
+                reader.coords(&key, Some([4, 4, 20, 12])).unwrap().unwrap().collect();
+            coords.sort();
+            coords
+        };
+        assert_eq!(coords(&morton), coords(&hilbert));
+
+        let match_key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let bboxes = Some(Arc::new(vec![[0, 0, 8, 8], [20, 20, 31, 31]]));
+        for match_opts in vec![
+            MatchOpts { zoom: 14, bbox: Some([4, 4, 20, 12]), ..MatchOpts::default() },
+            MatchOpts { zoom: 14, proximity: Some([10, 10]), ..MatchOpts::default() },
+            MatchOpts {
+                zoom: 14,
+                bbox: Some([4, 4, 20, 12]),
+                proximity: Some([10, 10]),
+                ..MatchOpts::default()
+            },
+            MatchOpts { zoom: 14, bboxes: bboxes.clone(), ..MatchOpts::default() },
+        ] {
+            let matching = |reader: &GridStore| {
+                reader
+                    .streaming_get_matching(&match_key, &match_opts, 100)
+                    .unwrap()
+                    .map(|entry| entry.grid_entry)
+                    .collect::<Vec<GridEntry>>()
+            };
+            let (from_morton, from_hilbert) = (matching(&morton), matching(&hilbert));
+            assert!(!from_morton.is_empty());
+            assert_eq!(
+                sorted(from_morton.clone()),
+                sorted(from_hilbert.clone()),
+                "{:?}",
+                match_opts
+            );
+            if match_opts.proximity.is_some() {
+                assert_eq!(from_morton[0], from_hilbert[0], "the nearest comes first either way");
+            }
+        }
+
+        let mut builder = GridStoreBuilder::open_existing(&hilbert.path).unwrap();
+        builder.set_generation(2);
+        drop(hilbert);
+        let path = directory.path().join("hilbert");
+        builder.finish().unwrap();
+        assert_eq!(GridStore::new(&path).unwrap().curve, Curve::Hilbert, "rebuilds keep the curve");
+    }
+
+    #[test]
+    fn offsets_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let path = directory.path().join("offsets");
+        let mut builder = GridStoreBuilder::new(&path).unwrap();
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries: Vec<GridEntry> = (1..=3)
+            .map(|id| GridEntry { id, x: 5, y: 5, relev: 1., score: 1, source_phrase_hash: 0 })
+            .collect();
+        builder.insert(&key, entries).unwrap();
+        // feature 1 is at the far side of the tile from the proximity point, feature 2 at the
+        // near side, and feature 3, which has no offset, is measured to the middle
+        builder.set_offset(1, 5, 5, [15, 8]).unwrap();
+        builder.set_offset(2, 5, 5, [0, 8]).unwrap();
+        assert!(builder.set_offset(3, 5, 5, [16, 0]).is_err());
+        // an explicit offset at the middle of the tile is kept as one
+        builder
+            .insert(
+                &GridKey { phrase_id: 2, lang_set: 1 },
+                vec![GridEntry { id: 4, x: 9, y: 9, relev: 1., score: 1, source_phrase_hash: 0 }],
+            )
+            .unwrap();
+        builder.set_offset(4, 9, 9, [8, 8]).unwrap();
+        builder.finish().unwrap();
+
+        let open = |path: &std::path::Path| {
+            GridStore::new_with_options(path, 6, 1, 200., global_bbox_for_zoom(6), 1.).unwrap()
+        };
+        let match_key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let match_opts = MatchOpts { zoom: 6, proximity: Some([4, 5]), ..MatchOpts::default() };
+        let distances = |reader: &GridStore| {
+            reader
+                .streaming_get_matching(&match_key, &match_opts, 10)
+                .unwrap()
+                .map(|entry| (entry.grid_entry.id, (entry.distance * 32.).round() as u32))
+                .collect::<Vec<_>>()
+        };
+        let reader = open(&path);
+        assert_eq!(distances(&reader), vec![(2, 17), (3, 32), (1, 47)]);
+        assert_eq!(reader.get(&key).unwrap().unwrap().count(), 3, "offsets don't split entries");
+        let reversed: Vec<_> = reader
+            .reverse([4, 5], 6, 3)
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.grid_entry.id, (entry.distance * 32.).round() as u32))
+            .collect();
+        assert_eq!(reversed, vec![(2, 17), (3, 32), (1, 47)], "reverse lookups use offsets too");
+        let expected_offsets = reader.offsets().unwrap();
+        assert_eq!(expected_offsets.len(), 3);
+
+        drop(reader);
+        let mut builder = GridStoreBuilder::open_existing(&path).unwrap();
+        builder.set_generation(2);
+        builder.finish().unwrap();
+        assert_eq!(distances(&open(&path)), vec![(2, 17), (3, 32), (1, 47)], "rebuilds keep them");
+        assert_eq!(open(&path).offsets().unwrap(), expected_offsets);
+
+        let merged_path = directory.path().join("merged");
+        GridStoreBuilder::merge(&[&path], &merged_path).unwrap();
+        assert_eq!(distances(&open(&merged_path)), vec![(2, 17), (3, 32), (1, 47)]);
+        assert_eq!(open(&merged_path).offsets().unwrap(), expected_offsets);
+    }
+
+    #[test]
+    fn mmap_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        builder.load_bin_boundaries(vec![0, 2, 4]).unwrap();
+        for phrase_id in 0..4 {
+            let entries: Vec<GridEntry> = (0..20)
+                .map(|id| GridEntry {
+                    id: id + phrase_id as u32,
+                    x: id as u16,
+                    y: 2,
+                    relev: 1.,
+                    score: (id % 3) as u8,
+                    source_phrase_hash: 0,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let open = |mmap: bool| {
+            let open_opts = OpenOpts { mmap, ..OpenOpts::default() };
+            GridStore::new_with_open_opts(directory.path(), 14, 1, 200., vec![], 1., open_opts)
+                .unwrap()
+        };
+        let (copied, mapped) = (open(false), open(true));
+        let key = GridKey { phrase_id: 3, lang_set: 1 };
+        assert_eq!(
+            copied.get(&key).unwrap().unwrap().collect::<Vec<_>>(),
+            mapped.get(&key).unwrap().unwrap().collect::<Vec<_>>()
+        );
+        assert!(mapped.get(&GridKey { phrase_id: 9, lang_set: 1 }).unwrap().is_none());
+
+        let near = MatchOpts { zoom: 14, proximity: Some([5, 2]), ..MatchOpts::default() };
+        for match_phrase in vec![MatchPhrase::Exact(2), MatchPhrase::Range { start: 1, end: 3 }] {
+            for match_opts in vec![MatchOpts::default(), near.clone()] {
+                let match_key = MatchKey { match_phrase: match_phrase.clone(), lang_set: 1 };
+                let matching = |reader: &GridStore| {
+                    reader
+                        .streaming_get_matching(&match_key, &match_opts, 100)
+                        .unwrap()
+                        .collect::<Vec<MatchEntry>>()
+                };
+                let expected = matching(&copied);
+                assert!(!expected.is_empty());
+                assert_eq!(expected, matching(&mapped));
+            }
+        }
+    }
+
+    #[test]
+    fn record_order_test() {
+        let build = |record_order: RecordOrder| {
+            let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+            let opts = BuilderOpts { record_order, ..BuilderOpts::default() };
+            let mut builder = GridStoreBuilder::new_with_options(directory.path(), opts).unwrap();
+            builder.load_bin_boundaries(vec![0, 2, 4]).unwrap();
+            for phrase_id in 0..4 {
+                for lang_set in 1..3 {
+                    let entries: Vec<GridEntry> = (0..10)
+                        .map(|id| GridEntry {
+                            id: id + phrase_id as u32,
+                            x: id as u16,
+                            y: lang_set as u16,
+                            relev: 1.,
+                            score: (id % 3) as u8,
+                            source_phrase_hash: 0,
+                        })
+                        .collect();
+                    builder.insert(&GridKey { phrase_id, lang_set }, entries).unwrap();
+                }
+            }
+            builder.finish().unwrap();
+            directory
+        };
+        let by_phrase = build(RecordOrder::PhraseId);
+        let frequencies = vec![(3, 10), (1, 5), (2, 1), (0, 0)].into_iter().collect();
+        let by_frequency = build(RecordOrder::AccessFrequency { frequencies, max_phrases: 2 });
+
+        let manifest = StoreManifest::read(by_frequency.path()).unwrap().unwrap();
+        assert_eq!(manifest.hot_phrases, 2);
+        let report = GridStore::new(by_frequency.path()).unwrap().verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.records_checked, 8 + 4 + 4, "records, prefix bins and hot copies");
+
+        // reads of the hot phrases go to their copies, so losing the originals doesn't show
+        {
+            let db = rocksdb::DB::open_default(by_frequency.path()).unwrap();
+            let hot_phrases = db.get("~HOT_PHRASES").unwrap().unwrap();
+            assert_eq!(
+                hot_phrases,
+                [3u64, 1].iter().flat_map(|id| id.to_le_bytes().to_vec()).collect::<Vec<u8>>(),
+                "only the two hottest phrases are copied, hottest first"
+            );
+            let mut db_key = Vec::new();
+            GridKey { phrase_id: 3, lang_set: 1 }
+                .write_to(TypeMarker::SinglePhrase, PhraseIdWidth::current(), &mut db_key)
+                .unwrap();
+            db.delete(&db_key).unwrap();
+        }
+
+        for mmap in vec![false, true] {
+            let open = |directory: &tempfile::TempDir| {
+                let open_opts = OpenOpts { mmap, ..OpenOpts::default() };
+                GridStore::new_with_open_opts(directory.path(), 14, 1, 200., vec![], 1., open_opts)
+                    .unwrap()
+            };
+            let (expected, reader) = (open(&by_phrase), open(&by_frequency));
+            for phrase_id in 0..4 {
+                let key = GridKey { phrase_id, lang_set: 1 };
+                assert_eq!(
+                    expected.get(&key).unwrap().unwrap().collect::<Vec<_>>(),
+                    reader.get(&key).unwrap().unwrap().collect::<Vec<_>>()
+                );
+            }
+
+            let match_phrases = vec![
+                MatchPhrase::Exact(3),
+                MatchPhrase::Exact(1),
+                MatchPhrase::Exact(0),
+                MatchPhrase::Range { start: 1, end: 3 },
+            ];
+            for match_phrase in match_phrases {
+                for lang_set in vec![1, std::u128::MAX] {
+                    let match_key = MatchKey { match_phrase: match_phrase.clone(), lang_set };
+                    let matching = |reader: &GridStore| {
+                        reader
+                            .streaming_get_matching(&match_key, &MatchOpts::default(), 100)
+                            .unwrap()
+                            .collect::<Vec<MatchEntry>>()
+                    };
+                    let expected = matching(&expected);
+                    assert!(!expected.is_empty());
+                    assert_eq!(expected, matching(&reader));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "shared-cache")]
+    #[test]
+    fn shared_cache_test() {
+        use crate::gridstore::shared_cache::SharedCache;
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let store_path = directory.path().join("store");
+        let mut builder =
+            GridStoreBuilder::new(&store_path).unwrap().with_compression(Compression::Zstd(3));
+        for phrase_id in 0..4 {
+            let entries: Vec<GridEntry> = (0..50)
+                .map(|id| GridEntry {
+                    id: id + phrase_id as u32,
+                    x: (id % 7) as u16,
+                    y: 1,
+                    relev: 1.,
+                    score: (id % 4) as u8,
+                    source_phrase_hash: 0,
+                })
+                .collect();
+            builder.insert(&GridKey { phrase_id, lang_set: 1 }, entries).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let cache_path = directory.path().join("cache");
+        let open = |cached: bool| {
+            let shared_cache = if cached {
+                Some(Arc::new(SharedCache::open(&cache_path, 64, 4096).unwrap()))
+            } else {
+                None
+            };
+            let open_opts = OpenOpts { shared_cache, ..OpenOpts::default() };
+            GridStore::new_with_open_opts(&store_path, 14, 1, 200., vec![], 1., open_opts).unwrap()
+        };
+        let uncached = open(false);
+        // stores in two processes sharing the cache
+        let (first, second) = (open(true), open(true));
+
+        let match_opts = MatchOpts { zoom: 14, proximity: Some([3, 1]), ..MatchOpts::default() };
+        for phrase_id in 0..4 {
+            let key = GridKey { phrase_id, lang_set: 1 };
+            let expected = uncached.get(&key).unwrap().unwrap().collect::<Vec<_>>();
+            for store in &[&first, &second, &first] {
+                assert_eq!(store.get(&key).unwrap().unwrap().collect::<Vec<_>>(), expected);
+            }
+
+            let match_key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 1 };
+            let matching = |store: &GridStore| {
+                store
+                    .streaming_get_matching(&match_key, &match_opts, 100)
+                    .unwrap()
+                    .collect::<Vec<MatchEntry>>()
+            };
+            let expected = matching(&uncached);
+            assert!(!expected.is_empty());
+            assert_eq!(matching(&second), expected);
+            assert_eq!(matching(&first), expected);
+        }
+
+        let cache_file = std::fs::read(&cache_path).unwrap();
+        assert!(cache_file[64..].iter().any(|byte| *byte != 0), "records were cached");
+    }
+
+    #[test]
+    fn single_entry_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let single = GridEntry { id: 1, x: 10, y: 10, relev: 0.8, score: 3, source_phrase_hash: 7 };
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, vec![single.clone()]).unwrap();
+        // the same entry alongside another one is stored as a regular record
+        let other =
+            GridEntry { id: 2, x: 200, y: 200, relev: 0.8, score: 3, source_phrase_hash: 0 };
+        builder
+            .insert(&GridKey { phrase_id: 2, lang_set: 1 }, vec![single.clone(), other])
+            .unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new_with_options(
+            directory.path(),
+            14,
+            1,
+            200.,
+            global_bbox_for_zoom(14),
+            1.,
+        )
+        .unwrap();
+        let entries: Vec<_> =
+            reader.get(&GridKey { phrase_id: 1, lang_set: 1 }).unwrap().unwrap().collect();
+        assert_eq!(entries, vec![single], "single-entry keys round-trip");
+
+        let get_matching = |phrase_id: u64, lang_set: u128, match_opts: &MatchOpts| -> Vec<_> {
+            let key = MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set };
+            reader
+                .streaming_get_matching(&key, match_opts, 10)
+                .unwrap()
+                .filter(|entry| entry.grid_entry.id == 1)
+                .collect()
+        };
+        let opts = vec![
+            MatchOpts { zoom: 14, ..MatchOpts::default() },
+            MatchOpts { zoom: 14, proximity: Some([20, 20]), ..MatchOpts::default() },
+            MatchOpts { zoom: 14, bbox: Some([0, 0, 100, 100]), ..MatchOpts::default() },
+            MatchOpts { zoom: 14, bbox: Some([11, 0, 100, 100]), ..MatchOpts::default() },
+        ];
+        for match_opts in opts {
+            for lang_set in vec![1, 2] {
+                assert_eq!(
+                    get_matching(1, lang_set, &match_opts),
+                    get_matching(2, lang_set, &match_opts),
+                    "single-entry keys match the same as regular ones with {:?}",
+                    match_opts
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncation_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let opts = BuilderOpts { max_entries_per_key: Some(2), ..BuilderOpts::default() };
+        let mut builder = GridStoreBuilder::new_with_options(directory.path(), opts).unwrap();
+
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries: Vec<_> = (0..5)
+            .map(|id| GridEntry {
+                id,
+                x: id as u16,
+                y: 1,
+                relev: 1.,
+                score: id as u8,
+                source_phrase_hash: 0,
+            })
+            .collect();
+        builder.insert(&key, entries).expect("Unable to insert record");
+        builder
+            .insert(
+                &GridKey { phrase_id: 2, lang_set: 1 },
+                vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 }],
+            )
+            .expect("Unable to insert record");
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        let ids: Vec<_> = reader.get(&key).unwrap().unwrap().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![4, 3], "only the highest-scoring entries are kept");
+        assert_eq!(
+            reader.truncation_stats,
+            TruncationStats { keys_truncated: 1, entries_dropped: 3 },
+            "truncation is recorded in the store"
+        );
+        assert_eq!(reader.keys().count(), 2, "truncation markers don't show up as keys");
+
+        let truncated = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let complete = MatchKey { match_phrase: MatchPhrase::Exact(2), lang_set: 1 };
+        let range = MatchKey { match_phrase: MatchPhrase::Range { start: 0, end: 3 }, lang_set: 1 };
+        assert!(reader.is_truncated(&truncated).unwrap());
+        assert!(!reader.is_truncated(&complete).unwrap());
+        assert!(reader.is_truncated(&range).unwrap(), "a range with a truncated key is truncated");
+        let past = MatchKey { match_phrase: MatchPhrase::Range { start: 2, end: 10 }, lang_set: 1 };
+        assert!(!reader.is_truncated(&past).unwrap(), "a range past the truncated key isn't");
+        let missing = MatchKey { match_phrase: MatchPhrase::Exact(0), lang_set: 1 };
+        assert!(!reader.is_truncated(&missing).unwrap(), "a key without records isn't truncated");
+
+        let flags = |match_key: &MatchKey| -> Vec<bool> {
+            let opts = MatchOpts::default();
+            let entries = reader.streaming_get_matching(match_key, &opts, 10).unwrap();
+            entries.map(|entry| entry.truncated).collect()
+        };
+        assert_eq!(flags(&truncated), vec![true, true], "matches of truncated phrases are flagged");
+        assert_eq!(flags(&complete), vec![false]);
+
+        let subquery = PhrasematchSubquery {
+            store: &reader,
+            idx: 1,
+            non_overlapping_indexes: FixedBitSet::with_capacity(128),
+            weight: 1.,
+            mask: 1,
+            match_keys: vec![
+                MatchKeyWithId { key: truncated, id: 7, ..MatchKeyWithId::default() },
+                MatchKeyWithId { key: complete, id: 8, ..MatchKeyWithId::default() },
+            ],
+            bbox: None,
+        };
+        let (_, stats) =
+            stack_and_coalesce_with_stats(&vec![subquery], &MatchOpts::default()).unwrap();
+        assert_eq!(stats.truncated_match_keys, vec![7], "truncated phrases are reported");
+    }
+
     #[test]
     fn phrase_hash_test() {
         let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
@@ -140,6 +1715,42 @@ mod tests {
         assert_eq!(record[2], entries[0], "expected second result");
     }
 
+    #[test]
+    fn grouped_matching_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries = vec![
+            GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 1, x: 1, y: 2, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 1, x: 2, y: 1, relev: 1., score: 1, source_phrase_hash: 0 },
+            GridEntry { id: 2, x: 3, y: 3, relev: 1., score: 5, source_phrase_hash: 0 },
+            GridEntry { id: 3, x: 4, y: 4, relev: 0.8, score: 7, source_phrase_hash: 0 },
+            GridEntry { id: 3, x: 4, y: 5, relev: 0.8, score: 7, source_phrase_hash: 0 },
+        ];
+        builder.insert(&key, entries).expect("Unable to insert record");
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        let search_key = MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 };
+        let ungrouped: Vec<_> = reader
+            .streaming_get_matching(&search_key, &MatchOpts::default(), MAX_CONTEXTS)
+            .unwrap()
+            .collect();
+        let grouped: Vec<_> = reader
+            .get_matching_grouped(&search_key, &MatchOpts::default(), MAX_CONTEXTS)
+            .unwrap()
+            .collect();
+
+        let summary: Vec<_> = grouped.iter().map(|(id, _, covers)| (*id, *covers)).collect();
+        assert_eq!(summary, vec![(2, 1), (1, 3), (3, 2)], "one item per feature, best first");
+        for (id, best, _) in grouped.iter() {
+            let first = ungrouped.iter().find(|entry| entry.grid_entry.id == *id).unwrap();
+            assert_eq!(best, first, "best entry is the feature's first ungrouped entry");
+        }
+    }
+
     #[test]
     fn score_test() {
         let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
@@ -215,14 +1826,14 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false }
             ]
         );
 
@@ -236,18 +1847,18 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false }
             ]
         );
 
@@ -261,18 +1872,18 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false }
             ]
         );
 
@@ -286,18 +1897,18 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 1.0, truncated: false }
             ]
         );
 
@@ -311,18 +1922,18 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false }
             ]
         );
 
@@ -356,10 +1967,10 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0 },
-                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 7.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 1.0, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 0.96, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: false, distance: 0.0, scoredist: 7.0, truncated: false }
             ]
         );
 
@@ -408,18 +2019,18 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 15750.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 2.0, scoredist: 913.3852617539986 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 16.0, scoredist: 787.5000000000001 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 31.0, scoredist: 406.4516129032259 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 31.0, scoredist: 406.4516129032259 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 32.0, scoredist: 393.75000000000006 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 14.0, scoredist: 130.48360882199978 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 30.0, scoredist: 60.89235078359991 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 15750.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 2.0, scoredist: 913.3852617539986, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 42, y: 1, id: 22, source_phrase_hash: 0 }, matches_language: false, distance: 16.0, scoredist: 787.5000000000001, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 31, source_phrase_hash: 0 }, matches_language: false, distance: 31.0, scoredist: 406.4516129032259, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 57, y: 1, id: 29, source_phrase_hash: 0 }, matches_language: false, distance: 31.0, scoredist: 406.4516129032259, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 58, y: 1, id: 30, source_phrase_hash: 0 }, matches_language: false, distance: 32.0, scoredist: 393.75000000000006, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 14.0, scoredist: 130.48360882199978, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 56, y: 1, id: 28, source_phrase_hash: 0 }, matches_language: false, distance: 30.0, scoredist: 60.89235078359991, truncated: false }
             ]
         );
 
@@ -441,16 +2052,40 @@ mod tests {
         assert_eq!(
             records,
             [
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 15750.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 2.0, scoredist: 913.3852617539986 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002 },
-                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 14.0, scoredist: 130.48360882199978 }
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 26, y: 1, id: 14, source_phrase_hash: 0 }, matches_language: true, distance: 0.0, scoredist: 15750.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 15, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 25, y: 1, id: 13, source_phrase_hash: 0 }, matches_language: true, distance: 1.0, scoredist: 12600.000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 24, y: 1, id: 12, source_phrase_hash: 0 }, matches_language: true, distance: 2.0, scoredist: 913.3852617539986, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 23, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 7, x: 41, y: 1, id: 21, source_phrase_hash: 0 }, matches_language: false, distance: 15.0, scoredist: 840.0000000000002, truncated: false },
+                MatchEntry { grid_entry: GridEntry { relev: 1.0, score: 1, x: 40, y: 1, id: 20, source_phrase_hash: 0 }, matches_language: false, distance: 14.0, scoredist: 130.48360882199978, truncated: false }
             ]
         );
 
+        // past the proximity cutoff (5 miles is 9 tiles at z16) scoredist is driven by score alone
+        let records: Vec<_> = reader
+            .streaming_get_matching(
+                &search_key,
+                &MatchOpts {
+                    bbox: Some([10, 0, 41, 2]),
+                    proximity: Some([26, 1]),
+                    proximity_radius: Some(5.),
+                    ..MatchOpts::default()
+                },
+                MAX_CONTEXTS,
+            )
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0].scoredist, 15750.000000000002, "nearby scoredist is unchanged");
+        for record in records.iter().filter(|record| record.distance > 9.) {
+            if record.grid_entry.score == 7 {
+                assert_eq!(record.scoredist, 7., "far away scoredist only depends on score");
+            } else {
+                assert!(record.scoredist < 7., "far away scoredist only depends on score");
+            }
+        }
+
         let listed_keys: Result<Vec<_>, _> = reader.keys().collect();
         let mut orig_keys = keys.clone();
         orig_keys.sort();
@@ -487,7 +2122,7 @@ mod tests {
 
         // insert phrases
         for i in 0..=(phrases.len() as u32) {
-            let key = GridKey { phrase_id: i, lang_set: 1 };
+            let key = GridKey { phrase_id: i as u64, lang_set: 1 };
             let entries = vec![GridEntry {
                 id: i,
                 x: i as u16,
@@ -503,13 +2138,13 @@ mod tests {
         }
 
         // calculate bins
-        let mut bins: BTreeMap<u8, u32> = BTreeMap::new();
+        let mut bins: BTreeMap<u8, u64> = BTreeMap::new();
         for (i, phrase) in phrases.iter().enumerate() {
             // insert the first occurrence of every prefix
-            bins.entry(phrase.bytes().next().unwrap()).or_insert(i as u32);
+            bins.entry(phrase.bytes().next().unwrap()).or_insert(i as u64);
         }
         let mut boundaries: Vec<_> = bins.values().cloned().collect();
-        boundaries.push(phrases.len() as u32);
+        boundaries.push(phrases.len() as u64);
 
         builder_with_boundaries.load_bin_boundaries(boundaries).expect("Failed to load boundaries");
 
@@ -544,7 +2179,7 @@ mod tests {
         )
     });
 
-    fn find_prefix_range(prefix: &str) -> (u32, u32) {
+    fn find_prefix_range(prefix: &str) -> (u64, u64) {
         let phrases = &PREFIX_DATA.2;
 
         let start =
@@ -557,7 +2192,7 @@ mod tests {
             .unwrap()
             .0
             + 1;
-        (start as u32, end as u32)
+        (start as u64, end as u64)
     }
 
     #[test]
@@ -595,12 +2230,13 @@ mod tests {
                     score: 1,
                     x: i as u16,
                     y: 1,
-                    id: i,
+                    id: i as u32,
                     source_phrase_hash: 0,
                 },
                 matches_language: true,
                 distance: 0.0,
                 scoredist: 1.0,
+                truncated: false,
             })
         }
 
@@ -638,12 +2274,13 @@ mod tests {
                     score: 1,
                     x: i as u16,
                     y: 1,
-                    id: i,
+                    id: i as u32,
                     source_phrase_hash: 0,
                 },
                 matches_language: true,
                 distance: 0.0,
                 scoredist: 1.0,
+                truncated: false,
             })
         }
         assert_eq!(records_with_boundaries, expected);
@@ -679,6 +2316,7 @@ mod tests {
                     ..MatchKeyWithId::default()
                 }],
                 mask: 1 << 0,
+                bbox: None,
             };
             let stack = vec![subquery];
             let match_opts = MatchOpts {
@@ -695,4 +2333,210 @@ mod tests {
         // and so should the starts_with_bc ones
         assert_eq!(results[2], results[3]);
     }
+
+    #[test]
+    fn abort_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let path = directory.path().join("store");
+
+        // leave behind a store whose build didn't finish
+        {
+            let mut opts = rocksdb::Options::default();
+            opts.create_if_missing(true);
+            let db = rocksdb::DB::open(&opts, &path).unwrap();
+            db.put("~BUILDING", &[]).unwrap();
+            db.put("~BOUNDS", &[]).unwrap();
+        }
+        let err = GridStore::new(&path).unwrap_err();
+        assert!(err.to_string().contains("incomplete store"), "incomplete stores can't be opened");
+
+        GridStoreBuilder::new(&path).unwrap().abort().unwrap();
+        assert!(GridStore::new(&path).is_err(), "the incomplete store has been removed");
+
+        // a complete store is left alone
+        let mut builder = GridStoreBuilder::new(&path).unwrap();
+        let entries =
+            vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 }];
+        builder.insert(&GridKey { phrase_id: 1, lang_set: 1 }, entries).unwrap();
+        builder.finish().unwrap();
+        GridStoreBuilder::new(&path).unwrap().abort().unwrap();
+        let reader = GridStore::new(&path).unwrap();
+        assert_eq!(reader.get(&GridKey { phrase_id: 1, lang_set: 1 }).unwrap().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn newer_minor_format_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let key = GridKey { phrase_id: 1, lang_set: 1 };
+        let entries =
+            vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 }];
+        builder.insert(&key, entries).unwrap();
+        builder.finish().unwrap();
+        assert_eq!(GridStore::new(directory.path()).unwrap().unknown_sections, Vec::<u8>::new());
+
+        let set_format = |major: u16, minor: u16| {
+            let db = rocksdb::DB::open_default(directory.path()).unwrap();
+            let mut version = major.to_le_bytes().to_vec();
+            version.extend_from_slice(&minor.to_le_bytes());
+            db.put("~FORMAT", &version).unwrap();
+            // a section a newer builder added
+            db.put(&[9, 0, 0, 0, 1], &[1, 2, 3]).unwrap();
+        };
+        let open = |newer_minor_format| {
+            GridStore::new_with_open_opts(
+                directory.path(),
+                6,
+                0,
+                0.0,
+                vec![[0, 0, 63, 63]],
+                0.0,
+                OpenOpts { newer_minor_format, ..OpenOpts::default() },
+            )
+        };
+
+        set_format(FORMAT_MAJOR_VERSION, FORMAT_MINOR_VERSION + 1);
+        let reader = open(NewerMinorFormat::Open).unwrap();
+        assert_eq!(reader.unknown_sections, vec![9]);
+        assert_eq!(reader.keys().collect::<Result<Vec<_>, _>>().unwrap(), vec![key.clone()]);
+        assert_eq!(reader.get(&key).unwrap().unwrap().count(), 1);
+        drop(reader);
+        assert!(open(NewerMinorFormat::Refuse).is_err());
+
+        set_format(FORMAT_MAJOR_VERSION + 1, 0);
+        let err = open(NewerMinorFormat::Open).unwrap_err();
+        assert!(err.to_string().contains("unsupported format version"));
+    }
+
+    #[test]
+    fn migrate_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        let keys: Vec<GridKey> =
+            (1..4).map(|phrase_id| GridKey { phrase_id, lang_set: 1 }).collect();
+        for key in keys.iter() {
+            let entries = vec![GridEntry {
+                id: key.phrase_id as u32,
+                x: 1,
+                y: 1,
+                relev: 1.,
+                score: 7,
+                source_phrase_hash: 0,
+            }];
+            builder.insert(key, entries).unwrap();
+        }
+        builder.finish().unwrap();
+        let expected: Vec<_> =
+            GridStore::new(directory.path()).unwrap().iter().map(Result::unwrap).collect();
+        assert_eq!(
+            GridStoreBuilder::migrate(directory.path()).unwrap(),
+            false,
+            "current stores are left alone"
+        );
+
+        // rewrite the store the way the first, unversioned builder laid it out
+        {
+            let db = rocksdb::DB::open_default(directory.path()).unwrap();
+            for key in keys.iter() {
+                let (mut wide, mut narrow) = (Vec::new(), Vec::new());
+                key.write_to(TypeMarker::SinglePhrase, PhraseIdWidth::U64, &mut wide).unwrap();
+                key.write_to(TypeMarker::SinglePhrase, PhraseIdWidth::U32, &mut narrow).unwrap();
+                let value = db.get(&wide).unwrap().unwrap();
+                db.delete(&wide).unwrap();
+                db.put(&narrow, &value).unwrap();
+            }
+            db.delete("~FORMAT").unwrap();
+        }
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.format_version, (1, 0));
+        assert_eq!(
+            reader.iter().map(Result::unwrap).collect::<Vec<_>>(),
+            expected,
+            "old stores are read in place"
+        );
+        drop(reader);
+
+        assert_eq!(GridStoreBuilder::migrate(directory.path()).unwrap(), true);
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.format_version, (FORMAT_MAJOR_VERSION, FORMAT_MINOR_VERSION));
+        assert_eq!(reader.iter().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        drop(reader);
+
+        {
+            let db = rocksdb::DB::open_default(directory.path()).unwrap();
+            let mut version = (FORMAT_MAJOR_VERSION + 1).to_le_bytes().to_vec();
+            version.extend_from_slice(&0u16.to_le_bytes());
+            db.put("~FORMAT", &version).unwrap();
+        }
+        let err = GridStoreBuilder::migrate(directory.path()).unwrap_err();
+        match err.downcast_ref::<StoreError>() {
+            Some(StoreError::UnsupportedFormat { major, .. }) => {
+                assert_eq!(*major, FORMAT_MAJOR_VERSION + 1)
+            }
+            _ => panic!("expected an unsupported format error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn wide_phrase_id_test() {
+        let wide_key = GridKey { phrase_id: 1 << 40, lang_set: 1 };
+        let narrow_keys: Vec<_> =
+            (1..=3).map(|phrase_id| GridKey { phrase_id, lang_set: 1 }).collect();
+        let entry = |id| GridEntry { id, x: 1, y: 1, relev: 1., score: 7, source_phrase_hash: 0 };
+
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let mut builder = GridStoreBuilder::new(directory.path()).unwrap();
+        for key in narrow_keys.iter() {
+            builder.insert(key, vec![entry(key.phrase_id as u32)]).unwrap();
+        }
+        builder.insert(&wide_key, vec![entry(100)]).unwrap();
+        builder.load_bin_boundaries(vec![1, 3, 1 << 40]).unwrap();
+        builder.finish().unwrap();
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.get(&wide_key).unwrap().unwrap().next().unwrap().id, 100);
+        let range =
+            MatchKey { match_phrase: MatchPhrase::Range { start: 3, end: 1 << 41 }, lang_set: 1 };
+        let ids: Vec<_> = reader
+            .streaming_get_matching(&range, &MatchOpts::default(), MAX_CONTEXTS)
+            .unwrap()
+            .map(|entry| entry.grid_entry.id)
+            .collect();
+        assert_eq!(ids, vec![100, 3]);
+        drop(reader);
+
+        // rewrite it the way a store from before phrase ids were widened would have been written
+        let db = rocksdb::DB::open_default(directory.path()).unwrap();
+        let keys: Vec<_> = db
+            .iterator(rocksdb::IteratorMode::Start)
+            .take_while(|(key, _)| key[0] == TypeMarker::SinglePhrase as u8)
+            .collect();
+        for (key, value) in keys {
+            db.delete(&key).unwrap();
+            if key[1..5] == [0, 0, 0, 0] {
+                let narrow_key: Vec<u8> = key[..1].iter().chain(key[5..].iter()).cloned().collect();
+                db.put(&narrow_key, &value).unwrap();
+            }
+        }
+        db.put("~BOUNDS", &[1u8, 0, 0, 0, 3, 0, 0, 0]).unwrap();
+        db.delete("~FORMAT").unwrap();
+        drop(db);
+
+        let reader = GridStore::new(directory.path()).unwrap();
+        assert_eq!(reader.keys().collect::<Result<Vec<_>, _>>().unwrap(), narrow_keys);
+        assert_eq!(reader.bin_boundaries, vec![1, 3].into_iter().collect());
+        assert_eq!(reader.get(&narrow_keys[1]).unwrap().unwrap().next().unwrap().id, 2);
+        assert!(reader.get(&wide_key).unwrap().is_none(), "old stores can't have wide phrase ids");
+        let ids: Vec<_> = reader
+            .streaming_get_matching(&range, &MatchOpts::default(), MAX_CONTEXTS)
+            .unwrap()
+            .map(|entry| entry.grid_entry.id)
+            .collect();
+        assert_eq!(ids, vec![3]);
+        let wide_exact = MatchKey { match_phrase: MatchPhrase::Exact(1 << 40), lang_set: 1 };
+        assert_eq!(
+            reader.streaming_get_matching(&wide_exact, &MatchOpts::default(), 1).unwrap().count(),
+            0
+        );
+    }
 }
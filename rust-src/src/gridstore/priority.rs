@@ -0,0 +1,169 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use crate::gridstore::common::QueryPriority;
+use crate::gridstore::settings::Settings;
+
+/// Lets batch queries yield to interactive ones in the same process. Queries given one in
+/// `MatchOpts::scheduler` are counted for as long as they run if they're interactive, and wait
+/// for the counted ones to finish before starting, for a bounded time, if they're batch. Queries
+/// without one never wait. Clones share one count, and compare equal only to each other.
+#[derive(Debug, Clone, Default)]
+pub struct QueryScheduler(Arc<SchedulerState>);
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    /// Overrides `Limits::batch_max_wait` if set
+    max_wait: Option<Duration>,
+    counts: Mutex<SchedulerCounts>,
+    /// Signalled whenever the last running interactive query finishes
+    idle: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerCounts {
+    interactive: usize,
+    waiting: usize,
+}
+
+impl QueryScheduler {
+    pub fn new() -> Self {
+        QueryScheduler::default()
+    }
+
+    /// A scheduler whose batch queries wait at most `max_wait` for interactive ones, rather than
+    /// `Limits::batch_max_wait`
+    pub fn with_max_wait(max_wait: Duration) -> Self {
+        QueryScheduler(Arc::new(SchedulerState { max_wait: Some(max_wait), ..Default::default() }))
+    }
+
+    /// How many interactive queries are running
+    pub fn running_interactive(&self) -> usize {
+        self.counts().interactive
+    }
+
+    /// How many batch queries are waiting for interactive ones to finish
+    pub fn waiting_batch(&self) -> usize {
+        self.counts().waiting
+    }
+
+    fn counts(&self) -> MutexGuard<'_, SchedulerCounts> {
+        self.0.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PartialEq for QueryScheduler {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The pool batch queries do their parallel work in, a quarter the size of the global pool that
+/// interactive queries use, so batch work can never take every thread
+fn batch_pool() -> Option<&'static ThreadPool> {
+    static BATCH_POOL: OnceLock<Option<ThreadPool>> = OnceLock::new();
+    BATCH_POOL
+        .get_or_init(|| {
+            let threads = std::cmp::max(rayon::current_num_threads() / 4, 1);
+            ThreadPoolBuilder::new().num_threads(threads).build().ok()
+        })
+        .as_ref()
+}
+
+/// Held for as long as a query runs; interactive queries are counted until it's dropped
+pub(crate) struct Admission {
+    counted: Option<QueryScheduler>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(scheduler) = &self.counted {
+            let mut counts = scheduler.counts();
+            counts.interactive -= 1;
+            if counts.interactive == 0 {
+                scheduler.0.idle.notify_all();
+            }
+        }
+    }
+}
+
+/// Lets a query start; only public entry points call this, once per query. Interactive queries
+/// start right away; batch queries first wait for the interactive queries running under the same
+/// scheduler to finish, for up to its max wait, so that a steady stream of interactive traffic
+/// slows batch work down but can't stall it.
+pub(crate) fn admit(priority: QueryPriority, scheduler: Option<&QueryScheduler>) -> Admission {
+    let scheduler = match scheduler {
+        Some(scheduler) => scheduler,
+        None => return Admission { counted: None },
+    };
+    let mut counts = scheduler.counts();
+    match priority {
+        QueryPriority::Interactive => {
+            counts.interactive += 1;
+            Admission { counted: Some(scheduler.clone()) }
+        }
+        QueryPriority::Batch => {
+            let max_wait =
+                scheduler.0.max_wait.unwrap_or_else(|| Settings::global().load().batch_max_wait);
+            counts.waiting += 1;
+            let mut counts = scheduler
+                .0
+                .idle
+                .wait_timeout_while(counts, max_wait, |counts| counts.interactive > 0)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+            counts.waiting -= 1;
+            Admission { counted: None }
+        }
+    }
+}
+
+/// Admits a query and runs it, with its parallel work in the thread pool for its priority
+pub(crate) fn run<R: Send, F: FnOnce() -> R + Send>(
+    priority: QueryPriority,
+    scheduler: Option<&QueryScheduler>,
+    f: F,
+) -> R {
+    let _admission = admit(priority, scheduler);
+    match (priority, batch_pool()) {
+        (QueryPriority::Batch, Some(pool)) => pool.install(f),
+        _ => f(),
+    }
+}
+
+#[test]
+fn batch_waits_for_interactive_test() {
+    use std::sync::mpsc;
+
+    // long enough that the batch query below can only start once the interactive one is done
+    let scheduler = QueryScheduler::with_max_wait(Duration::from_secs(3600));
+    let interactive = admit(QueryPriority::Interactive, Some(&scheduler));
+    assert_eq!(scheduler.running_interactive(), 1);
+
+    let (admitted, admissions) = mpsc::channel();
+    let batch = {
+        let scheduler = scheduler.clone();
+        std::thread::spawn(move || {
+            let _admission = admit(QueryPriority::Batch, Some(&scheduler));
+            admitted.send(()).unwrap();
+        })
+    };
+    while scheduler.waiting_batch() == 0 {
+        std::thread::yield_now();
+    }
+    assert!(admissions.try_recv().is_err(), "batch queries wait behind interactive ones");
+    drop(interactive);
+    admissions.recv().unwrap();
+    batch.join().unwrap();
+    assert_eq!(scheduler.waiting_batch(), 0);
+
+    let other = QueryScheduler::with_max_wait(Duration::from_secs(3600));
+    let _interactive = admit(QueryPriority::Interactive, Some(&other));
+    drop(admit(QueryPriority::Batch, Some(&scheduler)));
+    drop(admit(QueryPriority::Batch, None));
+    assert_ne!(scheduler, other, "only queries under the same scheduler wait for each other");
+
+    let in_batch_pool = run(QueryPriority::Batch, None, || rayon::current_thread_index().is_some());
+    assert!(in_batch_pool, "batch queries run in the batch pool");
+}
@@ -0,0 +1,153 @@
+//! The ranking math used at query time, exposed so indexer-side tooling and offline analysis can
+//! reproduce the exact scores a query would produce.
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::gridstore::common::{CoalesceEntry, MatchOpts};
+use crate::gridstore::spatial;
+
+/// The parts of a query and index that scoredist depends on besides the grid itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoredistOpts {
+    /// Coalesce radius of the index the grid comes from, in miles
+    pub coalesce_radius: f64,
+    /// Radius in miles beyond which scoredist is driven by score alone; see
+    /// `MatchOpts::proximity_radius`
+    pub proximity_radius: Option<f64>,
+}
+
+impl ScoredistOpts {
+    pub fn new(coalesce_radius: f64, match_opts: &MatchOpts) -> Self {
+        ScoredistOpts { coalesce_radius, proximity_radius: match_opts.proximity_radius }
+    }
+}
+
+/// Computes the scoredist of a grid with the given score that is `distance` tiles away from the
+/// proximity point at `zoom`, exactly as it's computed when a store is queried with proximity
+pub fn scoredist(score: u8, distance: f64, zoom: u16, opts: &ScoredistOpts) -> f64 {
+    match opts.proximity_radius {
+        Some(radius) if distance > spatial::proximity_radius(zoom, radius) => {
+            spatial::score_only_scoredist(score)
+        }
+        _ => spatial::scoredist(zoom, distance, score, opts.coalesce_radius),
+    }
+}
+
+/// Converts a distance in tiles at `zoom` into tiles at `target_zoom`, so that distances
+/// measured in stores with different zooms are comparable
+#[inline]
+pub fn zoom_normalized_distance(distance: f64, zoom: u16, target_zoom: u16) -> f64 {
+    distance * 2f64.powi(target_zoom as i32 - zoom as i32)
+}
+
+/// Like `scoredist`, but for a grid in a store at `zoom` that's compared with grids from stores at
+/// other zooms, so it's computed on its distance in tiles at `target_zoom`. Distances under a tile
+/// are floored in the grid's own tiles, as `scoredist` floors them, before they're converted, so a
+/// low-zoom grid whose tile covers the proximity point isn't taken to be any closer than its tile
+/// allows. At `target_zoom == zoom`, this is `scoredist`.
+pub fn zoom_normalized_scoredist(
+    score: u8,
+    distance: f64,
+    zoom: u16,
+    target_zoom: u16,
+    opts: &ScoredistOpts,
+) -> f64 {
+    let distance = if distance < 1. { spatial::MIN_TILE_DISTANCE } else { distance };
+    scoredist(score, zoom_normalized_distance(distance, zoom, target_zoom), target_zoom, opts)
+}
+
+/// How coalesce ranks what it finds, for trying out alternative rankings (e.g. weighting by
+/// popularity) without forking coalesce. Each method defaults to the built-in ranking, so
+/// policies only override what they change.
+pub trait ScorePolicy: Debug + Send + Sync {
+    /// The scoredist to rank `entry` by, given the scoredist the built-in ranking gave it in
+    /// `entry.scoredist`
+    fn scoredist(&self, entry: &CoalesceEntry) -> f64 {
+        entry.scoredist
+    }
+
+    /// The relevance of a context made up of `entries`, given `summed`, the sum of their
+    /// relevances that the built-in ranking uses. Coalesce's own penalties for unstacked and
+    /// out-of-order contexts are applied on top of this. Only `coalesce` calls this; tree
+    /// coalesce still ranks contexts by summed relevance.
+    fn context_relevance(&self, entries: &[CoalesceEntry], summed: f64) -> f64 {
+        let _ = entries;
+        summed
+    }
+}
+
+/// The built-in ranking
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultScorePolicy;
+
+impl ScorePolicy for DefaultScorePolicy {}
+
+/// A `ScorePolicy` to share between queries, which compares equal only to itself
+#[derive(Debug, Clone)]
+pub struct SharedScorePolicy(pub Arc<dyn ScorePolicy>);
+
+impl PartialEq for SharedScorePolicy {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(Arc::as_ptr(&self.0) as *const u8, Arc::as_ptr(&other.0) as *const u8)
+    }
+}
+
+#[test]
+fn scoredist_test() {
+    let opts = ScoredistOpts { coalesce_radius: 400., proximity_radius: None };
+    assert_eq!(
+        scoredist(0, 1., 14, &opts),
+        spatial::scoredist(14, 1., 0, 400.),
+        "without a proximity radius, scoredist matches the spatial calculation"
+    );
+
+    // a 10 mile radius is 8 tiles at zoom 14
+    let opts = ScoredistOpts { coalesce_radius: 400., proximity_radius: Some(10.) };
+    assert_eq!(scoredist(3, 8., 14, &opts), spatial::scoredist(14, 8., 3, 400.));
+    assert_eq!(
+        scoredist(3, 9., 14, &opts),
+        spatial::score_only_scoredist(3),
+        "past the proximity radius, scoredist only depends on score"
+    );
+
+    let match_opts = MatchOpts { proximity_radius: Some(10.), ..MatchOpts::default() };
+    assert_eq!(ScoredistOpts::new(400., &match_opts), opts);
+}
+
+#[test]
+fn zoom_normalized_scoredist_test() {
+    let opts = ScoredistOpts { coalesce_radius: 40., proximity_radius: None };
+    assert_eq!(zoom_normalized_distance(3., 14, 14), 3.);
+    assert_eq!(zoom_normalized_distance(3., 12, 14), 12.);
+    assert_eq!(zoom_normalized_distance(4., 16, 14), 1.);
+
+    // two tiles at z14 covers the same distance as one tile at z13
+    assert_eq!(
+        zoom_normalized_scoredist(3, 2., 14, 14, &opts),
+        zoom_normalized_scoredist(3, 1., 13, 14, &opts)
+    );
+    assert!(
+        scoredist(3, 1., 13, &opts) > scoredist(3, 2., 14, &opts),
+        "unnormalized, the lower zoom grid gets the better scoredist for the same distance"
+    );
+    for distance in vec![0., 0.5, 2., 100.] {
+        assert_eq!(
+            zoom_normalized_scoredist(3, distance, 6, 6, &opts),
+            scoredist(3, distance, 6, &opts),
+            "at the store's own zoom, it's plain scoredist"
+        );
+    }
+
+    // a z6 tile is hundreds of miles across, so a z6 grid covering the proximity point doesn't
+    // count as closer than a z14 grid a couple of miles away
+    let opts = ScoredistOpts { coalesce_radius: 200., proximity_radius: None };
+    assert!(
+        zoom_normalized_scoredist(3, 0., 6, 14, &opts)
+            < zoom_normalized_scoredist(3, 2., 14, 14, &opts)
+    );
+    assert_eq!(
+        zoom_normalized_scoredist(3, 0., 6, 14, &opts),
+        spatial::score_only_scoredist(3),
+        "a z6 grid on the proximity tile is past the proximity radius at z14"
+    );
+}
@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use serde::{Deserialize, Serialize};
+
+use crate::gridstore::coalesce::PROBE_MAX_CANDIDATES;
+use crate::gridstore::common::MAX_GRIDS_PER_PHRASE;
+
+/// Limits on the work a query can do, which can be changed while the process is running
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    /// Most grids coalesce fetches for a phrase from a store
+    pub max_grids_per_phrase: usize,
+    /// Most grids the selective subquery of a pair can have for coalesce to probe the other
+    /// subquery's store for each of them rather than falling back to a hash join
+    pub probe_max_candidates: usize,
+    /// Cap on coalesce's intermediate results for queries that don't set
+    /// `CoalesceOpts::max_memory_bytes`
+    pub max_memory_bytes: Option<usize>,
+    /// Longest a batch query waits for interactive queries to finish before starting anyway,
+    /// unless its scheduler was made with `QueryScheduler::with_max_wait`
+    pub batch_max_wait: Duration,
+    /// Longest a store spends reading the records for one `streaming_get_matching` call before
+    /// giving up with `QueryError::ReadTimedOut`, so one slow store can't hold up a whole query
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_grids_per_phrase: MAX_GRIDS_PER_PHRASE,
+            probe_max_candidates: PROBE_MAX_CANDIDATES,
+            max_memory_bytes: None,
+            batch_max_wait: Duration::from_millis(50),
+            read_timeout: None,
+        }
+    }
+}
+
+/// When `Settings` should tighten their limits by themselves, to protect an overloaded node: while
+/// reading records from stores is slow, e.g. because the page cache is under pressure, queries
+/// fetch fewer grids per phrase and skip probing stores once per candidate, which is mostly
+/// random reads. Limits are put back once reads are fast again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AdaptiveOpts {
+    /// Limits are tightened once the average time to read a phrase's records from a store goes
+    /// over this
+    pub degrade_above: Duration,
+    /// Limits are put back once the average is under this again
+    pub restore_below: Duration,
+    /// How much each read counts towards the average, from 0 to 1; the rest is the previous
+    /// average
+    pub smoothing: f64,
+    /// Fraction of `Limits::max_grids_per_phrase` that's fetched while limits are tightened
+    pub grid_fraction: f64,
+}
+
+impl Default for AdaptiveOpts {
+    fn default() -> Self {
+        AdaptiveOpts {
+            degrade_above: Duration::from_millis(20),
+            restore_below: Duration::from_millis(5),
+            smoothing: 0.05,
+            grid_fraction: 0.25,
+        }
+    }
+}
+
+impl AdaptiveOpts {
+    /// The tightened version of `limits`
+    fn degrade(&self, limits: &Limits) -> Limits {
+        let max_grids = (limits.max_grids_per_phrase as f64 * self.grid_fraction) as usize;
+        Limits {
+            max_grids_per_phrase: std::cmp::max(max_grids, 1),
+            probe_max_candidates: 0,
+            ..limits.clone()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Adaptive {
+    opts: AdaptiveOpts,
+    state: Mutex<AdaptiveState>,
+}
+
+#[derive(Debug, Default)]
+struct AdaptiveState {
+    /// Moving average of read times, in seconds
+    average: Option<f64>,
+    /// The limits to put back, while they're tightened
+    restore: Option<Limits>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    limits: ArcSwap<Limits>,
+    adaptive: ArcSwapOption<Adaptive>,
+}
+
+/// A shared handle on a set of `Limits`. Clones of a handle see each other's updates, which take
+/// effect for queries that start after them, so limits can be tuned live, e.g. during an
+/// incident, without restarting the process.
+#[derive(Debug, Clone, Default)]
+pub struct Settings(Arc<Shared>);
+
+impl Settings {
+    pub fn new(limits: Limits) -> Self {
+        Settings(Arc::new(Shared {
+            limits: ArcSwap::from_pointee(limits),
+            adaptive: ArcSwapOption::empty(),
+        }))
+    }
+
+    /// The process-wide settings. Coalesce reads its limits from these, except for how many grids
+    /// it fetches from a store, which comes from the store's settings. Stores share these unless
+    /// they're given their own with `GridStore::set_settings`.
+    pub fn global() -> &'static Settings {
+        static GLOBAL: OnceLock<Settings> = OnceLock::new();
+        GLOBAL.get_or_init(Settings::default)
+    }
+
+    /// The current limits
+    pub fn load(&self) -> Arc<Limits> {
+        self.0.limits.load_full()
+    }
+
+    /// Replaces the limits. While adaptive limits are tightened, this replaces the tightened
+    /// limits, and is undone when they're put back.
+    pub fn store(&self, limits: Limits) {
+        self.0.limits.store(Arc::new(limits));
+    }
+
+    /// Changes some of the limits, leaving the rest as they are even if they're changed
+    /// concurrently
+    pub fn update<F: Fn(&mut Limits)>(&self, f: F) {
+        self.0.limits.rcu(|limits| {
+            let mut limits = Limits::clone(limits);
+            f(&mut limits);
+            limits
+        });
+    }
+
+    /// Turns adaptive limits on with the given options, or off with `None`, putting back the
+    /// limits if they're tightened
+    pub fn set_adaptive(&self, opts: Option<AdaptiveOpts>) {
+        let adaptive = opts
+            .map(|opts| Arc::new(Adaptive { opts, state: Mutex::new(AdaptiveState::default()) }));
+        if let Some(previous) = self.0.adaptive.swap(adaptive) {
+            if let Some(limits) = previous.state.lock().unwrap().restore.take() {
+                self.store(limits);
+            }
+        }
+    }
+
+    pub(crate) fn is_adaptive(&self) -> bool {
+        self.0.adaptive.load().is_some()
+    }
+
+    /// Whether adaptive limits are currently tightened
+    pub fn is_degraded(&self) -> bool {
+        match self.0.adaptive.load().as_ref() {
+            Some(adaptive) => adaptive.state.lock().unwrap().restore.is_some(),
+            None => false,
+        }
+    }
+
+    /// Counts how long reading a phrase's records from a store took towards the average that
+    /// adaptive limits go by. Stores using these settings do this themselves.
+    pub fn record_read_latency(&self, latency: Duration) {
+        let adaptive = self.0.adaptive.load();
+        let adaptive = match adaptive.as_ref() {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+        let opts = &adaptive.opts;
+        let mut state = adaptive.state.lock().unwrap();
+        let latency = latency.as_secs_f64();
+        let average =
+            state.average.map_or(latency, |average| average + opts.smoothing * (latency - average));
+        state.average = Some(average);
+        if state.restore.is_none() && average > opts.degrade_above.as_secs_f64() {
+            let limits = self.load();
+            self.store(opts.degrade(&limits));
+            state.restore = Some(Limits::clone(&limits));
+        } else if state.restore.is_some() && average < opts.restore_below.as_secs_f64() {
+            if let Some(limits) = state.restore.take() {
+                self.store(limits);
+            }
+        }
+    }
+}
+
+#[test]
+fn settings_test() {
+    let settings = Settings::new(Limits { max_grids_per_phrase: 10, ..Limits::default() });
+    let shared = settings.clone();
+    let before = settings.load();
+    shared.update(|limits| limits.probe_max_candidates = 4);
+    assert_eq!(settings.load().probe_max_candidates, 4, "clones share updates");
+    assert_eq!(settings.load().max_grids_per_phrase, 10, "updates leave other limits alone");
+    assert_eq!(before.probe_max_candidates, 32, "loaded limits don't change under a query");
+
+    settings.store(Limits::default());
+    assert_eq!(*shared.load(), Limits::default());
+}
+
+#[test]
+fn adaptive_settings_test() {
+    let limits = Limits { max_grids_per_phrase: 100, ..Limits::default() };
+    let settings = Settings::new(limits.clone());
+    settings.record_read_latency(Duration::from_secs(1));
+    assert!(!settings.is_degraded(), "reads aren't watched until adaptive limits are on");
+
+    let opts = AdaptiveOpts {
+        degrade_above: Duration::from_millis(10),
+        restore_below: Duration::from_millis(2),
+        smoothing: 0.5,
+        ..AdaptiveOpts::default()
+    };
+    settings.set_adaptive(Some(opts.clone()));
+    settings.record_read_latency(Duration::from_millis(8));
+    assert!(!settings.is_degraded());
+    settings.record_read_latency(Duration::from_millis(16));
+    assert!(settings.is_degraded(), "the average went over 10ms");
+    assert_eq!(settings.load().max_grids_per_phrase, 25);
+    assert_eq!(settings.load().probe_max_candidates, 0);
+
+    settings.record_read_latency(Duration::from_millis(1));
+    assert!(settings.is_degraded(), "the average is still over 2ms");
+    for _ in 0..3 {
+        settings.record_read_latency(Duration::from_millis(1));
+    }
+    assert!(!settings.is_degraded());
+    assert_eq!(*settings.load(), limits, "limits are put back");
+
+    settings.set_adaptive(Some(opts));
+    settings.record_read_latency(Duration::from_secs(1));
+    assert!(settings.is_degraded());
+    settings.set_adaptive(None);
+    assert!(!settings.is_degraded());
+    assert_eq!(*settings.load(), limits, "turning adaptive limits off puts limits back");
+}
@@ -0,0 +1,406 @@
+//! A cache of decompressed records that several processes on a host can share, so that query
+//! processes reading the same compressed stores don't each decompress and hold their own copies
+//! of the hot records.
+//!
+//! The cache lives in a memory-mapped file made up of fixed-size slots. Each record key maps to
+//! one slot, and writing a record evicts whatever was in its slot before. Every slot has a
+//! sequence number that's odd while the slot is being written: writers take a slot by bumping it
+//! from even to odd, and give up rather than wait if another process got there first, and readers
+//! copy a slot out and only trust the copy if the sequence number was even and unchanged
+//! throughout. Nothing ever blocks on another process. Writers also stamp the slot with the time
+//! they took it, and a slot that's been odd for longer than `STALE_CLAIM_SECS` is taken over by
+//! the next writer, so a process that dies mid-write only costs the cache that one slot for a
+//! while. A writer that finishes after its slot was taken over leaves the sequence number alone.
+//!
+//! Slots hold the full namespace and key they were written for, which readers compare byte for
+//! byte, and a checksum of the namespace, key and value, which readers check before handing the
+//! value back, so a slot scribbled on by anything outside the protocol reads as a miss.
+use std::fmt;
+use std::fs::OpenOptions;
+use std::hash::Hasher;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::{Error, Fail};
+use fxhash::FxHasher64;
+use twox_hash::XxHash64;
+
+const MAGIC: &[u8; 8] = b"CMCACHE2";
+/// Magic, slot count and slot size, padded to keep the slots aligned
+const HEADER_LEN: usize = 64;
+/// Sequence number, checksum, namespace length, key length, value length and claim time
+const SLOT_HEADER_LEN: usize = 32;
+/// How long, in seconds, a slot can be claimed by a writer before another writer assumes the
+/// first one died and takes the slot over. Writes are a few copies of at most a slot's worth of
+/// bytes, so a live writer is never anywhere near this slow.
+const STALE_CLAIM_SECS: u32 = 10;
+
+#[derive(Debug, Fail)]
+pub enum SharedCacheError {
+    #[fail(
+        display = "shared cache at {:?} has {} slots of {} bytes, not the {} of {} asked for",
+        path, found_slots, found_slot_size, slots, slot_size
+    )]
+    Mismatched {
+        path: PathBuf,
+        slots: usize,
+        slot_size: usize,
+        found_slots: usize,
+        found_slot_size: usize,
+    },
+    #[fail(display = "{:?} isn't a shared cache file", path)]
+    NotACache { path: PathBuf },
+}
+
+/// A shared cache file, mapped into this process. Open one per process and hand it to stores with
+/// `OpenOpts::shared_cache`; stores keep their records apart by namespace, so one cache can serve
+/// any number of them.
+pub struct SharedCache {
+    path: PathBuf,
+    map: *mut u8,
+    len: usize,
+    slots: usize,
+    slot_size: usize,
+}
+
+// The map is only ever accessed through the slot protocol above, which is safe to run from
+// several threads as well as several processes
+unsafe impl Send for SharedCache {}
+unsafe impl Sync for SharedCache {}
+
+impl fmt::Debug for SharedCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedCache")
+            .field("path", &self.path)
+            .field("slots", &self.slots)
+            .field("slot_size", &self.slot_size)
+            .finish()
+    }
+}
+
+impl SharedCache {
+    /// Opens the cache file at `path`, creating it with `slots` slots of `slot_size` bytes if it
+    /// doesn't exist yet. A record is only cached if its namespace, key and decompressed value fit
+    /// in a slot alongside the slot's 32-byte header. Every process sharing a file has to open it
+    /// with the same geometry.
+    pub fn open<P: AsRef<Path>>(path: P, slots: usize, slot_size: usize) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        // keep each slot's sequence number 8-byte aligned
+        let slot_size = (slot_size.max(SLOT_HEADER_LEN + 8) + 7) & !7;
+        let slots = slots.max(1);
+        let len = HEADER_LEN + slots * slot_size;
+
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let fd = file.as_raw_fd();
+        // only one process lays out a new file
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let laid_out = Self::lay_out(&file, &path, len, slots, slot_size);
+        unsafe { libc::flock(fd, libc::LOCK_UN) };
+        laid_out?;
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(SharedCache { path, map: map as *mut u8, len, slots, slot_size })
+    }
+
+    /// Writes the header of a new cache file, or checks the header of an existing one
+    fn lay_out(
+        file: &std::fs::File,
+        path: &Path,
+        len: usize,
+        slots: usize,
+        slot_size: usize,
+    ) -> Result<(), Error> {
+        use std::io::{Read, Seek, SeekFrom, Write};
+        let mut file = file;
+        if file.metadata()?.len() == 0 {
+            let mut header = [0u8; HEADER_LEN];
+            header[..8].copy_from_slice(MAGIC);
+            header[8..16].copy_from_slice(&(slots as u64).to_le_bytes());
+            header[16..24].copy_from_slice(&(slot_size as u64).to_le_bytes());
+            file.set_len(len as u64)?;
+            file.write_all(&header)?;
+            return Ok(());
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        if file.read_exact(&mut header).is_err() || &header[..8] != MAGIC {
+            return Err(SharedCacheError::NotACache { path: path.to_owned() }.into());
+        }
+        let mut field = [0u8; 8];
+        field.copy_from_slice(&header[8..16]);
+        let found_slots = u64::from_le_bytes(field) as usize;
+        field.copy_from_slice(&header[16..24]);
+        let found_slot_size = u64::from_le_bytes(field) as usize;
+        if (found_slots, found_slot_size) != (slots, slot_size)
+            || file.metadata()?.len() != len as u64
+        {
+            return Err(SharedCacheError::Mismatched {
+                path: path.to_owned(),
+                slots,
+                slot_size,
+                found_slots,
+                found_slot_size,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The most bytes of namespace, key and value together that a slot holds
+    pub fn capacity(&self) -> usize {
+        self.slot_size - SLOT_HEADER_LEN
+    }
+
+    fn slot(&self, namespace: &[u8], key: &[u8]) -> *mut u8 {
+        let mut hasher = FxHasher64::default();
+        hasher.write(namespace);
+        hasher.write(key);
+        let index = (hasher.finish() % self.slots as u64) as usize;
+        unsafe { self.map.add(HEADER_LEN + index * self.slot_size) }
+    }
+
+    fn sequence(&self, slot: *mut u8) -> &AtomicU64 {
+        unsafe { &*(slot as *const AtomicU64) }
+    }
+
+    /// The cached value of `key` in `namespace`, if it's in the cache and intact
+    pub fn get(&self, namespace: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+        let slot = self.slot(namespace, key);
+        let sequence = self.sequence(slot);
+        let before = sequence.load(Ordering::Acquire);
+        // never written, or being written
+        if before == 0 || before % 2 == 1 {
+            return None;
+        }
+        // The copy may be torn by a concurrent writer, so it's checked against the sequence
+        // number before it's used, and lengths are bounded so a torn read stays in the slot.
+        let (checksum, namespace_len, key_len, value_len) = unsafe {
+            (
+                ptr::read_volatile(slot.add(8) as *const u64),
+                ptr::read_volatile(slot.add(16) as *const u32) as usize,
+                ptr::read_volatile(slot.add(20) as *const u32) as usize,
+                ptr::read_volatile(slot.add(24) as *const u32) as usize,
+            )
+        };
+        if namespace_len != namespace.len()
+            || key_len != key.len()
+            || namespace_len + key_len + value_len > self.capacity()
+        {
+            return None;
+        }
+        let mut stored_id = vec![0u8; namespace_len + key_len];
+        let mut value = vec![0u8; value_len];
+        unsafe {
+            let data = slot.add(SLOT_HEADER_LEN);
+            ptr::copy_nonoverlapping(data, stored_id.as_mut_ptr(), stored_id.len());
+            ptr::copy_nonoverlapping(data.add(stored_id.len()), value.as_mut_ptr(), value_len);
+        }
+        fence(Ordering::Acquire);
+        if sequence.load(Ordering::Relaxed) != before
+            || &stored_id[..namespace_len] != namespace
+            || &stored_id[namespace_len..] != key
+            || slot_checksum(namespace, key, &value) != checksum
+        {
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Caches `value` as the value of `key` in `namespace`, evicting whatever was in its slot.
+    /// Values too big for a slot aren't cached, and neither are values whose slot another
+    /// process is writing.
+    pub fn insert(&self, namespace: &[u8], key: &[u8], value: &[u8]) {
+        if namespace.len() + key.len() + value.len() > self.capacity() {
+            return;
+        }
+        let slot = self.slot(namespace, key);
+        let sequence = self.sequence(slot);
+        let before = sequence.load(Ordering::Relaxed);
+        let now = now_secs();
+        // an odd sequence number is either a write in progress or one whose writer died, and only
+        // the latter is taken over; either way the claimed number is odd
+        let claimed = if before % 2 == 0 {
+            before + 1
+        } else if now.wrapping_sub(self.claim_time(slot).load(Ordering::Relaxed)) > STALE_CLAIM_SECS
+        {
+            before + 2
+        } else {
+            return;
+        };
+        if sequence.compare_exchange(before, claimed, Ordering::Acquire, Ordering::Relaxed).is_err()
+        {
+            return;
+        }
+        self.claim_time(slot).store(now, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(slot.add(8) as *mut u64, slot_checksum(namespace, key, value));
+            ptr::write_volatile(slot.add(16) as *mut u32, namespace.len() as u32);
+            ptr::write_volatile(slot.add(20) as *mut u32, key.len() as u32);
+            ptr::write_volatile(slot.add(24) as *mut u32, value.len() as u32);
+            let data = slot.add(SLOT_HEADER_LEN);
+            ptr::copy_nonoverlapping(namespace.as_ptr(), data, namespace.len());
+            let data = data.add(namespace.len());
+            ptr::copy_nonoverlapping(key.as_ptr(), data, key.len());
+            ptr::copy_nonoverlapping(value.as_ptr(), data.add(key.len()), value.len());
+        }
+        // fails if the slot was taken over in the meantime, in which case the writer that took
+        // it over publishes its own write
+        let _ =
+            sequence.compare_exchange(claimed, claimed + 1, Ordering::Release, Ordering::Relaxed);
+    }
+
+    /// When the slot was last claimed by a writer, in seconds since the epoch, wrapping
+    fn claim_time(&self, slot: *mut u8) -> &AtomicU32 {
+        unsafe { &*(slot.add(28) as *const AtomicU32) }
+    }
+
+    /// The cached value of `key` in `namespace`, or if it isn't cached, the result of `make`,
+    /// which is cached if it succeeds
+    pub fn get_or_insert_with<F>(
+        &self,
+        namespace: &[u8],
+        key: &[u8],
+        make: F,
+    ) -> Result<Vec<u8>, Error>
+    where
+        F: FnOnce() -> Result<Vec<u8>, Error>,
+    {
+        if let Some(value) = self.get(namespace, key) {
+            return Ok(value);
+        }
+        let value = make()?;
+        self.insert(namespace, key, &value);
+        Ok(value)
+    }
+}
+
+/// Seconds since the epoch, wrapping, for comparing claim times across processes
+fn now_secs() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as u32)
+}
+
+/// The checksum a slot holding `value` as the value of `key` in `namespace` is written with
+fn slot_checksum(namespace: &[u8], key: &[u8], value: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write_u32(namespace.len() as u32);
+    hasher.write(namespace);
+    hasher.write_u32(key.len() as u32);
+    hasher.write(key);
+    hasher.write(value);
+    hasher.finish()
+}
+
+impl Drop for SharedCache {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_cache_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let path = directory.path().join("cache");
+        // two mappings of one file, as two processes would have
+        let writer = SharedCache::open(&path, 16, 128).unwrap();
+        let reader = SharedCache::open(&path, 16, 128).unwrap();
+
+        assert_eq!(reader.get(b"1", b"key"), None);
+        writer.insert(b"1", b"key", b"value");
+        assert_eq!(reader.get(b"1", b"key"), Some(b"value".to_vec()));
+        assert_eq!(reader.get(b"2", b"key"), None, "namespaces keep stores' records apart");
+        assert_eq!(reader.get(b"1", b"other key"), None);
+
+        writer.insert(b"1", b"key", b"new value");
+        assert_eq!(reader.get(b"1", b"key"), Some(b"new value".to_vec()));
+
+        let too_big = vec![7u8; 200];
+        writer.insert(b"1", b"big", &too_big);
+        assert_eq!(reader.get(b"1", b"big"), None, "values that don't fit a slot aren't cached");
+
+        let mut made = 0;
+        for _ in 0..2 {
+            let value = reader
+                .get_or_insert_with(b"3", b"made", || {
+                    made += 1;
+                    Ok(b"made value".to_vec())
+                })
+                .unwrap();
+            assert_eq!(value, b"made value".to_vec());
+        }
+        assert_eq!(made, 1, "the second lookup comes from the cache");
+
+        // with one slot, every key evicts the last
+        let single = SharedCache::open(directory.path().join("single"), 1, 64).unwrap();
+        single.insert(b"1", b"a", b"1");
+        single.insert(b"1", b"b", b"2");
+        assert_eq!(single.get(b"1", b"a"), None);
+        assert_eq!(single.get(b"1", b"b"), Some(b"2".to_vec()));
+        assert_eq!(single.get(b"1b", b""), None, "namespaces and keys are compared whole");
+
+        // a slot changed outside the protocol fails its checksum
+        {
+            use std::io::{Seek, SeekFrom, Write};
+            let mut file =
+                OpenOptions::new().write(true).open(directory.path().join("single")).unwrap();
+            file.seek(SeekFrom::Start((HEADER_LEN + SLOT_HEADER_LEN + 2) as u64)).unwrap();
+            file.write_all(b"3").unwrap();
+        }
+        assert_eq!(single.get(b"1", b"b"), None);
+
+        assert!(SharedCache::open(&path, 32, 128).is_err(), "geometry has to match");
+        std::fs::write(directory.path().join("not a cache"), [0u8; 100]).unwrap();
+        assert!(SharedCache::open(directory.path().join("not a cache"), 16, 128).is_err());
+    }
+
+    #[test]
+    fn shared_cache_stale_claim_test() {
+        let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let cache = SharedCache::open(directory.path().join("cache"), 1, 64).unwrap();
+        cache.insert(b"1", b"a", b"1");
+        let slot = cache.slot(b"1", b"a");
+
+        // a writer that died right after claiming the slot
+        cache.sequence(slot).fetch_add(1, Ordering::SeqCst);
+        cache.claim_time(slot).store(now_secs(), Ordering::SeqCst);
+        assert_eq!(cache.get(b"1", b"a"), None, "a slot being written reads as a miss");
+        cache.insert(b"1", b"b", b"2");
+        assert_eq!(cache.get(b"1", b"b"), None, "a recent claim isn't taken over");
+
+        cache.claim_time(slot).store(now_secs() - STALE_CLAIM_SECS - 1, Ordering::SeqCst);
+        cache.insert(b"1", b"b", b"2");
+        assert_eq!(cache.get(b"1", b"b"), Some(b"2".to_vec()), "a stale claim is taken over");
+        assert_eq!(cache.sequence(slot).load(Ordering::SeqCst) % 2, 0);
+
+        // the dead writer coming back to life doesn't disturb the write that replaced its own
+        let taken_over = cache.sequence(slot).load(Ordering::SeqCst) - 3;
+        assert!(cache
+            .sequence(slot)
+            .compare_exchange(taken_over, taken_over + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err());
+        assert_eq!(cache.get(b"1", b"b"), Some(b"2".to_vec()));
+    }
+}
@@ -1,5 +1,11 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::gridstore::common::TileAnchor;
 use crate::gridstore::gridstore_format::{Coord, UniformVec};
-use itertools::Itertools;
+use failure::{Error, Fail};
+use fxhash::FxHashSet;
+use itertools::{Either, Itertools};
 use morton::{deinterleave_morton, interleave_morton};
 
 #[cfg(test)]
@@ -12,6 +18,10 @@ use crate::gridstore::gridstore_format;
 /// Returns (Some(min,max)) if the Coord Vector morton order range overlaps with the bounding box,
 /// [`None`] if the Coord Vector morton order range does not overlaps with the bounding box
 pub fn bbox_range<'a>(coords: UniformVec<'a, Coord>, bbox: [u16; 4]) -> Option<(u32, u32)> {
+    // an empty bbox, e.g. the intersection of boxes that don't overlap, contains nothing
+    if bbox[0] > bbox[2] || bbox[1] > bbox[3] {
+        return None;
+    }
     let min = interleave_morton(bbox[0], bbox[1]);
     let max = interleave_morton(bbox[2], bbox[3]);
     debug_assert!(min <= max, "Invalid bounding box");
@@ -62,14 +72,137 @@ pub fn bbox_filter<'a>(
     }
 
     let range = bbox_range(coords, bbox)?;
-    Some((range.0..=range.1).filter_map(move |idx| {
-        let grid = coords.get(idx as usize);
-        let (x, y) = deinterleave_morton(grid.coord);
-        if x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3] {
-            return Some(coords.get(idx as usize));
+    Some(bbox_walk(coords, bbox, range.0, range.1, false))
+}
+
+/// Iterates over the coords in `bbox` among those at indexes `start` through `end`, forward
+/// (descending z-order) or backward (ascending z-order). The z-order curve leaves and reenters
+/// the box, so the range between its corners takes in runs of coords outside it; when the walk
+/// hits one, it binary searches to the next z-order coord back in the box (LITMAX walking
+/// forward, BIGMIN backward) instead of checking every coord of the run.
+fn bbox_walk<'a>(
+    coords: UniformVec<'a, Coord>,
+    bbox: [u16; 4],
+    start: u32,
+    end: u32,
+    backward: bool,
+) -> impl Iterator<Item = Coord> + 'a {
+    let zmin = interleave_morton(bbox[0], bbox[1]);
+    let zmax = interleave_morton(bbox[2], bbox[3]);
+    let len = coords.len() as u32;
+    // the next index to look at, if there is one
+    let mut next = if start > end || end >= len {
+        None
+    } else if backward {
+        Some(end)
+    } else {
+        Some(start)
+    };
+    std::iter::from_fn(move || {
+        while let Some(idx) = next {
+            let grid = coords.get(idx as usize);
+            let (x, y) = deinterleave_morton(grid.coord);
+            let in_bbox = x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3];
+            next = if in_bbox {
+                if backward {
+                    idx.checked_sub(1).filter(|prev| *prev >= start)
+                } else {
+                    Some(idx + 1).filter(|after| *after <= end)
+                }
+            } else if backward {
+                // the last index at or before idx - 1 whose coord is at least the next one in
+                // the box
+                bigmin(grid.coord, zmin, zmax).and_then(|target| {
+                    let prev = idx.checked_sub(1).filter(|prev| *prev >= start)?;
+                    let found = coord_binary_search(&coords, target, start).ok()?;
+                    let found = if found < len && coords.get(found as usize).coord >= target {
+                        found
+                    } else {
+                        found.checked_sub(1)?
+                    };
+                    Some(std::cmp::min(found, prev)).filter(|prev| *prev >= start)
+                })
+            } else {
+                // the first index after idx whose coord is at most the next one in the box
+                litmax(grid.coord, zmin, zmax).and_then(|target| {
+                    let after = Some(idx + 1).filter(|after| *after <= end)?;
+                    let found = coord_binary_search(&coords, target, after).ok()?;
+                    Some(std::cmp::max(found, after)).filter(|after| *after <= end)
+                })
+            };
+            if in_bbox {
+                return Some(grid);
+            }
         }
         None
-    }))
+    })
+}
+
+/// Sets bit `bit` of the z-order coord `z` to `set`, and the lower bits of the same dimension to
+/// the opposite, e.g. so that the dimension's bits from `bit` down go from 0111 to 1000
+#[inline]
+fn load_bits(z: u32, bit: u32, set: bool) -> u32 {
+    let dimension = if bit & 1 == 0 { 0x5555_5555 } else { 0xAAAA_AAAA };
+    let lower = dimension & ((1u32 << bit) - 1);
+    if set {
+        (z | (1 << bit)) & !lower
+    } else {
+        (z & !(1 << bit)) | lower
+    }
+}
+
+/// The smallest z-order coord greater than `z` that's in the box with corners `zmin` and `zmax`,
+/// if there is one, for a `z` outside the box (Tropf and Herzog's BIGMIN)
+fn bigmin(z: u32, mut zmin: u32, mut zmax: u32) -> Option<u32> {
+    if z >= zmax {
+        return None;
+    }
+    if z < zmin {
+        return Some(zmin);
+    }
+    let mut bigmin = None;
+    for bit in (0..32).rev() {
+        let mask = 1u32 << bit;
+        match (z & mask != 0, zmin & mask != 0, zmax & mask != 0) {
+            (false, false, true) => {
+                bigmin = Some(load_bits(zmin, bit, true));
+                zmax = load_bits(zmax, bit, false);
+            }
+            (false, true, true) => return Some(zmin),
+            (true, false, false) => return bigmin,
+            (true, false, true) => zmin = load_bits(zmin, bit, true),
+            // the bit matches the whole box, or zmin > zmax in this dimension, which can't happen
+            // for a well-formed box
+            _ => {}
+        }
+    }
+    bigmin
+}
+
+/// The largest z-order coord less than `z` that's in the box with corners `zmin` and `zmax`, if
+/// there is one, for a `z` outside the box (Tropf and Herzog's LITMAX)
+fn litmax(z: u32, mut zmin: u32, mut zmax: u32) -> Option<u32> {
+    if z <= zmin {
+        return None;
+    }
+    if z > zmax {
+        return Some(zmax);
+    }
+    let mut litmax = None;
+    for bit in (0..32).rev() {
+        let mask = 1u32 << bit;
+        match (z & mask != 0, zmin & mask != 0, zmax & mask != 0) {
+            (false, false, true) => zmax = load_bits(zmax, bit, false),
+            (false, true, true) => return litmax,
+            (true, false, false) => return Some(zmax),
+            (true, false, true) => {
+                litmax = Some(load_bits(zmax, bit, false));
+                zmin = load_bits(zmin, bit, true);
+            }
+            _ => {}
+        }
+    }
+    litmax
 }
 
 /// Generate an Iterator over a Coord Vector given a proximity point
@@ -123,18 +256,11 @@ pub fn bbox_proximity_filter<'a>(
         Err(_) => return None,
     };
 
-    let filtered_get = move |idx| {
-        let grid = coords.get(idx as usize);
-        let (x, y) = deinterleave_morton(grid.coord);
-        if x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3] {
-            return Some(coords.get(idx as usize));
-        } else {
-            return None;
-        };
+    let head = match prox_mid.checked_sub(1) {
+        Some(end) => Either::Left(bbox_walk(coords, bbox, range.0, end, true)),
+        None => Either::Right(std::iter::empty()),
     };
-
-    let head = (range.0..prox_mid).rev().filter_map(filtered_get);
-    let tail = (prox_mid..=range.1).filter_map(filtered_get);
+    let tail = bbox_walk(coords, bbox, prox_mid, range.1, false);
     let coord_sets = head.into_iter().merge_by(tail.into_iter(), move |a, b| {
         let morton_distance_1 = (a.coord as i64 - prox_pt) as i64;
         let morton_distance_2 = (b.coord as i64 - prox_pt) as i64;
@@ -143,6 +269,104 @@ pub fn bbox_proximity_filter<'a>(
 
     Some(coord_sets)
 }
+
+/// Generate an Iterator over the coords of a Coord Vector in any of several bounding boxes, in
+/// the same descending z-order as `bbox_filter`. Each box is filtered on its own, and coords in
+/// more than one of them come out once.
+pub fn bboxes_filter<'a>(
+    coords: UniformVec<'a, Coord>,
+    bboxes: &[[u16; 4]],
+) -> Option<impl Iterator<Item = Coord> + 'a> {
+    let filtered: Vec<_> = bboxes.iter().filter_map(|bbox| bbox_filter(coords, *bbox)).collect();
+    if filtered.is_empty() {
+        return None;
+    }
+    // coords in the same tile at different sub-tile offsets are stored next to each other, in
+    // descending order of offset
+    let rank = |coord: &Coord| (coord.coord, coord.offset);
+    Some(filtered.into_iter().kmerge_by(move |a, b| rank(a) > rank(b)).coalesce(move |a, b| {
+        if rank(&a) == rank(&b) {
+            Ok(a)
+        } else {
+            Err((a, b))
+        }
+    }))
+}
+
+/// Like `bbox_proximity_filter`, but over the coords in any of several bounding boxes, which come
+/// out once each
+pub fn bboxes_proximity_filter<'a>(
+    coords: UniformVec<'a, Coord>,
+    bboxes: &[[u16; 4]],
+    proximity: [u16; 2],
+) -> Option<impl Iterator<Item = Coord> + 'a> {
+    let filtered: Vec<_> =
+        bboxes.iter().filter_map(|bbox| bbox_proximity_filter(coords, *bbox, proximity)).collect();
+    if filtered.is_empty() {
+        return None;
+    }
+    // each box's coords come out by distance, and the lower of two coords at the same distance
+    // first, so merging on both keeps the copies of a coord next to each other
+    let prox_pt = interleave_morton(proximity[0], proximity[1]) as i64;
+    let rank = move |coord: &Coord| {
+        ((coord.coord as i64 - prox_pt).abs(), coord.coord, std::cmp::Reverse(coord.offset))
+    };
+    Some(filtered.into_iter().kmerge_by(move |a, b| rank(a) < rank(b)).coalesce(move |a, b| {
+        if rank(&a) == rank(&b) {
+            Ok(a)
+        } else {
+            Err((a, b))
+        }
+    }))
+}
+
+/// Generate an Iterator over a Coord Vector ordered along a Hilbert curve rather than in z-order
+/// (see `Curve::Hilbert`), with each coord converted back to z-order for the rest of the query.
+/// Only coords in any of `bboxes` come out, if there are any boxes. The boxes are split into runs
+/// of the curve (see `hilbert_ranges`) and only the coords in those are read, though a box takes
+/// more runs of the Hilbert curve than of z-order, so this reads more of the record than
+/// `bbox_filter` does. Given a proximity point, coords come out by their distance from it along
+/// the curve, as in `proximity`, and every coord is checked against the boxes.
+pub fn hilbert_filter<'a>(
+    coords: UniformVec<'a, Coord>,
+    bboxes: Option<Arc<Vec<[u16; 4]>>>,
+    proximity: Option<[u16; 2]>,
+) -> impl Iterator<Item = Coord> + 'a {
+    let len = coords.len() as u32;
+    let getter = move |i| coords.get(i as usize);
+    let ordered = match (proximity, &bboxes) {
+        (Some([x, y]), _) => {
+            let prox_pt = hilbert_index(x, y) as i64;
+            let prox_mid = coord_binary_search(&coords, prox_pt as u32, 0).unwrap_or(0);
+            let head = (0..prox_mid).rev().map(getter);
+            let tail = (prox_mid..len).map(getter);
+            Either::Left(head.merge_by(tail, move |a, b| {
+                (a.coord as i64 - prox_pt).abs() < (b.coord as i64 - prox_pt).abs()
+            }))
+        }
+        (None, Some(bboxes)) => {
+            let runs = hilbert_ranges(bboxes).into_iter().flat_map(move |(start, end)| {
+                let first = coord_binary_search(&coords, (end - 1) as u32, 0).unwrap_or(len);
+                (first..len)
+                    .map(getter)
+                    .skip_while(move |coord| coord.coord as u64 >= end)
+                    .take_while(move |coord| coord.coord as u64 >= start)
+            });
+            Either::Right(Either::Left(runs))
+        }
+        (None, None) => Either::Right(Either::Right((0..len).map(getter))),
+    };
+    ordered.filter_map(move |coord| {
+        let (x, y) = hilbert_coords(coord.coord);
+        let in_bbox =
+            |bbox: &[u16; 4]| x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3];
+        match &bboxes {
+            Some(bboxes) if !bboxes.iter().any(in_bbox) => None,
+            _ => Some(Coord { coord: interleave_morton(x, y), ..coord }),
+        }
+    })
+}
+
 /// Binary search this FlatBuffers Coord Vector
 ///
 /// Derived from binary_search_by in core/slice/mod.rs except this expects descending order.
@@ -199,7 +423,7 @@ fn encoded_val_generator<T: Iterator<Item = u32>>(val: T) -> Vec<u8> {
     let mut coords: Vec<_> = Vec::new();
 
     for i in val {
-        let coord = gridstore_format::Coord { coord: i, ids: encoded_ids.clone() };
+        let coord = gridstore_format::Coord { coord: i, ids: encoded_ids.clone(), offset: None };
         coords.push(coord);
     }
     let encoded_coords = builder.write_uniform_vec(&coords);
@@ -295,6 +519,153 @@ mod test {
         assert_eq!(result.len(), 0, "result is on the z-order curve but not in the bbox");
     }
 
+    #[test]
+    fn hilbert_curve() {
+        // the curve steps from each tile to a neighbor, and is undone by hilbert_coords
+        let mut previous = hilbert_coords(0);
+        assert_eq!(previous, (0, 0));
+        for index in (1..1 << 12).chain((1u32 << 31) - 100..(1 << 31) + 100) {
+            let (x, y) = hilbert_coords(index);
+            assert_eq!(hilbert_index(x, y), index);
+            if index != (1 << 31) - 100 {
+                let step =
+                    (x as i32 - previous.0 as i32).abs() + (y as i32 - previous.1 as i32).abs();
+                assert_eq!(step, 1, "step to {}", index);
+            }
+            previous = (x, y);
+        }
+        assert_eq!(hilbert_index(u16::MAX, 0), u32::MAX, "the curve ends in the far corner");
+        // the tiles of a quadrant are one run of the curve, as in z-order
+        let quadrant: Vec<u32> =
+            (0..16u16).flat_map(|x| (0..16u16).map(move |y| hilbert_index(x, y))).collect();
+        assert_eq!(quadrant.iter().max(), Some(&255));
+    }
+
+    #[test]
+    fn hilbert_ranges_test() {
+        for bbox in [[0, 0, 0, 0], [3, 5, 9, 6], [0, 0, 15, 15], [1, 1, 14, 14], [7, 0, 8, 15]] {
+            let ranges = hilbert_ranges(&[bbox]);
+            assert!(ranges.len() <= MAX_HILBERT_RANGES);
+            assert!(ranges.windows(2).all(|pair| pair[0].0 > pair[1].1), "merged, descending");
+            let in_ranges = |index: u32| {
+                ranges.iter().any(|(start, end)| (index as u64) >= *start && (index as u64) < *end)
+            };
+            for x in 0..16u16 {
+                for y in 0..16u16 {
+                    let in_bbox = x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3];
+                    if in_bbox {
+                        assert!(in_ranges(hilbert_index(x, y)), "{:?} misses {:?}", bbox, (x, y));
+                    }
+                }
+            }
+            // boxes with short enough edges are split exactly; the rest take in extra tiles
+            let covered: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+            let area = ((bbox[2] - bbox[0] + 1) as u64) * ((bbox[3] - bbox[1] + 1) as u64);
+            assert_eq!(covered == area, bbox != [1, 1, 14, 14]);
+        }
+        let ranges = hilbert_ranges(&[[1, 1, 60000, 60000]]);
+        assert!(ranges.len() <= MAX_HILBERT_RANGES);
+        assert!(ranges.iter().any(|(start, end)| {
+            let index = hilbert_index(60000, 1) as u64;
+            index >= *start && index < *end
+        }));
+    }
+
+    #[test]
+    fn bigmin_litmax() {
+        // every box on a 16x16 grid, against every z-order coord around it
+        for (x0, y0, x1, y1) in
+            (0..16u16).flat_map(|x0| (x0..16).map(move |x1| (x0, x1))).flat_map(|(x0, x1)| {
+                (0..16u16).flat_map(move |y0| (y0..16).map(move |y1| (x0, y0, x1, y1)))
+            })
+        {
+            let zmin = interleave_morton(x0, y0);
+            let zmax = interleave_morton(x1, y1);
+            let in_bbox = |z: u32| {
+                let (x, y) = deinterleave_morton(z);
+                x >= x0 && x <= x1 && y >= y0 && y <= y1
+            };
+            for z in (0..256u32).filter(|z| !in_bbox(*z)) {
+                assert_eq!(
+                    bigmin(z, zmin, zmax),
+                    (z + 1..256).find(|z| in_bbox(*z)),
+                    "bigmin of {} in {:?}",
+                    z,
+                    (x0, y0, x1, y1)
+                );
+                assert_eq!(
+                    litmax(z, zmin, zmax),
+                    (0..z).rev().find(|z| in_bbox(*z)),
+                    "litmax of {} in {:?}",
+                    z,
+                    (x0, y0, x1, y1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn filter_bbox_skips_runs() {
+        // a sparse scattering of coords over a 32x32 grid, so that walks jump both onto coords
+        // and between them
+        let sparse: Vec<u32> = (0..1024u32).rev().filter(|z| (z * 7919) % 13 < 4).collect();
+        let buffer = encoded_val_generator(sparse.clone().into_iter());
+        let reader = gridstore_format::Reader::new(buffer.as_slice());
+        let coords = get_coords_from_reader(&reader);
+
+        for bbox in
+            &[[0, 0, 31, 31], [3, 5, 9, 20], [10, 10, 10, 10], [1, 17, 30, 18], [16, 0, 17, 31]]
+        {
+            let in_bbox = |z: &u32| {
+                let (x, y) = deinterleave_morton(*z);
+                x >= bbox[0] && x <= bbox[2] && y >= bbox[1] && y <= bbox[3]
+            };
+            let expected: Vec<u32> = sparse.iter().cloned().filter(in_bbox).collect();
+            let result: Vec<u32> = bbox_filter(coords, *bbox)
+                .map(|coords| coords.map(|c| c.coord).collect())
+                .unwrap_or_default();
+            assert_eq!(result, expected, "bbox_filter in {:?}", bbox);
+
+            let proximity = [(bbox[0] + bbox[2]) / 2, (bbox[1] + bbox[3]) / 2];
+            let mut result: Vec<u32> = bbox_proximity_filter(coords, *bbox, proximity)
+                .map(|coords| coords.map(|c| c.coord).collect())
+                .unwrap_or_default();
+            result.sort_unstable_by(|a, b| b.cmp(a));
+            assert_eq!(result, expected, "bbox_proximity_filter in {:?}", bbox);
+        }
+    }
+
+    #[test]
+    fn filter_bboxes() {
+        let buffer = encoded_val_generator((0..64u32).rev());
+        let reader = gridstore_format::Reader::new(buffer.as_slice());
+        let coords = get_coords_from_reader(&reader);
+        let bboxes = [[0, 0, 2, 2], [1, 1, 3, 3], [6, 6, 7, 7]];
+        let in_bboxes = |z: &u32| {
+            let (x, y) = deinterleave_morton(*z);
+            bboxes.iter().any(|b| x >= b[0] && x <= b[2] && y >= b[1] && y <= b[3])
+        };
+
+        let expected: Vec<u32> = (0..64u32).rev().filter(in_bboxes).collect();
+        let result: Vec<u32> = bboxes_filter(coords, &bboxes).unwrap().map(|c| c.coord).collect();
+        assert_eq!(result, expected, "overlapping boxes give each coord once, in z-order");
+
+        let proximity = [2, 2];
+        let prox_pt = interleave_morton(2, 2) as i64;
+        let result: Vec<u32> =
+            bboxes_proximity_filter(coords, &bboxes, proximity).unwrap().map(|c| c.coord).collect();
+        let mut sorted = result.clone();
+        sorted.sort_by_key(|z| ((*z as i64 - prox_pt).abs(), *z));
+        assert_eq!(result, sorted, "coords come out by distance");
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(sorted, expected);
+
+        assert!(bboxes_filter(coords, &[]).is_none());
+        assert!(bboxes_filter(coords, &[[8, 8, 9, 9]]).is_none(), "past the coords");
+        assert_eq!(bbox_hull(&bboxes), [0, 0, 7, 7]);
+        assert_eq!(bbox_hull(&[]), [u16::MAX, u16::MAX, 0, 0]);
+    }
+
     #[test]
     fn proximity_search() {
         let buffer = encoded_val_generator((1..10).rev()); // [9,8,7,6,5,4,3,2,1]
@@ -462,6 +833,35 @@ pub fn tile_dist(proximity_x: u16, proximity_y: u16, grid_x: u16, grid_y: u16) -
     ((dx * dx) + (dy * dy)).sqrt()
 }
 
+/// Like `tile_dist`, but to a point within the grid's tile given as a sub-tile offset (see
+/// `gridstore_format::Coord::offset`) rather than to the middle of the tile
+pub fn tile_dist_with_offset(
+    proximity_x: u16,
+    proximity_y: u16,
+    grid_x: u16,
+    grid_y: u16,
+    offset: Option<u8>,
+) -> f64 {
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return tile_dist(proximity_x, proximity_y, grid_x, grid_y),
+    };
+    // sixteenths of a tile from its top left corner, to tiles from its middle
+    let shift = |sixteenths: u8| (sixteenths as f64 + 0.5) / 16. - 0.5;
+    let dx = (proximity_x as f64) - (grid_x as f64 + shift(offset >> 4));
+    let dy = (proximity_y as f64) - (grid_y as f64 + shift(offset & 15));
+    ((dx * dx) + (dy * dy)).sqrt()
+}
+
+#[test]
+fn tile_dist_with_offset_test() {
+    assert_eq!(tile_dist_with_offset(1, 1, 3, 1, None), tile_dist(1, 1, 3, 1));
+    // the near and far edges of the tile, about halfway down it
+    assert!((tile_dist_with_offset(1, 1, 3, 1, Some(0x07)) - (2. - 15. / 32.)).abs() < 1e-3);
+    assert!((tile_dist_with_offset(1, 1, 3, 1, Some(0xf7)) - (2. + 15. / 32.)).abs() < 1e-3);
+    assert!(tile_dist_with_offset(1, 1, 1, 1, Some(0x77)) < 0.05, "near the middle of the tile");
+}
+
 #[test]
 fn tile_dist_test() {
     assert_eq!(
@@ -576,6 +976,10 @@ const E_POW: [f64; 8] = [
     1096.6331584284585,
 ];
 
+/// The distance, in tiles, that scoredist takes grids less than a tile from the proximity point to
+/// be at
+pub const MIN_TILE_DISTANCE: f64 = 0.8;
+
 pub fn scoredist(mut zoom: u16, mut distance: f64, mut score: u8, radius: f64) -> f64 {
     if zoom < 6 {
         zoom = 6;
@@ -586,7 +990,7 @@ pub fn scoredist(mut zoom: u16, mut distance: f64, mut score: u8, radius: f64) -
 
     // If the distance is 0, set a minimum distance to avoid dividing by distratios that approach zero
     if distance < 1. {
-        distance = 0.8;
+        distance = MIN_TILE_DISTANCE;
     }
 
     let mut dist_ratio: f64 = distance / proximity_radius(zoom, radius);
@@ -595,7 +999,22 @@ pub fn scoredist(mut zoom: u16, mut distance: f64, mut score: u8, radius: f64) -
     if dist_ratio > 1.0 {
         dist_ratio = 1.00;
     }
-    ((6. * E_POW[score as usize] / E_POW[7]) + 1.) / dist_ratio
+    score_only_scoredist(score) / dist_ratio
+}
+
+/// The scoredist of a grid beyond the proximity radius, which depends only on its score
+#[inline]
+pub fn score_only_scoredist(mut score: u8) -> f64 {
+    if score > 7 {
+        score = 7;
+    }
+    (6. * E_POW[score as usize] / E_POW[7]) + 1.
+}
+
+/// The best scoredist a grid with the given score can get, i.e., when it's on the proximity tile
+#[inline]
+pub fn max_scoredist(zoom: u16, score: u8, radius: f64) -> f64 {
+    scoredist(zoom, 0., score, radius)
 }
 
 #[inline(always)]
@@ -628,15 +1047,454 @@ pub fn adjust_bbox_zoom(bbox: [u16; 4], source_z: u16, target_z: u16) -> [u16; 4
     }
 }
 
-pub fn global_bbox_for_zoom(zoom: u16) -> Vec<[u16; 4]> {
+/// Longitude of the west edge of tiles in column `x`
+pub(crate) fn x_to_lon(x: f64, zoom: u16) -> f64 {
+    x / (1u32 << zoom) as f64 * 360. - 180.
+}
+
+/// Latitude of the north edge of tiles in row `y`
+pub(crate) fn y_to_lat(y: f64, zoom: u16) -> f64 {
+    let n = PI * (1. - 2. * y / (1u32 << zoom) as f64);
+    n.sinh().atan().to_degrees()
+}
+
+/// [longitude, latitude] of a point of the Web Mercator tile (x, y) at `zoom`
+pub fn tile_lonlat(x: u16, y: u16, zoom: u16, anchor: TileAnchor) -> [f64; 2] {
+    let offset = match anchor {
+        TileAnchor::Center => 0.5,
+        TileAnchor::Corner => 0.,
+    };
+    [x_to_lon(x as f64 + offset, zoom), y_to_lat(y as f64 + offset, zoom)]
+}
+
+/// The largest tile coord on either axis at `zoom`
+pub(crate) fn max_tile(zoom: u16) -> u16 {
     // do this at u32 to avoid overflow at z16
-    let max: u32 = (1u32 << zoom) - 1;
-    let max = max as u16;
+    ((1u32 << zoom) - 1) as u16
+}
+
+pub fn global_bbox_for_zoom(zoom: u16) -> Vec<[u16; 4]> {
+    let max = max_tile(zoom);
     vec![[0, 0, max, max]]
 }
 
+/// Whether `a` and `b` have any tiles in common
+pub(crate) fn bboxes_intersect(a: [u16; 4], b: [u16; 4]) -> bool {
+    a[0] <= b[2] && b[0] <= a[2] && a[1] <= b[3] && b[1] <= a[3]
+}
+
+/// `bbox` grown by `by` tiles on every side, stopping at the edges of the world at `zoom`
+pub(crate) fn widen_bbox(bbox: [u16; 4], by: u16, zoom: u16) -> [u16; 4] {
+    let max = max_tile(zoom);
+    [
+        bbox[0].saturating_sub(by),
+        bbox[1].saturating_sub(by),
+        bbox[2].saturating_add(by).min(max),
+        bbox[3].saturating_add(by).min(max),
+    ]
+}
+
+/// The tiles in both `a` and `b`, each min x, min y, max x, max y. Boxes that don't overlap give
+/// an inverted box, which contains nothing.
+pub(crate) fn intersect_bboxes(a: [u16; 4], b: [u16; 4]) -> [u16; 4] {
+    [
+        std::cmp::max(a[0], b[0]),
+        std::cmp::max(a[1], b[1]),
+        std::cmp::min(a[2], b[2]),
+        std::cmp::min(a[3], b[3]),
+    ]
+}
+
+/// The smallest bbox containing all of `bboxes`. No boxes give an inverted box, which contains
+/// nothing.
+pub(crate) fn bbox_hull(bboxes: &[[u16; 4]]) -> [u16; 4] {
+    bboxes.iter().fold([u16::MAX, u16::MAX, 0, 0], |hull, bbox| {
+        [
+            std::cmp::min(hull[0], bbox[0]),
+            std::cmp::min(hull[1], bbox[1]),
+            std::cmp::max(hull[2], bbox[2]),
+            std::cmp::max(hull[3], bbox[3]),
+        ]
+    })
+}
+
+/// Bits per axis of the grid the Hilbert curve fills, enough for a tile coord at any zoom, so
+/// that a coord's position along the curve doesn't depend on the store's zoom
+const HILBERT_ORDER: u32 = 16;
+
+/// Reflects and swaps a Hilbert curve quadrant of size `n` into the orientation of the curve's
+/// base pattern
+fn hilbert_rotate(n: u32, x: &mut u32, y: &mut u32, rx: u32, ry: u32) {
+    if ry == 0 {
+        if rx == 1 {
+            *x = n - 1 - *x;
+            *y = n - 1 - *y;
+        }
+        std::mem::swap(x, y);
+    }
+}
+
+/// The position of the tile at (x, y) along a Hilbert curve. Like z-order, every quadrant of the
+/// grid is one run of the curve, but the curve never jumps from one quadrant to a far-off one.
+pub(crate) fn hilbert_index(x: u16, y: u16) -> u32 {
+    let n = 1u32 << HILBERT_ORDER;
+    let (mut x, mut y) = (x as u32, y as u32);
+    let mut index = 0u32;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u32;
+        let ry = ((y & s) > 0) as u32;
+        index += s * s * ((3 * rx) ^ ry);
+        hilbert_rotate(n, &mut x, &mut y, rx, ry);
+        s /= 2;
+    }
+    index
+}
+
+/// Most runs of the Hilbert curve `hilbert_ranges` splits a bbox into. Past this, squares the box
+/// only partly covers are read whole, and their coords outside the box are skipped one by one.
+const MAX_HILBERT_RANGES: usize = 64;
+
+/// Runs of the Hilbert curve, as [start, end) positions in descending order, that between them
+/// take in every tile of `bboxes`. Every square of the quadtree is one run of the curve, so the
+/// boxes are split into the squares they cover, down to as many runs as `MAX_HILBERT_RANGES`.
+pub(crate) fn hilbert_ranges(bboxes: &[[u16; 4]]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for bbox in bboxes {
+        let (min_x, min_y, max_x, max_y) =
+            (bbox[0] as u32, bbox[1] as u32, bbox[2] as u32, bbox[3] as u32);
+        let run = |x: u32, y: u32, size: u32| {
+            let area = (size as u64) * (size as u64);
+            let start = (hilbert_index(x as u16, y as u16) as u64) & !(area - 1);
+            (start, start + area)
+        };
+        let mut size = 1u32 << HILBERT_ORDER;
+        let mut partial: Vec<(u32, u32)> = vec![(0, 0)];
+        let mut bbox_ranges = Vec::new();
+        while !partial.is_empty() {
+            let mut straddling = Vec::new();
+            for (x, y) in partial {
+                let (far_x, far_y) = (x + size - 1, y + size - 1);
+                if x > max_x || far_x < min_x || y > max_y || far_y < min_y {
+                    continue;
+                }
+                if (x >= min_x && far_x <= max_x && y >= min_y && far_y <= max_y) || size == 1 {
+                    bbox_ranges.push(run(x, y, size));
+                } else {
+                    straddling.push((x, y));
+                }
+            }
+            if bbox_ranges.len() + 4 * straddling.len() > MAX_HILBERT_RANGES {
+                bbox_ranges.extend(straddling.into_iter().map(|(x, y)| run(x, y, size)));
+                break;
+            }
+            size /= 2;
+            partial = straddling
+                .into_iter()
+                .flat_map(|(x, y)| vec![(x, y), (x + size, y), (x, y + size), (x + size, y + size)])
+                .collect();
+        }
+        ranges.extend(bbox_ranges);
+    }
+
+    ranges.sort_by(|a, b| b.cmp(a));
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if end >= last.0 => {
+                last.0 = last.0.min(start);
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// The tile at `index` along a Hilbert curve; the inverse of `hilbert_index`
+pub(crate) fn hilbert_coords(index: u32) -> (u16, u16) {
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut t = index;
+    let mut s = 1u32;
+    while s < 1 << HILBERT_ORDER {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        hilbert_rotate(s, &mut x, &mut y, rx, ry);
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x as u16, y as u16)
+}
+
+/// Roughly how many tiles of a polygon's cover span its width or height
+const POLYGON_COVER_TILES: f64 = 64.;
+const MAX_POLYGON_COVER_ZOOM: u16 = 16;
+
+/// An arbitrary region, e.g. a city boundary, to restrict matching to with `MatchOpts::polygon`.
+/// A tile is in the region if its center is. Matching only looks at coords within the polygon's
+/// bbox, then checks each against a coarse cover of the polygon, which settles most of them; only
+/// coords in cover tiles that the polygon's boundary crosses get a point-in-polygon test. It
+/// doesn't depend on zoom, so one mask serves stores of every zoom, and it's meant to be built
+/// once and shared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolygonMask {
+    /// The outer ring and any holes, in Web Mercator coords scaled to the unit square
+    rings: Vec<Vec<[f64; 2]>>,
+    /// Min x, min y, max x and max y of the outer ring, in the same coords
+    bounds: [f64; 4],
+    cover_zoom: u16,
+    /// z-order coords at `cover_zoom` of the tiles entirely inside the polygon
+    inside: FxHashSet<u32>,
+    /// z-order coords at `cover_zoom` of the tiles the polygon's boundary crosses
+    boundary: FxHashSet<u32>,
+}
+
+impl PolygonMask {
+    /// A mask of the polygon with the given rings of [longitude, latitude] points, the first its
+    /// outer ring and any others holes in it. Rings can be closed or not.
+    pub fn from_lonlat(rings: &[Vec<[f64; 2]>]) -> Result<Self, Error> {
+        if rings.is_empty() {
+            return Err(PolygonError::NoRings.into());
+        }
+        let rings: Vec<Vec<[f64; 2]>> = rings
+            .iter()
+            .enumerate()
+            .map(|(ring, points)| {
+                if points.len() < 3 {
+                    return Err(PolygonError::TooFewPoints { ring });
+                }
+                Ok(points.iter().map(|[lon, lat]| [lon_to_x(*lon), lat_to_y(*lat)]).collect())
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut bounds = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+        for [x, y] in rings[0].iter() {
+            bounds = [bounds[0].min(*x), bounds[1].min(*y), bounds[2].max(*x), bounds[3].max(*y)];
+        }
+        let extent = (bounds[2] - bounds[0]).max(bounds[3] - bounds[1]);
+        let mut cover_zoom = 0;
+        while cover_zoom < MAX_POLYGON_COVER_ZOOM
+            && extent * (1u32 << (cover_zoom + 1)) as f64 <= POLYGON_COVER_TILES
+        {
+            cover_zoom += 1;
+        }
+
+        let mut mask = PolygonMask {
+            rings,
+            bounds,
+            cover_zoom,
+            inside: FxHashSet::default(),
+            boundary: FxHashSet::default(),
+        };
+        mask.cover();
+        Ok(mask)
+    }
+
+    /// Sorts the cover tiles within the polygon's bbox into those its boundary crosses and those
+    /// entirely inside it
+    fn cover(&mut self) {
+        let n = (1u32 << self.cover_zoom) as f64;
+        let tile = |v: f64| (v * n).floor().clamp(0., n - 1.) as u16;
+        let rect =
+            |x: u16, y: u16| [x as f64 / n, y as f64 / n, (x as f64 + 1.) / n, (y as f64 + 1.) / n];
+        for ring in self.rings.iter() {
+            for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                for x in tile(a[0].min(b[0]))..=tile(a[0].max(b[0])) {
+                    for y in tile(a[1].min(b[1]))..=tile(a[1].max(b[1])) {
+                        if segment_crosses_rect(*a, *b, rect(x, y)) {
+                            self.boundary.insert(interleave_morton(x, y));
+                        }
+                    }
+                }
+            }
+        }
+        // no edges cross the rest, so each is all in or all out, like its center
+        for x in tile(self.bounds[0])..=tile(self.bounds[2]) {
+            for y in tile(self.bounds[1])..=tile(self.bounds[3]) {
+                let zcoord = interleave_morton(x, y);
+                if !self.boundary.contains(&zcoord)
+                    && self.contains_point((x as f64 + 0.5) / n, (y as f64 + 0.5) / n)
+                {
+                    self.inside.insert(zcoord);
+                }
+            }
+        }
+    }
+
+    /// Whether the point (in the mask's unit square coords) is in the polygon, by the even-odd
+    /// rule, so holes are left out
+    fn contains_point(&self, x: f64, y: f64) -> bool {
+        if x < self.bounds[0] || x > self.bounds[2] || y < self.bounds[1] || y > self.bounds[3] {
+            return false;
+        }
+        let mut inside = false;
+        for ring in self.rings.iter() {
+            for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                if (a[1] > y) != (b[1] > y) && x < (b[0] - a[0]) * (y - a[1]) / (b[1] - a[1]) + a[0]
+                {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+
+    /// The tiles at `zoom` within the polygon's bbox, as min x, min y, max x, max y
+    pub fn tile_bbox(&self, zoom: u16) -> [u16; 4] {
+        let n = (1u32 << zoom) as f64;
+        let tile = |v: f64| (v * n).floor().clamp(0., n - 1.) as u16;
+        [tile(self.bounds[0]), tile(self.bounds[1]), tile(self.bounds[2]), tile(self.bounds[3])]
+    }
+
+    /// Whether the tile with z-order coord `zcoord` at `zoom` is in the polygon
+    #[inline]
+    pub fn contains(&self, zcoord: u32, zoom: u16) -> bool {
+        if zoom >= self.cover_zoom {
+            let cover_tile = zcoord >> (2 * (zoom - self.cover_zoom));
+            if self.inside.contains(&cover_tile) {
+                return true;
+            }
+            if !self.boundary.contains(&cover_tile) {
+                return false;
+            }
+        }
+        let (x, y) = deinterleave_morton(zcoord);
+        let n = (1u32 << zoom) as f64;
+        self.contains_point((x as f64 + 0.5) / n, (y as f64 + 0.5) / n)
+    }
+}
+
+/// Whether any part of the segment from `a` to `b` is within `rect` (min x, min y, max x, max
+/// y), by Liang-Barsky clipping
+fn segment_crosses_rect(a: [f64; 2], b: [f64; 2], rect: [f64; 4]) -> bool {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let (mut t0, mut t1) = (0f64, 1f64);
+    let edges =
+        [(-dx, a[0] - rect[0]), (dx, rect[2] - a[0]), (-dy, a[1] - rect[1]), (dy, rect[3] - a[1])];
+    for &(p, q) in edges.iter() {
+        if p == 0. {
+            if q < 0. {
+                return false;
+            }
+        } else {
+            let r = q / p;
+            if p < 0. {
+                if r > t1 {
+                    return false;
+                }
+                t0 = t0.max(r);
+            } else {
+                if r < t0 {
+                    return false;
+                }
+                t1 = t1.min(r);
+            }
+        }
+    }
+    true
+}
+
+/// Web Mercator x of `lon`, scaled to the unit square
+fn lon_to_x(lon: f64) -> f64 {
+    (lon + 180.) / 360.
+}
+
+/// Web Mercator y of `lat`, scaled to the unit square; the inverse of `y_to_lat`
+fn lat_to_y(lat: f64) -> f64 {
+    let lat = lat.clamp(-85.051_128_779_806_59, 85.051_128_779_806_59);
+    (1. - lat.to_radians().tan().asinh() / PI) / 2.
+}
+
+#[derive(Debug, Fail)]
+enum PolygonError {
+    #[fail(display = "a polygon needs at least an outer ring")]
+    NoRings,
+    #[fail(display = "ring {} of the polygon has fewer than three points", ring)]
+    TooFewPoints { ring: usize },
+}
+
+#[test]
+fn polygon_mask_test() {
+    // a triangle with a square hole, at zoom 8 around tile (128, 128)
+    let point = |x: u16, y: u16| tile_lonlat(x, y, 8, TileAnchor::Corner);
+    let outer = vec![point(120, 120), point(140, 120), point(120, 140)];
+    let hole = vec![point(122, 122), point(125, 122), point(125, 125), point(122, 125)];
+    let mask = PolygonMask::from_lonlat(&[outer.clone(), hole]).unwrap();
+
+    assert_eq!(mask.tile_bbox(8), [120, 120, 140, 140]);
+    assert_eq!(mask.tile_bbox(7), [60, 60, 70, 70]);
+    let contains = |x: u16, y: u16, zoom: u16| mask.contains(interleave_morton(x, y), zoom);
+    assert!(contains(121, 121, 8));
+    assert!(contains(130, 121, 8));
+    assert!(!contains(130, 130, 8), "past the hypotenuse");
+    assert!(!contains(119, 121, 8));
+    assert!(!contains(123, 123, 8), "in the hole");
+    assert!(contains(126, 123, 8), "beside the hole");
+    // the same places at other zooms
+    assert!(contains(121 * 4 + 1, 121 * 4 + 1, 10));
+    assert!(!contains(123 * 4, 123 * 4, 10));
+    assert!(!contains(130 * 4, 130 * 4, 10));
+    assert!(contains(65, 60, 7));
+
+    // the cover agrees with testing every tile's center
+    let n = 256.;
+    for x in 110..150 {
+        for y in 110..150 {
+            let expected = mask.contains_point((x as f64 + 0.5) / n, (y as f64 + 0.5) / n);
+            assert_eq!(contains(x, y, 8), expected, "tile {}, {}", x, y);
+        }
+    }
+
+    let unclosed = PolygonMask::from_lonlat(&[outer.clone()]).unwrap();
+    let mut closed = outer.clone();
+    closed.push(outer[0]);
+    assert_eq!(
+        unclosed.contains(interleave_morton(121, 121), 8),
+        PolygonMask::from_lonlat(&[closed]).unwrap().contains(interleave_morton(121, 121), 8)
+    );
+    assert!(PolygonMask::from_lonlat(&[]).is_err());
+    assert!(PolygonMask::from_lonlat(&[outer[..2].to_vec()]).is_err());
+}
+
+#[test]
+fn tile_lonlat_test() {
+    assert_eq!(tile_lonlat(0, 0, 0, TileAnchor::Center), [0., 0.]);
+    let [lon, lat] = tile_lonlat(0, 0, 1, TileAnchor::Corner);
+    assert_eq!(lon, -180.);
+    assert!((lat - 85.051_128_779_806_59).abs() < 1e-9, "the corner is the north edge");
+    let [lon, lat] = tile_lonlat(3, 1, 2, TileAnchor::Center);
+    assert_eq!(lon, 135.);
+    assert!(lat > 0. && lat < tile_lonlat(3, 1, 2, TileAnchor::Corner)[1]);
+}
+
 #[test]
 fn scoredist_test() {
     assert_eq!(scoredist(14, 1., 0, 400.), 321.7508133738646, "scoredist for a feature 1 tile away from proximity point with score 0 and radius 400 should be 321.7508133738646");
     assert_eq!(scoredist(14, 0., 0, 400.), 402.1885167173308, "scoredist for a feature on the same tile as the proximity point with score 0 and radius 400 should be 402.1885167173308,");
 }
+
+#[test]
+fn score_only_scoredist_test() {
+    for score in 0..8 {
+        assert_eq!(
+            scoredist(14, 1000., score, 400.),
+            score_only_scoredist(score),
+            "beyond the radius scoredist is the score-only scoredist"
+        );
+    }
+}
+
+#[test]
+fn max_scoredist_test() {
+    for score in 0..8 {
+        let ceiling = max_scoredist(14, score, 400.);
+        for distance in &[0., 1., 10., 320., 1000.] {
+            assert!(scoredist(14, *distance, score, 400.) <= ceiling, "no grid beats the ceiling");
+        }
+        if score > 0 {
+            assert!(ceiling > max_scoredist(14, score - 1, 400.), "ceiling increases with score");
+        }
+    }
+}
@@ -318,6 +318,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 2,
+            bbox: None,
         };
 
         let b1 = PhrasematchSubquery {
@@ -331,6 +332,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
 
         let b2 = PhrasematchSubquery {
@@ -344,6 +346,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
 
         let phrasematch_results = vec![a1, b1, b2];
@@ -443,6 +446,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
 
         let b1 = PhrasematchSubquery {
@@ -456,6 +460,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
         let phrasematch_results = vec![a1, b1];
         let tree = stackable(&phrasematch_results);
@@ -500,6 +505,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
 
         let b1 = PhrasematchSubquery {
@@ -513,6 +519,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
         let phrasematch_results = vec![a1, b1];
         let tree = stackable(&phrasematch_results);
@@ -556,6 +563,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
 
         let b1 = PhrasematchSubquery {
@@ -569,6 +577,7 @@ mod test {
                 ..MatchKeyWithId::default()
             }],
             mask: 1,
+            bbox: None,
         };
         let phrasematch_results = vec![a1, b1];
         let tree = stackable(&phrasematch_results);
@@ -1,19 +1,26 @@
-use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
 use byteorder::{BigEndian, ReadBytesExt};
-use failure::Error;
-use itertools::Itertools;
+use failure::{Error, Fail};
+use itertools::Either;
 use min_max_heap::MinMaxHeap;
-use morton::deinterleave_morton;
+use morton::{deinterleave_morton, interleave_morton};
 use ordered_float::OrderedFloat;
-use rocksdb::{Direction, IteratorMode, Options, DB};
+use rocksdb::{DBPinnableSlice, Direction, IteratorMode, Options, DB};
 use serde::Serialize;
 
 use crate::gridstore::common::*;
 use crate::gridstore::gridstore_format;
+use crate::gridstore::scoring::{self, ScoredistOpts};
+use crate::gridstore::settings::{Limits, Settings};
+#[cfg(feature = "shared-cache")]
+use crate::gridstore::shared_cache::SharedCache;
 use crate::gridstore::spatial;
 
 #[derive(Debug, Serialize)]
@@ -21,7 +28,11 @@ pub struct GridStore {
     #[serde(skip_serializing)]
     db: DB,
     #[serde(skip_serializing)]
-    pub bin_boundaries: HashSet<u32>,
+    pub bin_boundaries: HashSet<u64>,
+    /// Whether any entries were truncated when the store was built, so reads can skip looking
+    /// up truncation markers in stores that have none
+    #[serde(skip_serializing)]
+    has_truncated_keys: bool,
     pub path: PathBuf,
     // options:
     pub zoom: u16,
@@ -29,51 +40,355 @@ pub struct GridStore {
     pub coalesce_radius: f64,
     pub bboxes: Vec<[u16; 4]>,
     pub max_score: f64,
+    /// Generation id recorded when the store was built, if any. See
+    /// `GridStoreBuilder::set_generation` for how it's picked.
+    pub generation: Option<u64>,
+    /// Hash of the store's logical contents recorded when it was built, if any. Builds from the
+    /// same input have the same hash, however they were laid out on disk.
+    pub content_hash: Option<u64>,
+    /// How many zoom levels out from the store's zoom the tile index is, if it was built with one
+    pub tile_index_zoom_levels: Option<u16>,
+    /// How many keys were truncated when the store was built
+    pub truncation_stats: TruncationStats,
+    /// Type markers of sections in a newer-format store that this reader doesn't know about and
+    /// skips over
+    pub unknown_sections: Vec<u8>,
+    /// Major and minor version of the format the store was written with
+    pub format_version: (u16, u16),
+    /// How many zoom levels out from the store's zoom it has coarse copies of its covers at
+    pub coarse_zoom_levels: Vec<u16>,
+    /// Weights the relevance of each relevance bucket (0.4, 0.6, 0.8 and 1) is scaled by when
+    /// matching, if the store was built with any
+    pub relev_weights: Option<[f64; 4]>,
+    /// The coarse copies, opened as stores of their own
+    #[serde(skip_serializing)]
+    coarse_stores: Vec<GridStore>,
+    /// How phrase ids are encoded in this store's keys
+    #[serde(skip_serializing)]
+    phrase_id_width: PhraseIdWidth,
+    /// Extents of merged covers in stores from before 4.2, which kept them by feature id and the
+    /// z-order coord of the cover across the whole store rather than per record, and which are
+    /// read in whole when the store's opened
+    #[serde(skip_serializing)]
+    legacy_extents: Option<Arc<RecordExtents>>,
+    /// Whether the store keeps the extents of merged covers per record
+    #[serde(skip_serializing)]
+    has_record_extents: bool,
+    /// Feature ids removed upstream since the store was built, which matching skips
+    #[serde(skip_serializing)]
+    tombstones: Arc<HashSet<u32>>,
+    /// Phrases whose records are read from their copies in the hot section, as written with
+    /// `RecordOrder::AccessFrequency`, and the rank each was copied under
+    #[serde(skip_serializing)]
+    hot_phrases: HashMap<u64, u32>,
+    /// How record values are compressed on disk
+    pub compression: Compression,
+    /// The curve grouped records order their coords along
+    pub curve: Curve,
+    /// Whether lookups read records in place from the mapped files, as in `OpenOpts::mmap`
+    #[serde(skip_serializing)]
+    mmap: bool,
+    /// Limits on queries against this store, shared with the process-wide settings by default
+    #[serde(skip_serializing)]
+    settings: Settings,
+    /// The cache decompressed records go through, as in `OpenOpts::shared_cache`, and the
+    /// namespace this store's records are kept under in it
+    #[cfg(feature = "shared-cache")]
+    #[serde(skip_serializing)]
+    shared_cache: Option<(Arc<SharedCache>, Vec<u8>)>,
 }
 
-#[inline]
-fn decode_value<T: AsRef<[u8]>>(value: T) -> impl Iterator<Item = GridEntry> {
-    let record_ref = {
-        let value_ref: &[u8] = value.as_ref();
-        // this is pretty sketch: we're opting out of compiler lifetime protection
-        // for this reference. This usage should be safe though, because we'll move the
-        // reference and the underlying owned object around together as a unit (the
-        // tuple below) so that when we pull the reference into the inner closures,
-        // we'll drag the owned object along, and won't drop it until the whole
-        // nest of closures is deleted
-        let static_ref: &'static [u8] = unsafe { std::mem::transmute(value_ref) };
-        (value, static_ref)
+/// A record value as it comes out of the db: copied out by an iterator, or pinned in place
+enum DbValue<'a> {
+    Copied(Box<[u8]>),
+    Pinned(DBPinnableSlice<'a>),
+}
+
+impl<'a> AsRef<[u8]> for DbValue<'a> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            DbValue::Copied(value) => value.as_ref(),
+            DbValue::Pinned(value) => value.as_ref(),
+        }
+    }
+}
+
+/// A record value as read from the db, decompressed if the store compresses them
+enum RecordValue<T> {
+    Raw(T),
+    Decompressed(Vec<u8>),
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for RecordValue<T> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            RecordValue::Raw(value) => value.as_ref(),
+            RecordValue::Decompressed(value) => value.as_ref(),
+        }
+    }
+}
+
+/// Which lookup tier results came from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum MatchTier {
+    /// The phrase id that was asked for
+    Exact,
+    /// The prefix bin the phrase id falls in, because the phrase id itself had no entries
+    Degraded,
+}
+
+/// What to do when opening a store written with a newer minor version of the format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewerMinorFormat {
+    /// Open it, skipping any sections this reader doesn't know about
+    Open,
+    /// Refuse to open it
+    Refuse,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenOpts {
+    pub newer_minor_format: NewerMinorFormat,
+    /// Have `get` and `streaming_get_matching` decode records in place from the store's
+    /// memory-mapped files, pinning them for as long as they're being read, instead of copying
+    /// each one onto the heap. Pages of the mapped files can be shared and evicted by the OS, so
+    /// this keeps resident memory down when many stores are open in one process.
+    pub mmap: bool,
+    /// Check every record against its checksum with `GridStore::verify` before returning the
+    /// store, and refuse to open it if any don't match. This reads the whole store.
+    pub verify: bool,
+    /// Get decompressed records from this cache, which other processes on the host can share,
+    /// and add the ones that aren't in it yet, instead of decompressing every record as it's
+    /// read. Only stores with compressed records use it.
+    #[cfg(feature = "shared-cache")]
+    pub shared_cache: Option<Arc<SharedCache>>,
+}
+
+impl Default for OpenOpts {
+    fn default() -> Self {
+        OpenOpts {
+            newer_minor_format: NewerMinorFormat::Open,
+            mmap: false,
+            verify: false,
+            #[cfg(feature = "shared-cache")]
+            shared_cache: None,
+        }
+    }
+}
+
+/// What `GridStore::verify` found
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct VerifyReport {
+    /// How many records were checked against their checksums. Stores written before checksums
+    /// were added have none to check.
+    pub records_checked: usize,
+    /// Keys whose records are missing, unreadable, or don't match their checksums
+    pub corrupt_keys: Vec<GridKey>,
+    /// Like `corrupt_keys`, for prefix bin records, keyed by the first phrase id of the bin
+    pub corrupt_bins: Vec<GridKey>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_keys.is_empty() && self.corrupt_bins.is_empty()
+    }
+}
+
+/// What `GridStore::health_check` found
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub keys_read: usize,
+    pub entries_decoded: usize,
+    /// Whether a test query for one of the sampled keys found its entries
+    pub query_ok: bool,
+    pub errors: Vec<String>,
+}
+
+/// How a record's entries are laid out, before any compression
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum RecordEncoding {
+    /// The one entry of a record packed on its own
+    SingleEntry,
+    /// Entries grouped by relevance and score, then by coord, with id lists shared between coords
+    Grouped,
+}
+
+/// How well one key's record packs down, from `GridStore::compression_stats`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct KeyCompressionStats {
+    pub key: GridKey,
+    pub entries: usize,
+    pub encoding: RecordEncoding,
+    /// Bytes the entries would take packed at a fixed width, as a baseline to compare against
+    pub unpacked_size: usize,
+    /// Bytes of the encoded record, before compression
+    pub encoded_size: usize,
+    /// Bytes of the record as written to the db
+    pub stored_size: usize,
+}
+
+impl KeyCompressionStats {
+    /// Bytes each entry takes packed at a fixed width: 4 for the id and source phrase hash, 2 each
+    /// for x and y, and 1 for the relevance and score
+    pub const UNPACKED_ENTRY_SIZE: usize = 9;
+
+    /// Stored size over unpacked size; lower is better, and above 1 the encoding costs space
+    pub fn ratio(&self) -> f64 {
+        self.stored_size as f64 / std::cmp::max(self.unpacked_size, 1) as f64
+    }
+}
+
+/// Where the copy of a store's covers zoomed out by `coarse_zoom_levels` is kept, inside the
+/// store's own directory
+pub(crate) fn coarse_store_path(path: &Path, coarse_zoom_levels: u16) -> PathBuf {
+    coarse_stores_dir(path).join(coarse_zoom_levels.to_string())
+}
+
+/// The directory all of a store's coarse copies are kept in
+pub(crate) fn coarse_stores_dir(path: &Path) -> PathBuf {
+    path.join("zooms")
+}
+
+/// Reads a GridKey back out of the part of a db key that follows the type marker
+fn decode_grid_key(key_body: &[u8], width: PhraseIdWidth) -> Result<GridKey, Error> {
+    let phrase_id = width.read(key_body)?;
+
+    let key_lang_partial = &key_body[width.bytes()..];
+    let lang_set: u128 = if key_lang_partial.len() == 0 {
+        // 0-length language array is the shorthand for "matches everything"
+        std::u128::MAX
+    } else {
+        let mut key_lang_full = [0u8; 16];
+        key_lang_full[(16 - key_lang_partial.len())..].copy_from_slice(key_lang_partial);
+
+        (&key_lang_full[..]).read_u128::<BigEndian>()?
     };
-    let reader = gridstore_format::Reader::new(record_ref.1);
-    let record = { gridstore_format::read_phrase_record_from(&reader) };
 
-    let iter = gridstore_format::read_var_vec_raw(record_ref.1, record.relev_scores)
-        .into_iter()
-        .flat_map(move |rs_obj| {
-            // grab a reference to the outer object to make sure it doesn't get freed
-            let _ref = &record_ref;
+    Ok(GridKey { phrase_id, lang_set })
+}
+
+#[inline]
+fn decode_single_entry(entry: gridstore_format::SingleEntry) -> GridEntry {
+    let (x, y) = deinterleave_morton(entry.coord);
+    GridEntry {
+        relev: relev_int_to_float(entry.relev_score >> 4),
+        // mask for the least significant four bits
+        score: entry.relev_score & 15,
+        x,
+        y,
+        id: entry.id >> 8,
+        source_phrase_hash: (entry.id & 255) as u8,
+    }
+}
+
+/// Extends a borrow of a record's value to 'static, so that the nest of closures decoding it can
+/// be returned alongside the owned value
+#[cfg(not(feature = "checked-decode"))]
+fn static_record_ref<T: AsRef<[u8]>>(value: &T) -> &'static [u8] {
+    let value_ref: &[u8] = value.as_ref();
+    // this is pretty sketch: we're opting out of compiler lifetime protection
+    // for this reference. This usage should be safe though, because callers move the
+    // owned object into the iterator using the reference, so that it isn't dropped
+    // until the whole nest of closures is deleted
+    unsafe { std::mem::transmute(value_ref) }
+}
+
+#[inline]
+fn decode_value<T: AsRef<[u8]>>(value: T, curve: Curve) -> impl Iterator<Item = GridEntry> {
+    if let Some(entry) = gridstore_format::SingleEntry::read(value.as_ref()) {
+        return Either::Left(std::iter::once(decode_single_entry(entry)));
+    }
+
+    #[cfg(not(feature = "checked-decode"))]
+    let iter = decode_record(static_record_ref(&value), curve).inspect(move |_| {
+        // grab a reference to the outer object to make sure it doesn't get freed
+        let _ref = &value;
+    });
+    // decode up front instead, with nothing borrowed past the lifetime of the value
+    #[cfg(feature = "checked-decode")]
+    let iter = decode_record(value.as_ref(), curve).collect::<Vec<_>>().into_iter();
+    Either::Right(iter)
+}
+
+fn decode_record<'a>(buffer: &'a [u8], curve: Curve) -> impl Iterator<Item = GridEntry> + 'a {
+    let reader = gridstore_format::Reader::new(buffer);
+    let record = { gridstore_format::read_phrase_record_from(&reader) };
 
+    gridstore_format::read_var_vec_raw(buffer, record.relev_scores).into_iter().flat_map(
+        move |rs_obj| {
             let relev_score = rs_obj.relev_score;
             let relev = relev_int_to_float(relev_score >> 4);
             // mask for the least significant four bits
             let score = relev_score & 15;
 
-            let nested_ref = record_ref.1;
-            gridstore_format::read_uniform_vec_raw(record_ref.1, rs_obj.coords)
-                .into_iter()
-                .flat_map(move |coords_obj| {
-                    let (x, y) = deinterleave_morton(coords_obj.coord);
+            gridstore_format::read_uniform_vec_raw(buffer, rs_obj.coords).into_iter().flat_map(
+                move |coords_obj| {
+                    let (x, y) = deinterleave_morton(curve.to_zcoord(coords_obj.coord));
 
-                    gridstore_format::read_fixed_vec_raw(nested_ref, coords_obj.ids)
-                        .into_iter()
-                        .map(move |id_comp| {
+                    gridstore_format::read_fixed_vec_raw(buffer, coords_obj.ids).into_iter().map(
+                        move |id_comp| {
                             let id = id_comp >> 8;
                             let source_phrase_hash = (id_comp & 255) as u8;
                             GridEntry { relev, score, x, y, id, source_phrase_hash }
-                        })
-                })
-        });
-    iter
+                        },
+                    )
+                },
+            )
+        },
+    )
+}
+
+/// Like `decode_value`, but only reads the z-order coords, optionally only those in `bbox`
+fn decode_coords_value<T: AsRef<[u8]>>(
+    value: T,
+    bbox: Option<[u16; 4]>,
+    curve: Curve,
+) -> impl Iterator<Item = u32> {
+    if let Some(entry) = gridstore_format::SingleEntry::read(value.as_ref()) {
+        let (x, y) = deinterleave_morton(entry.coord);
+        let in_bbox = match bbox {
+            Some([minx, miny, maxx, maxy]) => minx <= x && x <= maxx && miny <= y && y <= maxy,
+            None => true,
+        };
+        return Either::Left(Some(entry.coord).filter(|_| in_bbox).into_iter());
+    }
+
+    #[cfg(not(feature = "checked-decode"))]
+    let iter = decode_coords_record(static_record_ref(&value), bbox, curve).inspect(move |_| {
+        // grab a reference to the outer object to make sure it doesn't get freed
+        let _ref = &value;
+    });
+    #[cfg(feature = "checked-decode")]
+    let iter = decode_coords_record(value.as_ref(), bbox, curve).collect::<Vec<_>>().into_iter();
+    Either::Right(iter)
+}
+
+fn decode_coords_record<'a>(
+    buffer: &'a [u8],
+    bbox: Option<[u16; 4]>,
+    curve: Curve,
+) -> impl Iterator<Item = u32> + 'a {
+    let reader = gridstore_format::Reader::new(buffer);
+    let record = { gridstore_format::read_phrase_record_from(&reader) };
+
+    gridstore_format::read_var_vec_raw(buffer, record.relev_scores).into_iter().flat_map(
+        move |rs_obj| {
+            let coords_vec = gridstore_format::read_uniform_vec_raw(buffer, rs_obj.coords);
+            let coords = match (curve, bbox) {
+                (Curve::Hilbert, bbox) => Either::Left(Either::Left(spatial::hilbert_filter(
+                    coords_vec,
+                    bbox.map(|bbox| Arc::new(vec![bbox])),
+                    None,
+                ))),
+                (Curve::Morton, None) => Either::Left(Either::Right(coords_vec.into_iter())),
+                (Curve::Morton, Some(bbox)) => {
+                    Either::Right(spatial::bbox_filter(coords_vec, bbox).into_iter().flatten())
+                }
+            };
+            coords.map(|coords_obj| coords_obj.coord)
+        },
+    )
 }
 
 #[inline]
@@ -82,136 +397,584 @@ fn decode_matching_value<T: AsRef<[u8]>>(
     match_opts: &MatchOpts,
     matches_language: bool,
     coalesce_radius: f64,
+    merged_covers: Option<MergedCovers>,
+    relev_weights: Option<[f64; 4]>,
+    curve: Curve,
 ) -> impl Iterator<Item = MatchEntry> {
-    let match_opts = match_opts.clone();
-
-    let record_ref = {
-        let value_ref: &[u8] = value.as_ref();
-        // this is pretty sketch: we're opting out of compiler lifetime protection
-        // for this reference. This usage should be safe though, because we'll move the
-        // reference and the underlying owned object around together as a unit (the
-        // tuple below) so that when we pull the reference into the inner closures,
-        // we'll drag the owned object along, and won't drop it until the whole
-        // nest of closures is deleted
-        let static_ref: &'static [u8] = unsafe { std::mem::transmute(value_ref) };
-        (value, static_ref)
-    };
-    let reader = gridstore_format::Reader::new(record_ref.1);
-    let record = { gridstore_format::read_phrase_record_from(&reader) };
+    let matcher = CoverMatcher::new(match_opts, merged_covers, coalesce_radius);
+    if let Some(entry) = gridstore_format::SingleEntry::read(value.as_ref()) {
+        let matched =
+            match_single_entry(entry, &matcher, matches_language, relev_weights).into_iter();
+        return Either::Left(matched);
+    }
+
+    #[cfg(not(feature = "checked-decode"))]
+    let iter =
+        match_record(static_record_ref(&value), matcher, matches_language, relev_weights, curve)
+            .inspect(move |_| {
+                // grab a reference to the outer object to make sure it doesn't get freed
+                let _ref = &value;
+            });
+    // decode up front instead, with nothing borrowed past the lifetime of the value
+    #[cfg(feature = "checked-decode")]
+    let iter = match_record(value.as_ref(), matcher, matches_language, relev_weights, curve)
+        .collect::<Vec<_>>()
+        .into_iter();
+    Either::Right(iter)
+}
+
+fn match_record<'a>(
+    buffer: &'a [u8],
+    matcher: CoverMatcher,
+    matches_language: bool,
+    relev_weights: Option<[f64; 4]>,
+    curve: Curve,
+) -> impl Iterator<Item = MatchEntry> + 'a {
+    let language_boost = if matches_language { matcher.match_opts.language_boost } else { None };
+    let coalesce_radius = matcher.coalesce_radius;
+    let hilbert_bboxes = matcher
+        .match_opts
+        .bboxes
+        .clone()
+        .or_else(|| matcher.match_opts.bbox.map(|bbox| Arc::new(vec![bbox])));
+    let matcher = Arc::new(matcher);
 
-    let relevs = gridstore_format::read_var_vec_raw(record_ref.1, record.relev_scores)
-        .into_iter()
-        .map(|rs_obj| {
+    let reader = gridstore_format::Reader::new(buffer);
+    let record = { gridstore_format::read_phrase_record_from(&reader) };
+    let relevs = gridstore_format::read_var_vec_raw(buffer, record.relev_scores).into_iter().map(
+        move |rs_obj| {
             let relev_score = rs_obj.relev_score;
-            let relev = relev_int_to_float(relev_score >> 4);
+            let relev = weighted_relev(relev_score >> 4, relev_weights);
             // mask for the least significant four bits
             let score = relev_score & 15;
             (relev, score, rs_obj)
-        });
+        },
+    );
 
-    let iter = somewhat_eager_groupby(relevs.into_iter(), |(relev, _, _)| *relev)
-        .into_iter()
-        .flat_map(move |(relev, score_groups)| {
-            // grab a reference to the outer object to make sure it doesn't get freed
-            let _ref = &record_ref;
-
-            let match_opts = match_opts.clone();
-            let nested_ref = _ref.1;
-            let coords_per_score = score_groups.into_iter().map(move |(_, score, rs_obj)| {
-                let coords_vec = gridstore_format::read_uniform_vec_raw(nested_ref, rs_obj.coords);
-                let coords =
-                    match &match_opts {
-                        MatchOpts { bbox: None, proximity: None, .. } => {
-                            Some(Box::new(coords_vec.into_iter())
-                                as Box<dyn Iterator<Item = gridstore_format::Coord>>)
+    somewhat_eager_groupby(relevs.into_iter(), |(relev, _, _)| *relev).into_iter().flat_map(
+        move |(relev, score_groups)| {
+            let matcher = matcher.clone();
+            let hilbert_bboxes = hilbert_bboxes.clone();
+
+            // score groups are stored in descending score order, so their ceilings descend too
+            let ceiling_groups: Vec<_> = score_groups
+                .into_iter()
+                .map(|(_, score, rs_obj)| {
+                    let ceiling = match &matcher.match_opts {
+                        MatchOpts { proximity: Some(_), zoom, .. } => {
+                            spatial::max_scoredist(*zoom, score, coalesce_radius)
                         }
-                        MatchOpts { bbox: Some(bbox), proximity: None, .. } => {
-                            match spatial::bbox_filter(coords_vec, *bbox) {
-                                Some(v) => Some(Box::new(v)
-                                    as Box<dyn Iterator<Item = gridstore_format::Coord>>),
-                                None => None,
-                            }
+                        _ => score as f64,
+                    };
+                    (ceiling, (score, rs_obj))
+                })
+                .collect();
+
+            let start_group = move |(score, rs_obj): (u8, gridstore_format::RelevScore)| {
+                let coords_vec = gridstore_format::read_uniform_vec_raw(buffer, rs_obj.coords);
+                let match_opts = &matcher.match_opts;
+                let coords = match match_opts {
+                    // coords come back in z-order, for the tile filters and extents below
+                    _ if curve == Curve::Hilbert => Some(Box::new(spatial::hilbert_filter(
+                        coords_vec,
+                        hilbert_bboxes.clone(),
+                        match_opts.proximity,
+                    ))
+                        as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>),
+                    MatchOpts { bboxes: Some(bboxes), proximity: None, .. } => {
+                        spatial::bboxes_filter(coords_vec, bboxes).map(|v| {
+                            Box::new(v) as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>
+                        })
+                    }
+                    MatchOpts { bboxes: Some(bboxes), proximity: Some(prox_pt), .. } => {
+                        spatial::bboxes_proximity_filter(coords_vec, bboxes, *prox_pt).map(|v| {
+                            Box::new(v) as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>
+                        })
+                    }
+                    MatchOpts { bbox: None, proximity: None, .. } => {
+                        Some(Box::new(coords_vec.into_iter())
+                            as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>)
+                    }
+                    MatchOpts { bbox: Some(bbox), proximity: None, .. } => {
+                        match spatial::bbox_filter(coords_vec, *bbox) {
+                            Some(v) => Some(Box::new(v)
+                                as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>),
+                            None => None,
                         }
-                        MatchOpts { bbox: None, proximity: Some(prox_pt), .. } => {
-                            match spatial::proximity(coords_vec, *prox_pt) {
-                                Some(v) => Some(Box::new(v)
-                                    as Box<dyn Iterator<Item = gridstore_format::Coord>>),
-                                None => None,
-                            }
+                    }
+                    MatchOpts { bbox: None, proximity: Some(prox_pt), .. } => {
+                        match spatial::proximity(coords_vec, *prox_pt) {
+                            Some(v) => Some(Box::new(v)
+                                as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>),
+                            None => None,
                         }
-                        MatchOpts { bbox: Some(bbox), proximity: Some(prox_pt), .. } => {
-                            match spatial::bbox_proximity_filter(coords_vec, *bbox, *prox_pt) {
-                                Some(v) => Some(Box::new(v)
-                                    as Box<dyn Iterator<Item = gridstore_format::Coord>>),
-                                None => None,
-                            }
+                    }
+                    MatchOpts { bbox: Some(bbox), proximity: Some(prox_pt), .. } => {
+                        match spatial::bbox_proximity_filter(coords_vec, *bbox, *prox_pt) {
+                            Some(v) => Some(Box::new(v)
+                                as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>),
+                            None => None,
                         }
-                    };
+                    }
+                };
 
                 let coords = coords.unwrap_or_else(|| {
                     Box::new((Option::<gridstore_format::Coord>::None).into_iter())
-                        as Box<dyn Iterator<Item = gridstore_format::Coord>>
+                        as Box<dyn Iterator<Item = gridstore_format::Coord> + 'a>
                 });
-                let match_opts = match_opts.clone();
-                coords.map(move |coords_obj| {
-                    let (x, y) = deinterleave_morton(coords_obj.coord);
-
-                    let (distance, within_radius, scoredist) = match &match_opts {
-                        MatchOpts { proximity: Some(prox_pt), zoom, .. } => {
-                            let distance = spatial::tile_dist(prox_pt[0], prox_pt[1], x, y);
-                            (
-                                distance,
-                                // The proximity radius calculation is also done in scoredist
-                                // There could be an opportunity to optimize by doing it once
-                                distance <= spatial::proximity_radius(*zoom, coalesce_radius),
-                                spatial::scoredist(*zoom, distance, score, coalesce_radius),
+                let is_proximity = match_opts.proximity.is_some();
+                let per_feature = matcher.per_feature();
+                let matcher = matcher.clone();
+                let scored = coords
+                    .flat_map(move |coords_obj| {
+                        let ids = if per_feature {
+                            Either::Left(
+                                gridstore_format::read_fixed_vec_raw(buffer, coords_obj.ids)
+                                    .into_iter()
+                                    .map(Some),
                             )
-                        }
-                        _ => (0f64, false, score as f64),
-                    };
-                    (distance, within_radius, score, scoredist, x, y, coords_obj)
+                        } else {
+                            Either::Right(std::iter::once(None))
+                        };
+                        ids.map(move |id_comp| (coords_obj, id_comp))
+                    })
+                    .filter_map(move |(coords_obj, id_comp)| {
+                        let (x, y) = deinterleave_morton(coords_obj.coord);
+                        let (distance, within_radius, scoredist) = matcher.score(
+                            (x, y, coords_obj.coord, coords_obj.offset),
+                            id_comp.map(|id_comp| id_comp >> 8),
+                            score,
+                        )?;
+                        Some(ScoredCoord {
+                            distance,
+                            within_radius,
+                            score,
+                            scoredist,
+                            x,
+                            y,
+                            coords_obj,
+                            id_comp,
+                        })
+                    });
+
+                if is_proximity {
+                    // the proximity walk is in z-order distance rather than tile distance, so
+                    // only decode a block at a time and put each block in scoredist order
+                    Box::new(sort_in_blocks(scored, PROXIMITY_BLOCK_SIZE, |c| c.scoredist))
+                        as Box<dyn Iterator<Item = ScoredCoord> + 'a>
+                } else {
+                    Box::new(scored) as Box<dyn Iterator<Item = ScoredCoord> + 'a>
+                }
+            };
+
+            let all_coords = merge_by_score_ceiling(ceiling_groups, start_group, |c| c.scoredist);
+
+            all_coords.flat_map(move |scored: ScoredCoord| {
+                let ScoredCoord {
+                    distance,
+                    within_radius,
+                    score,
+                    scoredist,
+                    x,
+                    y,
+                    coords_obj,
+                    id_comp,
+                } = scored;
+                let ids = match id_comp {
+                    Some(id_comp) => Either::Left(std::iter::once(id_comp)),
+                    None => Either::Right(
+                        gridstore_format::read_fixed_vec_raw(buffer, coords_obj.ids).into_iter(),
+                    ),
+                };
+
+                ids.map(move |id_comp| {
+                    let id = id_comp >> 8;
+                    let source_phrase_hash = (id_comp & 255) as u8;
+                    MatchEntry {
+                        grid_entry: GridEntry {
+                            relev: language_adjusted_relev(relev, matches_language, within_radius),
+                            score,
+                            x,
+                            y,
+                            id,
+                            source_phrase_hash,
+                        },
+                        matches_language,
+                        distance,
+                        scoredist: language_boost.map_or(scoredist, |boost| boost.apply(scoredist)),
+                        truncated: false,
+                    }
                 })
-            });
+            })
+        },
+    )
+}
 
-            let all_coords = coords_per_score.kmerge_by(
-            |
-                (_distance1, _within_radius1, _score1, scoredist1, _x1, _y1, _coords_obj1),
-                (_distance2, _within_radius2, _score2, scoredist2, _x2, _y2, _coords_obj2)
-            | {
-                scoredist1.partial_cmp(scoredist2).unwrap() == Ordering::Greater
-            });
+/// (distance, within_radius, scoredist) of a grid at (x, y), and at the sub-tile offset within
+/// the tile if it has one, with the given score
+#[inline]
+fn score_coord(
+    (x, y, offset): (u16, u16, Option<u8>),
+    score: u8,
+    match_opts: &MatchOpts,
+    scoredist_opts: &ScoredistOpts,
+    coalesce_radius: f64,
+) -> (f64, bool, f64) {
+    match match_opts {
+        MatchOpts { proximity: Some(prox_pt), zoom, .. } => {
+            let distance = spatial::tile_dist_with_offset(prox_pt[0], prox_pt[1], x, y, offset);
+            let scoredist = scoring::scoredist(score, distance, *zoom, scoredist_opts);
+            (
+                distance,
+                // The proximity radius calculation is also done in scoredist
+                // There could be an opportunity to optimize by doing it once
+                distance <= spatial::proximity_radius(*zoom, coalesce_radius),
+                scoredist,
+            )
+        }
+        _ => (0f64, false, score as f64),
+    }
+}
 
-            let nested_ref = record_ref.1;
-            all_coords.flat_map(
-                move |(distance, within_radius, score, scoredist, x, y, coords_obj)| {
-                    let ids = gridstore_format::read_fixed_vec_raw(nested_ref, coords_obj.ids);
-
-                    ids.into_iter().map(move |id_comp| {
-                        let id = id_comp >> 8;
-                        let source_phrase_hash = (id_comp & 255) as u8;
-                        MatchEntry {
-                            grid_entry: GridEntry {
-                                relev: relev
-                                    * (if matches_language || within_radius {
-                                        1f64
-                                    } else {
-                                        0.96f64
-                                    }),
-                                score,
-                                x,
-                                y,
-                                id,
-                                source_phrase_hash,
-                            },
-                            matches_language,
-                            distance,
-                            scoredist,
-                        }
-                    })
-                },
+/// Extents (min x, min y, max x, max y) of the merged covers in a record (see
+/// `GridStoreBuilder::set_merge_adjacent_covers`), keyed by feature id and the z-order coord of
+/// the cover that stands in for them
+#[derive(Debug)]
+struct RecordExtents {
+    extents: HashMap<(u32, u32), [u16; 4]>,
+    /// How many tiles past the cover standing in for it any of the extents reaches
+    reach: u16,
+}
+
+impl RecordExtents {
+    fn new(extents: HashMap<(u32, u32), [u16; 4]>) -> Self {
+        let reach = extents
+            .values()
+            .map(|extent| std::cmp::max(extent[2] - extent[0], extent[3] - extent[1]))
+            .max()
+            .unwrap_or(0);
+        RecordExtents { extents, reach }
+    }
+
+    fn get(&self, id: u32, zcoord: u32) -> Option<&[u16; 4]> {
+        self.extents.get(&(id, zcoord))
+    }
+}
+
+/// How matching treats the merged covers of a record: by the extents they stand in for, rather
+/// than the tile each is stored at
+#[derive(Clone)]
+struct MergedCovers {
+    extents: Arc<RecordExtents>,
+    scoring: Option<Arc<ExtentScoring>>,
+}
+
+impl MergedCovers {
+    /// (distance, within_radius, scoredist) of the feature's cover at `zcoord`, if it's a merged
+    /// cover and the query is a proximity query
+    fn score(&self, id: u32, zcoord: u32, score: u8) -> Option<(f64, bool, f64)> {
+        Some(self.scoring.as_ref()?.score(self.extents.get(id, zcoord)?, score))
+    }
+}
+
+/// Checks entries against a query's boxes by their extents, for records with merged covers, whose
+/// tiles are only filtered against the boxes widened by how far the record's extents reach
+struct ExtentBboxCheck {
+    bbox: [u16; 4],
+    bboxes: Option<Arc<Vec<[u16; 4]>>>,
+    extents: Arc<RecordExtents>,
+}
+
+impl ExtentBboxCheck {
+    /// Whether the feature's cover at `zcoord`, which is the tile at (`x`, `y`), is in the boxes
+    fn contains(&self, id: u32, zcoord: u32, x: u16, y: u16) -> bool {
+        let extent = self.extents.get(id, zcoord);
+        cover_in_bbox(x, y, extent, &self.bbox)
+            && self
+                .bboxes
+                .as_ref()
+                .map_or(true, |bboxes| bboxes.iter().any(|bbox| cover_in_bbox(x, y, extent, bbox)))
+    }
+}
+
+/// Whether a cover at (`x`, `y`) is in `bbox`: either the tile it's stored at is, or, if it stands
+/// in for others, any of the extent they cover is. Border covers are copies stored outside their
+/// extent, and are in the boxes their own tile is in.
+fn cover_in_bbox(x: u16, y: u16, extent: Option<&[u16; 4]>, bbox: &[u16; 4]) -> bool {
+    spatial::bboxes_intersect([x, y, x, y], *bbox)
+        || extent.map_or(false, |extent| spatial::bboxes_intersect(*extent, *bbox))
+}
+
+/// Rescores merged covers by the tile of their extent nearest the proximity point rather than by
+/// the tile the cover is stored at
+struct ExtentScoring {
+    match_opts: MatchOpts,
+    scoredist_opts: ScoredistOpts,
+    coalesce_radius: f64,
+}
+
+impl ExtentScoring {
+    /// Only needed for proximity queries
+    fn new(match_opts: &MatchOpts, coalesce_radius: f64) -> Option<Arc<Self>> {
+        match_opts.proximity?;
+        Some(Arc::new(ExtentScoring {
+            match_opts: match_opts.clone(),
+            scoredist_opts: ScoredistOpts::new(coalesce_radius, match_opts),
+            coalesce_radius,
+        }))
+    }
+
+    /// (distance, within_radius, scoredist) of a merged cover with the given extent. The extent
+    /// is a bounding box, so the nearest tile in it may not itself be covered.
+    fn score(&self, extent: &[u16; 4], score: u8) -> (f64, bool, f64) {
+        let prox_pt = self.match_opts.proximity.unwrap_or([extent[0], extent[1]]);
+        let x = prox_pt[0].max(extent[0]).min(extent[2]);
+        let y = prox_pt[1].max(extent[1]).min(extent[3]);
+        score_coord(
+            (x, y, None),
+            score,
+            &self.match_opts,
+            &self.scoredist_opts,
+            self.coalesce_radius,
+        )
+    }
+}
+
+/// The order of reverse results: nearest first, then best scored, then by id so that ties come
+/// out the same way every time
+fn reverse_order(a: &MatchEntry, b: &MatchEntry) -> Ordering {
+    let key = |entry: &MatchEntry| {
+        (
+            OrderedFloat(entry.distance),
+            Reverse(entry.grid_entry.score),
+            entry.grid_entry.id,
+            Reverse(OrderedFloat(entry.grid_entry.relev)),
+        )
+    };
+    key(a).cmp(&key(b))
+}
+
+/// Grids that don't match the query language are penalized unless they're nearby
+#[inline]
+fn language_adjusted_relev(relev: f64, matches_language: bool, within_radius: bool) -> f64 {
+    relev * (if matches_language || within_radius { 1f64 } else { 0.96f64 })
+}
+
+/// Filters and scores a record's covers for a query, the same way whether the record is a single
+/// inline entry or grouped by relevance, score and coord
+struct CoverMatcher {
+    /// The query's options, with its boxes widened to take in the merged covers whose extents
+    /// reach into them, for the tile filters of grouped records
+    match_opts: MatchOpts,
+    /// The query's own boxes, when they've been widened
+    bbox_check: Option<ExtentBboxCheck>,
+    merged_covers: Option<MergedCovers>,
+    scoredist_opts: ScoredistOpts,
+    coalesce_radius: f64,
+}
+
+impl CoverMatcher {
+    fn new(
+        match_opts: &MatchOpts,
+        merged_covers: Option<MergedCovers>,
+        coalesce_radius: f64,
+    ) -> Self {
+        let mut match_opts = match_opts.clone();
+        // Merged covers are in a box if their extent is, wherever the cover standing in for them
+        // is, so the tile filters look as far past the boxes as any extent in the record reaches,
+        // and each cover is checked against the boxes themselves in `score`.
+        let bbox_check = match (&merged_covers, match_opts.bbox) {
+            (Some(covers), Some(bbox)) if covers.extents.reach > 0 => {
+                let check = ExtentBboxCheck {
+                    bbox,
+                    bboxes: match_opts.bboxes.clone(),
+                    extents: covers.extents.clone(),
+                };
+                let (reach, zoom) = (covers.extents.reach, match_opts.zoom);
+                match_opts.bbox = Some(spatial::widen_bbox(bbox, reach, zoom));
+                match_opts.bboxes = match_opts.bboxes.map(|bboxes| {
+                    Arc::new(
+                        bboxes.iter().map(|bbox| spatial::widen_bbox(*bbox, reach, zoom)).collect(),
+                    )
+                });
+                Some(check)
+            }
+            _ => None,
+        };
+        let scoredist_opts = ScoredistOpts::new(coalesce_radius, &match_opts);
+        CoverMatcher { match_opts, bbox_check, merged_covers, scoredist_opts, coalesce_radius }
+    }
+
+    /// Whether the features at a coord have to be filtered and scored one by one, by the extents
+    /// of their merged covers, rather than all together
+    fn per_feature(&self) -> bool {
+        self.bbox_check.is_some()
+            || self.merged_covers.as_ref().map_or(false, |covers| covers.scoring.is_some())
+    }
+
+    /// (distance, within_radius, scoredist) of a cover with the given score at tile (x, y), which
+    /// is z-order coord `zcoord`, and at the sub-tile offset if it has one, or `None` if the query
+    /// filters it out. With `id`, the cover is that feature's, and is filtered and scored by its
+    /// extent if it's a merged cover.
+    #[inline]
+    fn score(
+        &self,
+        (x, y, zcoord, offset): (u16, u16, u32, Option<u8>),
+        id: Option<u32>,
+        score: u8,
+    ) -> Option<(f64, bool, f64)> {
+        let match_opts = &self.match_opts;
+        let in_boxes = match (&self.bbox_check, id) {
+            (Some(check), Some(id)) => check.contains(id, zcoord, x, y),
+            _ => {
+                let in_bbox = |bbox: &[u16; 4]| cover_in_bbox(x, y, None, bbox);
+                match_opts.bbox.as_ref().map_or(true, in_bbox)
+                    && match_opts.bboxes.as_ref().map_or(true, |bboxes| bboxes.iter().any(in_bbox))
+            }
+        };
+        if !in_boxes {
+            return None;
+        }
+        if let Some(mask) = &match_opts.exclude_tiles {
+            if mask.excludes(zcoord, match_opts.zoom) {
+                return None;
+            }
+        }
+        if let Some(polygon) = &match_opts.polygon {
+            if !polygon.contains(zcoord, match_opts.zoom) {
+                return None;
+            }
+        }
+        let merged = match (&self.merged_covers, id) {
+            (Some(covers), Some(id)) => covers.score(id, zcoord, score),
+            _ => None,
+        };
+        Some(merged.unwrap_or_else(|| {
+            score_coord(
+                (x, y, offset),
+                score,
+                match_opts,
+                &self.scoredist_opts,
+                self.coalesce_radius,
             )
-        });
-    iter
+        }))
+    }
+}
+
+/// The relevance of a relevance bucket, scaled by the store's weight for the bucket if it has
+/// any (see `GridStoreBuilder::set_relev_weights`)
+#[inline]
+fn weighted_relev(relev_int: u8, relev_weights: Option<[f64; 4]>) -> f64 {
+    let relev = relev_int_to_float(relev_int);
+    match relev_weights {
+        Some(weights) => relev * weights[std::cmp::min(relev_int, 3) as usize],
+        None => relev,
+    }
+}
+
+/// The fast path of `decode_matching_value` for records with a single entry
+fn match_single_entry(
+    entry: gridstore_format::SingleEntry,
+    matcher: &CoverMatcher,
+    matches_language: bool,
+    relev_weights: Option<[f64; 4]>,
+) -> Option<MatchEntry> {
+    let grid_entry = decode_single_entry(entry);
+    let (distance, within_radius, mut scoredist) = matcher.score(
+        (grid_entry.x, grid_entry.y, entry.coord, None),
+        Some(grid_entry.id),
+        grid_entry.score,
+    )?;
+    if let (true, Some(boost)) = (matches_language, matcher.match_opts.language_boost) {
+        scoredist = boost.apply(scoredist);
+    }
+    Some(MatchEntry {
+        grid_entry: GridEntry {
+            relev: language_adjusted_relev(
+                weighted_relev(entry.relev_score >> 4, relev_weights),
+                matches_language,
+                within_radius,
+            ),
+            ..grid_entry
+        },
+        matches_language,
+        distance,
+        scoredist,
+        truncated: false,
+    })
+}
+
+/// Number of coords decoded at a time when walking outward from a proximity point
+const PROXIMITY_BLOCK_SIZE: usize = 64;
+
+/// A coord of a record scored for a query
+#[derive(Copy, Clone)]
+struct ScoredCoord {
+    distance: f64,
+    within_radius: bool,
+    score: u8,
+    scoredist: f64,
+    x: u16,
+    y: u16,
+    coords_obj: gridstore_format::Coord,
+    /// The feature id (with its source phrase hash) the scoring is for, only set for coords whose
+    /// features are scored one by one; otherwise all the features at the coord share it
+    id_comp: Option<u32>,
+}
+
+/// Pull `block_size` items at a time from `iter` and yield each block in descending `key` order
+fn sort_in_blocks<I, K>(mut iter: I, block_size: usize, key: K) -> impl Iterator<Item = I::Item>
+where
+    I: Iterator,
+    K: Fn(&I::Item) -> f64,
+{
+    let mut block: Vec<I::Item> = Vec::with_capacity(block_size);
+    std::iter::from_fn(move || {
+        if block.is_empty() {
+            block.extend(iter.by_ref().take(block_size));
+            block.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap());
+            // reverse so we can pop from the back without losing the original order of ties
+            block.reverse();
+        }
+        block.pop()
+    })
+}
+
+/// Merge streams by descending `key`, only starting a stream once its ceiling (the best key it
+/// could possibly produce) could beat the best item already pending from the started ones.
+///
+/// `groups` must be sorted by descending ceiling.
+fn merge_by_score_ceiling<G, I, S, K>(
+    groups: Vec<(f64, G)>,
+    mut start: S,
+    key: K,
+) -> impl Iterator<Item = I::Item>
+where
+    I: Iterator,
+    S: FnMut(G) -> I,
+    K: Fn(&I::Item) -> f64,
+{
+    let mut pending = groups.into_iter().peekable();
+    let mut started: Vec<std::iter::Peekable<I>> = Vec::new();
+    std::iter::from_fn(move || loop {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, stream) in started.iter_mut().enumerate() {
+            if let Some(item) = stream.peek() {
+                let item_key = key(item);
+                if best.map_or(true, |(_, best_key)| item_key > best_key) {
+                    best = Some((i, item_key));
+                }
+            }
+        }
+
+        match pending.peek() {
+            Some((ceiling, _)) if best.map_or(true, |(_, best_key)| *ceiling > best_key) => {
+                let (_, group) = pending.next().unwrap();
+                started.push(start(group).peekable());
+            }
+            _ => return best.and_then(|(i, _)| started[i].next()),
+        }
+    })
 }
 
 struct QueueElement<T: Iterator<Item = MatchEntry>> {
@@ -268,49 +1031,465 @@ impl GridStore {
         coalesce_radius: f64,
         bboxes: Vec<[u16; 4]>,
         max_score: f64,
+    ) -> Result<Self, Error> {
+        GridStore::new_with_open_opts(
+            path,
+            zoom,
+            type_id,
+            coalesce_radius,
+            bboxes,
+            max_score,
+            OpenOpts::default(),
+        )
+    }
+
+    pub fn new_with_open_opts<P: AsRef<Path>>(
+        path: P,
+        zoom: u16,
+        type_id: u16,
+        coalesce_radius: f64,
+        bboxes: Vec<[u16; 4]>,
+        max_score: f64,
+        open_opts: OpenOpts,
     ) -> Result<Self, Error> {
         let path = path.as_ref().to_owned();
         let mut opts = Options::default();
         opts.set_read_only(true);
         opts.set_allow_mmap_reads(true);
         let db = DB::open(&opts, &path)?;
+        if db.get("~BUILDING")?.is_some() {
+            return Err(StoreError::Incomplete { path }.into());
+        }
+
+        // stores from before the format was versioned are read as 1.0
+        let (major, minor) = match db.get("~FORMAT")? {
+            Some(entry) => {
+                let encoded_version: &[u8] = entry.as_ref();
+                match encoded_version {
+                    [a, b, c, d, ..] => {
+                        (u16::from_le_bytes([*a, *b]), u16::from_le_bytes([*c, *d]))
+                    }
+                    _ => return Err(StoreError::BadFormatVersion { path }.into()),
+                }
+            }
+            None => (1, 0),
+        };
+        // older major versions are still read, with the key encoding they were written with
+        let newer_minor = major == FORMAT_MAJOR_VERSION && minor > FORMAT_MINOR_VERSION;
+        if major == 0
+            || major > FORMAT_MAJOR_VERSION
+            || (newer_minor && open_opts.newer_minor_format == NewerMinorFormat::Refuse)
+        {
+            return Err(StoreError::UnsupportedFormat { path, major, minor }.into());
+        }
+        let unknown_sections: Vec<u8> = if newer_minor {
+            sections(&db).into_iter().filter(|m| TypeMarker::from_u8(*m).is_none()).collect()
+        } else {
+            Vec::new()
+        };
+
+        let phrase_id_width = PhraseIdWidth::for_format(major);
 
-        let bin_boundaries: HashSet<u32> = match db.get("~BOUNDS")? {
+        let bin_boundaries: HashSet<u64> = match db.get("~BOUNDS")? {
             Some(entry) => {
                 let encoded_boundaries: &[u8] = entry.as_ref();
                 encoded_boundaries
-                    .chunks(4)
-                    .filter_map(|chunk| {
-                        if chunk.len() == 4 {
-                            Some(u32::from_le_bytes(chunk.try_into().unwrap()))
-                        } else {
-                            None
+                    .chunks(phrase_id_width.bytes())
+                    .filter_map(|chunk| match phrase_id_width {
+                        PhraseIdWidth::U32 => {
+                            chunk.try_into().ok().map(|chunk| u32::from_le_bytes(chunk) as u64)
                         }
+                        PhraseIdWidth::U64 => chunk.try_into().ok().map(u64::from_le_bytes),
                     })
                     .collect()
             }
             None => HashSet::new(),
         };
 
-        Ok(GridStore {
-            db,
-            path,
-            bin_boundaries,
-            zoom,
-            type_id,
+        let generation: Option<u64> = match db.get("~GENERATION")? {
+            Some(entry) => {
+                let encoded_generation: &[u8] = entry.as_ref();
+                encoded_generation.try_into().ok().map(u64::from_le_bytes)
+            }
+            None => None,
+        };
+
+        let content_hash: Option<u64> = match db.get("~CONTENT_HASH")? {
+            Some(entry) => {
+                let encoded_hash: &[u8] = entry.as_ref();
+                encoded_hash.try_into().ok().map(u64::from_le_bytes)
+            }
+            None => None,
+        };
+
+        let has_truncated_keys = db
+            .iterator(IteratorMode::From(&[TypeMarker::Truncated as u8], Direction::Forward))
+            .next()
+            .map_or(false, |(key, _)| key[0] == TypeMarker::Truncated as u8);
+
+        // stores from before 4.2 kept the extents of merged covers across the whole store, keyed
+        // by feature id and z-order coord
+        let legacy_extents: HashMap<(u32, u32), [u16; 4]> = db
+            .iterator(IteratorMode::From(&[TypeMarker::Extent as u8], Direction::Forward))
+            .take_while(|(key, _)| key[0] == TypeMarker::Extent as u8)
+            .filter_map(|(key, value)| decode_extent(&key[1..], &value))
+            .collect();
+        let legacy_extents = if legacy_extents.is_empty() {
+            None
+        } else {
+            Some(Arc::new(RecordExtents::new(legacy_extents)))
+        };
+        let has_record_extents = db
+            .iterator(IteratorMode::From(&[TypeMarker::RecordExtent as u8], Direction::Forward))
+            .next()
+            .map_or(false, |(key, _)| key[0] == TypeMarker::RecordExtent as u8);
+
+        let tile_index_zoom_levels = match db.get("~TILE_INDEX")? {
+            Some(entry) => {
+                let encoded_levels: &[u8] = entry.as_ref();
+                encoded_levels.try_into().ok().map(u16::from_le_bytes)
+            }
+            None => None,
+        };
+
+        let truncation_stats = match db.get("~TRUNCATION")? {
+            Some(entry) => TruncationStats::from_bytes(entry.as_ref())?,
+            None => TruncationStats::default(),
+        };
+
+        let compression = match db.get("~COMPRESSION")? {
+            Some(entry) => Compression::from_bytes(entry.as_ref())?,
+            None => Compression::None,
+        };
+        let curve = match db.get("~CURVE")? {
+            Some(entry) => Curve::from_bytes(entry.as_ref())?,
+            None => Curve::Morton,
+        };
+
+        let coarse_zoom_levels: Vec<u16> = match db.get("~COARSE_ZOOMS")? {
+            Some(entry) => {
+                let encoded_levels: &[u8] = entry.as_ref();
+                encoded_levels
+                    .chunks_exact(2)
+                    .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+                    .filter(|levels| *levels <= zoom)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let relev_weights: Option<[f64; 4]> = match db.get("~RELEV_WEIGHTS")? {
+            Some(entry) => {
+                let encoded_weights: &[u8] = entry.as_ref();
+                let mut weights = [1.; 4];
+                for (i, weight) in encoded_weights.chunks_exact(8).take(4).enumerate() {
+                    weights[i] = f64::from_le_bytes(weight.try_into()?);
+                }
+                Some(weights)
+            }
+            None => None,
+        };
+
+        let hot_phrases: HashMap<u64, u32> = match db.get("~HOT_PHRASES")? {
+            Some(entry) => {
+                let encoded_phrases: &[u8] = entry.as_ref();
+                encoded_phrases
+                    .chunks_exact(8)
+                    .enumerate()
+                    .map(|(rank, chunk)| Ok((u64::from_le_bytes(chunk.try_into()?), rank as u32)))
+                    .collect::<Result<_, Error>>()?
+            }
+            None => HashMap::new(),
+        };
+
+        let coarse_stores = coarse_zoom_levels
+            .iter()
+            .map(|levels| {
+                let coarse_zoom = zoom - levels;
+                GridStore::new_with_open_opts(
+                    coarse_store_path(&path, *levels),
+                    coarse_zoom,
+                    type_id,
+                    coalesce_radius,
+                    bboxes
+                        .iter()
+                        .map(|bbox| spatial::adjust_bbox_zoom(*bbox, zoom, coarse_zoom))
+                        .collect(),
+                    max_score,
+                    open_opts.clone(),
+                )
+                .map(|coarse_store| GridStore { relev_weights, ..coarse_store })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let store = GridStore {
+            db,
+            path,
+            bin_boundaries,
+            has_truncated_keys,
+            zoom,
+            type_id,
             coalesce_radius,
             bboxes,
             max_score,
-        })
+            generation,
+            content_hash,
+            tile_index_zoom_levels,
+            truncation_stats,
+            unknown_sections,
+            format_version: (major, minor),
+            coarse_zoom_levels,
+            relev_weights,
+            coarse_stores,
+            phrase_id_width,
+            legacy_extents,
+            has_record_extents,
+            tombstones: Arc::new(HashSet::new()),
+            hot_phrases,
+            compression,
+            curve,
+            mmap: open_opts.mmap,
+            settings: Settings::global().clone(),
+            #[cfg(feature = "shared-cache")]
+            shared_cache: None,
+        };
+        #[cfg(feature = "shared-cache")]
+        let store = GridStore {
+            shared_cache: open_opts
+                .shared_cache
+                .map(|cache| (cache, shared_cache_namespace(&store))),
+            ..store
+        };
+        if open_opts.verify {
+            let report = store.verify()?;
+            if !report.is_ok() {
+                let corrupt = report.corrupt_keys.len() + report.corrupt_bins.len();
+                return Err(StoreError::Corrupt { path: store.path.clone(), corrupt }.into());
+            }
+        }
+        Ok(store)
+    }
+
+    /// The handle on this store's limits, which can be used to change them at runtime
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Gives this store its own settings instead of the process-wide ones
+    pub fn set_settings(&mut self, settings: Settings) {
+        for coarse_store in self.coarse_stores.iter_mut() {
+            coarse_store.set_settings(settings.clone());
+        }
+        self.settings = settings;
+    }
+
+    /// The store's current limits
+    pub fn limits(&self) -> Arc<Limits> {
+        self.settings.load()
+    }
+
+    /// Iterates over the records from `start` on. With `OpenOpts::mmap`, only keys are read by
+    /// the iterator, and each value is pinned in place.
+    fn records_from<'i>(
+        &'i self,
+        start: &[u8],
+    ) -> impl Iterator<Item = Result<(Box<[u8]>, DbValue<'i>), Error>> + 'i {
+        if self.mmap {
+            let mut keys = self.db.raw_iterator();
+            keys.seek(start);
+            Either::Left(std::iter::from_fn(move || {
+                if !keys.valid() {
+                    return None;
+                }
+                let key: Box<[u8]> = keys.key()?.to_vec().into_boxed_slice();
+                keys.next();
+                match self.db.get_pinned(&key) {
+                    Ok(Some(value)) => Some(Ok((key, DbValue::Pinned(value)))),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err.into())),
+                }
+            }))
+        } else {
+            let db_iter = self.db.iterator(IteratorMode::From(start, Direction::Forward));
+            Either::Right(db_iter.map(|(key, value)| Ok((key, DbValue::Copied(value)))))
+        }
+    }
+
+    /// The key of the hot copy of the record at `db_key`, which is under `phrase_id`, if the
+    /// builder copied that phrase's records to the hot section
+    fn hot_copy_key(&self, phrase_id: u64, db_key: &[u8]) -> Option<Vec<u8>> {
+        let rank = self.hot_phrases.get(&phrase_id)?;
+        let mut hot_key = Vec::with_capacity(db_key.len() + 4);
+        write_hot_copy_key(*rank, db_key, &mut hot_key);
+        Some(hot_key)
+    }
+
+    /// Like `records_from`, but reads the hot copies of one phrase's records, starting from
+    /// `hot_key`, and hands them back under the keys of the records they're copies of
+    fn hot_records_from<'i>(
+        &'i self,
+        hot_key: &[u8],
+    ) -> impl Iterator<Item = Result<(Box<[u8]>, DbValue<'i>), Error>> + 'i {
+        let mut section = [0u8; 5];
+        section.copy_from_slice(&hot_key[..5]);
+        self.records_from(hot_key)
+            .take_while(move |record| match record {
+                Ok((key, _)) => key.starts_with(&section),
+                Err(_) => true,
+            })
+            .map(|record| {
+                record.map(|(key, value)| {
+                    let mut db_key = Vec::with_capacity(key.len() - 4);
+                    db_key.push(TypeMarker::SinglePhrase as u8);
+                    db_key.extend_from_slice(&key[5..]);
+                    (db_key.into_boxed_slice(), value)
+                })
+            })
+    }
+
+    /// Decompresses a record value read from the db, if the store compresses them, through the
+    /// shared cache under `key` if it's given. In audit mode, the record is also checked to be
+    /// well-formed.
+    fn read_record<T: AsRef<[u8]>>(
+        &self,
+        key: Option<&[u8]>,
+        value: T,
+    ) -> Result<RecordValue<T>, Error> {
+        let record = match self.compression {
+            Compression::None => RecordValue::Raw(value),
+            compression => {
+                RecordValue::Decompressed(self.decompress(key, compression, value.as_ref())?)
+            }
+        };
+        #[cfg(feature = "checked-decode")]
+        gridstore_format::validate_record(record.as_ref())?;
+        Ok(record)
     }
 
+    #[cfg(feature = "shared-cache")]
+    fn decompress(
+        &self,
+        key: Option<&[u8]>,
+        compression: Compression,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        match (&self.shared_cache, key) {
+            (Some((cache, namespace)), Some(key)) => {
+                // the cached copy may have been written by another process, so it's checked to be
+                // well-formed before anything decodes it
+                if let Some(record) = cache.get(namespace, key) {
+                    if gridstore_format::validate_record(&record).is_ok() {
+                        return Ok(record);
+                    }
+                }
+                let record = compression.decompress(value)?;
+                cache.insert(namespace, key, &record);
+                Ok(record)
+            }
+            _ => compression.decompress(value),
+        }
+    }
+
+    #[cfg(not(feature = "shared-cache"))]
+    fn decompress(
+        &self,
+        _key: Option<&[u8]>,
+        compression: Compression,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        compression.decompress(value)
+    }
+
+    /// Sets the feature ids that matching should skip, replacing any set before, so that
+    /// features removed upstream stop being returned without rebuilding the store. Lookups with
+    /// `get` and `iter` still return them; `GridStoreBuilder::open_existing` and `delete` purge
+    /// them from the store itself.
+    pub fn set_tombstones<I: IntoIterator<Item = u32>>(&mut self, ids: I) {
+        self.tombstones = Arc::new(ids.into_iter().collect());
+        for coarse_store in self.coarse_stores.iter_mut() {
+            coarse_store.tombstones = self.tombstones.clone();
+        }
+    }
+
+    /// The store to query for grids at `zoom`: the coarsest of this store's coarse copies that's
+    /// still at least as fine as `zoom`, or this store itself if none are. Wide-area queries can
+    /// be run against the coarse copy, with its fewer covers, instead of a separate store.
+    pub fn at_zoom(&self, zoom: u16) -> &GridStore {
+        self.coarse_stores
+            .iter()
+            .filter(|coarse_store| coarse_store.zoom >= zoom)
+            .min_by_key(|coarse_store| coarse_store.zoom)
+            .unwrap_or(self)
+    }
+
+    /// Reads tombstoned feature ids from a file with one id per line, as in `set_tombstones`.
+    /// Blank lines are ignored.
+    pub fn load_tombstones<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let contents = std::fs::read_to_string(path)?;
+        let ids = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::parse::<u32>)
+            .collect::<Result<Vec<u32>, _>>()?;
+        self.set_tombstones(ids);
+        Ok(())
+    }
+
+    /// Decodes every entry stored under exactly `key`, without any matching options applied,
+    /// or `None` if the store has no such key. Entries come back in the order the builder wrote
+    /// them, tombstoned ones included, so this is the call to round-trip builder output with.
     #[inline(never)]
-    pub fn get(&self, key: &GridKey) -> Result<Option<impl Iterator<Item = GridEntry>>, Error> {
+    pub fn get(
+        &self,
+        key: &GridKey,
+    ) -> Result<Option<impl Iterator<Item = GridEntry> + '_>, Error> {
+        if key.phrase_id > self.phrase_id_width.max_phrase_id() {
+            return Ok(None);
+        }
+        let mut db_key: Vec<u8> = Vec::new();
+        key.write_to(TypeMarker::SinglePhrase, self.phrase_id_width, &mut db_key)?;
+        let read_key = self.hot_copy_key(key.phrase_id, &db_key);
+        let read_key = read_key.as_ref().unwrap_or(&db_key);
+
+        let value = if self.mmap {
+            self.db.get_pinned(read_key)?.map(Either::Left)
+        } else {
+            self.db.get(read_key)?.map(Either::Right)
+        };
+        Ok(match value {
+            Some(value) => Some(decode_value(self.read_record(Some(&db_key), value)?, self.curve)),
+            None => None,
+        })
+    }
+
+    /// The z-order (morton) coords of the tiles with entries under exactly `key`, optionally
+    /// only those within `bbox` (min x, min y, max x, max y), or `None` if the store has no such
+    /// key. Ids, relevances and scores are never decoded, so this is the cheap way to compare
+    /// the coverage of keys. Coords are in the order of the store's curve (descending, in
+    /// z-order) within each relevance/score bucket of the record, and one with entries in several
+    /// buckets comes back once for each.
+    pub fn coords(
+        &self,
+        key: &GridKey,
+        bbox: Option<[u16; 4]>,
+    ) -> Result<Option<impl Iterator<Item = u32> + '_>, Error> {
+        if key.phrase_id > self.phrase_id_width.max_phrase_id() {
+            return Ok(None);
+        }
         let mut db_key: Vec<u8> = Vec::new();
-        key.write_to(TypeMarker::SinglePhrase, &mut db_key)?;
+        key.write_to(TypeMarker::SinglePhrase, self.phrase_id_width, &mut db_key)?;
+        let read_key = self.hot_copy_key(key.phrase_id, &db_key);
+        let read_key = read_key.as_ref().unwrap_or(&db_key);
 
-        Ok(match self.db.get(&db_key)? {
-            Some(value) => Some(decode_value(value)),
+        let value = if self.mmap {
+            self.db.get_pinned(read_key)?.map(Either::Left)
+        } else {
+            self.db.get(read_key)?.map(Either::Right)
+        };
+        Ok(match value {
+            Some(value) => {
+                Some(decode_coords_value(self.read_record(Some(&db_key), value)?, bbox, self.curve))
+            }
             None => None,
         })
     }
@@ -320,7 +1499,7 @@ impl GridStore {
         match_key: &MatchKey,
         match_opts: &MatchOpts,
         max_values: usize,
-    ) -> Result<impl Iterator<Item = MatchEntry>, Error> {
+    ) -> Result<impl Iterator<Item = MatchEntry> + '_, Error> {
         let (fetch_start, fetch_end, fetch_type_marker) = match match_key.match_phrase {
             MatchPhrase::Exact(id) => (id, id + 1, TypeMarker::SinglePhrase),
             MatchPhrase::Range { start, end } => {
@@ -332,24 +1511,82 @@ impl GridStore {
             }
         };
 
-        let match_opts = match_opts.clone();
+        let mut match_opts = match_opts.clone();
+        if let Some(polygon) = &match_opts.polygon {
+            // only coords in the polygon's bbox can be in the polygon
+            let polygon_bbox = polygon.tile_bbox(match_opts.zoom);
+            match_opts.bbox = Some(match match_opts.bbox {
+                Some(bbox) => spatial::intersect_bboxes(bbox, polygon_bbox),
+                None => polygon_bbox,
+            });
+        }
+        if let Some(bboxes) = &match_opts.bboxes {
+            // only the parts of the boxes in `bbox` can have matches, and only the box around
+            // those parts needs to be read
+            let bboxes: Vec<[u16; 4]> = bboxes
+                .iter()
+                .map(|bbox| match match_opts.bbox {
+                    Some(outer) => spatial::intersect_bboxes(*bbox, outer),
+                    None => *bbox,
+                })
+                .filter(|bbox| bbox[0] <= bbox[2] && bbox[1] <= bbox[3])
+                .collect();
+            match_opts.bbox = Some(spatial::bbox_hull(&bboxes));
+            match_opts.bboxes = Some(Arc::new(bboxes));
+        }
 
         let mut range_key = match_key.clone();
         range_key.match_phrase = MatchPhrase::Range { start: fetch_start, end: fetch_end };
         let mut db_key: Vec<u8> = Vec::new();
-        range_key.write_start_to(fetch_type_marker, &mut db_key)?;
-
-        let db_iter = self
-            .db
-            .iterator(IteratorMode::From(&db_key, Direction::Forward))
-            .take_while(|(k, _)| range_key.matches_key(fetch_type_marker, k).unwrap());
+        let width = self.phrase_id_width;
+        range_key.write_start_to(fetch_type_marker, width, &mut db_key)?;
 
         let mut pri_queue = MinMaxHeap::<QueueElement<_>>::new();
+        let extent_scoring = ExtentScoring::new(&match_opts, self.coalesce_radius);
+
+        // entries of a prefix bin can't be traced back to their phrase
+        let bin_truncated =
+            matches!(fetch_type_marker, TypeMarker::PrefixBin) && self.is_truncated(&range_key)?;
 
-        for (key, value) in db_iter {
-            let matches_language = match_key.matches_language(&key).unwrap();
-            let mut entry_iter =
-                decode_matching_value(value, &match_opts, matches_language, self.coalesce_radius);
+        let started = if self.settings.is_adaptive() { Some(Instant::now()) } else { None };
+        let deadline =
+            self.settings.load().read_timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        // an exact phrase with hot copies is read from those instead
+        let hot_key = match (&match_key.match_phrase, fetch_type_marker) {
+            (MatchPhrase::Exact(id), TypeMarker::SinglePhrase) => self.hot_copy_key(*id, &db_key),
+            _ => None,
+        };
+        let records = match &hot_key {
+            Some(hot_key) => Either::Left(self.hot_records_from(hot_key)),
+            None => Either::Right(self.records_from(&db_key)),
+        };
+        for record in records {
+            match_opts.check_cancelled()?;
+            if let Some((deadline, timeout)) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(
+                        QueryError::ReadTimedOut { path: self.path.clone(), timeout }.into()
+                    );
+                }
+            }
+            let (key, value) = record?;
+            if !range_key.matches_key(fetch_type_marker, width, &key)? {
+                break;
+            }
+            let matches_language = match_key.matches_language(width, &key).unwrap();
+            let tombstones = self.tombstones.clone();
+            let truncated = bin_truncated || self.is_truncated_record(&key)?;
+            let mut entry_iter = decode_matching_value(
+                self.read_record(Some(&key), value)?,
+                &match_opts,
+                matches_language,
+                self.coalesce_radius,
+                self.merged_covers(&key, &extent_scoring),
+                self.relev_weights,
+                self.curve,
+            )
+            .filter(move |entry| !tombstones.contains(&entry.grid_entry.id))
+            .map(move |entry| MatchEntry { truncated, ..entry });
             if let Some(next_entry) = entry_iter.next() {
                 let queue_element = QueueElement { next_entry, entry_iter };
                 if pri_queue.len() >= max_values {
@@ -364,6 +1601,9 @@ impl GridStore {
                 }
             }
         }
+        if let Some(started) = started {
+            self.settings.record_read_latency(started.elapsed());
+        }
 
         let iter = std::iter::from_fn(move || {
             if let Some(mut best_entry) = pri_queue.peek_max_mut() {
@@ -381,47 +1621,695 @@ impl GridStore {
         Ok(iter)
     }
 
-    pub fn keys<'i>(&'i self) -> impl Iterator<Item = Result<GridKey, Error>> + 'i {
-        let db_iter = self.db.iterator(IteratorMode::Start);
-        db_iter.take_while(|(key, _)| key[0] == 0).map(|(key, _)| {
-            let phrase_id = (&key[1..]).read_u32::<BigEndian>()?;
+    /// Like `streaming_get_matching`, but if an exact phrase id has no matching entries, falls
+    /// back to the entries of the prefix bin it falls in, so that a phrase id that's slightly off
+    /// (e.g. from a tokenizer disagreement) still finds the phrases sharing its prefix. Results
+    /// from the fallback are flagged as `MatchTier::Degraded`.
+    pub fn streaming_get_matching_with_fallback(
+        &self,
+        match_key: &MatchKey,
+        match_opts: &MatchOpts,
+        max_values: usize,
+    ) -> Result<(MatchTier, impl Iterator<Item = MatchEntry> + '_), Error> {
+        let mut exact = self.streaming_get_matching(match_key, match_opts, max_values)?.peekable();
+        let phrase_id = match match_key.match_phrase {
+            MatchPhrase::Exact(phrase_id) if exact.peek().is_none() => phrase_id,
+            _ => return Ok((MatchTier::Exact, exact)),
+        };
+        let start = self.bin_boundaries.iter().filter(|b| **b <= phrase_id).max();
+        let end = self.bin_boundaries.iter().filter(|b| **b > phrase_id).min();
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                let bin_key = MatchKey {
+                    match_phrase: MatchPhrase::Range { start: *start, end: *end },
+                    lang_set: match_key.lang_set,
+                };
+                let degraded =
+                    self.streaming_get_matching(&bin_key, match_opts, max_values)?.peekable();
+                Ok((MatchTier::Degraded, degraded))
+            }
+            _ => Ok((MatchTier::Exact, exact)),
+        }
+    }
 
-            let key_lang_partial = &key[5..];
-            let lang_set: u128 = if key_lang_partial.len() == 0 {
-                // 0-length language array is the shorthand for "matches everything"
-                std::u128::MAX
-            } else {
-                let mut key_lang_full = [0u8; 16];
-                key_lang_full[(16 - key_lang_partial.len())..].copy_from_slice(key_lang_partial);
+    /// Like `streaming_get_matching`, but collapses each feature's covers into a single item of
+    /// (feature id, best-ranked entry, number of matching covers), in the order of each
+    /// feature's best entry
+    pub fn get_matching_grouped(
+        &self,
+        match_key: &MatchKey,
+        match_opts: &MatchOpts,
+        max_values: usize,
+    ) -> Result<impl Iterator<Item = (u32, MatchEntry, usize)>, Error> {
+        let mut groups: Vec<(u32, MatchEntry, usize)> = Vec::new();
+        let mut positions: HashMap<u32, usize> = HashMap::new();
+        for entry in self.streaming_get_matching(match_key, match_opts, max_values)? {
+            let id = entry.grid_entry.id;
+            match positions.entry(id) {
+                Entry::Occupied(position) => groups[*position.get()].2 += 1,
+                Entry::Vacant(position) => {
+                    position.insert(groups.len());
+                    groups.push((id, entry, 1));
+                }
+            }
+        }
+        Ok(groups.into_iter())
+    }
 
-                (&key_lang_full[..]).read_u128::<BigEndian>()?
-            };
+    /// Features covering or nearest to `point`, a tile at `zoom`, each with its grid nearest the
+    /// point: those covering it first, at a distance of 0, then the rest by distance and score.
+    /// Distances are in tiles at the store's zoom. With a tile index (see
+    /// `GridStoreBuilder::set_tile_index`), only the records with covers in the coarse tiles
+    /// around the point are read, working outward until no further record could hold a nearer
+    /// feature; without one, like `iter`, this reads every record in the store, so it's meant for
+    /// stores small enough to scan.
+    pub fn reverse(
+        &self,
+        point: [u16; 2],
+        zoom: u16,
+        limit: usize,
+    ) -> Result<Vec<MatchEntry>, Error> {
+        let match_opts = MatchOpts { proximity: Some(point), zoom, ..MatchOpts::default() }
+            .adjust_to_zoom(self.zoom);
+        let extent_scoring = ExtentScoring::new(&match_opts, self.coalesce_radius);
+
+        let mut nearest: HashMap<u32, MatchEntry> = HashMap::new();
+        match self.tile_index_zoom_levels {
+            Some(levels) if limit > 0 => {
+                self.reverse_by_tile(levels, &match_opts, &extent_scoring, limit, &mut nearest)?
+            }
+            _ => {
+                let db_iter = self.db.iterator(IteratorMode::Start);
+                for (key, value) in
+                    db_iter.take_while(|(key, _)| key[0] == TypeMarker::SinglePhrase as u8)
+                {
+                    self.add_nearest(&key, value, &match_opts, &extent_scoring, &mut nearest)?;
+                }
+            }
+        }
+
+        let mut entries: Vec<MatchEntry> = nearest.into_values().collect();
+        entries.sort_by(reverse_order);
+        entries.truncate(limit);
+        Ok(entries)
+    }
 
-            Ok(GridKey { phrase_id, lang_set })
+    /// Reads the records for `reverse` through the tile index, a ring of coarse tiles at a time
+    /// outward from the coarse tile the point is in
+    fn reverse_by_tile(
+        &self,
+        levels: u16,
+        match_opts: &MatchOpts,
+        extent_scoring: &Option<Arc<ExtentScoring>>,
+        limit: usize,
+        nearest: &mut HashMap<u32, MatchEntry>,
+    ) -> Result<(), Error> {
+        let point = match match_opts.proximity {
+            Some(point) => point,
+            None => return Ok(()),
+        };
+        let levels = std::cmp::min(levels, self.zoom);
+        let tile_size = 1i64 << levels;
+        let max_tile = (1i64 << (self.zoom - levels)) - 1;
+        let (center_x, center_y) = ((point[0] >> levels) as i64, (point[1] >> levels) as i64);
+
+        let mut visited: BTreeSet<GridKey> = BTreeSet::new();
+        let mut db_key: Vec<u8> = Vec::with_capacity(MAX_KEY_LENGTH);
+        for ring in 0i64.. {
+            for x in std::cmp::max(center_x - ring, 0)..=std::cmp::min(center_x + ring, max_tile) {
+                for y in
+                    std::cmp::max(center_y - ring, 0)..=std::cmp::min(center_y + ring, max_tile)
+                {
+                    if std::cmp::max((x - center_x).abs(), (y - center_y).abs()) != ring {
+                        continue;
+                    }
+                    let keys = self.keys_for_tile((x << levels) as u16, (y << levels) as u16);
+                    for grid_key in keys {
+                        let grid_key = grid_key?;
+                        if visited.contains(&grid_key) {
+                            continue;
+                        }
+                        db_key.clear();
+                        grid_key.write_to(
+                            TypeMarker::SinglePhrase,
+                            self.phrase_id_width,
+                            &mut db_key,
+                        )?;
+                        if let Some(value) = self.db.get(&db_key)? {
+                            self.add_nearest(&db_key, value, match_opts, extent_scoring, nearest)?;
+                        }
+                        visited.insert(grid_key);
+                    }
+                }
+            }
+
+            let whole_world = center_x - ring <= 0
+                && center_y - ring <= 0
+                && center_x + ring >= max_tile
+                && center_y + ring >= max_tile;
+            if whole_world {
+                return Ok(());
+            }
+            // every tile within `ring` coarse tiles' worth of tiles of the point has been read, so
+            // any cover not yet read is at least that far away, sub-tile offsets included
+            if nearest.len() >= limit {
+                let mut distances: Vec<f64> =
+                    nearest.values().map(|entry| entry.distance).collect();
+                let (_, limit_distance, _) =
+                    distances.select_nth_unstable_by(limit - 1, |a, b| a.partial_cmp(b).unwrap());
+                if *limit_distance < (ring * tile_size) as f64 {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the match of each feature in the record at `key` that's nearest the proximity point
+    /// of `match_opts`, if it's nearer than the one in `nearest` already
+    fn add_nearest<T: AsRef<[u8]>>(
+        &self,
+        key: &[u8],
+        value: T,
+        match_opts: &MatchOpts,
+        extent_scoring: &Option<Arc<ExtentScoring>>,
+        nearest: &mut HashMap<u32, MatchEntry>,
+    ) -> Result<(), Error> {
+        // distances are measured by `decode_matching_value`, so they take sub-tile offsets and
+        // merged cover extents into account
+        let entries = decode_matching_value(
+            self.read_record(None, value)?,
+            match_opts,
+            true,
+            self.coalesce_radius,
+            self.merged_covers(key, extent_scoring),
+            self.relev_weights,
+            self.curve,
+        );
+        for entry in entries {
+            if self.tombstones.contains(&entry.grid_entry.id) {
+                continue;
+            }
+            match nearest.entry(entry.grid_entry.id) {
+                Entry::Occupied(mut best) => {
+                    if reverse_order(&entry, best.get()) == Ordering::Less {
+                        best.insert(entry);
+                    }
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The extent (min x, min y, max x, max y) of the tiles a feature's cover at (x, y) in the
+    /// record for `grid_key` stands in for, if it's a merged cover from a store built with
+    /// `set_merge_adjacent_covers`
+    pub fn extent(
+        &self,
+        grid_key: &GridKey,
+        id: u32,
+        x: u16,
+        y: u16,
+    ) -> Result<Option<[u16; 4]>, Error> {
+        let zcoord = interleave_morton(x, y);
+        if let Some(extents) = &self.legacy_extents {
+            return Ok(extents.get(id, zcoord).cloned());
+        }
+        let mut db_key: Vec<u8> = Vec::new();
+        grid_key.write_to(TypeMarker::SinglePhrase, self.phrase_id_width, &mut db_key)?;
+        Ok(self.record_extents(&db_key).remove(&(id, zcoord)))
+    }
+
+    /// The extents of the merged covers in the record at `db_key`, keyed by feature id and
+    /// z-order coord
+    fn record_extents(&self, db_key: &[u8]) -> HashMap<(u32, u32), [u16; 4]> {
+        if !self.has_record_extents {
+            return HashMap::new();
+        }
+        let mut prefix: Vec<u8> = Vec::with_capacity(db_key.len() + 2);
+        write_extent_prefix(db_key, &mut prefix);
+        self.db
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, value)| decode_extent(&key[prefix.len()..], &value))
+            .collect()
+    }
+
+    /// How matching treats the merged covers of the record at `db_key`, if it has any
+    fn merged_covers(
+        &self,
+        db_key: &[u8],
+        scoring: &Option<Arc<ExtentScoring>>,
+    ) -> Option<MergedCovers> {
+        let extents = match &self.legacy_extents {
+            Some(extents) => extents.clone(),
+            None => {
+                let extents = self.record_extents(db_key);
+                if extents.is_empty() {
+                    return None;
+                }
+                Arc::new(RecordExtents::new(extents))
+            }
+        };
+        Some(MergedCovers { extents, scoring: scoring.clone() })
+    }
+
+    /// The manifest written alongside the store when it was built, if it has one
+    pub fn manifest(&self) -> Result<Option<StoreManifest>, Error> {
+        StoreManifest::read(&self.path)
+    }
+
+    /// Whether any of the entries matching this key were truncated when the store was built,
+    /// meaning results for it may be incomplete
+    pub fn is_truncated(&self, match_key: &MatchKey) -> Result<bool, Error> {
+        if !self.has_truncated_keys {
+            return Ok(false);
+        }
+        // truncation markers sort by the key they mark, so the first one at or after the start of
+        // the key's phrase range is inside the range if any of them are
+        let mut db_key: Vec<u8> = vec![TypeMarker::Truncated as u8];
+        match_key.write_start_to(TypeMarker::SinglePhrase, self.phrase_id_width, &mut db_key)?;
+        let mut db_iter = self.db.raw_iterator();
+        db_iter.seek(&db_key);
+        match db_iter.key() {
+            Some(key) if key[0] == TypeMarker::Truncated as u8 => {
+                match_key.matches_key(TypeMarker::SinglePhrase, self.phrase_id_width, &key[1..])
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether the record under this db key was truncated when the store was built
+    fn is_truncated_record(&self, db_key: &[u8]) -> Result<bool, Error> {
+        if !self.has_truncated_keys {
+            return Ok(false);
+        }
+        let mut marker_key: Vec<u8> = Vec::with_capacity(db_key.len() + 1);
+        marker_key.push(TypeMarker::Truncated as u8);
+        marker_key.extend_from_slice(db_key);
+        Ok(self.db.get_pinned(&marker_key)?.is_some())
+    }
+
+    /// Lists every phrase/langfield key with entries in the store, in key order. Only keys are
+    /// read; the records themselves are left alone.
+    pub fn keys<'i>(&'i self) -> impl Iterator<Item = Result<GridKey, Error>> + 'i {
+        let mut db_keys = self.db.raw_iterator();
+        db_keys.seek([TypeMarker::SinglePhrase as u8]);
+        std::iter::from_fn(move || {
+            let key = db_keys.key().filter(|key| key[0] == TypeMarker::SinglePhrase as u8)?;
+            let grid_key = decode_grid_key(&key[1..], self.phrase_id_width);
+            db_keys.next();
+            Some(grid_key)
         })
     }
 
+    /// Lists every key the given feature id is indexed under. This requires the store to have
+    /// been built with the feature index enabled; otherwise nothing will be found.
+    pub fn keys_for_feature<'i>(
+        &'i self,
+        id: u32,
+    ) -> impl Iterator<Item = Result<GridKey, Error>> + 'i {
+        let mut db_key: Vec<u8> = Vec::with_capacity(5);
+        db_key.push(TypeMarker::FeatureIndex as u8);
+        db_key.extend_from_slice(&id.to_be_bytes());
+
+        let db_iter = self.db.iterator(IteratorMode::From(&db_key, Direction::Forward));
+        db_iter
+            .take_while(move |(key, _)| key.starts_with(&db_key))
+            .map(move |(key, _)| decode_grid_key(&key[5..], self.phrase_id_width))
+    }
+
+    /// Lists every key with entries in the coarse tile containing the tile (x, y) at the store's
+    /// zoom, as `reverse` reads them. This requires the store to have been built with the tile
+    /// index enabled; otherwise nothing will be found.
+    pub fn keys_for_tile<'i>(
+        &'i self,
+        x: u16,
+        y: u16,
+    ) -> impl Iterator<Item = Result<GridKey, Error>> + 'i {
+        let shift = std::cmp::min(2 * self.tile_index_zoom_levels.unwrap_or(0) as u32, 31);
+        let tile = interleave_morton(x, y) >> shift;
+        let mut db_key: Vec<u8> = Vec::with_capacity(5);
+        db_key.push(TypeMarker::TileIndex as u8);
+        db_key.extend_from_slice(&tile.to_be_bytes());
+
+        let db_iter = self.db.iterator(IteratorMode::From(&db_key, Direction::Forward));
+        db_iter
+            .take_while(move |(key, _)| key.starts_with(&db_key))
+            .map(move |(key, _)| decode_grid_key(&key[5..], self.phrase_id_width))
+    }
+
+    /// Whether the store was built with the feature index enabled
+    pub(crate) fn has_feature_index(&self) -> bool {
+        sections(&self.db).contains(&(TypeMarker::FeatureIndex as u8))
+    }
+
+    /// The phrases the builder copied to the hot section, in the order they were copied
+    pub(crate) fn hot_phrase_ids(&self) -> Vec<u64> {
+        let mut hot_phrases: Vec<(u32, u64)> =
+            self.hot_phrases.iter().map(|(phrase_id, rank)| (*rank, *phrase_id)).collect();
+        hot_phrases.sort();
+        hot_phrases.into_iter().map(|(_, phrase_id)| phrase_id).collect()
+    }
+
+    /// The keys that were truncated when the store was built
+    pub(crate) fn truncated_grid_keys(&self) -> Result<Vec<GridKey>, Error> {
+        self.db
+            .iterator(IteratorMode::From(&[TypeMarker::Truncated as u8], Direction::Forward))
+            .take_while(|(key, _)| key[0] == TypeMarker::Truncated as u8)
+            .map(|(key, _)| decode_grid_key(&key[2..], self.phrase_id_width))
+            .collect()
+    }
+
+    /// Sub-tile offsets of entries (see `GridStoreBuilder::set_offset`), keyed by feature id and
+    /// z-order coord. They're kept in the records, so this reads every record in the store.
+    pub(crate) fn offsets(&self) -> Result<BTreeMap<(u32, u32), u8>, Error> {
+        let mut offsets = BTreeMap::new();
+        let db_iter = self.db.iterator(IteratorMode::Start);
+        for (_, value) in db_iter.take_while(|(key, _)| key[0] == 0) {
+            let record = self.read_record(None, value)?;
+            let buffer: &[u8] = record.as_ref();
+            if gridstore_format::SingleEntry::read(buffer).is_some() {
+                continue;
+            }
+            let reader = gridstore_format::Reader::new(buffer);
+            let phrase_record = gridstore_format::read_phrase_record_from(&reader);
+            for rs_obj in reader.read_var_vec(phrase_record.relev_scores).iter() {
+                for coords_obj in reader.read_uniform_vec(rs_obj.coords).iter() {
+                    let offset = match coords_obj.offset {
+                        Some(offset) => offset,
+                        None => continue,
+                    };
+                    let zcoord = self.curve.to_zcoord(coords_obj.coord);
+                    for id_comp in reader.read_fixed_vec(coords_obj.ids).iter() {
+                        offsets.insert((id_comp >> 8, zcoord), offset);
+                    }
+                }
+            }
+        }
+        Ok(offsets)
+    }
+
+    /// Extents of the merged covers among `entries`, the entries of the record for `grid_key`,
+    /// keyed by feature id and the z-order coord of the cover
+    pub(crate) fn cover_extents(
+        &self,
+        grid_key: &GridKey,
+        entries: &[GridEntry],
+    ) -> Result<CoverExtents, Error> {
+        if let Some(extents) = &self.legacy_extents {
+            return Ok(entries
+                .iter()
+                .filter_map(|entry| {
+                    let zcoord = interleave_morton(entry.x, entry.y);
+                    extents.get(entry.id, zcoord).map(|extent| ((entry.id, zcoord), *extent))
+                })
+                .collect());
+        }
+        let mut db_key: Vec<u8> = Vec::new();
+        grid_key.write_to(TypeMarker::SinglePhrase, self.phrase_id_width, &mut db_key)?;
+        Ok(self.record_extents(&db_key).into_iter().collect())
+    }
+
     pub fn iter<'i>(
         &'i self,
     ) -> impl Iterator<Item = Result<(GridKey, Vec<GridEntry>), Error>> + 'i {
         let db_iter = self.db.iterator(IteratorMode::Start);
-        db_iter.take_while(|(key, _)| key[0] == 0).map(|(key, value)| {
-            let phrase_id = (&key[1..]).read_u32::<BigEndian>()?;
+        db_iter.take_while(|(key, _)| key[0] == 0).map(move |(key, value)| {
+            let grid_key = decode_grid_key(&key[1..], self.phrase_id_width)?;
+            // a full scan would push the hot records out of the shared cache
+            let entries: Vec<_> =
+                decode_value(self.read_record(None, value)?, self.curve).collect();
 
-            let key_lang_partial = &key[5..];
-            let lang_set: u128 = if key_lang_partial.len() == 0 {
-                // 0-length language array is the shorthand for "matches everything"
-                std::u128::MAX
+            Ok((grid_key, entries))
+        })
+    }
+
+    /// Reports how large each key's record is encoded and as stored, against a fixed-width
+    /// baseline, to find the shapes of data that compress poorly. Like `iter`, this reads every
+    /// record in the store.
+    pub fn compression_stats<'i>(
+        &'i self,
+    ) -> impl Iterator<Item = Result<KeyCompressionStats, Error>> + 'i {
+        let db_iter = self.db.iterator(IteratorMode::Start);
+        db_iter.take_while(|(key, _)| key[0] == 0).map(move |(key, value)| {
+            let grid_key = decode_grid_key(&key[1..], self.phrase_id_width)?;
+            let stored_size = value.len();
+            let record = self.read_record(None, value)?;
+            let encoded_size = record.as_ref().len();
+            let encoding = if gridstore_format::SingleEntry::read(record.as_ref()).is_some() {
+                RecordEncoding::SingleEntry
             } else {
-                let mut key_lang_full = [0u8; 16];
-                key_lang_full[(16 - key_lang_partial.len())..].copy_from_slice(key_lang_partial);
+                RecordEncoding::Grouped
+            };
+            let entries = decode_value(record, self.curve).count();
+            Ok(KeyCompressionStats {
+                key: grid_key,
+                entries,
+                encoding,
+                unpacked_size: entries * KeyCompressionStats::UNPACKED_ENTRY_SIZE,
+                encoded_size,
+                stored_size,
+            })
+        })
+    }
 
-                (&key_lang_full[..]).read_u128::<BigEndian>()?
+    /// Reads up to `max_keys` keys from the start of the store, checks that their entries decode
+    /// to sensible values, and runs a test query for the first one, for serving layers to call at
+    /// startup. Problems are reported rather than returned as errors.
+    pub fn health_check(&self, max_keys: usize) -> HealthReport {
+        let mut report = HealthReport::default();
+        let mut test_key: Option<(GridKey, usize)> = None;
+        let tiles = 1u32 << self.zoom;
+        for item in self.iter().take(max_keys) {
+            let (key, entries) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    report.errors.push(format!("key {}: {}", report.keys_read, err));
+                    continue;
+                }
             };
+            report.keys_read += 1;
+            for entry in entries.iter() {
+                if entry.x as u32 >= tiles || entry.y as u32 >= tiles {
+                    report.errors.push(format!(
+                        "phrase {}: entry for feature {} is outside zoom {} at ({}, {})",
+                        key.phrase_id, entry.id, self.zoom, entry.x, entry.y
+                    ));
+                }
+                if !(entry.relev > 0. && entry.relev <= 1.) {
+                    report.errors.push(format!(
+                        "phrase {}: entry for feature {} has relevance {}",
+                        key.phrase_id, entry.id, entry.relev
+                    ));
+                }
+            }
+            report.entries_decoded += entries.len();
+            if test_key.is_none() && !entries.is_empty() {
+                test_key = Some((key, entries.len()));
+            }
+        }
 
-            let entries: Vec<_> = decode_value(value).collect();
+        match test_key {
+            Some((key, expected)) => {
+                let match_key = MatchKey {
+                    match_phrase: MatchPhrase::Exact(key.phrase_id),
+                    lang_set: key.lang_set,
+                };
+                let match_opts = MatchOpts { zoom: self.zoom, ..MatchOpts::default() };
+                let found = self
+                    .streaming_get_matching(&match_key, &match_opts, std::usize::MAX)
+                    .map(|results| results.count());
+                match found {
+                    Ok(found) if found >= expected => report.query_ok = true,
+                    Ok(_) => report
+                        .errors
+                        .push(format!("test query for phrase {} missed entries", key.phrase_id)),
+                    Err(err) => report.errors.push(format!("test query failed: {}", err)),
+                }
+            }
+            // an empty store has nothing to query, which isn't a problem in itself
+            None => report.query_ok = true,
+        }
 
-            Ok((GridKey { phrase_id, lang_set }, entries))
-        })
+        report.healthy = report.errors.is_empty() && report.query_ok;
+        report
     }
+
+    /// Checks every record in the store against the checksum the builder wrote for it, listing
+    /// the keys of records that were lost, truncated or otherwise corrupted, so they can be
+    /// reported rather than turning up as failures mid-query.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        let corrupt = |report: &mut VerifyReport, db_key: &[u8]| -> Result<(), Error> {
+            let key = decode_grid_key(&db_key[1..], self.phrase_id_width)?;
+            if db_key[0] == TypeMarker::PrefixBin as u8 {
+                report.corrupt_bins.push(key);
+            } else {
+                report.corrupt_keys.push(key);
+            }
+            Ok(())
+        };
+        let checksum_matches = |checksum_key: &[u8], value: &[u8]| match self.db.get(checksum_key) {
+            Ok(Some(entry)) => {
+                let checksum: &[u8] = entry.as_ref();
+                checksum.try_into().ok().map(u64::from_le_bytes) == Some(record_checksum(value))
+            }
+            _ => false,
+        };
+        if !sections(&self.db).contains(&(TypeMarker::Checksum as u8)) {
+            return Ok(report);
+        }
+
+        let mut checksum_key: Vec<u8> = Vec::with_capacity(MAX_KEY_LENGTH + 1);
+        for record in self.records_from(&[TypeMarker::SinglePhrase as u8]) {
+            let (key, value) = match record {
+                Ok(record) => record,
+                // the key of an unreadable record is lost along with it
+                Err(_) => break,
+            };
+            if key[0] > TypeMarker::PrefixBin as u8 {
+                break;
+            }
+            report.records_checked += 1;
+            write_checksum_key(&key, &mut checksum_key);
+            if !checksum_matches(&checksum_key, value.as_ref()) {
+                corrupt(&mut report, &key)?;
+            }
+        }
+
+        // hot copies are read in place of their records, so they have to match their checksums
+        let mut record_key: Vec<u8> = Vec::with_capacity(MAX_KEY_LENGTH);
+        for record in self.records_from(&[TypeMarker::HotCopy as u8]) {
+            let (key, value) = match record {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+            if key[0] != TypeMarker::HotCopy as u8 || key.len() < 5 {
+                break;
+            }
+            report.records_checked += 1;
+            record_key.clear();
+            record_key.push(TypeMarker::SinglePhrase as u8);
+            record_key.extend_from_slice(&key[5..]);
+            write_checksum_key(&record_key, &mut checksum_key);
+            if !checksum_matches(&checksum_key, value.as_ref()) {
+                corrupt(&mut report, &record_key)?;
+            }
+        }
+
+        // records that are gone altogether still have their checksums
+        let checksums =
+            self.db.iterator(IteratorMode::From(&[TypeMarker::Checksum as u8], Direction::Forward));
+        for (key, _) in checksums.take_while(|(key, _)| key[0] == TypeMarker::Checksum as u8) {
+            if key.len() > 1 && !matches!(self.db.get(&key[1..]), Ok(Some(_))) {
+                corrupt(&mut report, &key[1..])?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Counts the grid entries in the store per tile at a coarser zoom, keyed by (x, y), for
+    /// sanity-checking the geographic coverage of a build. Entries are counted once for every
+    /// key they appear under. Zooms at or above the store's zoom count per store tile.
+    pub fn coverage_heatmap(&self, zoom: u16) -> Result<BTreeMap<(u16, u16), u64>, Error> {
+        let shift = self.zoom.saturating_sub(zoom);
+        let mut heatmap = BTreeMap::new();
+        for item in self.iter() {
+            let (_, entries) = item?;
+            for entry in entries {
+                *heatmap.entry((entry.x >> shift, entry.y >> shift)).or_insert(0) += 1;
+            }
+        }
+        Ok(heatmap)
+    }
+}
+
+#[test]
+fn merge_by_score_ceiling_test() {
+    use std::cell::RefCell;
+
+    let started = RefCell::new(Vec::new());
+    let groups = vec![(9., vec![9., 5., 1.]), (6., vec![6., 2.]), (4., vec![4., 3.])];
+    let mut merged = merge_by_score_ceiling(
+        groups,
+        |group: Vec<f64>| {
+            started.borrow_mut().push(group[0]);
+            group.into_iter()
+        },
+        |x| *x,
+    );
+
+    assert_eq!(merged.next(), Some(9.));
+    assert_eq!(*started.borrow(), vec![9.], "lower groups aren't decoded until they could win");
+    assert_eq!(merged.next(), Some(6.));
+    assert_eq!(merged.next(), Some(5.));
+    assert_eq!(*started.borrow(), vec![9., 6.]);
+    let rest: Vec<_> = merged.collect();
+    assert_eq!(rest, vec![4., 3., 2., 1.]);
+}
+
+#[test]
+fn sort_in_blocks_test() {
+    let sorted: Vec<_> =
+        sort_in_blocks(vec![1., 3., 2., 6., 5., 4.].into_iter(), 3, |x| *x).collect();
+    assert_eq!(sorted, vec![3., 2., 1., 6., 5., 4.], "each block is sorted independently");
+}
+
+/// The namespace a store's records are kept under in a shared cache: the store's full identity,
+/// where it is and what it was built from, rather than a hash of it, so no two stores can share a
+/// namespace. Stores rebuilt in place get a namespace of their own as long as they were built
+/// with a generation or content hash.
+#[cfg(feature = "shared-cache")]
+fn shared_cache_namespace(store: &GridStore) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::fs::canonicalize(&store.path).unwrap_or_else(|_| store.path.clone());
+    let mut namespace = Vec::new();
+    for field in &[store.generation, store.content_hash] {
+        match field {
+            Some(value) => {
+                namespace.push(1);
+                namespace.extend_from_slice(&value.to_le_bytes());
+            }
+            None => namespace.push(0),
+        }
+    }
+    namespace.extend_from_slice(&store.zoom.to_le_bytes());
+    namespace.extend_from_slice(path.as_os_str().as_bytes());
+    namespace
+}
+
+/// Lists the type markers of the sections present in a store, seeking from one section to the
+/// next rather than reading through them. Metadata keys all start with `~`, after every section.
+fn sections(db: &DB) -> Vec<u8> {
+    let mut sections = Vec::new();
+    let mut next = 0u8;
+    while let Some((key, _)) = db.iterator(IteratorMode::From(&[next], Direction::Forward)).next() {
+        if key.is_empty() {
+            break;
+        }
+        let marker = key[0];
+        if marker >= b'~' {
+            break;
+        }
+        sections.push(marker);
+        next = marker + 1;
+    }
+    sections
+}
+
+/// Reasons a store can't be opened at all
+#[derive(Debug, Fail)]
+pub enum StoreError {
+    #[fail(display = "incomplete store at {:?}: its build didn't finish", path)]
+    Incomplete { path: PathBuf },
+    #[fail(display = "store at {:?} has a malformed format version", path)]
+    BadFormatVersion { path: PathBuf },
+    #[fail(display = "store at {:?} has unsupported format version {}.{}", path, major, minor)]
+    UnsupportedFormat { path: PathBuf, major: u16, minor: u16 },
+    #[fail(display = "store at {:?} has {} corrupt records", path, corrupt)]
+    Corrupt { path: PathBuf, corrupt: usize },
 }
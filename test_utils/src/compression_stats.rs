@@ -0,0 +1,10 @@
+use ::test_utils::dump_compression_stats;
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        panic!("Expected 2 arguments: a gridstore and an output path")
+    }
+    dump_compression_stats(&args[1], &args[2]);
+}
@@ -0,0 +1,11 @@
+use ::test_utils::dump_heatmap_to_geojson;
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        panic!("Expected 3 arguments: a gridstore, a zoom, and an output path")
+    }
+    let zoom: u16 = args[2].parse().expect("Zoom must be a number");
+    dump_heatmap_to_geojson(&args[1], zoom, &args[3]);
+}
@@ -27,13 +27,65 @@ pub fn round(value: f64, digits: i32) -> f64 {
     (value * multiplier).round() / multiplier
 }
 
-/// Convert an array of language ids into the langfield to use for GridKey or MatchKey
-pub fn langarray_to_langfield(array: &[u32]) -> u128 {
-    let mut out = 0u128;
-    for lang in array {
-        out = out | (1 << *lang as usize);
+/// Assert that two lists of coalesce results are the same contexts in the same order, with their
+/// relevances, distances and scoredists within `eps` of each other rather than exactly equal.
+/// Entries are compared on their grids, idx, mask and language match; tmp ids and phrasematch ids
+/// are bookkeeping and aren't compared.
+pub fn assert_contexts_equal_within(
+    expected: &[CoalesceContext],
+    actual: &[CoalesceContext],
+    eps: f64,
+) {
+    let within = |a: f64, b: f64| (a - b).abs() <= eps || a == b;
+    assert_eq!(expected.len(), actual.len(), "Different numbers of contexts");
+    for (i, (expected, actual)) in expected.iter().zip(actual.iter()).enumerate() {
+        let context_matches = expected.mask == actual.mask
+            && within(expected.relev, actual.relev)
+            && expected.entries.len() == actual.entries.len()
+            && expected.entries.iter().zip(actual.entries.iter()).all(|(e, a)| {
+                e.grid_entry.id == a.grid_entry.id
+                    && e.grid_entry.x == a.grid_entry.x
+                    && e.grid_entry.y == a.grid_entry.y
+                    && e.grid_entry.score == a.grid_entry.score
+                    && e.grid_entry.source_phrase_hash == a.grid_entry.source_phrase_hash
+                    && within(e.grid_entry.relev, a.grid_entry.relev)
+                    && e.idx == a.idx
+                    && e.mask == a.mask
+                    && e.matches_language == a.matches_language
+                    && within(e.distance, a.distance)
+                    && within(e.scoredist, a.scoredist)
+            });
+        assert!(
+            context_matches,
+            "Context {} differs by more than {}:\nexpected: {:?}\nactual: {:?}",
+            i, eps, expected, actual
+        );
     }
-    out
+}
+
+/// Kendall rank correlation (tau) between two rankings of the same items: 1 if the items common
+/// to both are in the same order, -1 if they're reversed. Items in only one ranking are ignored,
+/// and rankings with fewer than two items in common count as the same order.
+pub fn rank_correlation<T: PartialEq>(expected: &[T], actual: &[T]) -> f64 {
+    // positions in `actual` of the items of `expected` that are in both, in expected order
+    let positions: Vec<usize> =
+        expected.iter().filter_map(|item| actual.iter().position(|other| other == item)).collect();
+    let n = positions.len();
+    if n < 2 {
+        return 1.;
+    }
+    let mut concordant = 0i64;
+    let mut discordant = 0i64;
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if positions[i] < positions[j] {
+                concordant += 1;
+            } else {
+                discordant += 1;
+            }
+        }
+    }
+    (concordant - discordant) as f64 / (n * (n - 1) / 2) as f64
 }
 
 /// Mapping of GridKey to all of the grid entries to insert into a store for that GridKey
@@ -46,8 +98,8 @@ pub struct StoreEntryBuildingBlock {
 #[derive(Serialize, Deserialize, Debug)]
 struct PrefixBoundary {
     prefix: String,
-    first: u32,
-    last: u32,
+    first: u64,
+    last: u64,
 }
 
 pub struct TestStore {
@@ -88,6 +140,179 @@ pub fn create_store(
     }
 }
 
+/// A store to build for a data-driven coalesce case
+#[derive(Deserialize, Debug)]
+pub struct CaseStore {
+    pub idx: u16,
+    pub zoom: u16,
+    pub type_id: u16,
+    pub coalesce_radius: f64,
+    #[serde(default)]
+    pub non_overlapping_indexes: HashSet<u32>,
+    pub entries: Vec<StoreEntryBuildingBlock>,
+}
+
+/// A subquery in the stack of a data-driven coalesce case, referring to its store by idx
+#[derive(Deserialize, Debug)]
+pub struct CaseSubquery {
+    pub idx: u16,
+    pub weight: f64,
+    pub mask: u32,
+    pub match_keys: Vec<MatchKeyWithId>,
+    #[serde(default)]
+    pub bbox: Option<[u16; 4]>,
+}
+
+/// An expected coalesce result: the grid ids of its entries in order and, optionally, its relev
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ExpectedContext {
+    pub ids: Vec<u32>,
+    #[serde(default)]
+    pub relev: Option<f64>,
+}
+
+/// A coalesce test case: the stores to build, the stack to coalesce against them, the match
+/// options, and the expected ordering of the results
+#[derive(Deserialize, Debug)]
+pub struct CoalesceCase {
+    pub description: String,
+    pub stores: Vec<CaseStore>,
+    pub stack: Vec<CaseSubquery>,
+    #[serde(default)]
+    pub opts: MatchOpts,
+    pub expected: Vec<ExpectedContext>,
+}
+
+impl CoalesceCase {
+    /// Builds the stores for this case, keyed by idx
+    pub fn build_stores(&self) -> HashMap<u16, TestStore> {
+        self.stores
+            .iter()
+            .map(|case_store| {
+                let entries = case_store
+                    .entries
+                    .iter()
+                    .map(|block| StoreEntryBuildingBlock {
+                        grid_key: block.grid_key.clone(),
+                        entries: block.entries.clone(),
+                    })
+                    .collect();
+                let store = create_store(
+                    entries,
+                    case_store.idx,
+                    case_store.zoom,
+                    case_store.type_id,
+                    case_store.non_overlapping_indexes.iter().map(|n| *n as usize).collect(),
+                    case_store.coalesce_radius,
+                );
+                (case_store.idx, store)
+            })
+            .collect()
+    }
+
+    /// Builds the stack for this case against stores returned by `build_stores`
+    pub fn stack<'a>(
+        &self,
+        stores: &'a HashMap<u16, TestStore>,
+    ) -> Vec<PhrasematchSubquery<&'a GridStore>> {
+        self.stack
+            .iter()
+            .map(|subquery| {
+                let test_store =
+                    stores.get(&subquery.idx).expect("Subquery refers to a missing store");
+                PhrasematchSubquery {
+                    store: &test_store.store,
+                    idx: subquery.idx,
+                    non_overlapping_indexes: test_store.non_overlapping_indexes.clone(),
+                    weight: subquery.weight,
+                    match_keys: subquery.match_keys.clone(),
+                    mask: subquery.mask,
+                    bbox: subquery.bbox,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Loads every `.json` coalesce case in a directory, sorted by file name
+pub fn load_coalesce_cases(dir: &Path) -> Vec<(PathBuf, CoalesceCase)> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Error reading cases dir")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let file = File::open(&path).expect("Error opening case file");
+            let case: CoalesceCase = serde_json::from_reader(file)
+                .unwrap_or_else(|e| panic!("Error deserializing {}: {}", path.display(), e));
+            (path, case)
+        })
+        .collect()
+}
+
+/// Migrate a carmen-cache store to a gridstore, using a JSON object of phrase text to phrase id
+pub fn migrate_carmen_cache(cache_path: &str, phrase_ids_path: &str, store_path: &str) {
+    let phrase_ids: HashMap<String, u64> =
+        serde_json::from_reader(io::BufReader::new(File::open(phrase_ids_path).unwrap()))
+            .expect("Error deserializing phrase ids");
+    let mut builder = GridStoreBuilder::new(store_path).unwrap();
+    let stats = builder
+        .insert_from_carmen_cache(cache_path, |phrase| phrase_ids.get(phrase).cloned())
+        .unwrap();
+    builder.finish().unwrap();
+    println!("{:?}", stats);
+}
+
+/// The [west, south, east, north] bounds in degrees of a web mercator tile
+pub fn tile_bounds(zoom: u16, x: u16, y: u16) -> [f64; 4] {
+    let n = (1u32 << zoom) as f64;
+    let lon = |x: f64| x / n * 360. - 180.;
+    let lat = |y: f64| (std::f64::consts::PI * (1. - 2. * y / n)).sinh().atan().to_degrees();
+    [lon(x as f64), lat(y as f64 + 1.), lon(x as f64 + 1.), lat(y as f64)]
+}
+
+/// Write a store's coverage heatmap at a coarse zoom as a GeoJSON FeatureCollection with one
+/// polygon per tile and its entry count as the `count` property
+pub fn dump_heatmap_to_geojson(store_path: &str, zoom: u16, geojson_path: &str) {
+    let reader = GridStore::new(store_path).unwrap();
+    let heatmap = reader.coverage_heatmap(zoom).unwrap();
+    let zoom = std::cmp::min(zoom, reader.zoom);
+    let features: Vec<serde_json::Value> = heatmap
+        .into_iter()
+        .map(|((x, y), count)| {
+            let [w, s, e, n] = tile_bounds(zoom, x, y);
+            serde_json::json!({
+                "type": "Feature",
+                "properties": { "x": x, "y": y, "count": count },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[w, s], [e, s], [e, n], [w, n], [w, s]]]
+                }
+            })
+        })
+        .collect();
+    let collection = serde_json::json!({ "type": "FeatureCollection", "features": features });
+    let output_file = File::create(geojson_path).unwrap();
+    serde_json::to_writer(BufWriter::new(output_file), &collection).unwrap();
+}
+
+/// Write one line of JSON per key of a store with how large its record is encoded and as stored,
+/// worst compression ratio first
+pub fn dump_compression_stats(store_path: &str, json_path: &str) {
+    let reader = GridStore::new(store_path).unwrap();
+    let mut stats: Vec<KeyCompressionStats> =
+        reader.compression_stats().map(|stats| stats.unwrap()).collect();
+    stats.sort_by(|a, b| b.ratio().partial_cmp(&a.ratio()).unwrap());
+    let mut writer = BufWriter::new(File::create(json_path).unwrap());
+    for stats in stats {
+        let line = serde_json::json!({ "ratio": stats.ratio(), "stats": stats });
+        writeln!(writer, "{}", line).unwrap();
+    }
+}
+
 // Gets the absolute path for a path relative to the carmen-core dir
 pub fn get_absolute_path(relative_path: &Path) -> Result<PathBuf, Error> {
     let dir = env::current_dir().expect("Error getting current dir");
@@ -123,7 +348,7 @@ fn load_db_from_json_reader<T: BufRead>(json_source: T, split_source: T, store_p
         }
     });
 
-    let boundaries: Vec<u32> =
+    let boundaries: Vec<u64> =
         serde_json::from_reader(split_source).expect("Error deserializing json from string");
     builder.load_bin_boundaries(boundaries).unwrap();
 
@@ -145,7 +370,7 @@ pub fn dump_db_to_json(store_path: &str, json_path: &str) {
         writer.write(b"\n").unwrap();
     }
 
-    let mut boundaries: Vec<u32> = reader.bin_boundaries.iter().cloned().collect();
+    let mut boundaries: Vec<u64> = reader.bin_boundaries.iter().cloned().collect();
     boundaries.sort();
     let splits_path = json_path.to_owned().replace(".gridstore.dat", "") + ".gridstore.splits";
     let splits_file = File::create(splits_path).unwrap();
@@ -229,6 +454,8 @@ struct SubqueryPlaceholder<T> {
     weight: f64,
     match_keys: Vec<MatchKeyWithId>,
     mask: u32,
+    #[serde(default)]
+    bbox: Option<[u16; 4]>,
 }
 
 pub fn prepare_phrasematches(
@@ -284,6 +511,7 @@ pub fn prepare_phrasematches(
                             mask: placeholder.mask,
                             idx: placeholder.idx,
                             non_overlapping_indexes: fbs,
+                            bbox: placeholder.bbox,
                         }
                     })
                     .collect();
@@ -350,6 +578,7 @@ pub fn prepare_stackable_phrasematches(
                             mask: placeholder.mask,
                             idx: placeholder.idx,
                             non_overlapping_indexes: fbs,
+                            bbox: placeholder.bbox,
                         }
                     })
                     .collect();
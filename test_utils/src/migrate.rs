@@ -0,0 +1,10 @@
+use ::test_utils::migrate_carmen_cache;
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        panic!("Expected 3 arguments: a carmen-cache path, a phrase ids path, and a gridstore path")
+    }
+    migrate_carmen_cache(&args[1], &args[2], &args[3]);
+}
@@ -48,6 +48,26 @@ tape('GridStoreBuilder append()', (t) => {
     t.end();
 });
 
+tape('GridStoreBuilder phrase ids', (t) => {
+    const tmpDir = tmp.dirSync();
+    const builder = new addon.GridStoreBuilder(tmpDir.name);
+    const entries = [{ id: 0, x: 0, y: 0, relev: 1, score: 1, source_phrase_hash: 0 }];
+    t.throws(() => builder.insert({ phrase_id: Math.pow(2, 53), lang_set: [0] }, entries), /safe integer/, 'rejects phrase ids that lose precision as numbers');
+    t.throws(() => builder.insert({ phrase_id: -1, lang_set: [0] }, entries), /safe integer/, 'rejects negative phrase ids');
+    t.throws(() => builder.insert({ phrase_id: 1.5, lang_set: [0] }, entries), /safe integer/, 'rejects fractional phrase ids');
+    builder.insert({ phrase_id: 0, lang_set: [0] }, entries);
+    builder.insert({ phrase_id: 1, lang_set: [0] }, entries);
+    t.throws(() => builder.renumber([1, 0]), /BigUint64Array/, 'rejects plain arrays of phrase ids');
+    builder.renumber(BigUint64Array.from([1n, 0n]));
+    builder.finish();
+
+    const reader = new addon.GridStore(tmpDir.name);
+    t.deepEquals(Array.from(reader.keys()), [{ phrase_id: 0, lang_set: [0] }, { phrase_id: 1, lang_set: [0] }], 'renumbered with a BigUint64Array');
+    t.throws(() => reader.get({ phrase_id: Math.pow(2, 53), lang_set: [0] }), /safe integer/, 'get() rejects unsafe phrase ids');
+    rimraf(tmpDir.name);
+    t.end();
+});
+
 tape('GridStoreBuilder compactAppend()', (t) => {
     const tmpDir = tmp.dirSync();
     const builder = new addon.GridStoreBuilder(tmpDir.name);
@@ -0,0 +1,33 @@
+//! Data-driven coalesce tests. Each `.json` file in `tests/cases` describes the stores to build
+//! (as lists of grid keys and entries), the stack to coalesce against them, the match options and
+//! the expected ordering of the results, so relevance fixes can land with a case file instead of
+//! a bespoke test function.
+use carmen_core::gridstore::*;
+use test_utils::*;
+
+use std::path::Path;
+
+#[test]
+fn coalesce_cases() {
+    let cases_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+    let cases = load_coalesce_cases(&cases_dir);
+    assert!(!cases.is_empty(), "No cases found in {}", cases_dir.display());
+
+    for (path, case) in cases {
+        println!("{}: {}", path.display(), case.description);
+        let stores = case.build_stores();
+        let stack = case.stack(&stores);
+        let result = stack_and_coalesce(&stack, &case.opts).unwrap();
+
+        let actual: Vec<ExpectedContext> = result
+            .iter()
+            .zip(case.expected.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(context, expected)| ExpectedContext {
+                ids: context.entries.iter().map(|entry| entry.grid_entry.id).collect(),
+                // only compare relevs for results that specify one
+                relev: expected.and_then(|e| e.relev).map(|_| round(context.relev, 4)),
+            })
+            .collect();
+        assert_eq!(actual, case.expected, "Unexpected results for {}", path.display());
+    }
+}
@@ -0,0 +1,69 @@
+//! Snapshot tests over the fixture stores and queries in `tests/cases`. Where
+//! `coalesce_cases_test` only checks the ids and a few relevances each case expects, these record
+//! every result in full, so any change to coalesce's ordering or scores shows up as a snapshot
+//! diff to review. After an intended change, review and accept the new snapshots with
+//! `cargo insta review`.
+use carmen_core::gridstore::*;
+use test_utils::*;
+
+use std::fmt::Write;
+use std::path::Path;
+
+/// One line per result, and one indented line per entry, with floats rounded so the snapshots
+/// don't churn on noise in the last digits
+fn describe(contexts: &[CoalesceContext]) -> String {
+    let mut out = String::new();
+    for (rank, context) in contexts.iter().enumerate() {
+        writeln!(
+            out,
+            "{}. relev {:.4} mask {:#b} confidence {:.4}",
+            rank, context.relev, context.mask, context.confidence
+        )
+        .unwrap();
+        for entry in context.entries.iter() {
+            let grid = &entry.grid_entry;
+            writeln!(
+                out,
+                "   id {} idx {} at {},{} relev {:.4} score {} distance {:.4} scoredist {:.4}{}",
+                grid.id,
+                entry.idx,
+                grid.x,
+                grid.y,
+                grid.relev,
+                grid.score,
+                entry.distance,
+                entry.scoredist,
+                if entry.matches_language { "" } else { " (other language)" }
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn coalesce_snapshots() {
+    let cases_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+    let cases = load_coalesce_cases(&cases_dir);
+    assert!(!cases.is_empty(), "No cases found in {}", cases_dir.display());
+
+    for (path, case) in cases {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_owned();
+        let stores = case.build_stores();
+        let stack = case.stack(&stores);
+
+        let result = stack_and_coalesce(&stack, &case.opts).unwrap();
+        insta::assert_snapshot!(format!("{}_stack_and_coalesce", name), describe(&result));
+
+        // coalesce takes a single stack as it is, so it only applies to cases whose subqueries
+        // can all stack together
+        let stacked = stack
+            .iter()
+            .enumerate()
+            .all(|(i, subquery)| stack[..i].iter().all(|other| other.mask & subquery.mask == 0));
+        if stacked {
+            let result = coalesce(stack.clone(), &case.opts).unwrap();
+            insta::assert_snapshot!(format!("{}_coalesce", name), describe(&result));
+        }
+    }
+}
@@ -2,6 +2,7 @@ use carmen_core::gridstore::*;
 use test_utils::*;
 
 use fixedbitset::FixedBitSet;
+use std::collections::HashSet;
 
 const ALL_LANGUAGES: u128 = u128::max_value();
 
@@ -36,6 +37,7 @@ fn coalesce_single_test_proximity_quadrants() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
 
@@ -48,7 +50,7 @@ fn coalesce_single_test_proximity_quadrants() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     let result_ids: Vec<u32> =
         result.iter().map(|context| context.entries[0].grid_entry.id).collect();
     let result_distances: Vec<f64> =
@@ -65,7 +67,7 @@ fn coalesce_single_test_proximity_quadrants() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     let result_ids: Vec<u32> =
         result.iter().map(|context| context.entries[0].grid_entry.id).collect();
     let result_distances: Vec<f64> =
@@ -82,7 +84,7 @@ fn coalesce_single_test_proximity_quadrants() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     let result_ids: Vec<u32> =
         result.iter().map(|context| context.entries[0].grid_entry.id).collect();
     let result_distances: Vec<f64> =
@@ -99,7 +101,7 @@ fn coalesce_single_test_proximity_quadrants() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     let result_ids: Vec<u32> =
         result.iter().map(|context| context.entries[0].grid_entry.id).collect();
     let result_distances: Vec<f64> =
@@ -139,13 +141,14 @@ fn coalesce_single_test_proximity_basic() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
     let match_opts = MatchOpts { zoom: 14, proximity: Some([2, 2]), ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     let result_ids: Vec<u32> =
         result.iter().map(|context| context.entries[0].grid_entry.id).collect();
     assert_eq!(
@@ -193,13 +196,14 @@ fn coalesce_single_test_language_penalty() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery.clone()];
     let match_opts = MatchOpts { zoom: 14, proximity: Some([2, 2]), ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result[0].relev, 1., "Contexts inside the proximity radius don't get a cross langauge penalty");
@@ -214,7 +218,7 @@ fn coalesce_single_test_language_penalty() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result[0].relev, 0.96, "With no proximity, cross language contexts get a penalty");
@@ -274,6 +278,7 @@ fn coalesce_multi_test_language_penalty() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -289,6 +294,7 @@ fn coalesce_multi_test_language_penalty() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
     ];
 
@@ -296,7 +302,7 @@ fn coalesce_multi_test_language_penalty() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result[0].relev, 1., "Contexts inside the proximity radius don't get a cross langauge penalty");
@@ -313,7 +319,7 @@ fn coalesce_multi_test_language_penalty() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result[0].relev, 0.96, "Cross language contexts get a penalty");
@@ -350,6 +356,7 @@ fn coalesce_single_test() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
 
@@ -359,7 +366,7 @@ fn coalesce_single_test() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
 
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
@@ -415,7 +422,7 @@ fn coalesce_single_test() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result[0].entries[0].grid_entry.id, 3, "1st result is the closest, even if its a slightly lower score");
@@ -427,8 +434,11 @@ fn coalesce_single_test() {
         CoalesceContext {
             mask: 1 << 0,
             relev: 1.,
+            confidence: 0.,
             entries: vec![CoalesceEntry {
                 phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
                 matches_language: true,
                 idx: 1,
                 tmp_id: 33554435,
@@ -452,8 +462,11 @@ fn coalesce_single_test() {
         CoalesceContext {
             mask: 1 << 0,
             relev: 1.,
+            confidence: 0.,
             entries: vec![CoalesceEntry {
                 phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
                 matches_language: true,
                 idx: 1,
                 tmp_id: 33554433,
@@ -477,8 +490,11 @@ fn coalesce_single_test() {
         CoalesceContext {
             mask: 1 << 0,
             relev: 0.8,
+            confidence: 0.,
             entries: vec![CoalesceEntry {
                 phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
                 matches_language: true,
                 idx: 1,
                 tmp_id: 33554434,
@@ -505,7 +521,7 @@ fn coalesce_single_test() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].entries.len(), 1, "Only one result is within the bbox");
     assert_eq!(result[0].entries[0].grid_entry.id, 1, "Result is the one that's within the bbox");
     assert_eq!(
@@ -513,8 +529,11 @@ fn coalesce_single_test() {
         CoalesceContext {
             mask: 1 << 0,
             relev: 1.,
+            confidence: 0.,
             entries: vec![CoalesceEntry {
                 phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
                 matches_language: true,
                 idx: 1,
                 tmp_id: 33554433,
@@ -536,19 +555,27 @@ fn coalesce_single_test() {
 
     // Test with bbox and proximity
     println!("Coalesce single - with bbox and proximity");
-    let match_opts = MatchOpts { zoom: 6, bbox: Some([1, 1, 1, 1]), proximity: Some([1, 1]) };
+    let match_opts = MatchOpts {
+        zoom: 6,
+        bbox: Some([1, 1, 1, 1]),
+        proximity: Some([1, 1]),
+        ..MatchOpts::default()
+    };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].entries.len(), 1, "Only one result is within the bbox");
     assert_eq!(
         result[0],
         CoalesceContext {
             mask: 1 << 0,
             relev: 1.,
+            confidence: 0.,
             entries: vec![CoalesceEntry {
                 phrasematch_id: 0,
+                lonlat: None,
+                zoom: 6,
                 matches_language: true,
                 idx: 1,
                 tmp_id: 33554433,
@@ -577,7 +604,7 @@ fn coalesce_single_languages_test() {
     let lang_sets: [Vec<u32>; 4] = [vec![0], vec![1], vec![0, 1], vec![2]];
     // Load each grid_entry with a grid key for each language
     for (i, langs) in lang_sets.iter().enumerate() {
-        let lang_set = langarray_to_langfield(&langs[..]);
+        let lang_set = langarray_to_langfield(&langs[..]).unwrap();
         let key = GridKey { phrase_id: 1, lang_set };
         let grid_entry =
             GridEntry { id: i as u32, x: 1, y: 1, relev: 1., score: 0, source_phrase_hash: 0 };
@@ -604,13 +631,14 @@ fn coalesce_single_languages_test() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
 
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
@@ -644,18 +672,19 @@ fn coalesce_single_languages_test() {
             id: 0,
             key: MatchKey {
                 match_phrase: MatchPhrase::Range { start: 1, end: 3 },
-                lang_set: langarray_to_langfield(&[0]),
+                lang_set: langarray_to_langfield(&[0]).unwrap(),
             },
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
 
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
@@ -689,18 +718,19 @@ fn coalesce_single_languages_test() {
             id: 0,
             key: MatchKey {
                 match_phrase: MatchPhrase::Range { start: 1, end: 3 },
-                lang_set: langarray_to_langfield(&[3]),
+                lang_set: langarray_to_langfield(&[3]).unwrap(),
             },
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
 
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
@@ -756,6 +786,7 @@ fn coalesce_single_nearby_only() {
             ..MatchKeyWithId::default()
         }],
         mask: 1 << 0,
+        bbox: None,
     };
     let stack = vec![subquery];
     let match_opts = MatchOpts { zoom: 14, proximity: Some([100, 100]), ..MatchOpts::default() };
@@ -820,6 +851,7 @@ fn coalesce_multi_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -835,6 +867,7 @@ fn coalesce_multi_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
 
@@ -844,7 +877,7 @@ fn coalesce_multi_test() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].relev, 1., "1st result has relevance 1");
     assert_eq!(result[0].mask, 3, "1st result context has correct mask");
     assert_eq!(result[0].entries.len(), 2, "1st result has 2 coalesce entries");
@@ -852,6 +885,8 @@ fn coalesce_multi_test() {
         result[0].entries[0],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 2,
             matches_language: true,
             idx: 1,
             tmp_id: 33554434,
@@ -873,6 +908,8 @@ fn coalesce_multi_test() {
         result[0].entries[1],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 1,
             matches_language: true,
             idx: 0,
             tmp_id: 1,
@@ -897,6 +934,8 @@ fn coalesce_multi_test() {
         result[1].entries[0],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 2,
             matches_language: true,
             idx: 1,
             tmp_id: 33554435,
@@ -918,6 +957,8 @@ fn coalesce_multi_test() {
         result[0].entries[1],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 1,
             matches_language: true,
             idx: 0,
             tmp_id: 1,
@@ -942,7 +983,7 @@ fn coalesce_multi_test() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].relev, 1., "1st result context has relevance 1");
     assert_eq!(result[0].mask, 3, "1st result context has correct mask");
     assert_eq!(result[0].entries.len(), 2, "1st result has 2 coalesce entries");
@@ -950,6 +991,8 @@ fn coalesce_multi_test() {
         result[0].entries[0],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 2,
             matches_language: true,
             idx: 1,
             tmp_id: 33554435,
@@ -971,12 +1014,14 @@ fn coalesce_multi_test() {
         result[0].entries[1],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 1,
             matches_language: true,
             idx: 0,
             tmp_id: 1,
             mask: 1 << 1,
             distance: 0.,
-            scoredist: 1.5839497841387566,
+            scoredist: 1.0148725130599983,
             grid_entry: GridEntry {
                 id: 1,
                 x: 1,
@@ -993,6 +1038,8 @@ fn coalesce_multi_test() {
         result[1].entries[0],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 2,
             matches_language: true,
             idx: 1,
             tmp_id: 33554434,
@@ -1014,12 +1061,14 @@ fn coalesce_multi_test() {
         result[1].entries[1],
         CoalesceEntry {
             phrasematch_id: 0,
+            lonlat: None,
+            zoom: 1,
             matches_language: true,
             idx: 0,
             tmp_id: 1,
             mask: 1 << 1,
             distance: 0.,
-            scoredist: 1.5839497841387566,
+            scoredist: 1.0148725130599983,
             grid_entry: GridEntry {
                 id: 1,
                 x: 1,
@@ -1060,7 +1109,7 @@ fn coalesce_multi_languages_test() {
         vec![
             // Insert grid with lang_set 1
             StoreEntryBuildingBlock {
-                grid_key: GridKey { phrase_id: 2, lang_set: langarray_to_langfield(&[1]) },
+                grid_key: GridKey { phrase_id: 2, lang_set: langarray_to_langfield(&[1]).unwrap() },
                 entries: vec![GridEntry {
                     id: 2,
                     x: 1,
@@ -1072,7 +1121,7 @@ fn coalesce_multi_languages_test() {
             },
             // Insert grid with lang_set 0
             StoreEntryBuildingBlock {
-                grid_key: GridKey { phrase_id: 2, lang_set: langarray_to_langfield(&[0]) },
+                grid_key: GridKey { phrase_id: 2, lang_set: langarray_to_langfield(&[0]).unwrap() },
                 entries: vec![GridEntry {
                     id: 3,
                     x: 1,
@@ -1107,6 +1156,7 @@ fn coalesce_multi_languages_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -1122,13 +1172,14 @@ fn coalesce_multi_languages_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result.len(), 2, "Two results are returned");
@@ -1167,6 +1218,7 @@ fn coalesce_multi_languages_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -1177,18 +1229,19 @@ fn coalesce_multi_languages_test() {
                 id: 1,
                 key: MatchKey {
                     match_phrase: MatchPhrase::Range { start: 1, end: 3 },
-                    lang_set: langarray_to_langfield(&[0]),
+                    lang_set: langarray_to_langfield(&[0]).unwrap(),
                 },
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result.len(), 2, "Two results are returned");
@@ -1227,6 +1280,7 @@ fn coalesce_multi_languages_test() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -1237,18 +1291,19 @@ fn coalesce_multi_languages_test() {
                 id: 1,
                 key: MatchKey {
                     match_phrase: MatchPhrase::Range { start: 1, end: 3 },
-                    lang_set: langarray_to_langfield(&[3]),
+                    lang_set: langarray_to_langfield(&[3]).unwrap(),
                 },
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     #[cfg_attr(rustfmt, rustfmt::skip)]
     {
         assert_eq!(result.len(), 2, "Two results are returned");
@@ -1324,6 +1379,7 @@ fn coalesce_multi_scoredist() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -1339,6 +1395,7 @@ fn coalesce_multi_scoredist() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     // Closer proximity to one grid
@@ -1347,7 +1404,7 @@ fn coalesce_multi_scoredist() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].entries[0].grid_entry.id, 3, "Closer feature is 1st");
     assert_eq!(result[1].entries[0].grid_entry.id, 2, "Farther feature is 2nd");
     assert_eq!(
@@ -1362,7 +1419,7 @@ fn coalesce_multi_scoredist() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    assert_eq!(result, tree_result);
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result[0].entries[0].grid_entry.id, 3, "Farther feature with higher score is 1st");
     assert_eq!(result[1].entries[0].grid_entry.id, 2, "Closer feature with lower score is 2nd");
     assert_eq!(
@@ -1372,6 +1429,170 @@ fn coalesce_multi_scoredist() {
     );
 }
 
+#[test]
+fn coalesce_multi_scoredist_mixed_zooms() {
+    // A parent feature covering the proximity point
+    let store0 = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 1, lang_set: 0 },
+            entries: vec![GridEntry {
+                id: 1,
+                x: 8,
+                y: 8,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            }],
+        }],
+        0,
+        4,
+        0,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+    // One tile from the proximity point at z6 is about 320 miles away
+    let store1 = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 2, lang_set: 0 },
+            entries: vec![GridEntry {
+                id: 2,
+                x: 33,
+                y: 32,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            }],
+        }],
+        1,
+        6,
+        1,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+    // 30 tiles from the proximity point at z14 is about 40 miles away
+    let store2 = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 3, lang_set: 0 },
+            entries: vec![GridEntry {
+                id: 3,
+                x: 8222,
+                y: 8192,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            }],
+        }],
+        2,
+        14,
+        2,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+
+    let stack: Vec<_> = vec![(&store0, 1, 1 << 2), (&store1, 2, 1 << 1), (&store2, 3, 1 << 0)]
+        .into_iter()
+        .enumerate()
+        .map(|(id, (store, phrase_id, mask))| PhrasematchSubquery {
+            store: &store.store,
+            idx: store.idx,
+            non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+            weight: 0.5,
+            match_keys: vec![MatchKeyWithId {
+                id: id as u32,
+                key: MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 0 },
+                ..MatchKeyWithId::default()
+            }],
+            mask,
+            bbox: None,
+        })
+        .collect();
+    let match_opts = MatchOpts { zoom: 14, proximity: Some([8192, 8192]), ..MatchOpts::default() };
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    let tree = stackable(&stack);
+    let tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
+    assert_contexts_equal_within(&result, &tree_result, 1e-9);
+    assert_eq!(result[0].relev, result[1].relev, "contexts tie on relevance");
+    assert_eq!(
+        result[0].entries[0].grid_entry.id, 3,
+        "the physically closer feature is 1st even though it's more tiles away in its own zoom"
+    );
+    assert_eq!(result[1].entries[0].grid_entry.id, 2);
+}
+
+#[test]
+fn tree_coalesce_scoredist_mixed_zoom_singles() {
+    // A z6 grid on the proximity tile, which could still be well over a hundred miles away
+    let store0 = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 1, lang_set: 0 },
+            entries: vec![GridEntry {
+                id: 1,
+                x: 32,
+                y: 32,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            }],
+        }],
+        0,
+        6,
+        0,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+    // 30 tiles from the proximity point at z14 is about 40 miles away
+    let store1 = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 2, lang_set: 0 },
+            entries: vec![GridEntry {
+                id: 2,
+                x: 8222,
+                y: 8192,
+                relev: 1.,
+                score: 3,
+                source_phrase_hash: 0,
+            }],
+        }],
+        1,
+        14,
+        1,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+
+    // the same mask, so neither stacks on the other and each is coalesced on its own
+    let stack: Vec<_> = vec![(&store0, 1), (&store1, 2)]
+        .into_iter()
+        .enumerate()
+        .map(|(id, (store, phrase_id))| PhrasematchSubquery {
+            store: &store.store,
+            idx: store.idx,
+            non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+            weight: 1.,
+            match_keys: vec![MatchKeyWithId {
+                id: id as u32,
+                key: MatchKey { match_phrase: MatchPhrase::Exact(phrase_id), lang_set: 0 },
+                ..MatchKeyWithId::default()
+            }],
+            mask: 1 << 0,
+            bbox: None,
+        })
+        .collect();
+    let match_opts = MatchOpts { zoom: 14, proximity: Some([8192, 8192]), ..MatchOpts::default() };
+    let tree = stackable(&stack);
+    let result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].relev, result[1].relev, "contexts tie on relevance");
+    assert_eq!(result[0].entries[0].grid_entry.id, 2, "the closer z14 grid is 1st");
+    assert_eq!(result[1].entries[0].grid_entry.id, 1);
+    let opts = scoring::ScoredistOpts { coalesce_radius: 200., proximity_radius: None };
+    assert_eq!(
+        result[1].entries[0].scoredist,
+        scoring::zoom_normalized_scoredist(3, 0., 6, 14, &opts),
+        "the z6 grid is scored on its distance in tiles at the query's zoom"
+    );
+}
+
 // TODO: language tests
 #[test]
 fn coalesce_multi_test_bbox() {
@@ -1434,6 +1655,7 @@ fn coalesce_multi_test_bbox() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store2.store,
@@ -1449,6 +1671,7 @@ fn coalesce_multi_test_bbox() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     // Test bbox at zoom 1 that should contain 2 grids
@@ -1457,7 +1680,7 @@ fn coalesce_multi_test_bbox() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let _tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    // assert_eq!(result, tree_result);
+    // assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result.len(), 2, "Bbox [1,0,0,1,0] - 2 results are within the bbox");
     assert_eq!(
         (result[0].entries[0].grid_entry.x, result[0].entries[0].grid_entry.y),
@@ -1475,7 +1698,7 @@ fn coalesce_multi_test_bbox() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let _tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    // assert_eq!(result, tree_result);
+    // assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result.len(), 2, "Bbox [2,0,0,1,3] - 2 results are within the bbox");
     assert_eq!(
         (result[0].entries[0].grid_entry.x, result[0].entries[0].grid_entry.y),
@@ -1494,7 +1717,7 @@ fn coalesce_multi_test_bbox() {
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let _tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    // assert_eq!(result, tree_result);
+    // assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result.len(), 2, "Bbox [6,14,30,15,64] - 2 results are within the bbox");
     assert_eq!(
         (result[0].entries[0].grid_entry.x, result[0].entries[0].grid_entry.y),
@@ -1524,6 +1747,7 @@ fn coalesce_multi_test_bbox() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 1,
+            bbox: None,
         },
         PhrasematchSubquery {
             store: &store3.store,
@@ -1539,13 +1763,14 @@ fn coalesce_multi_test_bbox() {
                 ..MatchKeyWithId::default()
             }],
             mask: 1 << 0,
+            bbox: None,
         },
     ];
     let match_opts = MatchOpts { zoom: 1, bbox: Some([0, 0, 1, 0]), ..MatchOpts::default() };
     let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
     let tree = stackable(&stack);
     let _tree_result = truncate_coalesce_results(tree_coalesce(&tree, &match_opts).unwrap());
-    // assert_eq!(result, tree_result);
+    // assert_contexts_equal_within(&result, &tree_result, 1e-9);
     assert_eq!(result.len(), 2, "Bbox [1,0,0,1,0] - 2 results are within the bbox");
     assert_eq!(
         (result[0].entries[0].grid_entry.x, result[0].entries[0].grid_entry.y),
@@ -1559,6 +1784,395 @@ fn coalesce_multi_test_bbox() {
     );
 }
 
+#[test]
+fn coalesce_single_stable_tiebreak() {
+    let entries: Vec<_> = (1..7)
+        .map(|id| GridEntry { id, x: id as u16, y: 1, relev: 1., score: 3, source_phrase_hash: 0 })
+        .collect();
+    let store = create_store(
+        vec![StoreEntryBuildingBlock { grid_key: GridKey { phrase_id: 1, lang_set: 1 }, entries }],
+        1,
+        6,
+        0,
+        FixedBitSet::with_capacity(128),
+        40.,
+    );
+    let subquery = PhrasematchSubquery {
+        store: &store.store,
+        idx: store.idx,
+        non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+        weight: 1.,
+        match_keys: vec![MatchKeyWithId {
+            id: 0,
+            key: MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 },
+            ..MatchKeyWithId::default()
+        }],
+        mask: 1 << 0,
+        bbox: None,
+    };
+    let stack = vec![subquery];
+    let tree = stackable(&stack);
+    let ids = |contexts: Vec<CoalesceContext>| -> Vec<u32> {
+        contexts.iter().map(|context| context.entries[0].grid_entry.id).collect()
+    };
+
+    let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    assert_eq!(ids(result), vec![6, 5, 4, 3, 2, 1], "ties are broken by position by default");
+
+    let match_opts = MatchOpts { zoom: 6, stable_tiebreak: true, ..MatchOpts::default() };
+    let mut expected: Vec<u32> = (1..7).collect();
+    expected.sort_by_key(|id| std::cmp::Reverse(stable_id_hash(*id)));
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    let tree_result = tree_coalesce(&tree, &match_opts).unwrap();
+    assert_eq!(ids(result), expected, "ties are broken by feature id hash");
+    assert_eq!(ids(tree_result), expected, "tree coalesce breaks ties the same way");
+}
+
+#[test]
+fn tree_coalesce_stable_tiebreak_past_cap() {
+    // more tied candidates than tree coalesce keeps, spread over enough phrases that none of them
+    // is cut short on its own
+    let phrases: u32 = 25;
+    let per_phrase = MAX_CONTEXTS as u32;
+    let blocks: Vec<_> = (0..phrases)
+        .map(|phrase| StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: phrase + 1, lang_set: 1 },
+            entries: (0..per_phrase)
+                .map(|i| {
+                    let id = phrase * per_phrase + i + 1;
+                    GridEntry { id, x: id as u16, y: 1, relev: 1., score: 3, source_phrase_hash: 0 }
+                })
+                .collect(),
+        })
+        .collect();
+    let store = create_store(blocks, 1, 14, 0, FixedBitSet::with_capacity(128), 40.);
+    let subquery = |phrases: Vec<u32>| PhrasematchSubquery {
+        store: &store.store,
+        idx: store.idx,
+        non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+        weight: 1.,
+        match_keys: phrases
+            .into_iter()
+            .enumerate()
+            .map(|(id, phrase)| MatchKeyWithId {
+                id: id as u32,
+                key: MatchKey { match_phrase: MatchPhrase::Exact(phrase), lang_set: 1 },
+                ..MatchKeyWithId::default()
+            })
+            .collect(),
+        mask: 1 << 0,
+        bbox: None,
+    };
+    let ids = |contexts: Vec<CoalesceContext>| -> Vec<u32> {
+        contexts.iter().map(|context| context.entries[0].grid_entry.id).collect()
+    };
+    let match_opts = MatchOpts { zoom: 14, stable_tiebreak: true, ..MatchOpts::default() };
+    assert!((phrases * per_phrase) as usize > MAX_CONTEXTS * 20);
+
+    let mut expected: Vec<u32> = (1..=phrases * per_phrase).collect();
+    expected.sort_by_key(|id| std::cmp::Reverse(stable_id_hash(*id)));
+    expected.truncate(MAX_CONTEXTS * 20);
+
+    let forward = vec![subquery((1..=phrases).collect())];
+    let result = tree_coalesce(&stackable(&forward), &match_opts).unwrap();
+    assert_eq!(ids(result), expected, "the kept ties are the ones the tie-break ranks highest");
+
+    let backward = vec![subquery((1..=phrases).rev().collect())];
+    let result = tree_coalesce(&stackable(&backward), &match_opts).unwrap();
+    assert_eq!(ids(result), expected, "which ties are kept doesn't depend on arrival order");
+}
+
+#[test]
+fn coalesce_single_tie_jitter() {
+    let mut entries: Vec<_> = (1..7)
+        .map(|id| GridEntry { id, x: id as u16, y: 1, relev: 1., score: 3, source_phrase_hash: 0 })
+        .collect();
+    // a better-scored feature that doesn't tie with the rest
+    entries.push(GridEntry { id: 10, x: 10, y: 1, relev: 1., score: 7, source_phrase_hash: 0 });
+    let store = create_store(
+        vec![StoreEntryBuildingBlock { grid_key: GridKey { phrase_id: 1, lang_set: 1 }, entries }],
+        1,
+        6,
+        0,
+        FixedBitSet::with_capacity(128),
+        40.,
+    );
+    let subquery = PhrasematchSubquery {
+        store: &store.store,
+        idx: store.idx,
+        non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+        weight: 1.,
+        match_keys: vec![MatchKeyWithId {
+            id: 0,
+            key: MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 },
+            ..MatchKeyWithId::default()
+        }],
+        mask: 1 << 0,
+        bbox: None,
+    };
+    let stack = vec![subquery];
+    let tree = stackable(&stack);
+    let ids = |seed: u64| -> Vec<u32> {
+        let match_opts = MatchOpts { zoom: 6, tie_jitter: Some(seed), ..MatchOpts::default() };
+        let result =
+            coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+        let tree_result = tree_coalesce(&tree, &match_opts).unwrap();
+        let ids: Vec<u32> = result.iter().map(|context| context.entries[0].grid_entry.id).collect();
+        let tree_ids: Vec<u32> =
+            tree_result.iter().map(|context| context.entries[0].grid_entry.id).collect();
+        assert_eq!(ids, tree_ids, "tree coalesce breaks ties the same way");
+        ids
+    };
+
+    let seed = tie_jitter_seed("request-1");
+    assert_eq!(seed, tie_jitter_seed("request-1"));
+    let mut expected: Vec<u32> = (1..7).collect();
+    expected.sort_by_key(|id| std::cmp::Reverse(seeded_id_hash(*id, seed)));
+    expected.insert(0, 10);
+    assert_eq!(ids(seed), expected, "ties are broken by a seeded hash of the feature id");
+    assert_eq!(ids(seed), expected, "the same seed always gives the same order");
+
+    let orders: HashSet<Vec<u32>> = (0..8).map(ids).collect();
+    assert!(orders.len() > 1, "different seeds order tied features differently");
+    for order in orders {
+        assert_eq!(order[0], 10, "features that don't tie are ranked as usual");
+        let mut tied = order[1..].to_vec();
+        tied.sort();
+        assert_eq!(tied, (1..7).collect::<Vec<u32>>());
+    }
+}
+
+#[test]
+fn coalesce_prefer_higher_zoom() {
+    let entries = vec![GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 3, source_phrase_hash: 0 }];
+    let stores = vec![
+        create_store(
+            vec![StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: 1 },
+                entries: entries.clone(),
+            }],
+            0,
+            6,
+            0,
+            FixedBitSet::with_capacity(128),
+            40.,
+        ),
+        create_store(
+            vec![StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: 1 },
+                entries,
+            }],
+            1,
+            14,
+            1,
+            FixedBitSet::with_capacity(128),
+            40.,
+        ),
+    ];
+    let subqueries: Vec<_> = stores
+        .iter()
+        .map(|store| PhrasematchSubquery {
+            store: &store.store,
+            idx: store.idx,
+            non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+            weight: 1.,
+            match_keys: vec![MatchKeyWithId {
+                id: store.idx as u32,
+                key: MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 },
+                ..MatchKeyWithId::default()
+            }],
+            mask: 1 << 0,
+            bbox: None,
+        })
+        .collect();
+    let tree = stackable(&subqueries);
+    let idxs = |match_opts: &MatchOpts| -> Vec<u16> {
+        let result = tree_coalesce(&tree, match_opts).unwrap();
+        result.iter().map(|context| context.entries[0].idx).collect()
+    };
+
+    let match_opts = MatchOpts { zoom: 14, ..MatchOpts::default() };
+    assert_eq!(idxs(&match_opts), vec![0, 1], "ties are broken by idx by default");
+
+    let match_opts = MatchOpts { zoom: 14, prefer_higher_zoom: true, ..MatchOpts::default() };
+    assert_eq!(idxs(&match_opts), vec![1, 0], "the result from the higher-zoom index wins the tie");
+
+    let match_opts = MatchOpts {
+        zoom: 14,
+        prefer_higher_zoom: true,
+        stable_tiebreak: true,
+        ..MatchOpts::default()
+    };
+    assert_eq!(idxs(&match_opts), vec![1, 0], "zoom is preferred ahead of the id hash");
+}
+
+#[test]
+fn reverse_coalesce_test() {
+    let entry = |id, x, y, score| GridEntry { id, x, y, relev: 1., score, source_phrase_hash: 0 };
+    let stores = vec![
+        create_store(
+            vec![StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: 1 },
+                entries: vec![entry(1, 2, 2, 5), entry(2, 0, 2, 7)],
+            }],
+            0,
+            2,
+            0,
+            FixedBitSet::with_capacity(128),
+            40.,
+        ),
+        create_store(
+            vec![StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: 1 },
+                entries: vec![entry(1, 5, 5, 3), entry(2, 6, 5, 3)],
+            }],
+            1,
+            3,
+            1,
+            FixedBitSet::with_capacity(128),
+            40.,
+        ),
+    ];
+    let stores: Vec<&GridStore> = stores.iter().map(|store| &store.store).collect();
+
+    let result = reverse_coalesce(&stores, [5, 5], 3, 10).unwrap();
+    let found: Vec<(u16, u32, f64)> =
+        result.iter().map(|entry| (entry.idx, entry.grid_entry.id, entry.distance)).collect();
+    assert_eq!(
+        found,
+        vec![(0, 1, 0.), (1, 1, 0.), (1, 2, 1.), (0, 2, 4.)],
+        "covering features from every store come first, then the rest by distance at the query zoom"
+    );
+    assert_eq!(result[0].zoom, 2);
+    assert_eq!(result[1].tmp_id, (1 << 25) + 1);
+
+    let result = reverse_coalesce(&stores, [5, 5], 3, 1).unwrap();
+    assert_eq!(result.len(), 1, "results are cut off at the limit across stores");
+}
+
+#[test]
+fn coalesce_single_subquery_bbox() {
+    let store = create_store(
+        vec![StoreEntryBuildingBlock {
+            grid_key: GridKey { phrase_id: 1, lang_set: 1 },
+            entries: vec![
+                GridEntry { id: 1, x: 1, y: 1, relev: 1., score: 3, source_phrase_hash: 0 },
+                GridEntry { id: 2, x: 10, y: 10, relev: 1., score: 3, source_phrase_hash: 0 },
+                GridEntry { id: 3, x: 20, y: 20, relev: 1., score: 3, source_phrase_hash: 0 },
+            ],
+        }],
+        1,
+        6,
+        0,
+        FixedBitSet::with_capacity(128),
+        40.,
+    );
+    let subquery = PhrasematchSubquery {
+        store: &store.store,
+        idx: store.idx,
+        non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+        weight: 1.,
+        match_keys: vec![MatchKeyWithId {
+            id: 0,
+            key: MatchKey { match_phrase: MatchPhrase::Exact(1), lang_set: 1 },
+            ..MatchKeyWithId::default()
+        }],
+        mask: 1 << 0,
+        bbox: Some([5, 5, 25, 25]),
+    };
+    let stack = vec![subquery];
+    let tree = stackable(&stack);
+    let ids = |contexts: Vec<CoalesceContext>| -> Vec<u32> {
+        contexts.iter().map(|context| context.entries[0].grid_entry.id).collect()
+    };
+
+    let match_opts = MatchOpts { zoom: 6, ..MatchOpts::default() };
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    let tree_result = tree_coalesce(&tree, &match_opts).unwrap();
+    assert_eq!(ids(result), vec![3, 2], "the subquery's bbox is applied");
+    assert_eq!(ids(tree_result), vec![3, 2], "the subquery's bbox is applied in tree coalesce");
+
+    let match_opts = MatchOpts { zoom: 6, bbox: Some([0, 0, 15, 15]), ..MatchOpts::default() };
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    let tree_result = tree_coalesce(&tree, &match_opts).unwrap();
+    assert_eq!(ids(result), vec![2], "the subquery's bbox is intersected with the global one");
+    assert_eq!(ids(tree_result), vec![2]);
+
+    let match_opts = MatchOpts { zoom: 6, bbox: Some([0, 0, 3, 3]), ..MatchOpts::default() };
+    let result = coalesce(stack.iter().map(|s| s.clone().into()).collect(), &match_opts).unwrap();
+    let tree_result = tree_coalesce(&tree, &match_opts).unwrap();
+    assert_eq!(ids(result), Vec::<u32>::new(), "boxes that don't overlap match nothing");
+    assert_eq!(ids(tree_result), Vec::<u32>::new());
+}
+
+#[test]
+fn coalesce_single_language_boost() {
+    let store = create_store(
+        vec![
+            StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: langarray_to_langfield(&[1]).unwrap() },
+                entries: vec![GridEntry {
+                    id: 1,
+                    x: 4600,
+                    y: 6200,
+                    relev: 1.,
+                    score: 1,
+                    source_phrase_hash: 0,
+                }],
+            },
+            StoreEntryBuildingBlock {
+                grid_key: GridKey { phrase_id: 1, lang_set: langarray_to_langfield(&[2]).unwrap() },
+                entries: vec![GridEntry {
+                    id: 2,
+                    x: 4602,
+                    y: 6200,
+                    relev: 1.,
+                    score: 3,
+                    source_phrase_hash: 0,
+                }],
+            },
+        ],
+        1,
+        14,
+        1,
+        FixedBitSet::with_capacity(128),
+        200.,
+    );
+    let stack = vec![PhrasematchSubquery {
+        store: &store.store,
+        idx: store.idx,
+        non_overlapping_indexes: store.non_overlapping_indexes.clone(),
+        weight: 1.,
+        match_keys: vec![MatchKeyWithId {
+            id: 0,
+            key: MatchKey {
+                match_phrase: MatchPhrase::Exact(1),
+                lang_set: langarray_to_langfield(&[1]).unwrap(),
+            },
+            ..MatchKeyWithId::default()
+        }],
+        mask: 1 << 0,
+        bbox: None,
+    }];
+    let ids = |match_opts: &MatchOpts| -> Vec<u32> {
+        let result =
+            coalesce(stack.iter().map(|s| s.clone().into()).collect(), match_opts).unwrap();
+        let tree = stackable(&stack);
+        let tree_result = truncate_coalesce_results(tree_coalesce(&tree, match_opts).unwrap());
+        assert_contexts_equal_within(&result, &tree_result, 1e-9);
+        result.iter().map(|context| context.entries[0].grid_entry.id).collect()
+    };
+
+    // both grids are nearby, so the one in another language isn't penalized
+    let match_opts = MatchOpts { zoom: 14, proximity: Some([4601, 6200]), ..MatchOpts::default() };
+    assert_eq!(ids(&match_opts), vec![2, 1], "without a boost, the higher score wins");
+    for boost in vec![LanguageBoost::Additive(20.), LanguageBoost::Multiplicative(1.2)] {
+        let match_opts = MatchOpts { language_boost: Some(boost), ..match_opts.clone() };
+        assert_eq!(ids(&match_opts), vec![1, 2], "the grid in the query language is boosted");
+    }
+}
+
 #[cfg(test)]
 fn truncate_coalesce_results(results: Vec<CoalesceContext>) -> Vec<CoalesceContext> {
     let mut new_results = Vec::new();
@@ -1572,5 +2186,14 @@ fn truncate_coalesce_results(results: Vec<CoalesceContext>) -> Vec<CoalesceConte
     new_results
 }
 
+#[test]
+fn rank_correlation_test() {
+    assert_eq!(rank_correlation(&[1, 2, 3, 4], &[1, 2, 3, 4]), 1.);
+    assert_eq!(rank_correlation(&[1, 2, 3, 4], &[4, 3, 2, 1]), -1.);
+    // one swapped pair out of six
+    assert!((rank_correlation(&[1, 2, 3, 4], &[2, 1, 3, 4]) - 2. / 3.).abs() < 1e-9);
+    assert_eq!(rank_correlation(&[1, 2, 5], &[2, 1, 6]), -1., "missing items are ignored");
+}
+
 // TODO: add proximity test with max score
 // TODO: add sort tests?